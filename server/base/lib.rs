@@ -4,6 +4,7 @@
 
 pub mod clock;
 mod error;
+pub mod metrics;
 pub mod strutil;
 pub mod time;
 
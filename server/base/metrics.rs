@@ -0,0 +1,363 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Minimal metrics registry rendered in the
+//! [Prometheus text format](https://prometheus.io/docs/instrumenting/exposition_formats/).
+//!
+//! Metrics are registered in the global [`REGISTRY`] by name and label values; registering the
+//! same name and labels again returns the existing metric. Callers on hot paths should hold on to
+//! the returned `Arc` rather than looking up the metric each time.
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Histogram buckets suitable for latencies of operations which typically take milliseconds.
+pub const DURATION_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::default();
+}
+
+/// Atomically adds `delta` to a `f64` stored as bits in `a`.
+fn add_f64(a: &AtomicU64, delta: f64) {
+    let mut cur = a.load(Ordering::Relaxed);
+    loop {
+        let new = (f64::from_bits(cur) + delta).to_bits();
+        match a.compare_exchange_weak(cur, new, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(c) => cur = c,
+        }
+    }
+}
+
+/// A monotonically increasing count.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value which may go up or down.
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, v: f64) {
+        self.0.store(v.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn add(&self, delta: f64) {
+        add_f64(&self.0, delta);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// A distribution of observed values, such as latencies in seconds.
+pub struct Histogram {
+    /// Upper bounds of each bucket, in increasing order. There's an implicit `+Inf` bucket.
+    buckets: &'static [f64],
+
+    /// The number of observations in each bucket (not cumulative), plus one for `+Inf`.
+    counts: Box<[AtomicU64]>,
+
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Histogram {
+            buckets,
+            counts: (0..=buckets.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, v: f64) {
+        let i = self
+            .buckets
+            .iter()
+            .position(|&b| v <= b)
+            .unwrap_or(self.buckets.len());
+        self.counts[i].fetch_add(1, Ordering::Relaxed);
+        add_f64(&self.sum, v);
+    }
+
+    /// Observes a duration, in seconds.
+    pub fn observe_duration(&self, d: time::Duration) {
+        let secs = match d.num_nanoseconds() {
+            Some(n) => n as f64 / 1e9,
+            None => d.num_seconds() as f64,
+        };
+        self.observe(secs);
+    }
+}
+
+/// A function evaluated each time the registry is rendered, for values which are cheaper to
+/// compute on demand than to keep up to date, such as the age of the most recent frame.
+pub type GaugeFn = Box<dyn Fn() -> f64 + Send + Sync>;
+
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    GaugeFn(GaugeFn),
+    Histogram(Arc<Histogram>),
+}
+
+struct Family {
+    help: &'static str,
+    type_: &'static str,
+
+    /// Metrics by their label pairs.
+    metrics: BTreeMap<Vec<(&'static str, String)>, Metric>,
+}
+
+#[derive(Default)]
+pub struct Registry(Mutex<BTreeMap<&'static str, Family>>);
+
+impl Registry {
+    /// Calls `f` with the metric of the given name and labels, creating it via `new` if absent.
+    ///
+    /// Panics if the name is already registered with a different type; this is a programming
+    /// error.
+    fn with<R>(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        type_: &'static str,
+        labels: &[(&'static str, &str)],
+        new: impl FnOnce() -> Metric,
+        f: impl FnOnce(&mut Metric) -> R,
+    ) -> R {
+        let mut l = self.0.lock();
+        let family = l.entry(name).or_insert_with(|| Family {
+            help,
+            type_,
+            metrics: BTreeMap::new(),
+        });
+        assert_eq!(family.type_, type_, "metric {} re-registered", name);
+        let labels = labels.iter().map(|&(k, v)| (k, v.to_owned())).collect();
+        f(family.metrics.entry(labels).or_insert_with(new))
+    }
+
+    pub fn counter(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&'static str, &str)],
+    ) -> Arc<Counter> {
+        self.with(
+            name,
+            help,
+            "counter",
+            labels,
+            || Metric::Counter(Arc::new(Counter::default())),
+            |m| match m {
+                Metric::Counter(c) => c.clone(),
+                _ => unreachable!(),
+            },
+        )
+    }
+
+    pub fn gauge(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&'static str, &str)],
+    ) -> Arc<Gauge> {
+        self.with(
+            name,
+            help,
+            "gauge",
+            labels,
+            || Metric::Gauge(Arc::new(Gauge::default())),
+            |m| match m {
+                Metric::Gauge(g) => g.clone(),
+                _ => panic!("metric {} is a gauge function", name),
+            },
+        )
+    }
+
+    /// Registers a gauge whose value is computed by `f` at render time, replacing any existing
+    /// function with the same name and labels.
+    ///
+    /// `f` is called with the registry locked, so it must not register metrics.
+    pub fn gauge_fn(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&'static str, &str)],
+        f: GaugeFn,
+    ) {
+        self.with(
+            name,
+            help,
+            "gauge",
+            labels,
+            || Metric::GaugeFn(Box::new(|| f64::NAN)),
+            move |m| *m = Metric::GaugeFn(f),
+        )
+    }
+
+    pub fn histogram(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        buckets: &'static [f64],
+        labels: &[(&'static str, &str)],
+    ) -> Arc<Histogram> {
+        self.with(
+            name,
+            help,
+            "histogram",
+            labels,
+            || Metric::Histogram(Arc::new(Histogram::new(buckets))),
+            |m| match m {
+                Metric::Histogram(h) => h.clone(),
+                _ => unreachable!(),
+            },
+        )
+    }
+
+    /// Removes all metrics with the given label pair, such as those of a deleted stream.
+    pub fn remove_labeled(&self, key: &str, value: &str) {
+        let mut l = self.0.lock();
+        for family in l.values_mut() {
+            let doomed: Vec<_> = family
+                .metrics
+                .keys()
+                .filter(|labels| labels.iter().any(|(k, v)| *k == key && v == value))
+                .cloned()
+                .collect();
+            for labels in &doomed {
+                family.metrics.remove(labels);
+            }
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let l = self.0.lock();
+        let mut out = String::new();
+        for (name, family) in l.iter() {
+            if family.metrics.is_empty() {
+                continue;
+            }
+            writeln!(&mut out, "# HELP {} {}", name, family.help).unwrap();
+            writeln!(&mut out, "# TYPE {} {}", name, family.type_).unwrap();
+            for (labels, metric) in &family.metrics {
+                match metric {
+                    Metric::Counter(c) => write_sample(&mut out, name, labels, None, c.get()),
+                    Metric::Gauge(g) => write_sample(&mut out, name, labels, None, g.get()),
+                    Metric::GaugeFn(f) => write_sample(&mut out, name, labels, None, f()),
+                    Metric::Histogram(h) => {
+                        let bucket_name = format!("{}_bucket", name);
+                        let mut cum = 0;
+                        for (i, c) in h.counts.iter().enumerate() {
+                            cum += c.load(Ordering::Relaxed);
+                            let le = match h.buckets.get(i) {
+                                Some(b) => b.to_string(),
+                                None => "+Inf".to_owned(),
+                            };
+                            write_sample(&mut out, &bucket_name, labels, Some(&le), cum);
+                        }
+                        let sum = f64::from_bits(h.sum.load(Ordering::Relaxed));
+                        write_sample(&mut out, &format!("{}_sum", name), labels, None, sum);
+                        write_sample(&mut out, &format!("{}_count", name), labels, None, cum);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn write_sample<V: std::fmt::Display>(
+    out: &mut String,
+    name: &str,
+    labels: &[(&'static str, String)],
+    le: Option<&str>,
+    v: V,
+) {
+    out.push_str(name);
+    let mut sep = '{';
+    let le = le.map(|le| ("le", le));
+    for (k, lv) in labels.iter().map(|(k, lv)| (*k, &lv[..])).chain(le) {
+        write!(out, "{}{}=\"", sep, k).unwrap();
+        for c in lv.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '"' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+        sep = ',';
+    }
+    if sep == ',' {
+        out.push('}');
+    }
+    writeln!(out, " {}", v).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let r = Registry::default();
+        let c = r.counter("test_total", "A counter.", &[("stream", "a \"b\"")]);
+        c.inc();
+        c.inc_by(2);
+        assert_eq!(
+            r.counter("test_total", "A counter.", &[("stream", "a \"b\"")])
+                .get(),
+            3
+        );
+        r.gauge("test_gauge", "A gauge.", &[]).set(1.5);
+        let h = r.histogram("test_seconds", "A histogram.", &[0.1, 1.0], &[]);
+        h.observe(0.0625);
+        h.observe(0.5);
+        h.observe(4.0);
+        assert_eq!(
+            r.render(),
+            "# HELP test_gauge A gauge.\n\
+             # TYPE test_gauge gauge\n\
+             test_gauge 1.5\n\
+             # HELP test_seconds A histogram.\n\
+             # TYPE test_seconds histogram\n\
+             test_seconds_bucket{le=\"0.1\"} 1\n\
+             test_seconds_bucket{le=\"1\"} 2\n\
+             test_seconds_bucket{le=\"+Inf\"} 3\n\
+             test_seconds_sum 4.5625\n\
+             test_seconds_count 3\n\
+             # HELP test_total A counter.\n\
+             # TYPE test_total counter\n\
+             test_total{stream=\"a \\\"b\\\"\"} 3\n"
+        );
+        r.remove_labeled("stream", "a \"b\"");
+        assert!(!r.render().contains("test_total"));
+    }
+}
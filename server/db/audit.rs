@@ -0,0 +1,352 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Audit log of access and configuration changes.
+//! See the `audit_event` table within `schema.sql` for more information.
+//!
+//! Events are buffered in memory and written on the next database flush, much as session use is.
+//! This keeps a video view from requiring its own SQLite transaction.
+
+use crate::auth::FromSqlIpAddr;
+use crate::db::FromSqlUuid;
+use crate::recording;
+use failure::{bail, Error};
+use rusqlite::{named_params, params, Connection, Transaction};
+use std::net::IpAddr;
+use std::ops::Range;
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Login,
+    LoginFailed,
+    Logout,
+
+    /// Viewing recorded or live video (or a snapshot) of a camera.
+    View,
+
+    Export,
+    PostSignals,
+
+    /// Adding, changing, or deleting cameras, sample file directories, users, sessions, API
+    /// tokens, webhooks, or holds.
+    Config,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Login => "login",
+            Kind::LoginFailed => "login_failed",
+            Kind::Logout => "logout",
+            Kind::View => "view",
+            Kind::Export => "export",
+            Kind::PostSignals => "post_signals",
+            Kind::Config => "config",
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "login" => Kind::Login,
+            "login_failed" => Kind::LoginFailed,
+            "logout" => Kind::Logout,
+            "view" => Kind::View,
+            "export" => Kind::Export,
+            "post_signals" => Kind::PostSignals,
+            "config" => Kind::Config,
+            _ => bail!("no such audit event kind {:?}", s),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub time_sec: i64,
+    pub kind: Kind,
+
+    /// The user responsible, if any. For a failed login, `username` is the one supplied and
+    /// `user_id` is unset.
+    pub user_id: Option<i32>,
+    pub username: Option<String>,
+    pub peer_addr: Option<IpAddr>,
+
+    pub camera_uuid: Option<Uuid>,
+
+    /// The time range of video viewed or exported, if known.
+    pub time_90k: Option<Range<recording::Time>>,
+
+    /// Free-form details, such as the error from a failed login or the change made.
+    pub detail: Option<String>,
+}
+
+impl Event {
+    pub fn new(time_sec: i64, kind: Kind) -> Self {
+        Event {
+            time_sec,
+            kind,
+            user_id: None,
+            username: None,
+            peer_addr: None,
+            camera_uuid: None,
+            time_90k: None,
+            detail: None,
+        }
+    }
+}
+
+/// Criteria for `list`. Unset fields match all events.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    /// Events at or after this time.
+    pub start_time_sec: Option<i64>,
+
+    /// Events before this time.
+    pub end_time_sec: Option<i64>,
+
+    pub kind: Option<Kind>,
+    pub username: Option<String>,
+    pub camera_uuid: Option<Uuid>,
+
+    /// The maximum number of events to return.
+    pub limit: Option<usize>,
+}
+
+impl Filter {
+    fn matches(&self, e: &Event) -> bool {
+        self.start_time_sec.map(|t| e.time_sec >= t).unwrap_or(true)
+            && self.end_time_sec.map(|t| e.time_sec < t).unwrap_or(true)
+            && self.kind.map(|k| e.kind == k).unwrap_or(true)
+            && (self.username.is_none() || self.username == e.username)
+            && (self.camera_uuid.is_none() || self.camera_uuid == e.camera_uuid)
+    }
+}
+
+fn addr_bytes(addr: Option<IpAddr>) -> Option<Vec<u8>> {
+    match addr {
+        None => None,
+        Some(IpAddr::V4(a)) => Some(a.octets().to_vec()),
+        Some(IpAddr::V6(a)) => Some(a.octets().to_vec()),
+    }
+}
+
+/// Lists events stored in the database which match `filter`, most recent first.
+/// This doesn't include events which haven't yet been flushed; see `State::list`.
+pub fn list(conn: &Connection, filter: &Filter) -> Result<Vec<Event>, Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        select
+            time_sec,
+            kind,
+            user_id,
+            username,
+            peer_addr,
+            camera_uuid,
+            start_time_90k,
+            end_time_90k,
+            detail
+        from
+            audit_event
+        where
+            (:start_time_sec is null or time_sec >= :start_time_sec) and
+            (:end_time_sec is null or time_sec < :end_time_sec) and
+            (:kind is null or kind = :kind) and
+            (:username is null or username = :username) and
+            (:camera_uuid is null or camera_uuid = :camera_uuid)
+        order by time_sec desc, id desc
+        limit :limit
+        "#,
+    )?;
+    let camera_uuid = filter.camera_uuid.as_ref().map(|u| &u.as_bytes()[..]);
+    let limit = filter.limit.map(|l| l as i64).unwrap_or(-1);
+    let mut rows = stmt.query(named_params! {
+        ":start_time_sec": &filter.start_time_sec,
+        ":end_time_sec": &filter.end_time_sec,
+        ":kind": &filter.kind.map(Kind::as_str),
+        ":username": &filter.username,
+        ":camera_uuid": &camera_uuid,
+        ":limit": &limit,
+    })?;
+    let mut events = Vec::new();
+    while let Some(row) = rows.next()? {
+        let kind: String = row.get(1)?;
+        let peer_addr: FromSqlIpAddr = row.get(4)?;
+        let camera_uuid: Option<FromSqlUuid> = row.get(5)?;
+        let start: Option<i64> = row.get(6)?;
+        let end: Option<i64> = row.get(7)?;
+        events.push(Event {
+            time_sec: row.get(0)?,
+            kind: Kind::from_str(&kind)?,
+            user_id: row.get(2)?,
+            username: row.get(3)?,
+            peer_addr: peer_addr.0,
+            camera_uuid: camera_uuid.map(|u| u.0),
+            time_90k: match (start, end) {
+                (Some(s), Some(e)) => Some(recording::Time(s)..recording::Time(e)),
+                _ => None,
+            },
+            detail: row.get(8)?,
+        });
+    }
+    Ok(events)
+}
+
+pub(crate) struct State {
+    /// Events which haven't yet been flushed to the database.
+    pending: Vec<Event>,
+
+    /// Events older than this are deleted on flush. If unset, events are kept forever.
+    max_age_sec: Option<i64>,
+}
+
+impl State {
+    pub fn init(conn: &Connection) -> Result<Self, Error> {
+        let max_age_sec: Option<i64> =
+            conn.query_row("select audit_max_age_sec from meta", params![], |row| {
+                row.get(0)
+            })?;
+        Ok(State {
+            pending: Vec::new(),
+            max_age_sec,
+        })
+    }
+
+    pub fn add(&mut self, event: Event) {
+        self.pending.push(event);
+    }
+
+    /// Lists events matching `filter`, most recent first, including ones not yet flushed.
+    pub fn list(&self, conn: &Connection, filter: &Filter) -> Result<Vec<Event>, Error> {
+        let mut events: Vec<Event> = self
+            .pending
+            .iter()
+            .rev()
+            .filter(|e| filter.matches(e))
+            .cloned()
+            .collect();
+        events.extend(list(conn, filter)?);
+        if let Some(l) = filter.limit {
+            events.truncate(l);
+        }
+        Ok(events)
+    }
+
+    /// Writes pending events and deletes ones older than the maximum age as of `now_sec`.
+    ///
+    /// The caller is expected to call `post_flush` afterward if the transaction is
+    /// successfully committed.
+    pub fn flush(&self, tx: &Transaction, now_sec: i64) -> Result<(), Error> {
+        let mut stmt = tx.prepare_cached(
+            r#"
+            insert into audit_event (time_sec,  kind,  user_id,  username,  peer_addr,
+                                     camera_uuid,  start_time_90k,  end_time_90k,  detail)
+                             values (:time_sec, :kind, :user_id, :username, :peer_addr,
+                                     :camera_uuid, :start_time_90k, :end_time_90k, :detail)
+            "#,
+        )?;
+        for e in &self.pending {
+            let camera_uuid = e.camera_uuid.as_ref().map(|u| &u.as_bytes()[..]);
+            stmt.execute(named_params! {
+                ":time_sec": &e.time_sec,
+                ":kind": &e.kind.as_str(),
+                ":user_id": &e.user_id,
+                ":username": &e.username,
+                ":peer_addr": &addr_bytes(e.peer_addr),
+                ":camera_uuid": &camera_uuid,
+                ":start_time_90k": &e.time_90k.as_ref().map(|r| r.start.0),
+                ":end_time_90k": &e.time_90k.as_ref().map(|r| r.end.0),
+                ":detail": &e.detail,
+            })?;
+        }
+        if let Some(a) = self.max_age_sec {
+            tx.execute(
+                "delete from audit_event where time_sec < ?",
+                params![now_sec - a],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Marks that the previous `flush` was completed successfully.
+    pub fn post_flush(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::testutil;
+
+    #[test]
+    fn round_trip() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        conn.execute("update meta set audit_max_age_sec = 100", params![])
+            .unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let camera_uuid = Uuid::new_v4();
+        let mut e = Event::new(1000, Kind::View);
+        e.user_id = Some(1);
+        e.username = Some("slamb".to_owned());
+        e.peer_addr = Some(IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 2)));
+        e.camera_uuid = Some(camera_uuid);
+        e.time_90k = Some(recording::Time(90_000)..recording::Time(180_000));
+        state.add(e);
+        state.add(Event::new(1001, Kind::LoginFailed));
+
+        // Pending events are listed before they're flushed.
+        let all = Filter::default();
+        let events = state.list(&conn, &all).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, Kind::LoginFailed);
+
+        let tx = conn.transaction().unwrap();
+        state.flush(&tx, 1050).unwrap();
+        tx.commit().unwrap();
+        state.post_flush();
+        let events = state
+            .list(
+                &conn,
+                &Filter {
+                    camera_uuid: Some(camera_uuid),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        let e = &events[0];
+        assert_eq!(e.kind, Kind::View);
+        assert_eq!(e.username.as_deref(), Some("slamb"));
+        assert_eq!(e.peer_addr, Some("192.168.1.2".parse().unwrap()));
+        assert_eq!(
+            e.time_90k,
+            Some(recording::Time(90_000)..recording::Time(180_000))
+        );
+        let events = state
+            .list(
+                &conn,
+                &Filter {
+                    end_time_sec: Some(1001),
+                    kind: Some(Kind::LoginFailed),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert!(events.is_empty());
+
+        // Both events age out at the next flush.
+        let tx = conn.transaction().unwrap();
+        state.flush(&tx, 1102).unwrap();
+        tx.commit().unwrap();
+        assert!(state.list(&conn, &all).unwrap().is_empty());
+    }
+}
@@ -5,6 +5,7 @@
 //! Authentication schema: users and sessions/cookies.
 
 use crate::schema::Permissions;
+use crate::totp;
 use base::{bail_t, format_err_t, strutil, ErrorKind, ResultExt};
 use failure::{bail, format_err, Error};
 use fnv::FnvHashMap;
@@ -49,9 +50,24 @@ pub struct User {
     pub unix_uid: Option<i32>,
     pub permissions: Permissions,
 
+    /// If set, a TOTP code (or recovery code) is required in addition to the password.
+    totp_secret: Option<Vec<u8>>,
+
+    /// Hashes of the unused recovery codes, as returned by `totp::hash_recovery_code`.
+    totp_recovery_codes: Vec<[u8; 32]>,
+
+    /// The time step of the most recently accepted TOTP code, which can't be accepted again.
+    totp_last_step: Option<i64>,
+
+    pub totp_failure_count: i64,
+
+    /// A secret from `State::begin_totp_enrollment` which hasn't yet been confirmed.
+    /// This isn't persisted; enrollment starts over on restart.
+    pending_totp_secret: Option<Vec<u8>>,
+
     /// True iff this `User` has changed since the last flush.
-    /// Only a couple things are flushed lazily: `password_failure_count` and (on upgrade to a new
-    /// algorithm) `password_hash`.
+    /// Only a few things are flushed lazily: `password_failure_count`, `totp_failure_count`, and
+    /// (on upgrade to a new algorithm) `password_hash`.
     dirty: bool,
 }
 
@@ -64,15 +80,115 @@ impl User {
             set_password_hash: None,
             unix_uid: self.unix_uid,
             permissions: self.permissions.clone(),
+            clear_totp: false,
         }
     }
 
     pub fn has_password(&self) -> bool {
         self.password_hash.is_some()
     }
-    fn disabled(&self) -> bool {
+    pub fn disabled(&self) -> bool {
         (self.flags & UserFlag::Disabled as i32) != 0
     }
+    pub fn totp_enrolled(&self) -> bool {
+        self.totp_secret.is_some()
+    }
+    pub fn totp_recovery_codes_remaining(&self) -> usize {
+        self.totp_recovery_codes.len()
+    }
+
+    /// Checks that this user is enabled and has the given password. Updates the failure count
+    /// or (on upgrade to a new algorithm) the hash; these are flushed lazily.
+    fn check_password(&mut self, password: &str) -> Result<(), Error> {
+        if self.disabled() {
+            bail!("user {:?} is disabled", self.username);
+        }
+        let new_hash = {
+            let hash = match self.password_hash.as_ref() {
+                None => bail!("no password set for user {:?}", self.username),
+                Some(h) => h,
+            };
+            let c = Arc::clone(&PASTA_CONFIG.lock());
+            match c.verify_password_update_hash(hash, password) {
+                libpasta::HashUpdate::Failed => {
+                    self.dirty = true;
+                    self.password_failure_count += 1;
+                    bail!("incorrect password for user {:?}", self.username);
+                }
+                libpasta::HashUpdate::Verified(new_pwd) => new_pwd,
+            }
+        };
+        if let Some(h) = new_hash {
+            self.password_hash = Some(h);
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Checks the given TOTP code or recovery code, if this user is enrolled in TOTP.
+    /// Accepting a code is persisted immediately so that it can't be reused; the failure count is
+    /// flushed lazily.
+    fn check_totp(
+        &mut self,
+        conn: &Connection,
+        code: Option<&str>,
+        when_sec: Option<i64>,
+    ) -> Result<(), Error> {
+        let secret = match self.totp_secret.as_ref() {
+            None => return Ok(()),
+            Some(s) => s,
+        };
+        let code = match code {
+            None => bail!("TOTP code required for user {:?}", self.username),
+            Some(c) => c,
+        };
+        let when_sec = when_sec.ok_or_else(|| format_err!("TOTP code requires request time"))?;
+        if let Some(step) = totp::verify(secret, code, when_sec, self.totp_last_step) {
+            conn.execute(
+                "update user set totp_last_step = ? where id = ?",
+                params![step, self.id],
+            )?;
+            self.totp_last_step = Some(step);
+            return Ok(());
+        }
+        let hash = totp::hash_recovery_code(code);
+        if let Some(i) = self.totp_recovery_codes.iter().position(|h| h == &hash) {
+            let mut remaining = self.totp_recovery_codes.clone();
+            remaining.remove(i);
+            conn.execute(
+                "update user set totp_recovery_codes = ? where id = ?",
+                params![recovery_codes_blob(&remaining), self.id],
+            )?;
+            self.totp_recovery_codes = remaining;
+            info!(
+                "user {:?} used a TOTP recovery code; {} remain",
+                self.username,
+                self.totp_recovery_codes.len()
+            );
+            return Ok(());
+        }
+        self.dirty = true;
+        self.totp_failure_count += 1;
+        bail!("incorrect TOTP code for user {:?}", self.username);
+    }
+}
+
+fn recovery_codes_blob(codes: &[[u8; 32]]) -> Vec<u8> {
+    codes.iter().flat_map(|c| c.iter().copied()).collect()
+}
+
+fn parse_recovery_codes(blob: &[u8]) -> Result<Vec<[u8; 32]>, Error> {
+    if blob.len() % 32 != 0 {
+        bail!("bad totp_recovery_codes length {}", blob.len());
+    }
+    Ok(blob
+        .chunks(32)
+        .map(|c| {
+            let mut h = [0u8; 32];
+            h.copy_from_slice(c);
+            h
+        })
+        .collect())
 }
 
 /// A change to a user.
@@ -89,6 +205,7 @@ pub struct UserChange {
     set_password_hash: Option<Option<String>>,
     pub unix_uid: Option<i32>,
     pub permissions: Permissions,
+    clear_totp: bool,
 }
 
 impl UserChange {
@@ -100,6 +217,7 @@ impl UserChange {
             set_password_hash: None,
             unix_uid: None,
             permissions: Permissions::default(),
+            clear_totp: false,
         }
     }
 
@@ -115,6 +233,15 @@ impl UserChange {
     pub fn disable(&mut self) {
         self.flags |= UserFlag::Disabled as i32;
     }
+
+    pub fn enable(&mut self) {
+        self.flags &= !(UserFlag::Disabled as i32);
+    }
+
+    /// Removes TOTP enrollment, so that the password alone suffices to log in.
+    pub fn clear_totp(&mut self) {
+        self.clear_totp = true;
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -148,7 +275,7 @@ impl AsRef<[u8]> for IpAddrBuf {
     }
 }
 
-pub struct FromSqlIpAddr(Option<IpAddr>);
+pub struct FromSqlIpAddr(pub Option<IpAddr>);
 
 impl rusqlite::types::FromSql for FromSqlIpAddr {
     fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
@@ -180,6 +307,9 @@ pub enum SessionFlag {
     Secure = 2,
     SameSite = 4,
     SameSiteStrict = 8,
+
+    /// An API token, presented via an `Authorization: Bearer` header rather than a cookie.
+    ApiToken = 16,
 }
 
 impl FromStr for SessionFlag {
@@ -200,18 +330,20 @@ impl FromStr for SessionFlag {
 pub enum RevocationReason {
     LoggedOut = 1,
     AlgorithmChange = 2,
+    RevokedByAdmin = 3,
+    RevokedByUser = 4,
 }
 
 #[derive(Debug, Default)]
 pub struct Session {
-    user_id: i32,
+    pub user_id: i32,
     flags: i32, // bitmask of SessionFlag enum values
     domain: Option<Vec<u8>>,
-    description: Option<String>,
+    pub description: Option<String>,
     seed: Seed,
 
     creation_password_id: Option<i32>,
-    creation: Request,
+    pub creation: Request,
 
     revocation: Request,
     revocation_reason: Option<i32>, // see RevocationReason enum
@@ -219,12 +351,17 @@ pub struct Session {
 
     pub permissions: Permissions,
 
-    last_use: Request,
-    use_count: i32,
+    pub last_use: Request,
+    pub use_count: i32,
+    pub expiration_time_sec: Option<i64>,
     dirty: bool,
 }
 
 impl Session {
+    pub fn api_token(&self) -> bool {
+        (self.flags & SessionFlag::ApiToken as i32) != 0
+    }
+
     pub fn csrf(&self) -> SessionHash {
         let r = blake3::keyed_hash(&self.seed.0, b"csrf");
         let mut h = SessionHash([0u8; 24]);
@@ -359,7 +496,11 @@ impl State {
                 password_id,
                 password_failure_count,
                 unix_uid,
-                permissions
+                permissions,
+                totp_secret,
+                totp_recovery_codes,
+                totp_last_step,
+                totp_failure_count
             from
                 user
             "#,
@@ -370,6 +511,7 @@ impl State {
             let name: String = row.get(1)?;
             let mut permissions = Permissions::new();
             permissions.merge_from_bytes(row.get_ref(7)?.as_blob()?)?;
+            let totp_recovery_codes = parse_recovery_codes(row.get_ref(9)?.as_blob()?)?;
             state.users_by_id.insert(
                 id,
                 User {
@@ -382,6 +524,11 @@ impl State {
                     unix_uid: row.get(6)?,
                     dirty: false,
                     permissions,
+                    totp_secret: row.get(8)?,
+                    totp_recovery_codes,
+                    totp_last_step: row.get(10)?,
+                    totp_failure_count: row.get(11)?,
+                    pending_totp_secret: None,
                 },
             );
             state.users_by_name.insert(name, id);
@@ -417,7 +564,11 @@ impl State {
                 password_failure_count = :password_failure_count,
                 flags = :flags,
                 unix_uid = :unix_uid,
-                permissions = :permissions
+                permissions = :permissions,
+                totp_secret = :totp_secret,
+                totp_recovery_codes = :totp_recovery_codes,
+                totp_last_step = :totp_last_step,
+                totp_failure_count = :totp_failure_count
             where
                 id = :id
             "#,
@@ -439,6 +590,17 @@ impl State {
                 .permissions
                 .write_to_bytes()
                 .expect("proto3->vec is infallible");
+            let u = e.get();
+            let (tsecret, tcodes, tstep, tcount) = if change.clear_totp {
+                (None, Vec::new(), None, 0)
+            } else {
+                (
+                    u.totp_secret.as_ref(),
+                    recovery_codes_blob(&u.totp_recovery_codes),
+                    u.totp_last_step,
+                    u.totp_failure_count,
+                )
+            };
             stmt.execute(named_params! {
                 ":username": &change.username[..],
                 ":password_hash": phash,
@@ -448,6 +610,10 @@ impl State {
                 ":unix_uid": &change.unix_uid,
                 ":id": &id,
                 ":permissions": &permissions,
+                ":totp_secret": &tsecret,
+                ":totp_recovery_codes": &tcodes,
+                ":totp_last_step": &tstep,
+                ":totp_failure_count": &tcount,
             })?;
         }
         let u = e.into_mut();
@@ -460,6 +626,13 @@ impl State {
         u.flags = change.flags;
         u.unix_uid = change.unix_uid;
         u.permissions = change.permissions;
+        if change.clear_totp {
+            u.totp_secret = None;
+            u.totp_recovery_codes.clear();
+            u.totp_last_step = None;
+            u.totp_failure_count = 0;
+            u.pending_totp_secret = None;
+        }
         Ok(u)
     }
 
@@ -499,6 +672,11 @@ impl State {
             unix_uid: change.unix_uid,
             dirty: false,
             permissions: change.permissions,
+            totp_secret: None,
+            totp_recovery_codes: Vec::new(),
+            totp_last_step: None,
+            totp_failure_count: 0,
+            pending_totp_secret: None,
         }))
     }
 
@@ -526,12 +704,44 @@ impl State {
         })
     }
 
+    /// Checks the given user's password, returning the user's permissions if it's correct and
+    /// the user is enabled.
+    ///
+    /// This is for protocols (such as RTSP) which have no way to supply a TOTP code, so it
+    /// refuses users enrolled in TOTP; they must use an API token instead. As with
+    /// `login_by_password`, an incorrect password increments the user's failure count.
+    pub fn verify_password(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> Result<Permissions, Error> {
+        let id = self
+            .users_by_name
+            .get(username)
+            .ok_or_else(|| format_err!("no such user {:?}", username))?;
+        let u = self
+            .users_by_id
+            .get_mut(id)
+            .expect("users_by_name implies users_by_id");
+        u.check_password(password)?;
+        if u.totp_enrolled() {
+            bail!(
+                "user {:?} is enrolled in TOTP; password alone is insufficient",
+                u.username
+            );
+        }
+        Ok(u.permissions.clone())
+    }
+
+    /// Logs in with the given password and, if the user is enrolled in TOTP, the given TOTP code
+    /// or recovery code.
     pub fn login_by_password(
         &mut self,
         conn: &Connection,
         req: Request,
         username: &str,
         password: String,
+        totp_code: Option<&str>,
         domain: Option<Vec<u8>>,
         session_flags: i32,
     ) -> Result<(RawSessionId, &Session), Error> {
@@ -543,28 +753,8 @@ impl State {
             .users_by_id
             .get_mut(id)
             .expect("users_by_name implies users_by_id");
-        if u.disabled() {
-            bail!("user {:?} is disabled", username);
-        }
-        let new_hash = {
-            let hash = match u.password_hash.as_ref() {
-                None => bail!("no password set for user {:?}", username),
-                Some(h) => h,
-            };
-            let c = Arc::clone(&PASTA_CONFIG.lock());
-            match c.verify_password_update_hash(hash, &password) {
-                libpasta::HashUpdate::Failed => {
-                    u.dirty = true;
-                    u.password_failure_count += 1;
-                    bail!("incorrect password for user {:?}", username);
-                }
-                libpasta::HashUpdate::Verified(new_pwd) => new_pwd,
-            }
-        };
-        if let Some(h) = new_hash {
-            u.password_hash = Some(h);
-            u.dirty = true;
-        }
+        u.check_password(&password)?;
+        u.check_totp(conn, totp_code, req.when_sec)?;
         let password_id = u.password_id;
         State::make_session_int(
             &self.rand,
@@ -574,11 +764,92 @@ impl State {
             domain,
             Some(password_id),
             session_flags,
+            None,
+            None,
             &mut self.sessions,
             u.permissions.clone(),
         )
     }
 
+    /// Starts TOTP enrollment for the given user, returning a new secret as a `otpauth://` URI.
+    /// Enrollment takes effect once confirmed via `confirm_totp_enrollment`.
+    pub fn begin_totp_enrollment(&mut self, uid: i32) -> Result<String, Error> {
+        let u = self
+            .users_by_id
+            .get_mut(&uid)
+            .ok_or_else(|| format_err!("no such uid {:?}", uid))?;
+        if u.totp_enrolled() {
+            bail!("user {:?} is already enrolled in TOTP", u.username);
+        }
+        let mut secret = vec![0u8; totp::SECRET_LEN];
+        self.rand.fill(&mut secret).unwrap();
+        let uri = totp::uri(&u.username, &secret);
+        u.pending_totp_secret = Some(secret);
+        Ok(uri)
+    }
+
+    /// Completes TOTP enrollment given a code for the secret from `begin_totp_enrollment`.
+    /// Returns the new recovery codes, which are stored only as hashes.
+    pub fn confirm_totp_enrollment(
+        &mut self,
+        conn: &Connection,
+        uid: i32,
+        code: &str,
+        when_sec: i64,
+    ) -> Result<Vec<String>, Error> {
+        let u = self
+            .users_by_id
+            .get_mut(&uid)
+            .ok_or_else(|| format_err!("no such uid {:?}", uid))?;
+        let secret = match u.pending_totp_secret.as_ref() {
+            None => bail!("no TOTP enrollment in progress for user {:?}", u.username),
+            Some(s) => s,
+        };
+        let step = totp::verify(secret, code, when_sec, None)
+            .ok_or_else(|| format_err!("incorrect TOTP code for user {:?}", u.username))?;
+        let codes: Vec<String> = (0..totp::RECOVERY_CODES)
+            .map(|_| totp::new_recovery_code(&self.rand))
+            .collect();
+        let hashes: Vec<[u8; 32]> = codes.iter().map(|c| totp::hash_recovery_code(c)).collect();
+        conn.execute(
+            r#"
+            update user
+            set
+                totp_secret = ?,
+                totp_recovery_codes = ?,
+                totp_last_step = ?,
+                totp_failure_count = 0
+            where
+                id = ?
+            "#,
+            params![secret, recovery_codes_blob(&hashes), step, uid],
+        )?;
+        u.totp_secret = u.pending_totp_secret.take();
+        u.totp_recovery_codes = hashes;
+        u.totp_last_step = Some(step);
+        u.totp_failure_count = 0;
+        Ok(codes)
+    }
+
+    /// Checks a TOTP code or recovery code for a user enrolled in TOTP, as proof of the second
+    /// factor before removing the enrollment. A recovery code accepted here is used up.
+    pub fn check_totp_code(
+        &mut self,
+        conn: &Connection,
+        uid: i32,
+        code: &str,
+        when_sec: i64,
+    ) -> Result<(), Error> {
+        let u = self
+            .users_by_id
+            .get_mut(&uid)
+            .ok_or_else(|| format_err!("no such uid {:?}", uid))?;
+        if !u.totp_enrolled() {
+            bail!("user {:?} isn't enrolled in TOTP", u.username);
+        }
+        u.check_totp(conn, Some(code), Some(when_sec))
+    }
+
     /// Makes a session directly (no password required).
     pub fn make_session<'s>(
         &'s mut self,
@@ -604,6 +875,40 @@ impl State {
             domain,
             None,
             flags,
+            None,
+            None,
+            &mut self.sessions,
+            permissions,
+        )
+    }
+
+    /// Makes a named API token for the given user, which expires at the given time (if any).
+    pub fn make_api_token<'s>(
+        &'s mut self,
+        conn: &Connection,
+        creation: Request,
+        uid: i32,
+        name: String,
+        expiration_time_sec: Option<i64>,
+        permissions: Permissions,
+    ) -> Result<(RawSessionId, &'s Session), Error> {
+        let u = self
+            .users_by_id
+            .get_mut(&uid)
+            .ok_or_else(|| format_err!("no such uid {:?}", uid))?;
+        if u.disabled() {
+            bail!("user is disabled");
+        }
+        State::make_session_int(
+            &self.rand,
+            conn,
+            creation,
+            u,
+            None,
+            None,
+            SessionFlag::ApiToken as i32,
+            Some(name),
+            expiration_time_sec,
             &mut self.sessions,
             permissions,
         )
@@ -617,6 +922,8 @@ impl State {
         domain: Option<Vec<u8>>,
         creation_password_id: Option<i32>,
         flags: i32,
+        description: Option<String>,
+        expiration_time_sec: Option<i64>,
         sessions: &'s mut FnvHashMap<SessionHash, Session>,
         permissions: Permissions,
     ) -> Result<(RawSessionId, &'s Session), Error> {
@@ -628,13 +935,13 @@ impl State {
         let mut stmt = conn.prepare_cached(
             r#"
             insert into user_session (session_id_hash,  user_id,  seed,  flags,  domain,
-                                      creation_password_id,  creation_time_sec,
+                                      description,  creation_password_id,  creation_time_sec,
                                       creation_user_agent,  creation_peer_addr,
-                                      permissions)
+                                      permissions,  expiration_time_sec)
                               values (:session_id_hash, :user_id, :seed, :flags, :domain,
-                                      :creation_password_id, :creation_time_sec,
+                                      :description, :creation_password_id, :creation_time_sec,
                                       :creation_user_agent, :creation_peer_addr,
-                                      :permissions)
+                                      :permissions, :expiration_time_sec)
             "#,
         )?;
         let addr = creation.addr_buf();
@@ -648,11 +955,13 @@ impl State {
             ":seed": &seed[..],
             ":flags": &flags,
             ":domain": &domain,
+            ":description": &description,
             ":creation_password_id": &creation_password_id,
             ":creation_time_sec": &creation.when_sec,
             ":creation_user_agent": &creation.user_agent,
             ":creation_peer_addr": &addr,
             ":permissions": &permissions_blob,
+            ":expiration_time_sec": &expiration_time_sec,
         })?;
         let e = match sessions.entry(hash) {
             ::std::collections::hash_map::Entry::Occupied(_) => panic!("duplicate session hash!"),
@@ -662,10 +971,12 @@ impl State {
             user_id: user.id,
             flags,
             domain,
+            description,
             creation_password_id,
             creation,
             seed: Seed(seed),
             permissions,
+            expiration_time_sec,
             ..Default::default()
         });
         Ok((session_id, session))
@@ -696,6 +1007,11 @@ impl State {
         if let Some(r) = s.revocation_reason {
             bail_t!(Unauthenticated, "session is no longer valid (reason={})", r);
         }
+        if let (Some(e), Some(now)) = (s.expiration_time_sec, req.when_sec) {
+            if now >= e {
+                bail_t!(Unauthenticated, "session expired at {}", e);
+            }
+        }
         s.last_use = req;
         s.use_count += 1;
         s.dirty = true;
@@ -705,6 +1021,50 @@ impl State {
         Ok((s, u))
     }
 
+    /// Lists sessions which haven't been revoked, along with their users, optionally only those
+    /// of the given user. Sessions are returned in order of creation.
+    pub fn list_sessions(
+        &mut self,
+        conn: &Connection,
+        user_id: Option<i32>,
+    ) -> Result<Vec<(SessionHash, &Session, &User)>, Error> {
+        let mut stmt = conn.prepare_cached(
+            r#"
+            select
+                session_id_hash
+            from
+                user_session
+            where
+                revocation_reason is null
+                and (:user_id is null or user_id = :user_id)
+            order by
+                creation_time_sec
+            "#,
+        )?;
+        let mut rows = stmt.query(named_params! {":user_id": &user_id})?;
+        let mut hashes = Vec::new();
+        while let Some(row) = rows.next()? {
+            let mut hash = SessionHash::default();
+            hash.0.copy_from_slice(row.get_ref(0)?.as_blob()?);
+            hashes.push(hash);
+        }
+        for hash in &hashes {
+            if let ::std::collections::hash_map::Entry::Vacant(e) = self.sessions.entry(*hash) {
+                e.insert(lookup_session(conn, hash)?);
+            }
+        }
+        let mut out = Vec::with_capacity(hashes.len());
+        for h in hashes {
+            let s = self.sessions.get(&h).unwrap();
+            let u = self
+                .users_by_id
+                .get(&s.user_id)
+                .ok_or_else(|| format_err!("session references nonexistent user!"))?;
+            out.push((h, s, u));
+        }
+        Ok(out)
+    }
+
     pub fn revoke_session(
         &mut self,
         conn: &Connection,
@@ -757,7 +1117,8 @@ impl State {
             update user
             set
                 password_failure_count = :password_failure_count,
-                password_hash = :password_hash
+                password_hash = :password_hash,
+                totp_failure_count = :totp_failure_count
             where
                 id = :id
             "#,
@@ -785,10 +1146,11 @@ impl State {
             u_stmt.execute(named_params! {
                 ":password_failure_count": &u.password_failure_count,
                 ":password_hash": &u.password_hash,
+                ":totp_failure_count": &u.totp_failure_count,
                 ":id": &id,
             })?;
         }
-        for s in self.sessions.values() {
+        for (hash, s) in &self.sessions {
             if !s.dirty {
                 continue;
             }
@@ -799,6 +1161,7 @@ impl State {
                 ":last_use_user_agent": &s.last_use.user_agent,
                 ":last_use_peer_addr": &addr,
                 ":use_count": &s.use_count,
+                ":hash": &hash.0[..],
             })?;
         }
         Ok(())
@@ -840,7 +1203,8 @@ fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, base
             last_use_user_agent,
             last_use_peer_addr,
             use_count,
-            permissions
+            permissions,
+            expiration_time_sec
         from
             user_session
         where
@@ -892,6 +1256,7 @@ fn lookup_session(conn: &Connection, hash: &SessionHash) -> Result<Session, base
             addr: last_use_addr.0,
         },
         use_count: row.get(17).err_kind(ErrorKind::Internal)?,
+        expiration_time_sec: row.get(19).err_kind(ErrorKind::Internal)?,
         dirty: false,
         permissions,
     })
@@ -938,6 +1303,7 @@ mod tests {
                 req.clone(),
                 "slamb",
                 "hunter2".to_owned(),
+                None,
                 Some(b"nvr.example.com".to_vec()),
                 0,
             )
@@ -951,6 +1317,7 @@ mod tests {
                 req.clone(),
                 "slamb",
                 "hunter3".to_owned(),
+                None,
                 Some(b"nvr.example.com".to_vec()),
                 0,
             )
@@ -963,6 +1330,7 @@ mod tests {
                     req.clone(),
                     "slamb",
                     "hunter2".to_owned(),
+                    None,
                     Some(b"nvr.example.com".to_vec()),
                     0,
                 )
@@ -1030,6 +1398,7 @@ mod tests {
                 req.clone(),
                 "slamb",
                 "hunter2".to_owned(),
+                None,
                 Some(b"nvr.example.com".to_vec()),
                 0,
             )
@@ -1060,6 +1429,117 @@ mod tests {
         );
     }
 
+    #[test]
+    fn list_sessions() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let req = Request {
+            when_sec: Some(42),
+            addr: None,
+            user_agent: Some(b"some ua".to_vec()),
+        };
+        let mut uids = Vec::new();
+        for name in &["slamb", "other"] {
+            let c = UserChange::add_user((*name).to_owned());
+            uids.push(state.apply(&conn, c).unwrap().id);
+        }
+        let mut sids = Vec::new();
+        for (i, &uid) in [uids[0], uids[0], uids[1]].iter().enumerate() {
+            let creation = Request {
+                when_sec: Some(42 + i as i64),
+                ..req.clone()
+            };
+            let (sid, _) = state
+                .make_session(&conn, creation, uid, None, 0, Permissions::default())
+                .unwrap();
+            sids.push(sid);
+        }
+        state
+            .revoke_session(
+                &conn,
+                RevocationReason::RevokedByAdmin,
+                None,
+                req.clone(),
+                &sids[0].hash(),
+            )
+            .unwrap();
+
+        // Reload so that sessions must be read from the database.
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        let all: Vec<SessionHash> = state
+            .list_sessions(&conn, None)
+            .unwrap()
+            .into_iter()
+            .map(|(h, _, _)| h)
+            .collect();
+        assert_eq!(all, vec![sids[1].hash(), sids[2].hash()]);
+        let mine = state.list_sessions(&conn, Some(uids[0])).unwrap();
+        assert_eq!(mine.len(), 1);
+        assert_eq!(mine[0].0, sids[1].hash());
+        assert_eq!(mine[0].2.id, uids[0]);
+        assert_eq!(mine[0].1.creation.when_sec, Some(43));
+    }
+
+    #[test]
+    fn api_token() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let req = |when_sec| Request {
+            when_sec: Some(when_sec),
+            addr: Some(::std::net::IpAddr::V4(::std::net::Ipv4Addr::new(
+                192, 168, 1, 2,
+            ))),
+            user_agent: Some(b"some ua".to_vec()),
+        };
+        let uid = {
+            let c = UserChange::add_user("slamb".to_owned());
+            state.apply(&conn, c).unwrap().id
+        };
+        let mut permissions = Permissions::new();
+        permissions.view_video = true;
+        let (sid, s) = state
+            .make_api_token(
+                &conn,
+                req(42),
+                uid,
+                "home assistant".to_owned(),
+                Some(100),
+                permissions,
+            )
+            .unwrap();
+        assert!(s.api_token());
+        assert_eq!(s.description.as_deref(), Some("home assistant"));
+        let (s, _) = state
+            .authenticate_session(&conn, req(50), &sid.hash())
+            .unwrap();
+        assert!(s.permissions.view_video);
+
+        // The last use should be saved on flush.
+        let tx = conn.transaction().unwrap();
+        state.flush(&tx).unwrap();
+        tx.commit().unwrap();
+        state.post_flush();
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        let sessions = state.list_sessions(&conn, Some(uid)).unwrap();
+        assert_eq!(sessions.len(), 1);
+        let s = sessions[0].1;
+        assert!(s.api_token());
+        assert_eq!(s.last_use.when_sec, Some(50));
+        assert_eq!(s.last_use.addr, req(50).addr);
+        assert_eq!(s.use_count, 1);
+
+        let e = state
+            .authenticate_session(&conn, req(100), &sid.hash())
+            .unwrap_err();
+        assert_eq!(format!("{}", e), "Unauthenticated: session expired at 100");
+    }
+
     #[test]
     fn upgrade_hash() {
         // This hash is generated with cost=1 vs the cost=2 of PASTA_CONFIG.
@@ -1092,6 +1572,7 @@ mod tests {
                 req.clone(),
                 "slamb",
                 "hunter2".to_owned(),
+                None,
                 Some(b"nvr.example.com".to_vec()),
                 0,
             )
@@ -1126,6 +1607,7 @@ mod tests {
                 req.clone(),
                 "slamb",
                 "hunter2".to_owned(),
+                None,
                 Some(b"nvr.example.com".to_vec()),
                 0,
             )
@@ -1158,6 +1640,7 @@ mod tests {
                 req.clone(),
                 "slamb",
                 "hunter2".to_owned(),
+                None,
                 Some(b"nvr.example.com".to_vec()),
                 0,
             )
@@ -1178,6 +1661,7 @@ mod tests {
                 req.clone(),
                 "slamb",
                 "hunter2".to_owned(),
+                None,
                 Some(b"nvr.example.com".to_vec()),
                 0,
             )
@@ -1231,6 +1715,7 @@ mod tests {
                 req.clone(),
                 "slamb",
                 "hunter2".to_owned(),
+                None,
                 Some(b"nvr.example.com".to_vec()),
                 0,
             )
@@ -1283,4 +1768,99 @@ mod tests {
         assert!(u.permissions.view_video);
         assert!(u.permissions.update_signals);
     }
+
+    #[test]
+    fn totp_login() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let uid = {
+            let mut c = UserChange::add_user("slamb".to_owned());
+            c.set_password("hunter2".to_owned());
+            state.apply(&conn, c).unwrap().id
+        };
+        let t = 1111111109;
+        let req = |when_sec| Request {
+            when_sec: Some(when_sec),
+            ..Default::default()
+        };
+        let login = |state: &mut State, when_sec, code: Option<&str>| {
+            state
+                .login_by_password(
+                    &conn,
+                    req(when_sec),
+                    "slamb",
+                    "hunter2".to_owned(),
+                    code,
+                    None,
+                    0,
+                )
+                .map(|_| ())
+        };
+
+        // Enrollment doesn't take effect until confirmed.
+        let uri = state.begin_totp_enrollment(uid).unwrap();
+        login(&mut state, t, None).unwrap();
+        let secret = state.users_by_id[&uid].pending_totp_secret.clone().unwrap();
+        assert!(uri.contains(&totp::base32(&secret)));
+        let code = |when_sec: i64| format!("{:06}", totp::code(&secret, when_sec / 30));
+        let recovery = state
+            .confirm_totp_enrollment(&conn, uid, &code(t), t)
+            .unwrap();
+        assert_eq!(recovery.len(), totp::RECOVERY_CODES);
+        assert!(state.begin_totp_enrollment(uid).is_err());
+
+        // A password alone is no longer sufficient for RTSP, and failures still count.
+        let e = state.verify_password("slamb", "hunter2").unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "user \"slamb\" is enrolled in TOTP; password alone is insufficient"
+        );
+        state.verify_password("slamb", "wrong").unwrap_err();
+        assert_eq!(state.users_by_id[&uid].password_failure_count, 1);
+
+        // Now a code is required, and the one used to confirm can't be replayed.
+        let e = login(&mut state, t, None).unwrap_err();
+        assert_eq!(format!("{}", e), "TOTP code required for user \"slamb\"");
+        let e = login(&mut state, t, Some(&code(t))).unwrap_err();
+        assert_eq!(format!("{}", e), "incorrect TOTP code for user \"slamb\"");
+        assert_eq!(state.users_by_id[&uid].totp_failure_count, 1);
+        login(&mut state, t + 30, Some(&code(t + 30))).unwrap();
+
+        // Each recovery code works once.
+        login(&mut state, t + 30, Some(&recovery[0])).unwrap();
+        login(&mut state, t + 30, Some(&recovery[0])).unwrap_err();
+
+        // Enrollment and used codes persist across reload.
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        let u = &state.users_by_id[&uid];
+        assert!(u.totp_enrolled());
+        assert_eq!(u.totp_recovery_codes_remaining(), totp::RECOVERY_CODES - 1);
+        login(&mut state, t + 30, Some(&code(t + 30))).unwrap_err();
+        login(&mut state, t + 30, Some(&recovery[1])).unwrap();
+
+        // Removing enrollment requires a code; the ones already used don't work.
+        state
+            .check_totp_code(&conn, uid, &recovery[1], t + 30)
+            .unwrap_err();
+        state
+            .check_totp_code(&conn, uid, &code(t + 60), t + 60)
+            .unwrap();
+
+        // Clearing enrollment makes the password sufficient again.
+        let mut c = state.users_by_id[&uid].change();
+        c.clear_totp();
+        state.apply(&conn, c).unwrap();
+        login(&mut state, t + 30, None).unwrap();
+        state.verify_password("slamb", "hunter2").unwrap();
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        assert!(!state.users_by_id[&uid].totp_enrolled());
+        let e = state
+            .check_totp_code(&conn, uid, &code(t + 90), t + 90)
+            .unwrap_err();
+        assert_eq!(format!("{}", e), "user \"slamb\" isn't enrolled in TOTP");
+    }
 }
@@ -14,7 +14,7 @@ use failure::Error;
 use fnv::{FnvHashMap, FnvHashSet};
 use log::{error, info, warn};
 use nix::fcntl::AtFlags;
-use rusqlite::params;
+use rusqlite::{named_params, params};
 use std::os::unix::io::AsRawFd;
 
 pub struct Options {
@@ -126,7 +126,8 @@ pub fn run(conn: &mut rusqlite::Connection, opts: &Options) -> Result<i32, Error
             select
               id,
               sample_file_dir_id,
-              cum_recordings
+              cum_recordings,
+              archive_sample_file_dir_id
             from
               stream
             where
@@ -136,14 +137,27 @@ pub fn run(conn: &mut rusqlite::Connection, opts: &Options) -> Result<i32, Error
         let mut rows = stmt.query(params![])?;
         while let Some(row) = rows.next()? {
             let stream_id = row.get(0)?;
-            let dir_id = row.get(1)?;
+            let stream_dir_id = row.get(1)?;
             let cum_recordings = row.get(2)?;
-            let mut stream = match dirs_by_id.get_mut(&dir_id) {
-                None => Stream::default(),
-                Some(d) => d.remove(&stream_id).unwrap_or_else(Stream::default),
-            };
-            stream.cum_recordings = Some(cum_recordings);
-            printed_error |= compare_stream(conn, dir_id, stream_id, opts, stream, &mut ctx)?;
+            let archive_dir_id: Option<i32> = row.get(3)?;
+
+            // Archived recordings are checked against the archive directory's files.
+            for dir_id in std::iter::once(stream_dir_id).chain(archive_dir_id) {
+                let mut stream = match dirs_by_id.get_mut(&dir_id) {
+                    None => Stream::default(),
+                    Some(d) => d.remove(&stream_id).unwrap_or_else(Stream::default),
+                };
+                stream.cum_recordings = Some(cum_recordings);
+                printed_error |= compare_stream(
+                    conn,
+                    dir_id,
+                    stream_dir_id,
+                    stream_id,
+                    opts,
+                    stream,
+                    &mut ctx,
+                )?;
+            }
         }
     }
 
@@ -209,9 +223,16 @@ struct Recording {
     /// Present iff there is a file. When `args.compare_lens` is true, the length; otherwise 0.
     file: Option<u64>,
 
+    /// Present iff there is an audio sample file. When `args.compare_lens` is true, the length;
+    /// otherwise 0.
+    audio_file: Option<u64>,
+
     /// Iff a `recording` row is present, a `RecordingSummary` from those fields.
     recording_row: Option<RecordingSummary>,
 
+    /// Iff a `recording` row is present and has audio, its `audio_sample_file_bytes`.
+    audio_row_bytes: Option<u64>,
+
     /// Iff a `recording_playback` row is present, a `RecordingSummary` computed from the index.
     /// This should match the recording row.
     playback_row: Option<RecordingSummary>,
@@ -270,15 +291,16 @@ fn read_dir(d: &dir::SampleFileDir, opts: &Options) -> Result<Dir, Error> {
             b"." | b".." | b"meta" => continue,
             _ => {}
         };
-        let id = match dir::parse_id(f.to_bytes()) {
-            Ok(id) => id,
-            Err(_) => {
-                error!(
-                    "sample file directory contains file {:?} which isn't an id",
-                    f
-                );
-                continue;
-            }
+        let (id, audio) = if let Ok(id) = dir::parse_id(f.to_bytes()) {
+            (id, false)
+        } else if let Ok(id) = dir::parse_audio_id(f.to_bytes()) {
+            (id, true)
+        } else {
+            error!(
+                "sample file directory contains file {:?} which isn't an id",
+                f
+            );
+            continue;
         };
         let len = if opts.compare_lens {
             nix::sys::stat::fstatat(fd, f, AtFlags::empty())?.st_size as u64
@@ -286,19 +308,26 @@ fn read_dir(d: &dir::SampleFileDir, opts: &Options) -> Result<Dir, Error> {
             0
         };
         let stream = dir.entry(id.stream()).or_insert_with(Stream::default);
-        stream
+        let recording = stream
             .recordings
             .entry(id.recording())
-            .or_insert_with(Recording::default)
-            .file = Some(len);
+            .or_insert_with(Recording::default);
+        if audio {
+            recording.audio_file = Some(len);
+        } else {
+            recording.file = Some(len);
+        }
     }
     Ok(dir)
 }
 
-/// Looks through a known stream for errors.
+/// Looks through a known stream's recordings in the given directory for errors.
+/// `stream_dir_id` is the stream's primary directory; `dir_id` is either it or the stream's archive
+/// directory.
 fn compare_stream(
     conn: &rusqlite::Connection,
     dir_id: i32,
+    stream_dir_id: i32,
     stream_id: i32,
     opts: &Options,
     mut stream: Stream,
@@ -306,6 +335,12 @@ fn compare_stream(
 ) -> Result<bool, Error> {
     let start = CompositeId::new(stream_id, 0);
     let end = CompositeId::new(stream_id, i32::max_value());
+    let p = named_params! {
+        ":start": start.0,
+        ":end": end.0,
+        ":stream_dir_id": stream_dir_id,
+        ":dir_id": dir_id,
+    };
     let mut printed_error = false;
     let cum_recordings = stream
         .cum_recordings
@@ -321,14 +356,17 @@ fn compare_stream(
               sample_file_bytes,
               wall_duration_90k + media_duration_delta_90k,
               video_samples,
-              video_sync_samples
+              video_sync_samples,
+              audio_sample_entry_id is not null,
+              audio_sample_file_bytes
             from
               recording
             where
-              composite_id between ? and ?
+              composite_id between :start and :end and
+              coalesce(sample_file_dir_id, :stream_dir_id) = :dir_id
             "#,
         )?;
-        let mut rows = stmt.query(params![start.0, end.0])?;
+        let mut rows = stmt.query(p)?;
         while let Some(row) = rows.next()? {
             let id = CompositeId(row.get(0)?);
             let s = RecordingSummary {
@@ -338,11 +376,14 @@ fn compare_stream(
                 video_samples: row.get(4)?,
                 video_sync_samples: row.get(5)?,
             };
-            stream
+            let has_audio: bool = row.get(6)?;
+            let audio_bytes = row.get::<_, i64>(7)? as u64;
+            let recording = stream
                 .recordings
                 .entry(id.recording())
-                .or_insert_with(Recording::default)
-                .recording_row = Some(s);
+                .or_insert_with(Recording::default);
+            recording.recording_row = Some(s);
+            recording.audio_row_bytes = if has_audio { Some(audio_bytes) } else { None };
         }
     }
 
@@ -351,15 +392,17 @@ fn compare_stream(
         let mut stmt = conn.prepare_cached(
             r#"
             select
-              composite_id,
-              video_index
+              p.composite_id,
+              p.video_index
             from
-              recording_playback
+              recording_playback p
+              left join recording r on (p.composite_id = r.composite_id)
             where
-              composite_id between ? and ?
+              p.composite_id between :start and :end and
+              coalesce(r.sample_file_dir_id, :stream_dir_id) = :dir_id
             "#,
         )?;
-        let mut rows = stmt.query(params![start.0, end.0])?;
+        let mut rows = stmt.query(p)?;
         while let Some(row) = rows.next()? {
             let id = CompositeId(row.get(0)?);
             let video_index: Vec<u8> = row.get(1)?;
@@ -388,14 +431,16 @@ fn compare_stream(
         let mut stmt = conn.prepare_cached(
             r#"
             select
-              composite_id
+              i.composite_id
             from
-              recording_integrity
+              recording_integrity i
+              left join recording r on (i.composite_id = r.composite_id)
             where
-              composite_id between ? and ?
+              i.composite_id between :start and :end and
+              coalesce(r.sample_file_dir_id, :stream_dir_id) = :dir_id
             "#,
         )?;
-        let mut rows = stmt.query(params![start.0, end.0])?;
+        let mut rows = stmt.query(p)?;
         while let Some(row) = rows.next()? {
             let id = CompositeId(row.get(0)?);
             stream
@@ -414,6 +459,32 @@ fn compare_stream(
         // cum_recordings (were being written when the process died).
         let db_rows_expected = !recording.garbage_row && id.recording() < cum_recordings;
 
+        // Audio sample files are written alongside video sample files and unlinked just before
+        // them, so one without the other (or without audio in the recording row) is orphaned.
+        match (recording.audio_file, recording.audio_row_bytes) {
+            (Some(_), _) if recording.file.is_none() || recording.audio_row_bytes.is_none() => {
+                error!("Orphaned audio sample file for {}: {:#?}", id, recording);
+                printed_error = true;
+
+                // Trashing unlinks the video sample file too, so only do so when there's no
+                // recording row that refers to it.
+                if opts.trash_orphan_sample_files && recording.recording_row.is_none() {
+                    ctx.files_to_trash.insert((dir_id, id));
+                }
+            }
+            (Some(len), Some(bytes)) => {
+                if opts.compare_lens && bytes != len {
+                    error!("Recording {} audio length mismatch: {:#?}", id, recording);
+                    printed_error = true;
+                }
+            }
+            (None, Some(_)) => {
+                error!("Recording {} missing audio file: {:#?}", id, recording);
+                printed_error = true;
+            }
+            (None, None) => {}
+        }
+
         let r = match recording.recording_row {
             Some(ref r) => {
                 if !db_rows_expected {
@@ -429,8 +500,9 @@ fn compare_stream(
                     if opts.trash_orphan_sample_files {
                         ctx.files_to_trash.insert((dir_id, id));
                     }
-                    if opts.delete_orphan_rows {
-                        // also delete playback/integrity rows, if any.
+                    if opts.delete_orphan_rows && dir_id == stream_dir_id {
+                        // also delete playback/integrity rows, if any. (In the archive directory,
+                        // this may be an interrupted copy of a recording still in the primary.)
                         ctx.rows_to_delete.insert(id);
                     }
                     printed_error = true;
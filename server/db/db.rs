@@ -26,6 +26,7 @@
 //!     A list of mutations is built up in-memory and occasionally flushed to reduce SSD write
 //!     cycles.
 
+use crate::audit;
 use crate::auth;
 use crate::days;
 use crate::dir;
@@ -33,12 +34,15 @@ use crate::raw;
 use crate::recording;
 use crate::schema;
 use crate::signal;
+use crate::webhook;
 use base::clock::{self, Clocks};
+use base::metrics::{Histogram, DURATION_BUCKETS, REGISTRY};
 use base::strutil::encode_size;
 use failure::{bail, format_err, Error};
 use fnv::{FnvHashMap, FnvHashSet};
 use hashlink::LinkedHashMap;
 use itertools::Itertools;
+use lazy_static::lazy_static;
 use log::{error, info, trace};
 use parking_lot::{Mutex, MutexGuard};
 use rusqlite::{named_params, params};
@@ -54,19 +58,22 @@ use std::str;
 use std::string::String;
 use std::sync::Arc;
 use std::vec::Vec;
+use time::Timespec;
 use uuid::Uuid;
 
 /// Expected schema version. See `guide/schema.md` for more information.
-pub const EXPECTED_VERSION: i32 = 6;
+pub const EXPECTED_VERSION: i32 = 7;
 
-/// Length of the video index cache.
+/// Length of the recording playback cache.
 /// The actual data structure is one bigger than this because we insert before we remove.
 /// Make it one less than a power of two so that the data structure's size is efficient.
-const VIDEO_INDEX_CACHE_LEN: usize = 1023;
+const PLAYBACK_CACHE_LEN: usize = 1023;
 
 const GET_RECORDING_PLAYBACK_SQL: &str = r#"
     select
-      video_index
+      video_index,
+      audio_index,
+      audio_start_offset_90k
     from
       recording_playback
     where
@@ -80,6 +87,11 @@ const INSERT_VIDEO_SAMPLE_ENTRY_SQL: &str = r#"
                                     :rfc6381_codec, :data)
 "#;
 
+const INSERT_AUDIO_SAMPLE_ENTRY_SQL: &str = r#"
+    insert into audio_sample_entry (rfc6381_codec,  sample_rate,  channels,  data)
+                            values (:rfc6381_codec, :sample_rate, :channels, :data)
+"#;
+
 const UPDATE_STREAM_COUNTERS_SQL: &str = r#"
     update stream
     set cum_recordings = :cum_recordings,
@@ -120,6 +132,13 @@ impl rusqlite::types::FromSql for VideoIndex {
     }
 }
 
+/// A cached `recording_playback` row; see `LockedDatabase::with_recording_playback`.
+struct CachedPlayback {
+    video_index: Box<[u8]>,
+    audio_index: Box<[u8]>,
+    audio_start_offset_90k: i32,
+}
+
 /// A concrete box derived from a ISO/IEC 14496-12 section 8.5.2 VisualSampleEntry box. Describes
 /// the codec, width, height, etc.
 #[derive(Debug)]
@@ -159,6 +178,41 @@ impl std::fmt::Debug for VideoSampleEntryToInsert {
     }
 }
 
+/// A concrete box derived from a ISO/IEC 14496-12 section 8.5.2 AudioSampleEntry box. Describes
+/// the codec, sample rate, etc.
+#[derive(Debug)]
+pub struct AudioSampleEntry {
+    pub id: i32,
+
+    // Fields matching AudioSampleEntryToInsert below.
+    pub data: Vec<u8>,
+    pub rfc6381_codec: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct AudioSampleEntryToInsert {
+    pub data: Vec<u8>,
+    pub rfc6381_codec: String,
+
+    /// The sample rate in Hz, which is also the timescale of the audio index's durations.
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl std::fmt::Debug for AudioSampleEntryToInsert {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use pretty_hex::PrettyHex;
+        f.debug_struct("AudioSampleEntryToInsert")
+            .field("data", &self.data.hex_dump())
+            .field("rfc6381_codec", &self.rfc6381_codec)
+            .field("sample_rate", &self.sample_rate)
+            .field("channels", &self.channels)
+            .finish()
+    }
+}
+
 /// A row used in `list_recordings_by_time` and `list_recordings_by_id`.
 #[derive(Copy, Clone, Debug)]
 pub struct ListRecordingsRow {
@@ -177,6 +231,11 @@ pub struct ListRecordingsRow {
     pub open_id: u32,
     pub flags: i32,
 
+    /// The audio sample entry, or `None` if this recording has no audio.
+    pub audio_sample_entry_id: Option<i32>,
+    pub audio_samples: i32,
+    pub audio_sample_file_bytes: i32,
+
     /// This is populated by `list_recordings_by_id` but not `list_recordings_by_time`.
     /// (It's not included in the `recording_cover` index, so adding it to
     /// `list_recordings_by_time` would be inefficient.)
@@ -192,6 +251,7 @@ pub struct ListAggregatedRecordingsRow {
     pub video_sync_samples: i64,
     pub sample_file_bytes: i64,
     pub video_sample_entry_id: i32,
+    pub audio_sample_entry_id: Option<i32>,
     pub stream_id: i32,
     pub run_start_id: i32,
     pub open_id: u32,
@@ -211,6 +271,7 @@ impl ListAggregatedRecordingsRow {
             video_sync_samples: row.video_sync_samples as i64,
             sample_file_bytes: row.sample_file_bytes as i64,
             video_sample_entry_id: row.video_sample_entry_id,
+            audio_sample_entry_id: row.audio_sample_entry_id,
             stream_id: row.id.stream(),
             run_start_id: recording_id - row.run_offset,
             open_id: row.open_id,
@@ -228,6 +289,12 @@ impl ListAggregatedRecordingsRow {
 #[derive(Debug)]
 pub struct RecordingPlayback<'a> {
     pub video_index: &'a [u8],
+
+    /// The audio index, or an empty slice if the recording has no audio.
+    pub audio_index: &'a [u8],
+
+    /// The start of the first audio sample relative to the start of the first video frame.
+    pub audio_start_offset_90k: i32,
 }
 
 /// Bitmask in the `flags` field in the `recordings` table; see `schema.sql`.
@@ -262,6 +329,13 @@ pub struct RecordingToInsert {
     pub video_sample_entry_id: i32,
     pub video_index: Vec<u8>,
     pub sample_file_blake3: Option<[u8; 32]>,
+    pub audio_sample_entry_id: Option<i32>,
+    pub audio_samples: i32,
+    pub audio_sample_file_bytes: i32,
+    pub audio_index: Vec<u8>,
+
+    /// The start of the first audio sample relative to the start of the first video frame.
+    pub audio_start_offset_90k: i32,
 }
 
 impl RecordingToInsert {
@@ -278,6 +352,9 @@ impl RecordingToInsert {
             run_offset: self.run_offset,
             open_id,
             flags: self.flags | RecordingFlags::Uncommitted as i32,
+            audio_sample_entry_id: self.audio_sample_entry_id,
+            audio_samples: self.audio_samples,
+            audio_sample_file_bytes: self.audio_sample_file_bytes,
             prev_media_duration_and_runs: Some((self.prev_media_duration, self.prev_runs)),
         }
     }
@@ -289,7 +366,13 @@ pub(crate) struct ListOldestRecordingsRow {
     pub id: CompositeId,
     pub start: recording::Time,
     pub wall_duration_90k: i32,
+
+    /// The total bytes of the video and (if any) audio sample files.
     pub sample_file_bytes: i32,
+
+    /// The archive directory holding the sample files, or `None` if they're in the stream's
+    /// primary directory.
+    pub sample_file_dir_id: Option<i32>,
 }
 
 #[derive(Debug)]
@@ -322,6 +405,11 @@ impl SampleFileDir {
             .clone())
     }
 
+    /// Returns true if the directory is open, as required to read or write sample files.
+    pub fn is_open(&self) -> bool {
+        self.dir.is_some()
+    }
+
     /// Returns expected existing metadata when opening this directory.
     fn meta(&self, db_uuid: &Uuid) -> schema::DirMeta {
         let mut meta = schema::DirMeta::default();
@@ -353,6 +441,100 @@ pub struct Camera {
     pub username: Option<String>,
     pub password: Option<String>,
     pub streams: [Option<i32>; 2],
+
+    /// The runtime status of the camera's ONVIF event subscription, updated by its subscriber
+    /// (if any) without holding the database lock.
+    pub onvif_status: Arc<Mutex<OnvifStatus>>,
+}
+
+/// The state of a camera's ONVIF event subscription.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OnvifState {
+    /// No subscriber is running, such as when the camera has no ONVIF event mappings.
+    Idle,
+
+    /// The subscriber is connecting to the camera and subscribing.
+    Subscribing,
+
+    /// The subscription is active and renewed as needed.
+    Subscribed,
+
+    /// The subscriber failed and will retry after a delay; see `OnvifStatus::last_error`.
+    Error,
+}
+
+impl OnvifState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OnvifState::Idle => "idle",
+            OnvifState::Subscribing => "subscribing",
+            OnvifState::Subscribed => "subscribed",
+            OnvifState::Error => "error",
+        }
+    }
+}
+
+/// Runtime status of a camera's ONVIF event subscription, as shared between its subscriber,
+/// notification handler, and API callers.
+#[derive(Clone, Debug)]
+pub struct OnvifStatus {
+    pub state: OnvifState,
+
+    /// The time `state` last changed.
+    pub since: Option<recording::Time>,
+
+    /// The most recent error, which is kept after a successful subscription.
+    pub last_error: Option<String>,
+
+    /// The number of failures since the last successful subscription.
+    pub failures: u32,
+
+    /// The termination time of the current subscription, if subscribed.
+    pub expiration: Option<recording::Time>,
+
+    /// The time the most recent event was received.
+    pub last_event: Option<recording::Time>,
+}
+
+impl Default for OnvifStatus {
+    fn default() -> Self {
+        OnvifStatus {
+            state: OnvifState::Idle,
+            since: None,
+            last_error: None,
+            failures: 0,
+            expiration: None,
+            last_event: None,
+        }
+    }
+}
+
+impl OnvifStatus {
+    /// Changes to `state` as of `now`.
+    pub fn set_state(&mut self, state: OnvifState, now: recording::Time) {
+        self.state = state;
+        self.since = Some(now);
+        match state {
+            OnvifState::Subscribed => self.failures = 0,
+            _ => self.expiration = None,
+        }
+    }
+
+    /// Changes to `OnvifState::Error` with the given message.
+    pub fn set_error(&mut self, msg: String, now: recording::Time) {
+        self.set_state(OnvifState::Error, now);
+        self.last_error = Some(msg);
+        self.failures += 1;
+    }
+
+    /// Returns true if no event has arrived within `max_age` of `now`. A subscription which
+    /// hasn't yet delivered an event is given `max_age` from its last state change.
+    pub fn is_stale(&self, now: recording::Time, max_age: recording::Duration) -> bool {
+        match self.last_event.or(self.since) {
+            None => true,
+            Some(t) => now - t > max_age,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -408,6 +590,19 @@ pub struct Stream {
     pub type_: StreamType,
     pub rtsp_url: String,
     pub retain_bytes: i64,
+
+    /// If set, recordings which ended more than this many seconds ago are deleted.
+    pub retain_max_age_sec: Option<i64>,
+
+    /// If set, recordings which ended less than this many seconds ago are never deleted to make
+    /// room, even if the stream exceeds `retain_bytes`.
+    pub retain_min_age_sec: Option<i64>,
+
+    /// If set, recordings are moved to this sample file directory once they've ended more than
+    /// `archive_age_sec` ago. Set iff `archive_age_sec` is set.
+    pub archive_sample_file_dir_id: Option<i32>,
+    pub archive_age_sec: Option<i64>,
+
     pub flush_if_sec: i64,
 
     /// The time range of recorded data associated with this stream (minimum start time and maximum
@@ -423,9 +618,10 @@ pub struct Stream {
     pub fs_bytes: i64,
 
     /// On flush, delete the following recordings (move them to the `garbage` table, to be
-    /// collected later). Note they must be the oldest recordings. The later collection involves
-    /// the syncer unlinking the files on disk and syncing the directory then enqueueing for
-    /// another following flush removal from the `garbage` table.
+    /// collected later). Note they must be the oldest recordings other than held ones, in
+    /// ascending order. The later collection involves the syncer unlinking the files on disk and
+    /// syncing the directory then enqueueing for another following flush removal from the
+    /// `garbage` table.
     to_delete: Vec<ListOldestRecordingsRow>,
 
     /// The total bytes to delete with the next flush.
@@ -437,6 +633,10 @@ pub struct Stream {
     pub bytes_to_add: i64,
     pub fs_bytes_to_add: i64,
 
+    /// The filesystem bytes by which the stream exceeded `retain_bytes` as of its most recent
+    /// rotation because its remaining recordings were younger than `retain_min_age_sec`, or 0.
+    pub retention_overage_bytes: i64,
+
     /// The total duration of undeleted recorded data. This may not be `range.end - range.start`
     /// due to gaps and overlap.
     pub duration: recording::Duration,
@@ -468,6 +668,104 @@ pub struct Stream {
     synced_recordings: usize,
 
     on_live_segment: Vec<Box<dyn FnMut(LiveSegment) -> bool + Send>>,
+
+    /// The runtime status of the stream's connection, updated by its streamer (if any) without
+    /// holding the database lock.
+    pub status: Arc<Mutex<StreamStatus>>,
+}
+
+/// The state of a stream's connection to its camera.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StreamState {
+    /// No streamer is running, such as when the stream isn't recording.
+    Idle,
+
+    /// The streamer is opening the RTSP session.
+    Connecting,
+
+    /// The RTSP session is open.
+    Connected,
+
+    /// The streamer failed and will retry shortly; see `StreamStatus::last_error`.
+    Error,
+}
+
+impl StreamState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StreamState::Idle => "idle",
+            StreamState::Connecting => "connecting",
+            StreamState::Connected => "connected",
+            StreamState::Error => "error",
+        }
+    }
+}
+
+/// Runtime status of a stream, as shared between its streamer and API callers.
+#[derive(Clone, Debug)]
+pub struct StreamStatus {
+    pub state: StreamState,
+
+    /// The time `state` last changed.
+    pub since: Option<recording::Time>,
+
+    /// The most recent error, which is kept after a successful reconnect.
+    pub last_error: Option<String>,
+
+    /// The start of the current RTSP session, if connected.
+    pub session_start: Option<recording::Time>,
+
+    /// The wall time of the most recent video frame.
+    pub last_frame: Option<recording::Time>,
+
+    /// The received bitrate and video frame rate, averaged over a few seconds.
+    pub bits_per_sec: f64,
+    pub frames_per_sec: f64,
+}
+
+impl Default for StreamStatus {
+    fn default() -> Self {
+        StreamStatus {
+            state: StreamState::Idle,
+            since: None,
+            last_error: None,
+            session_start: None,
+            last_frame: None,
+            bits_per_sec: 0.,
+            frames_per_sec: 0.,
+        }
+    }
+}
+
+impl StreamStatus {
+    /// Changes to `state` as of `now`, resetting per-session fields.
+    pub fn set_state(&mut self, state: StreamState, now: recording::Time) {
+        self.state = state;
+        self.since = Some(now);
+        self.session_start = if state == StreamState::Connected {
+            Some(now)
+        } else {
+            None
+        };
+        self.bits_per_sec = 0.;
+        self.frames_per_sec = 0.;
+    }
+
+    /// Changes to `StreamState::Error` with the given message.
+    pub fn set_error(&mut self, msg: String, now: recording::Time) {
+        self.set_state(StreamState::Error, now);
+        self.last_error = Some(msg);
+    }
+
+    /// Returns true if a streamer should be delivering frames but none has arrived within
+    /// `max_age` of `now`. A stream which hasn't yet received a frame is given `max_age` from
+    /// its last state change.
+    pub fn is_stalled(&self, now: recording::Time, max_age: recording::Duration) -> bool {
+        match self.last_frame.or(self.since) {
+            None => true,
+            Some(t) => now - t > max_age,
+        }
+    }
 }
 
 /// Bounds of a live view segment. Currently this is a single frame of video.
@@ -492,6 +790,74 @@ pub struct StreamChange {
     pub rtsp_url: String,
     pub record: bool,
     pub flush_if_sec: i64,
+    pub retain_max_age_sec: Option<i64>,
+    pub retain_min_age_sec: Option<i64>,
+    pub archive_sample_file_dir_id: Option<i32>,
+    pub archive_age_sec: Option<i64>,
+}
+
+/// Representation of a `stream_motion` row, which configures built-in motion detection.
+#[derive(Clone, Debug)]
+pub struct StreamMotion {
+    pub stream_id: i32,
+    pub signal_id: u32,
+    pub sensitivity: u8,
+
+    /// The unparsed zones; see `schema.sql`.
+    pub zones: Option<String>,
+}
+
+/// Representation of an `onvif_event_mapping` row, which maps a camera's ONVIF events onto a
+/// signal; see `schema.sql`.
+#[derive(Clone, Debug)]
+pub struct OnvifEventMapping {
+    pub id: i32,
+    pub camera_id: i32,
+
+    /// The topic without namespace prefixes, such as `RuleEngine/CellMotionDetector/Motion`.
+    pub topic: String,
+
+    /// The name and value of a source item which matching events must have, if any.
+    pub source: Option<(String, String)>,
+
+    pub signal_id: u32,
+    pub active_state: u16,
+    pub inactive_state: u16,
+}
+
+/// Representation of an `onvif_subscription` row, a camera's ONVIF event subscription.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OnvifSubscription {
+    pub camera_id: i32,
+
+    /// The token in the notification URL, `/onvif/notify/<token>`, or `None` for a PullPoint
+    /// subscription.
+    pub token: Option<String>,
+    pub manager_address: String,
+    pub termination_time_sec: i64,
+}
+
+/// A time range of a stream which is protected from deletion by retention; see `schema.sql`.
+#[derive(Clone, Debug)]
+pub struct Hold {
+    pub id: i32,
+    pub stream_id: i32,
+    pub time: Range<recording::Time>,
+    pub reason: String,
+
+    /// The username of the hold's creator, if known.
+    pub creator: Option<String>,
+    pub creation_time: recording::Time,
+}
+
+/// A hold to create via `LockedDatabase::add_hold`.
+#[derive(Clone, Debug)]
+pub struct HoldToInsert {
+    pub stream_id: i32,
+    pub time: Range<recording::Time>,
+    pub reason: String,
+    pub creator: Option<String>,
+    pub creation_time: recording::Time,
 }
 
 /// Information about a camera, used by `add_camera` and `update_camera`.
@@ -552,7 +918,7 @@ fn init_recordings(
         select
           recording.start_time_90k,
           recording.wall_duration_90k,
-          recording.sample_file_bytes
+          recording.sample_file_bytes + recording.audio_sample_file_bytes
         from
           recording
         where
@@ -589,13 +955,17 @@ pub struct LockedDatabase {
 
     auth: auth::State,
     signal: signal::State,
+    audit: audit::State,
+    webhooks: webhook::State,
 
     sample_file_dirs_by_id: BTreeMap<i32, SampleFileDir>,
     cameras_by_id: BTreeMap<i32, Camera>,
     streams_by_id: BTreeMap<i32, Stream>,
+    holds_by_id: BTreeMap<i32, Hold>,
     cameras_by_uuid: BTreeMap<Uuid, i32>, // values are ids.
     video_sample_entries_by_id: BTreeMap<i32, Arc<VideoSampleEntry>>,
-    video_index_cache: RefCell<LinkedHashMap<i64, Box<[u8]>, fnv::FnvBuildHasher>>,
+    audio_sample_entries_by_id: BTreeMap<i32, Arc<AudioSampleEntry>>,
+    playback_cache: RefCell<LinkedHashMap<i64, CachedPlayback, fnv::FnvBuildHasher>>,
     on_flush: Vec<Box<dyn Fn() + Send>>,
 }
 
@@ -653,10 +1023,60 @@ impl StreamStateChanger {
         let existing_streams = existing.map(|e| e.streams).unwrap_or_default();
         for (i, ref mut sc) in change.streams.iter_mut().enumerate() {
             let type_ = StreamType::from_index(i).unwrap();
+            if let (Some(min), Some(max)) = (sc.retain_min_age_sec, sc.retain_max_age_sec) {
+                if min > max {
+                    bail!(
+                        "{} stream's retain_min_age_sec {} exceeds its retain_max_age_sec {}",
+                        type_.as_str(),
+                        min,
+                        max
+                    );
+                }
+            }
+            match (sc.archive_sample_file_dir_id, sc.archive_age_sec) {
+                (None, None) => {}
+                (Some(a), Some(_)) if Some(a) == sc.sample_file_dir_id => bail!(
+                    "{} stream's archive dir {} is its sample file dir",
+                    type_.as_str(),
+                    a
+                ),
+                (Some(_), Some(_)) if sc.sample_file_dir_id.is_none() => bail!(
+                    "{} stream has an archive dir but no sample file dir",
+                    type_.as_str()
+                ),
+                (Some(_), Some(_)) => {}
+                _ => bail!(
+                    "{} stream must set both or neither of archive_sample_file_dir_id and \
+                     archive_age_sec",
+                    type_.as_str()
+                ),
+            }
             let mut have_data = false;
             if let Some(sid) = existing_streams[i] {
                 let s = streams_by_id.get(&sid).unwrap();
-                if s.range.is_some() {
+                if s.archive_sample_file_dir_id.is_some()
+                    && s.archive_sample_file_dir_id != sc.archive_sample_file_dir_id
+                {
+                    let archived: i64 = tx.query_row(
+                        r#"
+                        select count(*) from recording
+                        where stream_id = ? and sample_file_dir_id is not null
+                        "#,
+                        params![sid],
+                        |row| row.get(0),
+                    )?;
+                    if archived > 0 {
+                        bail!(
+                            "can't change archive_sample_file_dir_id {:?}->{:?} for stream {} \
+                             with {} archived recordings",
+                            s.archive_sample_file_dir_id,
+                            sc.archive_sample_file_dir_id,
+                            sid,
+                            archived
+                        );
+                    }
+                }
+                if s.range.is_some() || !s.uncommitted.is_empty() {
                     have_data = true;
                     if let (Some(d), false) = (
                         s.sample_file_dir_id,
@@ -676,6 +1096,11 @@ impl StreamStateChanger {
                     && !sc.record
                 {
                     // Delete stream.
+                    tx.execute(
+                        "delete from stream_motion where stream_id = ?",
+                        params![sid],
+                    )?;
+                    tx.execute("delete from hold where stream_id = ?", params![sid])?;
                     let mut stmt = tx.prepare_cached(
                         r#"
                         delete from stream where id = ?
@@ -693,6 +1118,10 @@ impl StreamStateChanger {
                             rtsp_url = :rtsp_url,
                             record = :record,
                             flush_if_sec = :flush_if_sec,
+                            retain_max_age_sec = :retain_max_age_sec,
+                            retain_min_age_sec = :retain_min_age_sec,
+                            archive_sample_file_dir_id = :archive_sample_file_dir_id,
+                            archive_age_sec = :archive_age_sec,
                             sample_file_dir_id = :sample_file_dir_id
                         where
                             id = :id
@@ -702,6 +1131,10 @@ impl StreamStateChanger {
                         ":rtsp_url": &sc.rtsp_url,
                         ":record": sc.record,
                         ":flush_if_sec": sc.flush_if_sec,
+                        ":retain_max_age_sec": sc.retain_max_age_sec,
+                        ":retain_min_age_sec": sc.retain_min_age_sec,
+                        ":archive_sample_file_dir_id": sc.archive_sample_file_dir_id,
+                        ":archive_age_sec": sc.archive_age_sec,
                         ":sample_file_dir_id": sc.sample_file_dir_id,
                         ":id": sid,
                     })?;
@@ -722,10 +1155,14 @@ impl StreamStateChanger {
                     r#"
                     insert into stream (camera_id,  sample_file_dir_id,  type,  rtsp_url,  record,
                                         retain_bytes, flush_if_sec,  cum_recordings,
-                                        cum_media_duration_90k, cum_runs)
+                                        cum_media_duration_90k, cum_runs,
+                                        retain_max_age_sec,  retain_min_age_sec,
+                                        archive_sample_file_dir_id,  archive_age_sec)
                                 values (:camera_id, :sample_file_dir_id, :type, :rtsp_url, :record,
                                         0,            :flush_if_sec, 0,
-                                        0,                0)
+                                        0,                0,
+                                        :retain_max_age_sec, :retain_min_age_sec,
+                                        :archive_sample_file_dir_id, :archive_age_sec)
                     "#,
                 )?;
                 stmt.execute(named_params! {
@@ -735,6 +1172,10 @@ impl StreamStateChanger {
                     ":rtsp_url": &sc.rtsp_url,
                     ":record": sc.record,
                     ":flush_if_sec": sc.flush_if_sec,
+                    ":retain_max_age_sec": sc.retain_max_age_sec,
+                    ":retain_min_age_sec": sc.retain_min_age_sec,
+                    ":archive_sample_file_dir_id": sc.archive_sample_file_dir_id,
+                    ":archive_age_sec": sc.archive_age_sec,
                 })?;
                 let id = tx.last_insert_rowid() as i32;
                 sids[i] = Some(id);
@@ -759,6 +1200,10 @@ impl StreamStateChanger {
                         sample_file_dir_id: sc.sample_file_dir_id,
                         rtsp_url: mem::replace(&mut sc.rtsp_url, String::new()),
                         retain_bytes: 0,
+                        retain_max_age_sec: sc.retain_max_age_sec,
+                        retain_min_age_sec: sc.retain_min_age_sec,
+                        archive_sample_file_dir_id: sc.archive_sample_file_dir_id,
+                        archive_age_sec: sc.archive_age_sec,
                         flush_if_sec: sc.flush_if_sec,
                         range: None,
                         sample_file_bytes: 0,
@@ -768,6 +1213,7 @@ impl StreamStateChanger {
                         fs_bytes_to_delete: 0,
                         bytes_to_add: 0,
                         fs_bytes_to_add: 0,
+                        retention_overage_bytes: 0,
                         duration: recording::Duration(0),
                         committed_days: days::Map::default(),
                         record: sc.record,
@@ -777,6 +1223,7 @@ impl StreamStateChanger {
                         uncommitted: VecDeque::new(),
                         synced_recordings: 0,
                         on_live_segment: Vec::new(),
+                        status: Arc::new(Mutex::new(StreamStatus::default())),
                     });
                 }
                 (Entry::Vacant(_), None) => {}
@@ -786,6 +1233,10 @@ impl StreamStateChanger {
                     e.rtsp_url = sc.rtsp_url;
                     e.record = sc.record;
                     e.flush_if_sec = sc.flush_if_sec;
+                    e.retain_max_age_sec = sc.retain_max_age_sec;
+                    e.retain_min_age_sec = sc.retain_min_age_sec;
+                    e.archive_sample_file_dir_id = sc.archive_sample_file_dir_id;
+                    e.archive_age_sec = sc.archive_age_sec;
                 }
                 (Entry::Occupied(e), None) => {
                     e.remove();
@@ -878,7 +1329,7 @@ impl LockedDatabase {
             bail!("can't sync un-added recording {}", id);
         }
         let l = stream.uncommitted[stream.synced_recordings].lock();
-        let bytes = i64::from(l.sample_file_bytes);
+        let bytes = i64::from(l.sample_file_bytes) + i64::from(l.audio_sample_file_bytes);
         stream.bytes_to_add += bytes;
         stream.fs_bytes_to_add += round_up(bytes);
         stream.synced_recordings += 1;
@@ -953,6 +1404,7 @@ impl LockedDatabase {
             None => bail!("database is read-only"),
             Some(o) => o,
         };
+        let flush_start = clocks.monotonic();
         let tx = self.conn.transaction()?;
         let mut new_ranges =
             FnvHashMap::with_capacity_and_hasher(self.streams_by_id.len(), Default::default());
@@ -992,18 +1444,26 @@ impl LockedDatabase {
                     };
 
                     // raw::delete_recordings does a bulk transfer of a range from recording to
-                    // garbage, rather than operating on each element of to_delete. This is
-                    // guaranteed to give the same result because to_delete is guaranteed to be the
-                    // oldest recordings for the stream.
-                    let start = CompositeId::new(stream_id, 0);
-                    let end = CompositeId(l.id.0 + 1);
-                    let n = raw::delete_recordings(&tx, dir, start..end)? as usize;
+                    // garbage, rather than operating on each element of to_delete. to_delete is
+                    // guaranteed to be the oldest recordings for the stream other than held ones,
+                    // in ascending order, so each run of consecutive ids can be transferred as one
+                    // range.
+                    let mut start = s.to_delete[0].id;
+                    let mut n = 0;
+                    for (i, row) in s.to_delete.iter().enumerate() {
+                        let end = CompositeId(row.id.0 + 1);
+                        let next = s.to_delete.get(i + 1).map(|r| r.id);
+                        if next != Some(end) {
+                            n += raw::delete_recordings(&tx, dir, start..end)? as usize;
+                            start = next.unwrap_or(end);
+                        }
+                    }
                     if n != s.to_delete.len() {
                         bail!(
                             "Found {} rows in {} .. {}, expected {}: {:?}",
                             n,
-                            start,
-                            end,
+                            s.to_delete[0].id,
+                            CompositeId(l.id.0 + 1),
                             s.to_delete.len(),
                             &s.to_delete
                         );
@@ -1011,8 +1471,8 @@ impl LockedDatabase {
                 }
             }
         }
-        for dir in self.sample_file_dirs_by_id.values() {
-            raw::mark_sample_files_deleted(&tx, &dir.garbage_unlinked)?;
+        for (&id, dir) in &self.sample_file_dirs_by_id {
+            raw::mark_sample_files_deleted(&tx, id, &dir.garbage_unlinked)?;
         }
         for (&stream_id, r) in &mut new_ranges {
             *r = raw::get_range(&tx, stream_id)?;
@@ -1032,6 +1492,7 @@ impl LockedDatabase {
         }
         self.auth.flush(&tx)?;
         self.signal.flush(&tx)?;
+        self.audit.flush(&tx, clocks.realtime().sec)?;
         tx.commit()?;
 
         #[derive(Default)]
@@ -1058,7 +1519,6 @@ impl LockedDatabase {
         for (stream_id, new_range) in new_ranges.drain() {
             let s = self.streams_by_id.get_mut(&stream_id).unwrap();
             let dir_id = s.sample_file_dir_id.unwrap();
-            let dir = self.sample_file_dirs_by_id.get_mut(&dir_id).unwrap();
             let log = dir_logs.entry(dir_id).or_default();
 
             // Process delete_oldest_recordings.
@@ -1070,7 +1530,12 @@ impl LockedDatabase {
             log.deleted.reserve(s.to_delete.len());
             for row in s.to_delete.drain(..) {
                 log.deleted.push(row.id);
-                dir.garbage_needs_unlink.insert(row.id);
+                let d = row.sample_file_dir_id.unwrap_or(dir_id);
+                self.sample_file_dirs_by_id
+                    .get_mut(&d)
+                    .unwrap()
+                    .garbage_needs_unlink
+                    .insert(row.id);
                 let d = recording::Duration(i64::from(row.wall_duration_90k));
                 s.duration -= d;
                 s.committed_days.adjust(row.start..row.start + d, -1);
@@ -1092,7 +1557,10 @@ impl LockedDatabase {
                 s.cum_media_duration += media_dur;
                 s.cum_runs += if l.run_offset == 0 { 1 } else { 0 };
                 let end = l.start + wall_dur;
-                s.add_recording(l.start..end, l.sample_file_bytes);
+                s.add_recording(
+                    l.start..end,
+                    l.sample_file_bytes + l.audio_sample_file_bytes,
+                );
             }
             s.synced_recordings = 0;
 
@@ -1101,6 +1569,7 @@ impl LockedDatabase {
         }
         self.auth.post_flush();
         self.signal.post_flush();
+        self.audit.post_flush();
         self.flush_count += 1;
         let mut log_msg = String::with_capacity(256);
         for (&dir_id, log) in &dir_logs {
@@ -1124,6 +1593,7 @@ impl LockedDatabase {
         if log_msg.is_empty() {
             log_msg.push_str(" no recording changes");
         }
+        FLUSH_DURATION.observe_duration(clocks.monotonic() - flush_start);
         info!("Flush {} (why: {}):{}", self.flush_count, reason, &log_msg);
         for cb in &self.on_flush {
             cb();
@@ -1133,7 +1603,7 @@ impl LockedDatabase {
 
     /// Sets a watcher which will receive an (empty) event on successful flush.
     /// The lock will be held while this is run, so it should not do any I/O.
-    pub(crate) fn on_flush(&mut self, run: Box<dyn Fn() + Send>) {
+    pub fn on_flush(&mut self, run: Box<dyn Fn() + Send>) {
         self.on_flush.push(run);
     }
 
@@ -1189,7 +1659,11 @@ impl LockedDatabase {
         }
 
         let o = match self.open.as_ref() {
-            None => return Ok(()), // read-only mode; all done.
+            None => {
+                // read-only mode; all done.
+                self.link_archive_dirs();
+                return Ok(());
+            }
             Some(o) => o,
         };
 
@@ -1215,10 +1689,28 @@ impl LockedDatabase {
             d.write_meta(&meta)?;
             dir.dir = Some(d);
         }
+        self.link_archive_dirs();
 
         Ok(())
     }
 
+    /// Lets the open primary directory of each stream read sample files which have been moved to
+    /// its (also open) archive directory.
+    fn link_archive_dirs(&self) {
+        let dirs = &self.sample_file_dirs_by_id;
+        for s in self.streams_by_id.values() {
+            let (d, a) = match (s.sample_file_dir_id, s.archive_sample_file_dir_id) {
+                (Some(d), Some(a)) => (d, a),
+                _ => continue,
+            };
+            let d = dirs.get(&d).and_then(|d| d.dir.as_ref());
+            let a = dirs.get(&a).and_then(|a| a.dir.as_ref());
+            if let (Some(d), Some(a)) = (d, a) {
+                d.add_archive(a);
+            }
+        }
+    }
+
     pub fn streams_by_id(&self) -> &BTreeMap<i32, Stream> {
         &self.streams_by_id
     }
@@ -1228,6 +1720,11 @@ impl LockedDatabase {
         &self.video_sample_entries_by_id
     }
 
+    /// Returns an immutable view of the audio sample entries.
+    pub fn audio_sample_entries_by_id(&self) -> &BTreeMap<i32, Arc<AudioSampleEntry>> {
+        &self.audio_sample_entries_by_id
+    }
+
     /// Gets a given camera by uuid.
     pub fn get_camera(&self, uuid: Uuid) -> Option<&Camera> {
         self.cameras_by_uuid.get(&uuid).map(|id| {
@@ -1329,6 +1826,7 @@ impl LockedDatabase {
         // * forced split (when exceeding a duration limit)
         // * a missing id (one that was deleted out of order)
         // * video_sample_entry mismatch (if the parameters changed during a RTSP session)
+        // * audio_sample_entry mismatch (likewise)
         //
         // This iteration works because in a run, the start_time+duration of recording id r
         // is equal to the start_time of recording id r+1. Thus ascending times guarantees
@@ -1350,6 +1848,7 @@ impl LockedDatabase {
                         + recording::Duration(row.wall_duration_90k as i64);
                     let needs_flush = a.ids.end != recording_id
                         || row.video_sample_entry_id != a.video_sample_entry_id
+                        || row.audio_sample_entry_id != a.audio_sample_entry_id
                         || new_dur >= forced_split;
                     if needs_flush {
                         // flush then start a new entry.
@@ -1426,18 +1925,24 @@ impl LockedDatabase {
             let l = s.uncommitted[i as usize].lock();
             return f(&RecordingPlayback {
                 video_index: &l.video_index,
+                audio_index: &l.audio_index,
+                audio_start_offset_90k: l.audio_start_offset_90k,
             });
         }
 
         // Committed path.
-        let mut cache = self.video_index_cache.borrow_mut();
+        let mut cache = self.playback_cache.borrow_mut();
         use hashlink::linked_hash_map::RawEntryMut;
         match cache.raw_entry_mut().from_key(&id.0) {
             RawEntryMut::Occupied(mut occupied) => {
                 trace!("cache hit for recording {}", id);
                 occupied.to_back();
-                let video_index = occupied.get();
-                f(&RecordingPlayback { video_index })
+                let p = occupied.get();
+                f(&RecordingPlayback {
+                    video_index: &p.video_index,
+                    audio_index: &p.audio_index,
+                    audio_start_offset_90k: p.audio_start_offset_90k,
+                })
             }
             RawEntryMut::Vacant(vacant) => {
                 trace!("cache miss for recording {}", id);
@@ -1445,11 +1950,19 @@ impl LockedDatabase {
                 let mut rows = stmt.query(named_params! {":composite_id": id.0})?;
                 if let Some(row) = rows.next()? {
                     let video_index: VideoIndex = row.get(0)?;
+                    let audio_index: Option<Vec<u8>> = row.get(1)?;
+                    let p = CachedPlayback {
+                        video_index: video_index.0,
+                        audio_index: audio_index.unwrap_or_default().into_boxed_slice(),
+                        audio_start_offset_90k: row.get(2)?,
+                    };
                     let result = f(&RecordingPlayback {
-                        video_index: &video_index.0[..],
+                        video_index: &p.video_index,
+                        audio_index: &p.audio_index,
+                        audio_start_offset_90k: p.audio_start_offset_90k,
                     });
-                    vacant.insert(id.0, video_index.0);
-                    if cache.len() > VIDEO_INDEX_CACHE_LEN {
+                    vacant.insert(id.0, p);
+                    if cache.len() > PLAYBACK_CACHE_LEN {
                         cache.pop_front();
                     }
                     return result;
@@ -1459,8 +1972,135 @@ impl LockedDatabase {
         }
     }
 
+    /// Returns the ids of up to `limit` committed recordings within `ids` which have no
+    /// thumbnail, in ascending order.
+    pub fn list_recordings_without_thumbnails(
+        &self,
+        ids: Range<CompositeId>,
+        limit: usize,
+    ) -> Result<Vec<CompositeId>, Error> {
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            select
+              composite_id
+            from
+              recording
+            where
+              :start <= composite_id and
+              composite_id < :end and
+              not exists (select 1 from recording_thumbnail t
+                          where t.composite_id = recording.composite_id)
+            order by
+              composite_id
+            limit :limit
+            "#,
+        )?;
+        let mut rows = stmt.query(named_params! {
+            ":start": ids.start.0,
+            ":end": ids.end.0,
+            ":limit": limit as i64,
+        })?;
+        let mut out = Vec::new();
+        while let Some(row) = rows.next()? {
+            out.push(CompositeId(row.get(0)?));
+        }
+        Ok(out)
+    }
+
+    /// Calls `f` with the recording id of each recording within `ids` which has a thumbnail.
+    pub fn list_thumbnails(
+        &self,
+        stream_id: i32,
+        ids: Range<i32>,
+        f: &mut dyn FnMut(i32),
+    ) -> Result<(), Error> {
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            select
+              composite_id
+            from
+              recording_thumbnail
+            where
+              :start <= composite_id and
+              composite_id < :end
+            "#,
+        )?;
+        let mut rows = stmt.query(named_params! {
+            ":start": CompositeId::new(stream_id, ids.start).0,
+            ":end": CompositeId::new(stream_id, ids.end).0,
+        })?;
+        while let Some(row) = rows.next()? {
+            f(CompositeId(row.get(0)?).recording());
+        }
+        Ok(())
+    }
+
+    /// Returns the JPEG thumbnail of the given recording, if it has one.
+    pub fn get_thumbnail(&self, id: CompositeId) -> Result<Option<Vec<u8>>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("select data from recording_thumbnail where composite_id = ?")?;
+        let mut rows = stmt.query(params![id.0])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the raw blake3 hash of the given recording's sample file, if known.
+    /// This may be truncated; see `recording_integrity.sample_file_blake3` in `schema.sql`.
+    pub fn get_sample_file_blake3(&self, id: CompositeId) -> Result<Option<Vec<u8>>, Error> {
+        let mut stmt = self.conn.prepare_cached(
+            "select sample_file_blake3 from recording_integrity where composite_id = ?",
+        )?;
+        let mut rows = stmt.query(params![id.0])?;
+        match rows.next()? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the key pair used to sign exports' manifests.
+    pub fn export_signing_key(&self) -> Result<ring::signature::Ed25519KeyPair, Error> {
+        let key: Option<Vec<u8>> =
+            self.conn
+                .query_row("select export_signing_key from meta", params![], |row| {
+                    row.get(0)
+                })?;
+        let key = key.ok_or_else(|| format_err!("database has no export signing key"))?;
+        ring::signature::Ed25519KeyPair::from_pkcs8(&key)
+            .map_err(|e| format_err!("bad export signing key: {}", e))
+    }
+
+    /// Stores the JPEG thumbnail of the given committed recording.
+    /// Unlike most changes, this takes effect immediately rather than on the next flush.
+    pub fn insert_thumbnail(&self, id: CompositeId, data: &[u8]) -> Result<(), Error> {
+        self.conn.execute(
+            r#"
+            insert or replace into recording_thumbnail (composite_id, data)
+                                                values (?,            ?)
+            "#,
+            params![id.0, data],
+        )?;
+        Ok(())
+    }
+
+    /// Sets the stream's `retention_overage_bytes`, returning the previous value.
+    pub(crate) fn set_retention_overage(
+        &mut self,
+        stream_id: i32,
+        bytes: i64,
+    ) -> Result<i64, Error> {
+        let s = match self.streams_by_id.get_mut(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s,
+        };
+        Ok(std::mem::replace(&mut s.retention_overage_bytes, bytes))
+    }
+
     /// Queues for deletion the oldest recordings that aren't already queued.
-    /// `f` should return true for each row that should be deleted.
+    /// `f` should return true for each row that should be deleted, or false to stop.
+    /// Recordings overlapping a hold are skipped without being passed to `f`.
     pub(crate) fn delete_oldest_recordings(
         &mut self,
         stream_id: i32,
@@ -1474,7 +2114,15 @@ impl LockedDatabase {
             None => 0,
             Some(row) => row.id.recording() + 1,
         };
+        let holds = &self.holds_by_id;
         raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, end), &mut |r| {
+            let r_end = r.start + recording::Duration(i64::from(r.wall_duration_90k));
+            if holds
+                .values()
+                .any(|h| h.stream_id == stream_id && h.time.start < r_end && r.start < h.time.end)
+            {
+                return true;
+            }
             if f(&r) {
                 s.to_delete.push(r);
                 let bytes = i64::from(r.sample_file_bytes);
@@ -1486,6 +2134,71 @@ impl LockedDatabase {
         })
     }
 
+    /// Lists up to `limit` of the given stream's oldest recordings, starting from recording id
+    /// `start`, which are still in its primary sample file directory, aren't about to be deleted,
+    /// and ended before `cutoff`. Also returns the recording id at which to start the next search.
+    pub(crate) fn list_recordings_to_archive(
+        &self,
+        stream_id: i32,
+        start: i32,
+        cutoff: recording::Time,
+        limit: usize,
+    ) -> Result<(Vec<ListOldestRecordingsRow>, i32), Error> {
+        let s = match self.streams_by_id.get(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s,
+        };
+        let mut next = match s.to_delete.last() {
+            None => start,
+            Some(row) => start.max(row.id.recording() + 1),
+        };
+        let mut rows = Vec::new();
+        raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, next), &mut |r| {
+            if r.start + recording::Duration(i64::from(r.wall_duration_90k)) > cutoff {
+                return false;
+            }
+            next = r.id.recording() + 1;
+            if r.sample_file_dir_id.is_none() {
+                rows.push(r);
+            }
+            rows.len() < limit
+        })?;
+        Ok((rows, next))
+    }
+
+    /// Marks the given recording as archived, after its sample files have been copied to (and
+    /// synced within) `archive_dir_id`. The originals become garbage of the primary directory.
+    pub(crate) fn mark_archived(
+        &mut self,
+        id: CompositeId,
+        archive_dir_id: i32,
+    ) -> Result<(), Error> {
+        let s = match self.streams_by_id.get(&id.stream()) {
+            None => bail!("no stream for recording {}", id),
+            Some(s) => s,
+        };
+        let dir_id = match (s.sample_file_dir_id, s.archive_sample_file_dir_id) {
+            (Some(d), Some(a)) if a == archive_dir_id => d,
+            _ => bail!(
+                "stream {} doesn't archive to sample file dir {}",
+                s.id,
+                archive_dir_id
+            ),
+        };
+        if s.to_delete.iter().any(|r| r.id == id) {
+            bail!("recording {} is being deleted", id);
+        }
+        let tx = self.conn.transaction()?;
+        raw::mark_archived(&tx, id, dir_id, archive_dir_id)?;
+        tx.commit()?;
+        self.sample_file_dirs_by_id
+            .get_mut(&dir_id)
+            .unwrap()
+            .garbage_needs_unlink
+            .insert(id);
+        Ok(())
+    }
+
     /// Initializes the video_sample_entries. To be called during construction.
     fn init_video_sample_entries(&mut self) -> Result<(), Error> {
         info!("Loading video sample entries");
@@ -1528,6 +2241,42 @@ impl LockedDatabase {
         Ok(())
     }
 
+    /// Initializes the audio_sample_entries. To be called during construction.
+    fn init_audio_sample_entries(&mut self) -> Result<(), Error> {
+        info!("Loading audio sample entries");
+        let mut stmt = self.conn.prepare(
+            r#"
+            select
+                id,
+                rfc6381_codec,
+                sample_rate,
+                channels,
+                data
+            from
+                audio_sample_entry
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get(0)?;
+            self.audio_sample_entries_by_id.insert(
+                id,
+                Arc::new(AudioSampleEntry {
+                    id,
+                    rfc6381_codec: row.get(1)?,
+                    sample_rate: row.get::<_, i64>(2)?.try_into()?,
+                    channels: row.get::<_, i32>(3)?.try_into()?,
+                    data: row.get(4)?,
+                }),
+            );
+        }
+        info!(
+            "Loaded {} audio sample entries",
+            self.audio_sample_entries_by_id.len()
+        );
+        Ok(())
+    }
+
     /// Initializes the sample file dirs.
     /// To be called during construction.
     fn init_sample_file_dirs(&mut self) -> Result<(), Error> {
@@ -1608,6 +2357,7 @@ impl LockedDatabase {
                     username: row.get(5)?,
                     password: row.get(6)?,
                     streams: Default::default(),
+                    onvif_status: Arc::new(Mutex::new(OnvifStatus::default())),
                 },
             );
             self.cameras_by_uuid.insert(uuid.0, id);
@@ -1633,7 +2383,11 @@ impl LockedDatabase {
               cum_recordings,
               cum_media_duration_90k,
               cum_runs,
-              record
+              record,
+              retain_max_age_sec,
+              retain_min_age_sec,
+              archive_sample_file_dir_id,
+              archive_age_sec
             from
               stream;
             "#,
@@ -1659,6 +2413,10 @@ impl LockedDatabase {
                     sample_file_dir_id: row.get(3)?,
                     rtsp_url: row.get(4)?,
                     retain_bytes: row.get(5)?,
+                    retain_max_age_sec: row.get(11)?,
+                    retain_min_age_sec: row.get(12)?,
+                    archive_sample_file_dir_id: row.get(13)?,
+                    archive_age_sec: row.get(14)?,
                     flush_if_sec,
                     range: None,
                     sample_file_bytes: 0,
@@ -1668,6 +2426,7 @@ impl LockedDatabase {
                     fs_bytes_to_delete: 0,
                     bytes_to_add: 0,
                     fs_bytes_to_add: 0,
+                    retention_overage_bytes: 0,
                     duration: recording::Duration(0),
                     committed_days: days::Map::default(),
                     cum_recordings: row.get(7)?,
@@ -1677,6 +2436,7 @@ impl LockedDatabase {
                     uncommitted: VecDeque::new(),
                     synced_recordings: 0,
                     on_live_segment: Vec::new(),
+                    status: Arc::new(Mutex::new(StreamStatus::default())),
                 },
             );
             c.streams[type_.index()] = Some(id);
@@ -1685,6 +2445,100 @@ impl LockedDatabase {
         Ok(())
     }
 
+    /// Initializes the holds. To be called during construction.
+    fn init_holds(&mut self) -> Result<(), Error> {
+        info!("Loading holds");
+        let mut stmt = self.conn.prepare(
+            r#"
+            select
+              id,
+              stream_id,
+              start_time_90k,
+              end_time_90k,
+              reason,
+              creator,
+              creation_time_90k
+            from
+              hold
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get(0)?;
+            self.holds_by_id.insert(
+                id,
+                Hold {
+                    id,
+                    stream_id: row.get(1)?,
+                    time: recording::Time(row.get(2)?)..recording::Time(row.get(3)?),
+                    reason: row.get(4)?,
+                    creator: row.get(5)?,
+                    creation_time: recording::Time(row.get(6)?),
+                },
+            );
+        }
+        info!("Loaded {} holds", self.holds_by_id.len());
+        Ok(())
+    }
+
+    /// Returns an immutable view of the holds by id.
+    pub fn holds_by_id(&self) -> &BTreeMap<i32, Hold> {
+        &self.holds_by_id
+    }
+
+    /// Adds a hold, returning its id. Unlike most changes, this takes effect immediately rather
+    /// than on the next flush. Recordings already queued for deletion aren't affected.
+    pub fn add_hold(&mut self, h: HoldToInsert) -> Result<i32, Error> {
+        if !self.streams_by_id.contains_key(&h.stream_id) {
+            bail!("no such stream {}", h.stream_id);
+        }
+        if h.time.start >= h.time.end {
+            bail!("hold has empty time range {:?}", h.time);
+        }
+        self.conn.execute(
+            r#"
+            insert into hold (stream_id, start_time_90k, end_time_90k, reason, creator,
+                              creation_time_90k)
+                      values (?,         ?,              ?,            ?,      ?,
+                              ?)
+            "#,
+            params![
+                h.stream_id,
+                h.time.start.0,
+                h.time.end.0,
+                &h.reason,
+                &h.creator,
+                h.creation_time.0,
+            ],
+        )?;
+        let id = self.conn.last_insert_rowid() as i32;
+        self.holds_by_id.insert(
+            id,
+            Hold {
+                id,
+                stream_id: h.stream_id,
+                time: h.time,
+                reason: h.reason,
+                creator: h.creator,
+                creation_time: h.creation_time,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Releases a hold, allowing its recordings to be deleted by the next rotation.
+    pub fn release_hold(&mut self, id: i32) -> Result<(), Error> {
+        if self
+            .conn
+            .execute("delete from hold where id = ?", params![id])?
+            != 1
+        {
+            bail!("no such hold {}", id);
+        }
+        self.holds_by_id.remove(&id);
+        Ok(())
+    }
+
     /// Inserts the specified video sample entry if absent.
     /// On success, returns the id of a new or existing row.
     pub fn insert_video_sample_entry(
@@ -1740,6 +2594,51 @@ impl LockedDatabase {
         Ok(id)
     }
 
+    /// Inserts the specified audio sample entry if absent.
+    /// On success, returns the id of a new or existing row.
+    pub fn insert_audio_sample_entry(
+        &mut self,
+        entry: AudioSampleEntryToInsert,
+    ) -> Result<i32, Error> {
+        for (&id, a) in &self.audio_sample_entries_by_id {
+            if a.data == entry.data {
+                // As with video sample entries, the other fields are derived from data.
+                if a.sample_rate != entry.sample_rate || a.channels != entry.channels {
+                    bail!(
+                        "audio_sample_entry id {}: existing entry {:?}, new {:?}",
+                        id,
+                        a,
+                        &entry
+                    );
+                }
+                return Ok(id);
+            }
+        }
+
+        let mut stmt = self.conn.prepare_cached(INSERT_AUDIO_SAMPLE_ENTRY_SQL)?;
+        stmt.execute(named_params! {
+            ":rfc6381_codec": &entry.rfc6381_codec,
+            ":sample_rate": i64::from(entry.sample_rate),
+            ":channels": i32::from(entry.channels),
+            ":data": &entry.data,
+        })
+        .map_err(|e| Error::from(e).context(format!("Unable to insert {:#?}", &entry)))?;
+
+        let id = self.conn.last_insert_rowid() as i32;
+        self.audio_sample_entries_by_id.insert(
+            id,
+            Arc::new(AudioSampleEntry {
+                id,
+                rfc6381_codec: entry.rfc6381_codec,
+                sample_rate: entry.sample_rate,
+                channels: entry.channels,
+                data: entry.data,
+            }),
+        );
+
+        Ok(id)
+    }
+
     pub fn add_sample_file_dir(&mut self, path: String) -> Result<i32, Error> {
         let mut meta = schema::DirMeta::default();
         let uuid = Uuid::new_v4();
@@ -1789,7 +2688,8 @@ impl LockedDatabase {
 
     pub fn delete_sample_file_dir(&mut self, dir_id: i32) -> Result<(), Error> {
         for (&id, s) in self.streams_by_id.iter() {
-            if s.sample_file_dir_id == Some(dir_id) {
+            if s.sample_file_dir_id == Some(dir_id) || s.archive_sample_file_dir_id == Some(dir_id)
+            {
                 bail!("can't delete dir referenced by stream {}", id);
             }
         }
@@ -1874,6 +2774,7 @@ impl LockedDatabase {
                 username: camera.username,
                 password: camera.password,
                 streams,
+                onvif_status: Arc::new(Mutex::new(OnvifStatus::default())),
             },
         );
         self.cameras_by_uuid.insert(uuid, camera_id);
@@ -1922,6 +2823,10 @@ impl LockedDatabase {
         c.username = camera.username;
         c.password = camera.password;
         c.streams = streams.apply(&mut self.streams_by_id);
+        let streams_by_id = &self.streams_by_id;
+        self.holds_by_id
+            .retain(|_, h| streams_by_id.contains_key(&h.stream_id));
+        self.link_archive_dirs();
         Ok(())
     }
 
@@ -1935,14 +2840,31 @@ impl LockedDatabase {
         let mut streams_to_delete = Vec::new();
         let tx = self.conn.transaction()?;
         {
+            let mut motion_stmt =
+                tx.prepare_cached(r"delete from stream_motion where stream_id = :id")?;
+            let mut hold_stmt = tx.prepare_cached(r"delete from hold where stream_id = :id")?;
+            tx.execute(
+                r"delete from onvif_event_mapping where camera_id = :id",
+                named_params! {":id": id},
+            )?;
+            tx.execute(
+                r"delete from onvif_event_config where camera_id = :id",
+                named_params! {":id": id},
+            )?;
+            tx.execute(
+                r"delete from onvif_subscription where camera_id = :id",
+                named_params! {":id": id},
+            )?;
             let mut stream_stmt = tx.prepare_cached(r"delete from stream where id = :id")?;
             for (stream_id, stream) in &self.streams_by_id {
                 if stream.camera_id != id {
                     continue;
                 };
-                if stream.range.is_some() {
+                if stream.range.is_some() || !stream.uncommitted.is_empty() {
                     bail!("Can't remove camera {}; has recordings.", id);
                 }
+                motion_stmt.execute(named_params! {":id": stream_id})?;
+                hold_stmt.execute(named_params! {":id": stream_id})?;
                 let rows = stream_stmt.execute(named_params! {":id": stream_id})?;
                 if rows != 1 {
                     bail!("Stream {} missing from database", id);
@@ -1959,6 +2881,9 @@ impl LockedDatabase {
         for id in streams_to_delete {
             self.streams_by_id.remove(&id);
         }
+        let streams_by_id = &self.streams_by_id;
+        self.holds_by_id
+            .retain(|_, h| streams_by_id.contains_key(&h.stream_id));
         self.cameras_by_id.remove(&id);
         self.cameras_by_uuid.remove(&uuid);
         Ok(())
@@ -2025,16 +2950,50 @@ impl LockedDatabase {
         self.auth.get_user(username)
     }
 
+    pub fn verify_password(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> Result<schema::Permissions, Error> {
+        self.auth.verify_password(username, password)
+    }
+
     pub fn login_by_password(
         &mut self,
         req: auth::Request,
         username: &str,
         password: String,
+        totp_code: Option<&str>,
         domain: Option<Vec<u8>>,
         session_flags: i32,
     ) -> Result<(RawSessionId, &Session), Error> {
+        self.auth.login_by_password(
+            &self.conn,
+            req,
+            username,
+            password,
+            totp_code,
+            domain,
+            session_flags,
+        )
+    }
+
+    pub fn begin_totp_enrollment(&mut self, uid: i32) -> Result<String, Error> {
+        self.auth.begin_totp_enrollment(uid)
+    }
+
+    pub fn confirm_totp_enrollment(
+        &mut self,
+        uid: i32,
+        code: &str,
+        when_sec: i64,
+    ) -> Result<Vec<String>, Error> {
         self.auth
-            .login_by_password(&self.conn, req, username, password, domain, session_flags)
+            .confirm_totp_enrollment(&self.conn, uid, code, when_sec)
+    }
+
+    pub fn check_totp_code(&mut self, uid: i32, code: &str, when_sec: i64) -> Result<(), Error> {
+        self.auth.check_totp_code(&self.conn, uid, code, when_sec)
     }
 
     pub fn make_session(
@@ -2049,6 +3008,24 @@ impl LockedDatabase {
             .make_session(&self.conn, creation, uid, domain, flags, permissions)
     }
 
+    pub fn make_api_token(
+        &mut self,
+        creation: Request,
+        uid: i32,
+        name: String,
+        expiration_time_sec: Option<i64>,
+        permissions: schema::Permissions,
+    ) -> Result<(RawSessionId, &Session), Error> {
+        self.auth.make_api_token(
+            &self.conn,
+            creation,
+            uid,
+            name,
+            expiration_time_sec,
+            permissions,
+        )
+    }
+
     pub fn authenticate_session(
         &mut self,
         req: auth::Request,
@@ -2057,6 +3034,123 @@ impl LockedDatabase {
         self.auth.authenticate_session(&self.conn, req, sid)
     }
 
+    /// Records an event in the audit log. It's written on the next flush. If the database is
+    /// read-only, the event is discarded.
+    pub fn audit(&mut self, event: audit::Event) {
+        if self.open.is_some() {
+            self.audit.add(event);
+        }
+    }
+
+    /// Updates metrics derived from the database's state, such as sample file directory usage.
+    /// These are set just before metrics are rendered rather than on every change.
+    pub fn update_metrics(&self) {
+        for d in self.sample_file_dirs_by_id.values() {
+            let labels = [("dir", &d.path[..])];
+            let (mut fs_bytes, mut retain_bytes) = (0, 0);
+            for s in self.streams_by_id.values() {
+                if s.sample_file_dir_id == Some(d.id) {
+                    fs_bytes += s.fs_bytes;
+                    retain_bytes += s.retain_bytes;
+                }
+            }
+            REGISTRY
+                .gauge(
+                    "moonfire_dir_fs_bytes",
+                    "Filesystem bytes used by the sample files of the directory's streams.",
+                    &labels,
+                )
+                .set(fs_bytes as f64);
+            REGISTRY
+                .gauge(
+                    "moonfire_dir_retain_bytes",
+                    "Sum of the retain_bytes limits of the directory's streams.",
+                    &labels,
+                )
+                .set(retain_bytes as f64);
+            REGISTRY
+                .gauge(
+                    "moonfire_dir_garbage_files",
+                    "Deleted recordings whose sample files are still in the garbage table.",
+                    &labels,
+                )
+                .set((d.garbage_needs_unlink.len() + d.garbage_unlinked.len()) as f64);
+        }
+    }
+
+    // ---- webhooks ----
+
+    pub fn webhooks_by_id(&self) -> &BTreeMap<i32, webhook::Webhook> {
+        self.webhooks.webhooks_by_id()
+    }
+
+    /// Adds a webhook with a newly generated secret, returning its id.
+    pub fn add_webhook(&mut self, url: String, filter: webhook::Filter) -> Result<i32, Error> {
+        self.webhooks.add(&self.conn, url, filter)
+    }
+
+    pub fn delete_webhook(&mut self, id: i32) -> Result<(), Error> {
+        self.webhooks.delete(&mut self.conn, id)
+    }
+
+    /// Sends `event` to subscribers (see `subscribe_events`) and queues deliveries of it to each
+    /// matching webhook. If the database is read-only, no deliveries are queued.
+    ///
+    /// Errors are logged rather than returned, so that a failure to notify doesn't fail the
+    /// operation which caused the event.
+    pub fn enqueue_webhook_event(&mut self, event: webhook::Event) {
+        self.webhooks.broadcast(&event);
+        if self.open.is_none() {
+            return;
+        }
+        if let Err(e) = self.webhooks.enqueue(&self.conn, self, &event) {
+            error!("Unable to queue webhook event {:?}: {}", event, e);
+        }
+    }
+
+    /// Returns a receiver of all events, as they happen. A subscriber which falls too far behind
+    /// misses events; see `tokio::sync::broadcast`.
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<webhook::Event> {
+        self.webhooks.subscribe()
+    }
+
+    /// Returns a notifier which is signalled when a webhook delivery is queued.
+    pub fn webhook_notify(&self) -> Arc<tokio::sync::Notify> {
+        self.webhooks.notify()
+    }
+
+    /// Lists up to `limit` webhook deliveries which are due as of `now_sec`, oldest first.
+    pub fn list_due_webhook_deliveries(
+        &self,
+        now_sec: i64,
+        limit: usize,
+    ) -> Result<Vec<webhook::Delivery>, Error> {
+        self.webhooks.list_due(&self.conn, now_sec, limit)
+    }
+
+    /// Records the result of an attempt to send a webhook delivery, returning true if the
+    /// delivery is finished (successfully or not) and false if it will be retried.
+    pub fn finish_webhook_delivery(
+        &self,
+        delivery: &webhook::Delivery,
+        now_sec: i64,
+        result: Result<(), String>,
+    ) -> Result<bool, Error> {
+        self.webhooks.finish(&self.conn, delivery, now_sec, result)
+    }
+
+    /// Lists audit log events matching `filter`, most recent first.
+    pub fn list_audit_events(&self, filter: &audit::Filter) -> Result<Vec<audit::Event>, Error> {
+        self.audit.list(&self.conn, filter)
+    }
+
+    pub fn list_sessions(
+        &mut self,
+        user_id: Option<i32>,
+    ) -> Result<Vec<(auth::SessionHash, &Session, &User)>, Error> {
+        self.auth.list_sessions(&self.conn, user_id)
+    }
+
     pub fn revoke_session(
         &mut self,
         reason: auth::RevocationReason,
@@ -2089,7 +3183,152 @@ impl LockedDatabase {
         signals: &[u32],
         states: &[u16],
     ) -> Result<(), base::Error> {
-        self.signal.update_signals(when, signals, states)
+        let changes = self.signal.update_signals(when.clone(), signals, states)?;
+        for (signal_id, state) in changes {
+            self.enqueue_webhook_event(webhook::Event::Signal {
+                signal_id,
+                state,
+                time: when.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the motion detection configuration of all streams which have one.
+    pub fn list_stream_motion(&self) -> Result<Vec<StreamMotion>, Error> {
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            select
+              stream_id,
+              signal_id,
+              sensitivity,
+              zones
+            from
+              stream_motion
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        let mut out = Vec::new();
+        while let Some(row) = rows.next()? {
+            out.push(StreamMotion {
+                stream_id: row.get(0)?,
+                signal_id: row.get(1)?,
+                sensitivity: row.get(2)?,
+                zones: row.get(3)?,
+            });
+        }
+        Ok(out)
+    }
+
+    /// Returns all ONVIF event mappings, ordered by camera.
+    pub fn list_onvif_event_mappings(&self) -> Result<Vec<OnvifEventMapping>, Error> {
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            select
+              id,
+              camera_id,
+              topic,
+              source_name,
+              source_value,
+              signal_id,
+              active_state,
+              inactive_state
+            from
+              onvif_event_mapping
+            order by
+              camera_id,
+              id
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        let mut out = Vec::new();
+        while let Some(row) = rows.next()? {
+            let source_name: Option<String> = row.get(3)?;
+            let source_value: Option<String> = row.get(4)?;
+            out.push(OnvifEventMapping {
+                id: row.get(0)?,
+                camera_id: row.get(1)?,
+                topic: row.get(2)?,
+                source: source_name.zip(source_value),
+                signal_id: row.get(5)?,
+                active_state: row.get(6)?,
+                inactive_state: row.get(7)?,
+            });
+        }
+        Ok(out)
+    }
+
+    /// Returns the ids of cameras configured to fetch ONVIF events via PullPoint subscriptions.
+    pub fn list_onvif_pull_point_cameras(&self) -> Result<Vec<i32>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("select camera_id from onvif_event_config where pull_point")?;
+        let mut rows = stmt.query(params![])?;
+        let mut out = Vec::new();
+        while let Some(row) = rows.next()? {
+            out.push(row.get(0)?);
+        }
+        Ok(out)
+    }
+
+    /// Returns all ONVIF event subscriptions, including expired ones.
+    pub fn list_onvif_subscriptions(&self) -> Result<Vec<OnvifSubscription>, Error> {
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            select
+              camera_id,
+              token,
+              manager_address,
+              termination_time_sec
+            from
+              onvif_subscription
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        let mut out = Vec::new();
+        while let Some(row) = rows.next()? {
+            out.push(OnvifSubscription {
+                camera_id: row.get(0)?,
+                token: row.get(1)?,
+                manager_address: row.get(2)?,
+                termination_time_sec: row.get(3)?,
+            });
+        }
+        Ok(out)
+    }
+
+    /// Returns the camera id of the ONVIF subscription with the given notification token.
+    pub fn get_onvif_subscription_camera(&self, token: &str) -> Result<Option<i32>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("select camera_id from onvif_subscription where token = ?")?;
+        let mut rows = stmt.query(params![token])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Inserts or replaces the ONVIF subscription for `s.camera_id`.
+    pub fn put_onvif_subscription(&mut self, s: &OnvifSubscription) -> Result<(), Error> {
+        if !self.cameras_by_id.contains_key(&s.camera_id) {
+            bail!("no such camera {}", s.camera_id);
+        }
+        self.conn.execute(
+            r#"
+            insert or replace into onvif_subscription (camera_id, token, manager_address,
+                                                       termination_time_sec)
+                                               values (:camera_id, :token, :manager_address,
+                                                       :termination_time_sec)
+            "#,
+            named_params! {
+                ":camera_id": s.camera_id,
+                ":token": &s.token,
+                ":manager_address": &s.manager_address,
+                ":termination_time_sec": s.termination_time_sec,
+            },
+        )?;
+        Ok(())
     }
 }
 
@@ -2117,12 +3356,23 @@ pub fn init(conn: &mut rusqlite::Connection) -> Result<(), Error> {
     {
         let uuid = ::uuid::Uuid::new_v4();
         let uuid_bytes = &uuid.as_bytes()[..];
-        tx.execute("insert into meta (uuid) values (?)", params![uuid_bytes])?;
+        let key = generate_export_signing_key()?;
+        tx.execute(
+            "insert into meta (uuid, export_signing_key) values (?, ?)",
+            params![uuid_bytes, &key],
+        )?;
     }
     tx.commit()?;
     Ok(())
 }
 
+/// Generates a PKCS #8 document holding a new Ed25519 key pair, for `meta.export_signing_key`.
+pub(crate) fn generate_export_signing_key() -> Result<Vec<u8>, Error> {
+    let doc = ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+        .map_err(|_| format_err!("unable to generate export signing key"))?;
+    Ok(doc.as_ref().to_vec())
+}
+
 /// Gets the schema version from the given database connection.
 /// A fully initialized database will return `Ok(Some(version))` where `version` is an integer that
 /// can be compared to `EXPECTED_VERSION`. An empty database will return `Ok(None)`. A partially
@@ -2196,6 +3446,27 @@ impl<C: Clocks + Clone> Drop for Database<C> {
     }
 }
 
+lazy_static! {
+    static ref LOCK_WAIT: Arc<Histogram> = REGISTRY.histogram(
+        "moonfire_db_lock_wait_seconds",
+        "Time spent waiting to acquire the database lock.",
+        DURATION_BUCKETS,
+        &[],
+    );
+    static ref LOCK_HOLD: Arc<Histogram> = REGISTRY.histogram(
+        "moonfire_db_lock_hold_seconds",
+        "Time the database lock was held.",
+        DURATION_BUCKETS,
+        &[],
+    );
+    static ref FLUSH_DURATION: Arc<Histogram> = REGISTRY.histogram(
+        "moonfire_db_flush_duration_seconds",
+        "Time taken by successful database flushes.",
+        DURATION_BUCKETS,
+        &[],
+    );
+}
+
 // Helpers for Database::lock(). Closures don't implement Fn.
 fn acquisition() -> &'static str {
     "database lock acquisition"
@@ -2234,6 +3505,8 @@ impl<C: Clocks + Clone> Database<C> {
         };
         let auth = auth::State::init(&conn)?;
         let signal = signal::State::init(&conn)?;
+        let audit = audit::State::init(&conn)?;
+        let webhooks = webhook::State::init(&conn)?;
         let db = Database {
             db: Some(Mutex::new(LockedDatabase {
                 conn,
@@ -2243,13 +3516,17 @@ impl<C: Clocks + Clone> Database<C> {
                 open_monotonic,
                 auth,
                 signal,
+                audit,
+                webhooks,
                 sample_file_dirs_by_id: BTreeMap::new(),
                 cameras_by_id: BTreeMap::new(),
                 cameras_by_uuid: BTreeMap::new(),
                 streams_by_id: BTreeMap::new(),
+                holds_by_id: BTreeMap::new(),
                 video_sample_entries_by_id: BTreeMap::new(),
-                video_index_cache: RefCell::new(LinkedHashMap::with_capacity_and_hasher(
-                    VIDEO_INDEX_CACHE_LEN + 1,
+                audio_sample_entries_by_id: BTreeMap::new(),
+                playback_cache: RefCell::new(LinkedHashMap::with_capacity_and_hasher(
+                    PLAYBACK_CACHE_LEN + 1,
                     Default::default(),
                 )),
                 on_flush: Vec::new(),
@@ -2259,9 +3536,11 @@ impl<C: Clocks + Clone> Database<C> {
         {
             let l = &mut *db.lock();
             l.init_video_sample_entries()?;
+            l.init_audio_sample_entries()?;
             l.init_sample_file_dirs()?;
             l.init_cameras()?;
             l.init_streams()?;
+            l.init_holds()?;
             for (&stream_id, ref mut stream) in &mut l.streams_by_id {
                 // TODO: we could use one thread per stream if we had multiple db conns.
                 let camera = l.cameras_by_id.get(&stream.camera_id).unwrap();
@@ -2280,7 +3559,10 @@ impl<C: Clocks + Clone> Database<C> {
     /// operations.
     pub fn lock(&self) -> DatabaseGuard<C> {
         let timer = clock::TimerGuard::new(&self.clocks, acquisition);
+        let start = self.clocks.monotonic();
         let db = self.db.as_ref().unwrap().lock();
+        let acquired = self.clocks.monotonic();
+        LOCK_WAIT.observe_duration(acquired - start);
         drop(timer);
         let _timer = clock::TimerGuard::<C, &'static str, fn() -> &'static str>::new(
             &self.clocks,
@@ -2289,6 +3571,7 @@ impl<C: Clocks + Clone> Database<C> {
         DatabaseGuard {
             clocks: &self.clocks,
             db,
+            acquired,
             _timer,
         }
     }
@@ -2305,9 +3588,18 @@ impl<C: Clocks + Clone> Database<C> {
 pub struct DatabaseGuard<'db, C: Clocks> {
     clocks: &'db C,
     db: MutexGuard<'db, LockedDatabase>,
+
+    /// Monotonic time at which the lock was acquired, for the hold time metric.
+    acquired: Timespec,
     _timer: clock::TimerGuard<'db, C, &'static str, fn() -> &'static str>,
 }
 
+impl<'db, C: Clocks> Drop for DatabaseGuard<'db, C> {
+    fn drop(&mut self) {
+        LOCK_HOLD.observe_duration(self.clocks.monotonic() - self.acquired);
+    }
+}
+
 impl<'db, C: Clocks + Clone> DatabaseGuard<'db, C> {
     /// Tries to flush unwritten changes from the stream directories.
     ///
@@ -2461,12 +3753,12 @@ mod tests {
     fn test_version_too_old() {
         testutil::init();
         let c = setup_conn();
-        c.execute_batch("delete from version; insert into version values (5, 0, '');")
+        c.execute_batch("delete from version; insert into version values (6, 0, '');")
             .unwrap();
         let e = Database::new(clock::RealClocks {}, c, false).err().unwrap();
         assert!(
             e.to_string()
-                .starts_with("Database schema version 5 is too old (expected 6)"),
+                .starts_with("Database schema version 6 is too old (expected 7)"),
             "got: {:?}",
             e
         );
@@ -2476,12 +3768,12 @@ mod tests {
     fn test_version_too_new() {
         testutil::init();
         let c = setup_conn();
-        c.execute_batch("delete from version; insert into version values (7, 0, '');")
+        c.execute_batch("delete from version; insert into version values (8, 0, '');")
             .unwrap();
         let e = Database::new(clock::RealClocks {}, c, false).err().unwrap();
         assert!(
             e.to_string()
-                .starts_with("Database schema version 7 is too new (expected 6)"),
+                .starts_with("Database schema version 8 is too new (expected 7)"),
             "got: {:?}",
             e
         );
@@ -2497,6 +3789,51 @@ mod tests {
         assert_eq!(0, db.cameras_by_id().values().count());
     }
 
+    #[test]
+    fn stream_status_stalled() {
+        let max_age = recording::Duration(30 * TIME_UNITS_PER_SEC);
+        let t0 = recording::Time(1_000 * TIME_UNITS_PER_SEC);
+        let mut s = StreamStatus::default();
+        assert!(s.is_stalled(t0, max_age)); // never started.
+        s.set_state(StreamState::Connecting, t0);
+        assert!(!s.is_stalled(t0 + max_age, max_age));
+        assert!(s.is_stalled(t0 + max_age + recording::Duration(1), max_age));
+        s.set_state(StreamState::Connected, t0);
+        assert_eq!(s.session_start, Some(t0));
+        s.last_frame = Some(t0 + max_age);
+        assert!(!s.is_stalled(t0 + max_age + max_age, max_age));
+
+        // An error keeps the last frame time, so a camera that goes offline is reported as
+        // stalled once its last frame is old enough.
+        s.set_error("connection refused".to_owned(), t0 + max_age);
+        assert_eq!(s.state, StreamState::Error);
+        assert_eq!(s.session_start, None);
+        assert!(s.is_stalled(t0 + max_age + max_age + recording::Duration(1), max_age));
+    }
+
+    #[test]
+    fn onvif_status_stale() {
+        let max_age = recording::Duration(300 * TIME_UNITS_PER_SEC);
+        let t0 = recording::Time(1_000 * TIME_UNITS_PER_SEC);
+        let mut s = OnvifStatus::default();
+        assert!(s.is_stale(t0, max_age)); // never subscribed.
+        s.set_state(OnvifState::Subscribed, t0);
+        assert!(!s.is_stale(t0 + max_age, max_age));
+        assert!(s.is_stale(t0 + max_age + recording::Duration(1), max_age));
+        s.last_event = Some(t0 + max_age);
+        assert!(!s.is_stale(t0 + max_age + max_age, max_age));
+
+        // Failures accumulate until the next successful subscription.
+        s.expiration = Some(t0 + max_age);
+        s.set_error("timed out".to_owned(), t0);
+        s.set_error("timed out".to_owned(), t0);
+        assert_eq!(s.failures, 2);
+        assert_eq!(s.expiration, None);
+        s.set_state(OnvifState::Subscribed, t0);
+        assert_eq!(s.failures, 0);
+        assert_eq!(s.last_error.as_deref(), Some("timed out"));
+    }
+
     /// Basic test of the full lifecycle of recording. Does not exercise error cases.
     #[test]
     fn test_full_lifecycle() {
@@ -2521,12 +3858,14 @@ mod tests {
                     rtsp_url: "rtsp://test-camera/main".to_owned(),
                     record: false,
                     flush_if_sec: 1,
+                    ..Default::default()
                 },
                 StreamChange {
                     sample_file_dir_id: Some(sample_file_dir_id),
                     rtsp_url: "rtsp://test-camera/sub".to_owned(),
                     record: true,
                     flush_if_sec: 1,
+                    ..Default::default()
                 },
             ],
         };
@@ -2574,6 +3913,17 @@ mod tests {
             0
         );
 
+        let hold_id = db
+            .lock()
+            .add_hold(HoldToInsert {
+                stream_id: main_stream_id,
+                time: recording::Time(1)..recording::Time(2),
+                reason: "test".to_owned(),
+                creator: Some("slamb".to_owned()),
+                creation_time: recording::Time(3),
+            })
+            .unwrap();
+
         // Closing and reopening the database should present the same contents.
         let conn = db.close();
         let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
@@ -2585,6 +3935,15 @@ mod tests {
                 .flush_if_sec,
             2
         );
+        {
+            let mut l = db.lock();
+            let h = l.holds_by_id().get(&hold_id).unwrap();
+            assert_eq!(h.stream_id, main_stream_id);
+            assert_eq!(h.time, recording::Time(1)..recording::Time(2));
+            assert_eq!(h.creator.as_deref(), Some("slamb"));
+            l.release_hold(hold_id).unwrap();
+            assert!(l.holds_by_id().is_empty());
+        }
         assert_no_recordings(&db, camera_uuid);
         assert_eq!(
             db.lock()
@@ -2626,6 +3985,11 @@ mod tests {
             video_sync_samples: 1,
             video_sample_entry_id: vse_id,
             video_index: [0u8; 100].to_vec(),
+            audio_sample_entry_id: None,
+            audio_samples: 0,
+            audio_sample_file_bytes: 0,
+            audio_index: Vec::new(),
+            audio_start_offset_90k: 0,
             sample_file_blake3: None,
         };
         let id = {
@@ -2653,6 +4017,30 @@ mod tests {
         let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
         assert_single_recording(&db, main_stream_id, &recording);
 
+        // The recording should lack a thumbnail until one is inserted.
+        {
+            let db = db.lock();
+            let all = CompositeId::new(main_stream_id, 0)..CompositeId::new(main_stream_id + 1, 0);
+            assert_eq!(
+                db.list_recordings_without_thumbnails(all.clone(), 16)
+                    .unwrap(),
+                vec![id]
+            );
+            assert_eq!(db.get_thumbnail(id).unwrap(), None);
+            db.insert_thumbnail(id, b"jpeg").unwrap();
+            assert_eq!(
+                db.list_recordings_without_thumbnails(all, 16).unwrap(),
+                vec![]
+            );
+            assert_eq!(db.get_thumbnail(id).unwrap(), Some(b"jpeg".to_vec()));
+            let mut thumbnails = Vec::new();
+            db.list_thumbnails(main_stream_id, 0..i32::max_value(), &mut |r| {
+                thumbnails.push(r)
+            })
+            .unwrap();
+            assert_eq!(&thumbnails, &[id.recording()]);
+        }
+
         // Deleting a recording should succeed, update the min/max times, and mark it as garbage.
         {
             let mut db = db.lock();
@@ -2719,4 +4107,35 @@ mod tests {
         assert_eq!(super::round_up(8_192), 8_192);
         assert_eq!(super::round_up(8_193), 12_288);
     }
+
+    #[test]
+    fn onvif_subscription() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let mut l = tdb.db.lock();
+        let mut s = OnvifSubscription {
+            camera_id: testutil::TEST_CAMERA_ID,
+            token: Some("abc".to_owned()),
+            manager_address: "http://camera/onvif/Subscription?Idx=0".to_owned(),
+            termination_time_sec: 1_600_000_000,
+        };
+        l.put_onvif_subscription(&s).unwrap();
+        s.termination_time_sec += 600;
+        l.put_onvif_subscription(&s).unwrap();
+        assert_eq!(l.list_onvif_subscriptions().unwrap(), &[s.clone()]);
+        assert_eq!(
+            l.get_onvif_subscription_camera("abc").unwrap(),
+            Some(testutil::TEST_CAMERA_ID)
+        );
+        assert_eq!(l.get_onvif_subscription_camera("def").unwrap(), None);
+        s.token = None;
+        l.put_onvif_subscription(&s).unwrap();
+        assert_eq!(l.get_onvif_subscription_camera("abc").unwrap(), None);
+        s.camera_id = 42;
+        l.put_onvif_subscription(&s).unwrap_err();
+
+        // Deleting the camera deletes its subscription.
+        l.delete_camera(testutil::TEST_CAMERA_ID).unwrap();
+        assert!(l.list_onvif_subscriptions().unwrap().is_empty());
+    }
 }
@@ -50,18 +50,33 @@ pub struct SampleFileDir {
     pub(crate) fd: Arc<Fd>,
 
     reader: reader::Reader,
+
+    /// Archive directories which the reader also searches for sample files; see
+    /// [SampleFileDir::add_archive].
+    archives: reader::Archives,
 }
 
 /// The on-disk filename of a recording file within the sample file directory.
-/// This is the [`CompositeId`](crate::db::CompositeId) as 16 hexadigits. It's
-/// null-terminated so it can be passed to system calls without copying.
-pub(crate) struct CompositeIdPath([u8; 17]);
+/// This is the [`CompositeId`](crate::db::CompositeId) as 16 hexadigits, followed by `.a` for
+/// the recording's audio sample file (if any). It's null-terminated so it can be passed to system
+/// calls without copying.
+pub(crate) struct CompositeIdPath {
+    buf: [u8; 19],
+    len: usize,
+}
 
 impl CompositeIdPath {
     pub(crate) fn from(id: CompositeId) -> Self {
-        let mut buf = [0u8; 17];
+        let mut buf = [0u8; 19];
         write!(&mut buf[..16], "{:016x}", id.0).expect("can't format id to pathname buf");
-        CompositeIdPath(buf)
+        CompositeIdPath { buf, len: 16 }
+    }
+
+    /// Returns the path of the audio sample file associated with the given recording.
+    pub(crate) fn audio(id: CompositeId) -> Self {
+        let mut buf = [0u8; 19];
+        write!(&mut buf[..18], "{:016x}.a", id.0).expect("can't format id to pathname buf");
+        CompositeIdPath { buf, len: 18 }
     }
 }
 
@@ -70,14 +85,14 @@ impl NixPath for CompositeIdPath {
         false
     }
     fn len(&self) -> usize {
-        16
+        self.len
     }
 
     fn with_nix_path<T, F>(&self, f: F) -> Result<T, nix::Error>
     where
         F: FnOnce(&CStr) -> T,
     {
-        let p = CStr::from_bytes_with_nul(&self.0[..]).expect("no interior nuls");
+        let p = CStr::from_bytes_with_nul(&self.buf[..=self.len]).expect("no interior nuls");
         Ok(f(p))
     }
 }
@@ -313,20 +328,79 @@ impl SampleFileDir {
 
     fn open_self(path: &str, create: bool) -> Result<Arc<SampleFileDir>, Error> {
         let fd = Arc::new(Fd::open(path, create)?);
-        let reader = reader::Reader::spawn(path, fd.clone());
-        Ok(Arc::new(SampleFileDir { fd, reader }))
+        let archives = reader::Archives::default();
+        let reader = reader::Reader::spawn(path, fd.clone(), archives.clone());
+        Ok(Arc::new(SampleFileDir {
+            fd,
+            reader,
+            archives,
+        }))
+    }
+
+    /// Makes sample files which have been moved to `archive` readable through this directory.
+    pub(crate) fn add_archive(&self, archive: &SampleFileDir) {
+        let mut archives = self.archives.lock();
+        if !archives.iter().any(|a| Arc::ptr_eq(a, &archive.fd)) {
+            archives.push(archive.fd.clone());
+        }
     }
 
     /// Opens the given sample file for reading.
     pub fn open_file(&self, composite_id: CompositeId, range: Range<u64>) -> reader::FileStream {
-        self.reader.open_file(composite_id, range)
+        self.reader.open_file(composite_id, false, range)
+    }
+
+    /// Opens the audio sample file associated with the given recording.
+    pub fn open_audio_file(
+        &self,
+        composite_id: CompositeId,
+        range: Range<u64>,
+    ) -> reader::FileStream {
+        self.reader.open_file(composite_id, true, range)
     }
 
     pub fn create_file(&self, composite_id: CompositeId) -> Result<fs::File, nix::Error> {
-        let p = CompositeIdPath::from(composite_id);
+        self.create(&CompositeIdPath::from(composite_id))
+    }
+
+    pub fn create_audio_file(&self, composite_id: CompositeId) -> Result<fs::File, nix::Error> {
+        self.create(&CompositeIdPath::audio(composite_id))
+    }
+
+    /// Copies the given recording's sample file (and audio sample file, if any) to `dest`,
+    /// replacing any partial copy left by an earlier attempt. The copies are synced, but the
+    /// caller is responsible for syncing `dest` itself.
+    pub(crate) fn copy_file_to(&self, id: CompositeId, dest: &SampleFileDir) -> Result<(), Error> {
+        self.copy_to(&CompositeIdPath::from(id), dest, false)?;
+        self.copy_to(&CompositeIdPath::audio(id), dest, true)
+    }
+
+    fn copy_to(
+        &self,
+        p: &CompositeIdPath,
+        dest: &SampleFileDir,
+        optional: bool,
+    ) -> Result<(), Error> {
+        let mut src = match crate::fs::openat(self.fd.0, p, OFlag::O_RDONLY, Mode::empty()) {
+            Err(nix::Error::Sys(nix::errno::Errno::ENOENT)) if optional => return Ok(()),
+            r => r?,
+        };
+        let mut dst = match dest.create(p) {
+            Err(nix::Error::Sys(nix::errno::Errno::EEXIST)) => {
+                nix::unistd::unlinkat(Some(dest.fd.0), p, nix::unistd::UnlinkatFlags::NoRemoveDir)?;
+                dest.create(p)?
+            }
+            r => r?,
+        };
+        std::io::copy(&mut src, &mut dst)?;
+        dst.sync_all()?;
+        Ok(())
+    }
+
+    fn create(&self, p: &CompositeIdPath) -> Result<fs::File, nix::Error> {
         crate::fs::openat(
             self.fd.0,
-            &p,
+            p,
             OFlag::O_WRONLY | OFlag::O_EXCL | OFlag::O_CREAT,
             Mode::S_IRUSR | Mode::S_IWUSR,
         )
@@ -340,8 +414,14 @@ impl SampleFileDir {
         self.fd.statfs()
     }
 
-    /// Unlinks the given sample file within this directory.
+    /// Unlinks the given sample file within this directory, along with its audio sample file if
+    /// any. The audio sample file is unlinked first so that it's never orphaned.
     pub(crate) fn unlink_file(&self, id: CompositeId) -> Result<(), nix::Error> {
+        let p = CompositeIdPath::audio(id);
+        match nix::unistd::unlinkat(Some(self.fd.0), &p, nix::unistd::UnlinkatFlags::NoRemoveDir) {
+            Ok(()) | Err(nix::Error::Sys(nix::errno::Errno::ENOENT)) => {}
+            Err(e) => return Err(e),
+        }
         let p = CompositeIdPath::from(id);
         nix::unistd::unlinkat(Some(self.fd.0), &p, nix::unistd::UnlinkatFlags::NoRemoveDir)
     }
@@ -371,6 +451,14 @@ pub(crate) fn parse_id(id: &[u8]) -> Result<CompositeId, ()> {
     Ok(CompositeId(v as i64))
 }
 
+/// Parses an audio sample file's filename: a composite id followed by `.a`.
+pub(crate) fn parse_audio_id(id: &[u8]) -> Result<CompositeId, ()> {
+    if id.len() != 18 || &id[16..] != b".a" {
+        return Err(());
+    }
+    parse_id(&id[..16])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -384,6 +472,18 @@ mod tests {
         parse_id(b"meta").unwrap_err();
         parse_id(b"0").unwrap_err();
         parse_id(b"000000010000000x").unwrap_err();
+        parse_id(b"0000000100000002.a").unwrap_err();
+    }
+
+    #[test]
+    fn parse_audio_id() {
+        use super::parse_audio_id;
+        assert_eq!(
+            parse_audio_id(b"0000000100000002.a").unwrap().0,
+            0x0000000100000002
+        );
+        parse_audio_id(b"0000000100000002").unwrap_err();
+        parse_audio_id(b"0000000100000002.b").unwrap_err();
     }
 
     /// Ensures that a DirMeta with all fields filled fits within the maximum size.
@@ -34,9 +34,14 @@ use base::bail_t;
 use base::clock::{RealClocks, TimerGuard};
 use base::{format_err_t, Error, ErrorKind, ResultExt};
 use nix::{fcntl::OFlag, sys::stat::Mode};
+use parking_lot::Mutex;
 
 use crate::CompositeId;
 
+/// File descriptors of archive directories to which this directory's sample files may have been
+/// moved. Shared between the [super::SampleFileDir] and its reader thread.
+pub(super) type Archives = Arc<Mutex<Vec<Arc<super::Fd>>>>;
+
 /// Handle for a reader thread, used to send it commands.
 ///
 /// The reader will shut down after the last handle is closed.
@@ -44,7 +49,7 @@ use crate::CompositeId;
 pub(super) struct Reader(tokio::sync::mpsc::UnboundedSender<ReaderCommand>);
 
 impl Reader {
-    pub(super) fn spawn(path: &str, dir: Arc<super::Fd>) -> Self {
+    pub(super) fn spawn(path: &str, dir: Arc<super::Fd>, archives: Archives) -> Self {
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
         let page_size = usize::try_from(
             nix::unistd::sysconf(nix::unistd::SysconfVar::PAGE_SIZE)
@@ -55,12 +60,25 @@ impl Reader {
         assert_eq!(page_size.count_ones(), 1, "invalid page size {}", page_size);
         std::thread::Builder::new()
             .name(format!("r-{}", path))
-            .spawn(move || ReaderInt { dir, page_size }.run(rx))
+            .spawn(move || {
+                ReaderInt {
+                    dir,
+                    archives,
+                    page_size,
+                }
+                .run(rx)
+            })
             .expect("unable to create reader thread");
         Self(tx)
     }
 
-    pub(super) fn open_file(&self, composite_id: CompositeId, range: Range<u64>) -> FileStream {
+    /// Opens the given recording's sample file (or, if `audio` is true, its audio sample file).
+    pub(super) fn open_file(
+        &self,
+        composite_id: CompositeId,
+        audio: bool,
+        range: Range<u64>,
+    ) -> FileStream {
         if range.is_empty() {
             return FileStream {
                 state: FileStreamState::Invalid,
@@ -70,6 +88,7 @@ impl Reader {
         let (tx, rx) = tokio::sync::oneshot::channel();
         self.send(ReaderCommand::OpenFile {
             composite_id,
+            audio,
             range,
             tx,
         });
@@ -210,6 +229,7 @@ enum ReaderCommand {
     /// Opens a file and reads the first chunk.
     OpenFile {
         composite_id: CompositeId,
+        audio: bool,
         range: std::ops::Range<u64>,
         tx: tokio::sync::oneshot::Sender<Result<(Option<OpenFile>, Vec<u8>), Error>>,
     },
@@ -228,6 +248,9 @@ struct ReaderInt {
     /// File descriptor of the sample file directory.
     dir: Arc<super::Fd>,
 
+    /// Directories to try when a sample file isn't in `dir`.
+    archives: Archives,
+
     /// The page size as returned by `sysconf`; guaranteed to be a power of two.
     page_size: usize,
 }
@@ -240,6 +263,7 @@ impl ReaderInt {
             match cmd {
                 ReaderCommand::OpenFile {
                     composite_id,
+                    audio,
                     range,
                     tx,
                 } => {
@@ -249,7 +273,7 @@ impl ReaderInt {
                     }
                     let _guard =
                         TimerGuard::new(&RealClocks {}, || format!("open {}", composite_id));
-                    let _ = tx.send(self.open(composite_id, range));
+                    let _ = tx.send(self.open(composite_id, audio, range));
                 }
                 ReaderCommand::ReadNextChunk { file, tx } => {
                     if tx.is_closed() {
@@ -269,9 +293,13 @@ impl ReaderInt {
     fn open(
         &self,
         composite_id: CompositeId,
+        audio: bool,
         range: Range<u64>,
     ) -> Result<(Option<OpenFile>, Vec<u8>), Error> {
-        let p = super::CompositeIdPath::from(composite_id);
+        let p = match audio {
+            false => super::CompositeIdPath::from(composite_id),
+            true => super::CompositeIdPath::audio(composite_id),
+        };
 
         // Reader::open_file checks for an empty range, but check again right
         // before the unsafe block to make it easier to audit the safety constraints.
@@ -298,8 +326,11 @@ impl ReaderInt {
             )
         })?;
 
-        let file = crate::fs::openat(self.dir.0, &p, OFlag::O_RDONLY, Mode::empty())
-            .err_kind(ErrorKind::Unknown)?;
+        let file = match crate::fs::openat(self.dir.0, &p, OFlag::O_RDONLY, Mode::empty()) {
+            Err(nix::Error::Sys(nix::errno::Errno::ENOENT)) => self.open_archived(&p),
+            r => r,
+        }
+        .err_kind(ErrorKind::Unknown)?;
 
         // Check the actual on-disk file length. It's an error (a bug or filesystem corruption)
         // for it to be less than the requested read. Check for this now rather than crashing
@@ -360,6 +391,18 @@ impl ReaderInt {
         }))
     }
 
+    /// Opens a sample file which isn't in the primary directory, trying each archive directory.
+    fn open_archived(&self, p: &super::CompositeIdPath) -> Result<std::fs::File, nix::Error> {
+        let archives = self.archives.lock().clone();
+        for a in &archives {
+            match crate::fs::openat(a.0, p, OFlag::O_RDONLY, Mode::empty()) {
+                Err(nix::Error::Sys(nix::errno::Errno::ENOENT)) => continue,
+                r => return r,
+            }
+        }
+        Err(nix::Error::Sys(nix::errno::Errno::ENOENT))
+    }
+
     fn chunk(&self, mut file: OpenFile) -> (Option<OpenFile>, Vec<u8>) {
         // Read a chunk that's large enough to minimize thread handoffs but
         // short enough to keep memory usage under control. It's hopefully
@@ -407,9 +450,34 @@ mod tests {
             .tempdir()
             .unwrap();
         let fd = std::sync::Arc::new(super::super::Fd::open(tmpdir.path(), false).unwrap());
-        let reader = super::Reader::spawn("/path/goes/here", fd);
+        let reader = super::Reader::spawn("/path/goes/here", fd, Default::default());
         std::fs::write(tmpdir.path().join("0123456789abcdef"), b"blah blah").unwrap();
-        let f = reader.open_file(crate::CompositeId(0x01234567_89abcdef), 1..8);
+        let f = reader.open_file(crate::CompositeId(0x01234567_89abcdef), false, 1..8);
+        assert_eq!(f.try_concat().await.unwrap(), b"lah bla");
+    }
+
+    #[tokio::test]
+    async fn archived() {
+        crate::testutil::init();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-db-test-reader")
+            .tempdir()
+            .unwrap();
+        let primary = tmpdir.path().join("primary");
+        let archive = tmpdir.path().join("archive");
+        let fd = std::sync::Arc::new(super::super::Fd::open(&primary, true).unwrap());
+        let archive_fd = std::sync::Arc::new(super::super::Fd::open(&archive, true).unwrap());
+        let archives = super::Archives::default();
+        let reader = super::Reader::spawn("/path/goes/here", fd, archives.clone());
+        std::fs::write(archive.join("0123456789abcdef"), b"blah blah").unwrap();
+        let id = crate::CompositeId(0x01234567_89abcdef);
+        reader
+            .open_file(id, false, 1..8)
+            .try_concat()
+            .await
+            .unwrap_err();
+        archives.lock().push(archive_fd);
+        let f = reader.open_file(id, false, 1..8);
         assert_eq!(f.try_concat().await.unwrap(), b"lah bla");
     }
 }
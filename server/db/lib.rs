@@ -13,6 +13,7 @@
 
 #![cfg_attr(all(feature = "nightly", test), feature(test))]
 
+pub mod audit;
 pub mod auth;
 pub mod check;
 mod coding;
@@ -28,7 +29,9 @@ mod raw;
 pub mod recording;
 use proto::schema;
 pub mod signal;
+mod totp;
 pub mod upgrade;
+pub mod webhook;
 pub mod writer;
 
 // This is only for #[cfg(test)], but it's also used by the dependent crate, and it appears that
@@ -25,7 +25,10 @@ const LIST_RECORDINGS_BY_TIME_SQL: &str = r#"
         recording.video_samples,
         recording.video_sync_samples,
         recording.video_sample_entry_id,
-        recording.open_id
+        recording.open_id,
+        recording.audio_sample_entry_id,
+        recording.audio_samples,
+        recording.audio_sample_file_bytes
     from
         recording
     where
@@ -50,6 +53,9 @@ const LIST_RECORDINGS_BY_ID_SQL: &str = r#"
         recording.video_sync_samples,
         recording.video_sample_entry_id,
         recording.open_id,
+        recording.audio_sample_entry_id,
+        recording.audio_samples,
+        recording.audio_sample_file_bytes,
         recording.prev_media_duration_90k,
         recording.prev_runs
     from
@@ -87,7 +93,8 @@ const LIST_OLDEST_RECORDINGS_SQL: &str = r#"
       composite_id,
       start_time_90k,
       wall_duration_90k,
-      sample_file_bytes
+      sample_file_bytes + audio_sample_file_bytes,
+      sample_file_dir_id
     from
       recording
     where
@@ -149,9 +156,12 @@ fn list_recordings_inner(
             video_sync_samples: row.get(8)?,
             video_sample_entry_id: row.get(9)?,
             open_id: row.get(10)?,
+            audio_sample_entry_id: row.get(11)?,
+            audio_samples: row.get(12)?,
+            audio_sample_file_bytes: row.get(13)?,
             prev_media_duration_and_runs: match include_prev {
                 false => None,
-                true => Some((recording::Duration(row.get(11)?), row.get(12)?)),
+                true => Some((recording::Duration(row.get(14)?), row.get(15)?)),
             },
         })?;
     }
@@ -182,11 +192,13 @@ pub(crate) fn insert_recording(
             insert into recording (composite_id, stream_id, open_id, run_offset, flags,
                                sample_file_bytes, start_time_90k, prev_media_duration_90k,
                                prev_runs, wall_duration_90k, media_duration_delta_90k,
-                               video_samples, video_sync_samples, video_sample_entry_id)
+                               video_samples, video_sync_samples, video_sample_entry_id,
+                               audio_sample_entry_id, audio_samples, audio_sample_file_bytes)
                        values (:composite_id, :stream_id, :open_id, :run_offset, :flags,
                                :sample_file_bytes, :start_time_90k, :prev_media_duration_90k,
                                :prev_runs, :wall_duration_90k, :media_duration_delta_90k,
-                               :video_samples, :video_sync_samples, :video_sample_entry_id)
+                               :video_samples, :video_sync_samples, :video_sample_entry_id,
+                               :audio_sample_entry_id, :audio_samples, :audio_sample_file_bytes)
             "#,
         )
         .with_context(|e| format!("can't prepare recording insert: {}", e))?;
@@ -205,6 +217,9 @@ pub(crate) fn insert_recording(
         ":video_samples": r.video_samples,
         ":video_sync_samples": r.video_sync_samples,
         ":video_sample_entry_id": r.video_sample_entry_id,
+        ":audio_sample_entry_id": r.audio_sample_entry_id,
+        ":audio_samples": r.audio_samples,
+        ":audio_sample_file_bytes": r.audio_sample_file_bytes,
     })
     .with_context(|e| {
         format!(
@@ -238,14 +253,18 @@ pub(crate) fn insert_recording(
     let mut stmt = tx
         .prepare_cached(
             r#"
-            insert into recording_playback (composite_id,  video_index)
-                                    values (:composite_id, :video_index)
+            insert into recording_playback (composite_id,  video_index,  audio_index,
+                                            audio_start_offset_90k)
+                                    values (:composite_id, :video_index, :audio_index,
+                                            :audio_start_offset_90k)
             "#,
         )
         .with_context(|e| format!("can't prepare recording_playback insert: {}", e))?;
     stmt.execute(named_params! {
         ":composite_id": id.0,
         ":video_index": &r.video_index,
+        ":audio_index": r.audio_sample_entry_id.map(|_| &r.audio_index),
+        ":audio_start_offset_90k": r.audio_start_offset_90k,
     })
     .with_context(|e| format!("unable to insert recording_playback for {:#?}: {}", r, e))?;
 
@@ -253,7 +272,8 @@ pub(crate) fn insert_recording(
 }
 
 /// Transfers the given recording range from the `recording` and associated tables to the `garbage`
-/// table. `sample_file_dir_id` is assumed to be correct.
+/// table. `sample_file_dir_id` is assumed to be the stream's primary directory; archived
+/// recordings are instead sent to the garbage of the directory which holds them.
 ///
 /// Returns the number of recordings which were deleted.
 pub(crate) fn delete_recordings(
@@ -265,7 +285,7 @@ pub(crate) fn delete_recordings(
         r#"
         insert into garbage (sample_file_dir_id, composite_id)
         select
-          :sample_file_dir_id,
+          coalesce(sample_file_dir_id, :sample_file_dir_id),
           composite_id
         from
           recording
@@ -290,6 +310,14 @@ pub(crate) fn delete_recordings(
           composite_id < :end
         "#,
     )?;
+    let mut del_thumbnail = tx.prepare_cached(
+        r#"
+        delete from recording_thumbnail
+        where
+          :start <= composite_id and
+          composite_id < :end
+        "#,
+    )?;
     let mut del_main = tx.prepare_cached(
         r#"
         delete from recording
@@ -324,6 +352,15 @@ pub(crate) fn delete_recordings(
             n_integrity
         );
     }
+    let n_thumbnail = del_thumbnail.execute(p)?;
+    if n_thumbnail > n {
+        // fewer is okay; thumbnails are optional.
+        bail!(
+            "inserted {} garbage rows but deleted {} recording_thumbnail rows!",
+            n,
+            n_thumbnail
+        );
+    }
     let n_main = del_main.execute(p)?;
     if n_main != n {
         bail!(
@@ -339,14 +376,16 @@ pub(crate) fn delete_recordings(
 /// been `unlink()`ed and the parent directory `fsync()`ed.
 pub(crate) fn mark_sample_files_deleted(
     tx: &rusqlite::Transaction,
+    dir_id: i32,
     ids: &[CompositeId],
 ) -> Result<(), Error> {
     if ids.is_empty() {
         return Ok(());
     }
-    let mut stmt = tx.prepare_cached("delete from garbage where composite_id = ?")?;
+    let mut stmt =
+        tx.prepare_cached("delete from garbage where sample_file_dir_id = ? and composite_id = ?")?;
     for &id in ids {
-        let changes = stmt.execute(params![id.0])?;
+        let changes = stmt.execute(params![dir_id, id.0])?;
         if changes != 1 {
             // panic rather than return error. Errors get retried indefinitely, but there's no
             // recovery from this condition.
@@ -360,6 +399,34 @@ pub(crate) fn mark_sample_files_deleted(
     Ok(())
 }
 
+/// Marks the given recording as moved from `from_dir_id` to `to_dir_id`, leaving the original
+/// sample file as garbage. This shouldn't be called until the copy has been `fsync()`ed.
+pub(crate) fn mark_archived(
+    tx: &rusqlite::Transaction,
+    id: CompositeId,
+    from_dir_id: i32,
+    to_dir_id: i32,
+) -> Result<(), Error> {
+    let changes = tx.execute(
+        r#"
+        update recording set sample_file_dir_id = :to_dir_id
+        where composite_id = :composite_id and sample_file_dir_id is null
+        "#,
+        named_params! {
+            ":to_dir_id": to_dir_id,
+            ":composite_id": id.0,
+        },
+    )?;
+    if changes != 1 {
+        bail!("no unarchived recording {}", id);
+    }
+    tx.execute(
+        "insert into garbage (sample_file_dir_id, composite_id) values (?, ?)",
+        params![from_dir_id, id.0],
+    )?;
+    Ok(())
+}
+
 /// Gets the time range of recordings for the given stream.
 pub(crate) fn get_range(
     conn: &rusqlite::Connection,
@@ -437,6 +504,7 @@ pub(crate) fn list_oldest_recordings(
             start: recording::Time(row.get(1)?),
             wall_duration_90k: row.get(2)?,
             sample_file_bytes: row.get(3)?,
+            sample_file_dir_id: row.get(4)?,
         });
         if !should_continue {
             break;
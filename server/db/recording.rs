@@ -175,6 +175,87 @@ impl SampleIndexEncoder {
     }
 }
 
+/// An iterator through an audio index (as described in `design/schema.md`).
+/// Initially invalid; call `next()` before each read.
+///
+/// Durations are in units of the audio sample entry's sample rate rather than 90 kHz units.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AudioIndexIterator {
+    /// The index byte position of the next sample to read.
+    i: usize,
+
+    /// The starting data byte position of this sample within the audio sample file.
+    pub pos: i32,
+
+    /// The starting time of this sample within the recording.
+    pub start: i32,
+
+    /// The duration of this sample.
+    pub duration: i32,
+
+    /// The byte length of this sample.
+    pub bytes: i32,
+}
+
+impl AudioIndexIterator {
+    pub fn next(&mut self, data: &[u8]) -> Result<bool, Error> {
+        self.pos += self.bytes;
+        self.start += self.duration;
+        if self.i == data.len() {
+            return Ok(false);
+        }
+        let (raw1, i1) = match decode_varint32(data, self.i) {
+            Ok(tuple) => tuple,
+            Err(()) => bail!("bad varint 1 at offset {}", self.i),
+        };
+        let (raw2, i2) = match decode_varint32(data, i1) {
+            Ok(tuple) => tuple,
+            Err(()) => bail!("bad varint 2 at offset {}", i1),
+        };
+        self.i = i2;
+        let duration_delta = unzigzag32(raw1);
+        self.duration += duration_delta;
+        if self.duration <= 0 {
+            bail!(
+                "non-positive audio duration {} after applying delta {}",
+                self.duration,
+                duration_delta
+            );
+        }
+        let bytes_delta = unzigzag32(raw2);
+        self.bytes += bytes_delta;
+        if self.bytes <= 0 {
+            bail!(
+                "non-positive audio bytes {} after applying delta {} at ts {}",
+                self.bytes,
+                bytes_delta,
+                self.start
+            );
+        }
+        Ok(true)
+    }
+}
+
+/// An encoder for an audio index (as described in `design/schema.md`).
+#[derive(Debug, Default)]
+pub struct AudioIndexEncoder {
+    prev_duration: i32,
+    prev_bytes: i32,
+}
+
+impl AudioIndexEncoder {
+    pub fn add_sample(&mut self, duration: i32, bytes: i32, r: &mut db::RecordingToInsert) {
+        let duration_delta = duration - self.prev_duration;
+        self.prev_duration = duration;
+        let bytes_delta = bytes - self.prev_bytes;
+        self.prev_bytes = bytes;
+        r.audio_samples += 1;
+        r.audio_sample_file_bytes += bytes;
+        append_varint32(zigzag32(duration_delta), &mut r.audio_index);
+        append_varint32(zigzag32(bytes_delta), &mut r.audio_index);
+    }
+}
+
 /// A segment represents a view of some or all of a single recording.
 /// This struct is not specific to a container format; for `.mp4`s, it's wrapped in a
 /// `moonfire_nvr::mp4::Segment`. Other container/transport formats could be
@@ -431,6 +512,33 @@ mod tests {
         assert_eq!(2, r.video_sync_samples);
     }
 
+    /// Tests a round trip from `AudioIndexEncoder` to `AudioIndexIterator`.
+    #[test]
+    fn test_audio_round_trip() {
+        testutil::init();
+        let mut r = db::RecordingToInsert::default();
+        let mut e = AudioIndexEncoder::default();
+        let samples = [(1024, 371), (1024, 365), (1024, 380), (512, 200)];
+        for &(duration, bytes) in &samples {
+            e.add_sample(duration, bytes, &mut r);
+        }
+        assert_eq!(r.audio_samples, 4);
+        assert_eq!(r.audio_sample_file_bytes, 371 + 365 + 380 + 200);
+        let mut it = AudioIndexIterator::default();
+        let mut pos = 0;
+        let mut start = 0;
+        for &(duration, bytes) in &samples {
+            assert!(it.next(&r.audio_index).unwrap());
+            assert_eq!(it.start, start);
+            assert_eq!(it.pos, pos);
+            assert_eq!(it.duration, duration);
+            assert_eq!(it.bytes, bytes);
+            start += duration;
+            pos += bytes;
+        }
+        assert!(!it.next(&r.audio_index).unwrap());
+    }
+
     /// Tests a round trip from `SampleIndexEncoder` to `SampleIndexIterator`.
     #[test]
     fn test_round_trip() {
@@ -12,7 +12,15 @@ create table meta (
   -- The maximum number of entries in the signal_state table. If an update
   -- causes this to be exceeded, older times will be garbage collected to stay
   -- within the limit.
-  max_signal_changes integer check (max_signal_changes >= 0)
+  max_signal_changes integer check (max_signal_changes >= 0),
+
+  -- A PKCS #8 document holding the Ed25519 key pair used to sign exports'
+  -- manifests. The public key is included with each signature.
+  export_signing_key blob,
+
+  -- The maximum age of `audit_event` rows. Older rows are deleted on database
+  -- flush. If null, they're kept forever. Defaults to 90 days.
+  audit_max_age_sec integer default 7776000 check (audit_max_age_sec > 0)
 );
 
 -- This table tracks the schema version.
@@ -130,6 +138,24 @@ create table stream (
   -- on this stream.
   cum_runs integer not null check (cum_runs >= 0),
 
+  -- If non-null, recordings which ended more than this many seconds ago will
+  -- be deleted, regardless of retain_bytes.
+  retain_max_age_sec integer check (retain_max_age_sec > 0),
+
+  -- If non-null, recordings which ended less than this many seconds ago will
+  -- not be deleted to stay within retain_bytes. If the stream exceeds
+  -- retain_bytes only with such recordings, it will be allowed to exceed the
+  -- limit rather than delete them; the excess is reported through the API's
+  -- stream and health endpoints. Must be at most retain_max_age_sec.
+  retain_min_age_sec integer check (retain_min_age_sec > 0),
+
+  -- If non-null, recordings which ended more than archive_age_sec ago are
+  -- moved from sample_file_dir_id into this directory rather than staying
+  -- until deleted. Retention limits still apply to the stream's recordings in
+  -- both directories. archive_age_sec must be non-null iff this is.
+  archive_sample_file_dir_id integer references sample_file_dir (id),
+  archive_age_sec integer check (archive_age_sec > 0),
+
   unique (camera_id, type)
 );
 
@@ -200,6 +226,18 @@ create table recording (
   video_sync_samples integer not null check (video_sync_samples > 0),
   video_sample_entry_id integer references video_sample_entry (id),
 
+  -- The audio sample entry, or null if this recording has no audio. Audio
+  -- samples are stored in a separate sample file; see design/schema.md.
+  audio_sample_entry_id integer references audio_sample_entry (id),
+  audio_samples integer not null default 0 check (audio_samples >= 0),
+  audio_sample_file_bytes integer not null default 0
+      check (audio_sample_file_bytes >= 0),
+
+  -- If non-null, the directory to which this recording's sample files have
+  -- been moved, which is the stream's archive_sample_file_dir_id as of the
+  -- move. If null, they're in the stream's sample_file_dir_id.
+  sample_file_dir_id integer references sample_file_dir (id),
+
   check (composite_id >> 32 = stream_id)
 );
 
@@ -219,7 +257,10 @@ create index recording_cover on recording (
   video_sample_entry_id,
   sample_file_bytes,
   run_offset,
-  flags
+  flags,
+  audio_sample_entry_id,
+  audio_samples,
+  audio_sample_file_bytes
 );
 
 -- Fields which are only needed to check/correct database integrity problems
@@ -270,9 +311,64 @@ create table recording_playback (
   composite_id integer primary key references recording (composite_id),
 
   -- See design/schema.md#video_index for a description of this field.
-  video_index blob not null check (length(video_index) > 0)
+  video_index blob not null check (length(video_index) > 0),
+
+  -- See design/schema.md#audio_index for a description of this field.
+  -- Null if the recording has no audio.
+  audio_index blob,
+
+  -- The start of the first audio sample relative to the start of the first
+  -- video frame, in 90 kHz units. Negative if the audio started first. Zero if
+  -- the recording has no audio.
+  audio_start_offset_90k integer not null default 0
+);
 
-  -- audio_index could be added here in the future.
+-- Small JPEG thumbnails of recordings' first key frames, for the UI's recording
+-- list. These are generated in the background some time after the recording
+-- is committed, so a recording may have none.
+create table recording_thumbnail (
+  -- See description on recording table.
+  composite_id integer primary key references recording (composite_id),
+
+  data blob not null check (length(data) > 0)
+);
+
+-- Configuration of built-in motion detection, which analyzes a stream's key
+-- frames and records the result as a signal.
+create table stream_motion (
+  stream_id integer primary key references stream (id),
+
+  -- The signal to update. Its type must have a state with motion = 1
+  -- ("moving") and a state with motion = 0 ("still").
+  signal_id integer not null references signal (id),
+
+  -- How readily a change is considered motion, from 1 (least sensitive) to
+  -- 100 (most sensitive).
+  sensitivity integer not null check (sensitivity between 1 and 100),
+
+  -- The areas to analyze, as semicolon-separated "left,top,right,bottom"
+  -- rectangles, each coordinate a percentage of the frame's width or height.
+  -- For example, "0,50,100,100" is the bottom half of the frame. If null, the
+  -- whole frame is analyzed.
+  zones text
+);
+
+-- Time ranges of a stream to protect from deletion by retention, such as
+-- footage of an incident. Recordings overlapping a hold aren't deleted, but
+-- their bytes still count toward the stream's retain_bytes.
+create table hold (
+  id integer primary key,
+  stream_id integer not null references stream (id),
+  start_time_90k integer not null,
+  end_time_90k integer not null check (end_time_90k > start_time_90k),
+
+  -- A human-readable explanation of why the footage is held.
+  reason text not null,
+
+  -- The username of the hold's creator, or null if unknown.
+  creator text,
+
+  creation_time_90k integer not null
 );
 
 -- Files which are to be deleted (may or may not still exist).
@@ -315,6 +411,25 @@ create table video_sample_entry (
   pasp_v_spacing integer not null default 1 check (pasp_v_spacing > 0)
 );
 
+-- A concrete box derived from a ISO/IEC 14496-12 section 8.5.2
+-- AudioSampleEntry box. Describes the codec, sample rate, etc.
+create table audio_sample_entry (
+  id integer primary key,
+
+  -- The codec in RFC-6381 format, such as "mp4a.40.2".
+  rfc6381_codec text not null,
+
+  -- The sample rate in Hz. This is also the timescale of durations within
+  -- recording_playback.audio_index.
+  sample_rate integer not null check (sample_rate > 0),
+
+  channels integer not null check (channels > 0),
+
+  -- The serialized box, including the leading length and box type (mp4a in
+  -- the case of AAC).
+  data blob not null check (length(data) >= 36)
+);
+
 create table user (
   id integer primary key,
   username unique not null,
@@ -341,7 +456,24 @@ create table user (
 
   -- Permissions available for newly created tokens or when authenticating via
   -- unix_uid above. A serialized "Permissions" protobuf.
-  permissions blob not null default X''
+  permissions blob not null default X'',
+
+  -- If set, a secret for RFC 6238 time-based one-time passwords (HMAC-SHA1,
+  -- 30-second steps, 6 digits). Password logins additionally require a
+  -- current code or one of the recovery codes below.
+  totp_secret blob check (length(totp_secret) = 20),
+
+  -- Concatenated 32-byte BLAKE3 hashes of the unused TOTP recovery codes.
+  -- Each is removed when used.
+  totp_recovery_codes blob not null default X''
+      check (length(totp_recovery_codes) % 32 = 0),
+
+  -- The time step of the most recently accepted TOTP code, so that a code
+  -- can't be replayed.
+  totp_last_step integer,
+
+  -- Updated lazily on database flush; reset when TOTP enrollment changes.
+  totp_failure_count integer not null default 0
 );
 
 -- A single session, whether for browser or robot use.
@@ -361,12 +493,14 @@ create table user_session (
   -- and CSRF tokens.
   seed blob not null,
 
-  -- A bitwise mask of flags, currently all properties of the HTTP cookie
-  -- used to hold the session:
+  -- A bitwise mask of flags. All but the last are properties of the HTTP
+  -- cookie used to hold the session:
   -- 1: HttpOnly
   -- 2: Secure
   -- 4: SameSite=Lax
   -- 8: SameSite=Strict - 4 must also be set.
+  -- 16: API token. This session is presented via an
+  --     "Authorization: Bearer" header rather than a cookie.
   flags integer not null,
 
   -- The domain of the HTTP cookie used to store this session. The outbound
@@ -389,11 +523,12 @@ create table user_session (
 
   -- A value indicating the reason for revocation, with optional additional
   -- text detail. Enumeration values:
-  -- 0: logout link clicked (i.e. from within the session itself)
-  -- 1: obsoleted by a change in hashing algorithm (eg schema 5->6 upgrade)
+  -- 1: logout link clicked (i.e. from within the session itself)
+  -- 2: obsoleted by a change in hashing algorithm (eg schema 5->6 upgrade)
+  -- 3: revoked by an administrator (see `admin_users` permission)
+  -- 4: revoked by its user (while authenticated in another way)
   --
   -- This might be extended for a variety of other reasons:
-  -- x: user revoked (while authenticated in another way)
   -- x: password change invalidated all sessions created with that password
   -- x: expired (due to fixed total time or time inactive)
   -- x: evicted (due to too many sessions)
@@ -408,7 +543,10 @@ create table user_session (
   use_count not null default 0,
 
   -- Permissions associated with this token; a serialized "Permissions" protobuf.
-  permissions blob not null default X''
+  permissions blob not null default X'',
+
+  -- If non-null, the session is invalid as of this time (sec since epoch).
+  expiration_time_sec integer
 ) without rowid;
 
 create index user_session_uid on user_session (user_id);
@@ -497,5 +635,139 @@ create table signal_change (
   changes blob not null
 );
 
+-- An append-only log of access and configuration changes: logins, video views,
+-- exports, and the like. Rows older than `meta.audit_max_age_sec` are deleted.
+create table audit_event (
+  id integer primary key,
+
+  -- The time of the event, in seconds since 1970-01-01 00:00:00Z.
+  time_sec integer not null,
+
+  -- One of "login", "login_failed", "logout", "view", "export",
+  -- "post_signals", or "config".
+  kind text not null,
+
+  -- The user responsible, if any. This isn't a foreign key, so that the log
+  -- outlives deleted users. For failed logins, username is as supplied and
+  -- user_id is null.
+  user_id integer,
+  username text,
+
+  -- The client's IPv4 or IPv6 address, if known.
+  peer_addr blob check (length(peer_addr) in (4, 16)),
+
+  -- The camera involved, if any. Not a foreign key, as with user_id.
+  camera_uuid blob check (length(camera_uuid) = 16),
+
+  -- The time range of video viewed or exported, if known, in 90 kHz units
+  -- since 1970-01-01 00:00:00Z excluding leap seconds.
+  start_time_90k integer,
+  end_time_90k integer,
+
+  -- Free-form details, such as the error from a failed login.
+  detail text
+);
+
+create index audit_event_time on audit_event (time_sec);
+
+-- An HTTP endpoint which is sent a JSON POST for each event matching its
+-- filters.
+create table webhook (
+  id integer primary key,
+
+  -- The http or https URL to POST to.
+  url text not null,
+
+  -- The key of the HMAC-SHA256 signature of each payload, which is sent in
+  -- the X-Moonfire-Signature header. Generated by the server.
+  secret text not null,
+
+  -- True if the webhook receives signal changes, limited to the signal ids
+  -- and states given as comma-separated lists in signal_ids and
+  -- signal_states. Null means all.
+  signals integer not null,
+  signal_ids text,
+  signal_states text,
+
+  -- True if the webhook receives stream failures and recoveries.
+  stream_health integer not null,
+
+  -- True if the webhook receives failures to write sample files because the
+  -- filesystem is full.
+  disk_full integer not null
+);
+
+-- An event queued for delivery to a webhook. Rows are deleted on successful
+-- delivery or after too many attempts.
+create table webhook_delivery (
+  id integer primary key,
+  webhook_id integer not null references webhook (id),
+
+  -- The JSON body to POST.
+  payload text not null,
+
+  -- The number of failed attempts so far.
+  attempts integer not null default 0,
+
+  -- The earliest time of the next attempt, in seconds since
+  -- 1970-01-01 00:00:00Z, or null if it hasn't been attempted.
+  next_attempt_time_sec integer,
+
+  -- The error of the most recent failed attempt.
+  last_error text
+);
+
+-- A mapping of a camera's ONVIF events onto a signal.
+create table onvif_event_mapping (
+  id integer primary key,
+  camera_id integer not null references camera (id),
+
+  -- The event's topic, without namespace prefixes, such as
+  -- "RuleEngine/CellMotionDetector/Motion" or "Device/Trigger/DigitalInput".
+  topic text not null,
+
+  -- If non-null, only events with a source item of this name and value match,
+  -- such as "InputToken" and "DIGIT_INPUT_000" to distinguish digital inputs.
+  source_name text,
+  source_value text check ((source_name is null) = (source_value is null)),
+
+  signal_id integer not null references signal (id),
+
+  -- The state to set when the event's boolean data item is true, or when an
+  -- event without one (such as a line crossing) happens.
+  active_state integer not null,
+
+  -- The state to set when the event's boolean data item is false.
+  inactive_state integer not null
+);
+
+-- How a camera with ONVIF event mappings delivers its events. A camera
+-- without a row here sends them as notifications to this server.
+create table onvif_event_config (
+  camera_id integer primary key references camera (id),
+
+  -- True if events should instead be fetched from a PullPoint subscription,
+  -- for cameras which can't reach this server.
+  pull_point integer not null
+);
+
+-- A camera's ONVIF event subscription, kept so it can be reused after a
+-- restart.
+create table onvif_subscription (
+  camera_id integer primary key references camera (id),
+
+  -- For a notification subscription, the random token in the notification URL
+  -- given to the camera, "/onvif/notify/<token>", which identifies the
+  -- camera's notifications. Null for a PullPoint subscription.
+  token text unique,
+
+  -- The address of the camera's subscription manager.
+  manager_address text not null,
+
+  -- When the subscription expires unless renewed, in seconds since
+  -- 1970-01-01 00:00:00Z.
+  termination_time_sec integer not null
+);
+
 insert into version (id, unix_time,                           notes)
-             values (6,  cast(strftime('%s', 'now') as int), 'db creation');
+             values (7,  cast(strftime('%s', 'now') as int), 'db creation');
@@ -261,12 +261,17 @@ impl State {
         }
     }
 
+    /// Sets the given signals to the given states for `when`.
+    ///
+    /// Returns the `(signal, state)` pairs which represent a change from the signal's prior
+    /// state at `when.start`, such as motion starting, as opposed to an extension of the
+    /// existing state.
     pub fn update_signals(
         &mut self,
         when: Range<recording::Time>,
         signals: &[u32],
         states: &[u16],
-    ) -> Result<(), base::Error> {
+    ) -> Result<Vec<(u32, u16)>, base::Error> {
         // Do input validation before any mutation.
         self.update_signals_validate(signals, states)?;
 
@@ -274,9 +279,17 @@ impl State {
         // Bailing early in the empty case isn't just an optimization; apply_observation_end would
         // be incorrect otherwise.
         if when.end <= when.start {
-            return Ok(());
+            return Ok(Vec::new());
         }
 
+        let prev = self.state_at(when.start);
+        let changes = signals
+            .iter()
+            .zip(states)
+            .filter(|&(s, &state)| prev.get(s).copied().unwrap_or(0) != state)
+            .map(|(&s, &state)| (s, state))
+            .collect();
+
         // Apply the end before the start so that the `prev` state can be examined.
         self.update_signals_end(when.clone(), signals, states);
         self.update_signals_start(when.start, signals, states);
@@ -284,7 +297,15 @@ impl State {
         self.debug_assert_point_invariants();
 
         self.gc();
-        Ok(())
+        Ok(changes)
+    }
+
+    /// Returns the state of each signal not in state 0 as of `t`.
+    fn state_at(&self, t: recording::Time) -> BTreeMap<u32, u16> {
+        match self.points_by_time.range(..=t).next_back() {
+            None => BTreeMap::new(),
+            Some((_, p)) => p.after(),
+        }
     }
 
     /// Performs garbage collection if the number of points exceeds `max_signal_changes`.
@@ -938,7 +959,10 @@ mod tests {
         );
         const START: recording::Time = recording::Time(140067462600000); // 2019-04-26T11:59:00
         const NOW: recording::Time = recording::Time(140067468000000); // 2019-04-26T12:00:00
-        s.update_signals(START..NOW, &[1, 2], &[2, 1]).unwrap();
+        assert_eq!(
+            s.update_signals(START..NOW, &[1, 2], &[2, 1]).unwrap(),
+            &[(1, 2), (2, 1)]
+        );
         let mut rows = Vec::new();
 
         const EXPECTED: &[ListStateChangesRow] = &[
@@ -1008,7 +1032,10 @@ mod tests {
         // Go through it again. This time, hit the max number of signals, forcing START to be
         // dropped.
         const SOON: recording::Time = recording::Time(140067473400000); // 2019-04-26T12:01:00
-        s.update_signals(NOW..SOON, &[1, 2], &[1, 2]).unwrap();
+        assert_eq!(
+            s.update_signals(NOW..SOON, &[1, 2], &[1, 2]).unwrap(),
+            &[(1, 1), (2, 2)]
+        );
         rows.clear();
         const EXPECTED2: &[ListStateChangesRow] = &[
             ListStateChangesRow {
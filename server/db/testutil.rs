@@ -7,6 +7,7 @@
 
 use crate::db;
 use crate::dir;
+use crate::recording;
 use crate::writer;
 use base::clock::Clocks;
 use fnv::FnvHashMap;
@@ -92,6 +93,7 @@ impl<C: Clocks + Clone> TestDb<C> {
                             rtsp_url: "rtsp://test-camera/main".to_owned(),
                             record: true,
                             flush_if_sec,
+                            ..Default::default()
                         },
                         Default::default(),
                     ],
@@ -130,7 +132,19 @@ impl<C: Clocks + Clone> TestDb<C> {
     /// a `SampleIndexEncoder`. Fills in a video sample entry id and such to make it valid.
     /// There will no backing sample file, so it won't be possible to generate a full `.mp4`.
     pub fn insert_recording_from_encoder(&self, r: db::RecordingToInsert) -> db::ListRecordingsRow {
-        use crate::recording::{self, TIME_UNITS_PER_SEC};
+        use crate::recording::TIME_UNITS_PER_SEC;
+        self.insert_recording_from_encoder_at(
+            recording::Time(1430006400i64 * TIME_UNITS_PER_SEC),
+            r,
+        )
+    }
+
+    /// As `insert_recording_from_encoder`, but starting at the given time.
+    pub fn insert_recording_from_encoder_at(
+        &self,
+        start: recording::Time,
+        r: db::RecordingToInsert,
+    ) -> db::ListRecordingsRow {
         let mut db = self.db.lock();
         let video_sample_entry_id = db
             .insert_video_sample_entry(db::VideoSampleEntryToInsert {
@@ -146,7 +160,7 @@ impl<C: Clocks + Clone> TestDb<C> {
             .add_recording(
                 TEST_STREAM_ID,
                 db::RecordingToInsert {
-                    start: recording::Time(1430006400i64 * TIME_UNITS_PER_SEC),
+                    start,
                     video_sample_entry_id,
                     wall_duration_90k: r.media_duration_90k,
                     ..r
@@ -172,7 +186,7 @@ impl<C: Clocks + Clone> TestDb<C> {
 // For benchmarking
 #[cfg(feature = "nightly")]
 pub fn add_dummy_recordings_to_db(db: &db::Database, num: usize) {
-    use crate::recording::{self, TIME_UNITS_PER_SEC};
+    use crate::recording::TIME_UNITS_PER_SEC;
     let mut data = Vec::new();
     data.extend_from_slice(include_bytes!("testdata/video_sample_index.bin"));
     let mut db = db.lock();
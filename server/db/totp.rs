@@ -0,0 +1,184 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Time-based one-time passwords ([RFC 6238](https://tools.ietf.org/html/rfc6238)) as a second
+//! factor for password logins.
+//!
+//! This uses the parameters understood by every common authenticator app: HMAC-SHA1, a 30-second
+//! time step, and 6-digit codes.
+
+use ring::hmac;
+
+/// The length of a secret in bytes, as recommended by RFC 4226 section 4.
+pub(crate) const SECRET_LEN: usize = 20;
+
+/// The length of a recovery code in bytes before encoding.
+const RECOVERY_CODE_LEN: usize = 5;
+
+pub(crate) const RECOVERY_CODES: usize = 10;
+
+const STEP_SEC: i64 = 30;
+
+/// The number of steps before or after the current one for which a code is accepted, to allow
+/// for clock skew between the server and the authenticator.
+const WINDOW_STEPS: i64 = 1;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Computes the 6-digit code for the given time step.
+pub(crate) fn code(secret: &[u8], step: i64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &(step as u64).to_be_bytes());
+    let h = tag.as_ref();
+    let offset = usize::from(h[h.len() - 1] & 0xf);
+    let bin = (u32::from(h[offset] & 0x7f) << 24)
+        | (u32::from(h[offset + 1]) << 16)
+        | (u32::from(h[offset + 2]) << 8)
+        | u32::from(h[offset + 3]);
+    bin % 1_000_000
+}
+
+/// Checks `input` against the codes for the steps around `when_sec`.
+///
+/// Returns the matching step, which must be after `last_step` so that an observed code can't be
+/// replayed.
+pub(crate) fn verify(
+    secret: &[u8],
+    input: &str,
+    when_sec: i64,
+    last_step: Option<i64>,
+) -> Option<i64> {
+    let input = input.trim();
+    if input.len() != 6 || !input.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let input: u32 = input.parse().ok()?;
+    let cur = when_sec.div_euclid(STEP_SEC);
+    (cur - WINDOW_STEPS..=cur + WINDOW_STEPS)
+        .filter(|&s| last_step.map(|l| s > l).unwrap_or(true))
+        .find(|&s| code(secret, s) == input)
+}
+
+/// Encodes with the RFC 4648 base32 alphabet, without padding, as authenticator apps expect.
+pub(crate) fn base32(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8 + 4) / 5);
+    let mut buf = 0u16;
+    let mut bits = 0;
+    for &b in data {
+        buf = (buf << 8) | u16::from(b);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(char::from(
+                BASE32_ALPHABET[usize::from((buf >> bits) & 0x1f)],
+            ));
+        }
+    }
+    if bits > 0 {
+        out.push(char::from(
+            BASE32_ALPHABET[usize::from((buf << (5 - bits)) & 0x1f)],
+        ));
+    }
+    out
+}
+
+/// Returns a `otpauth://` URI suitable for a QR code, as described in
+/// <https://github.com/google/google-authenticator/wiki/Key-Uri-Format>.
+pub(crate) fn uri(username: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/Moonfire%20NVR:{}?secret={}&issuer=Moonfire%20NVR",
+        percent_encode(username),
+        base32(secret)
+    )
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(char::from(b));
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Formats a recovery code from its raw bytes as `XXXX-XXXX`.
+pub(crate) fn recovery_code(raw: &[u8; RECOVERY_CODE_LEN]) -> String {
+    let mut s = base32(&raw[..]);
+    s.insert(4, '-');
+    s
+}
+
+pub(crate) fn new_recovery_code(rand: &dyn ring::rand::SecureRandom) -> String {
+    let mut raw = [0u8; RECOVERY_CODE_LEN];
+    rand.fill(&mut raw).unwrap();
+    recovery_code(&raw)
+}
+
+/// Hashes a recovery code for storage. Recovery codes are random, so unlike passwords they don't
+/// need a slow, salted hash. Case and dashes are ignored.
+pub(crate) fn hash_recovery_code(code: &str) -> [u8; 32] {
+    let normalized: String = code
+        .trim()
+        .chars()
+        .filter(|&c| c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    *blake3::hash(normalized.as_bytes()).as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test vectors from RFC 6238 appendix B, truncated to 6 digits.
+    #[test]
+    fn rfc6238() {
+        let secret = b"12345678901234567890";
+        assert_eq!(code(secret, 59 / STEP_SEC), 287082);
+        assert_eq!(code(secret, 1111111109 / STEP_SEC), 81804);
+        assert_eq!(code(secret, 1234567890 / STEP_SEC), 5924);
+        assert_eq!(code(secret, 20000000000 / STEP_SEC), 353130);
+    }
+
+    #[test]
+    fn verify_window_and_replay() {
+        let secret = b"12345678901234567890";
+        let step = 1111111109 / STEP_SEC;
+        assert_eq!(verify(secret, "081804", 1111111109, None), Some(step));
+        assert_eq!(verify(secret, "081804", 1111111109 + 30, None), Some(step));
+        assert_eq!(verify(secret, "081804", 1111111109 + 90, None), None);
+        assert_eq!(verify(secret, "081804", 1111111109, Some(step)), None);
+        assert_eq!(verify(secret, "81804", 1111111109, None), None);
+    }
+
+    #[test]
+    fn base32_rfc4648() {
+        assert_eq!(base32(b""), "");
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(base32(b"fo"), "MZXQ");
+        assert_eq!(base32(b"foo"), "MZXW6");
+        assert_eq!(base32(b"foob"), "MZXW6YQ");
+        assert_eq!(base32(b"fooba"), "MZXW6YTB");
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+    }
+
+    #[test]
+    fn recovery_codes() {
+        let c = recovery_code(b"fooba");
+        assert_eq!(c, "MZXW-6YTB");
+        assert_eq!(hash_recovery_code(&c), hash_recovery_code(" mzxw6ytb "));
+        assert_ne!(hash_recovery_code(&c), hash_recovery_code("MZXW-6YTC"));
+    }
+
+    #[test]
+    fn uri_escapes_username() {
+        assert_eq!(
+            uri("a b", b"fooba"),
+            "otpauth://totp/Moonfire%20NVR:a%20b?secret=MZXW6YTB&issuer=Moonfire%20NVR"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+/// Upgrades a version 6 schema to a version 7 schema.
+///
+/// This adds audio support, recording thumbnails, motion detection configuration, retention by
+/// age, holds, archive directories, an export signing key, API tokens, TOTP second factors, and an
+/// audit log. Existing recordings have no audio and aren't archived, so no sample files are
+/// touched. Thumbnails for existing recordings are generated after the upgrade.
+use failure::Error;
+use rusqlite::params;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    tx.execute_batch(
+        r#"
+        create table audio_sample_entry (
+          id integer primary key,
+          rfc6381_codec text not null,
+          sample_rate integer not null check (sample_rate > 0),
+          channels integer not null check (channels > 0),
+          data blob not null check (length(data) >= 36)
+        );
+
+        alter table recording add column audio_sample_entry_id integer
+            references audio_sample_entry (id);
+        alter table recording add column audio_samples integer not null default 0
+            check (audio_samples >= 0);
+        alter table recording add column audio_sample_file_bytes integer not null default 0
+            check (audio_sample_file_bytes >= 0);
+        alter table recording_playback add column audio_index blob;
+        alter table recording_playback add column audio_start_offset_90k integer not null
+            default 0;
+
+        alter table meta add column export_signing_key blob;
+        alter table meta add column audit_max_age_sec integer default 7776000
+            check (audit_max_age_sec > 0);
+
+        create table audit_event (
+          id integer primary key,
+          time_sec integer not null,
+          kind text not null,
+          user_id integer,
+          username text,
+          peer_addr blob check (length(peer_addr) in (4, 16)),
+          camera_uuid blob check (length(camera_uuid) = 16),
+          start_time_90k integer,
+          end_time_90k integer,
+          detail text
+        );
+        create index audit_event_time on audit_event (time_sec);
+
+        create table webhook (
+          id integer primary key,
+          url text not null,
+          secret text not null,
+          signals integer not null,
+          signal_ids text,
+          signal_states text,
+          stream_health integer not null,
+          disk_full integer not null
+        );
+
+        create table webhook_delivery (
+          id integer primary key,
+          webhook_id integer not null references webhook (id),
+          payload text not null,
+          attempts integer not null default 0,
+          next_attempt_time_sec integer,
+          last_error text
+        );
+
+        create table onvif_event_mapping (
+          id integer primary key,
+          camera_id integer not null references camera (id),
+          topic text not null,
+          source_name text,
+          source_value text check ((source_name is null) = (source_value is null)),
+          signal_id integer not null references signal (id),
+          active_state integer not null,
+          inactive_state integer not null
+        );
+
+        create table onvif_event_config (
+          camera_id integer primary key references camera (id),
+          pull_point integer not null
+        );
+
+        create table onvif_subscription (
+          camera_id integer primary key references camera (id),
+          token text unique,
+          manager_address text not null,
+          termination_time_sec integer not null
+        );
+
+        alter table user_session add column expiration_time_sec integer;
+
+        alter table user add column totp_secret blob
+            check (length(totp_secret) = 20);
+        alter table user add column totp_recovery_codes blob not null default X''
+            check (length(totp_recovery_codes) % 32 = 0);
+        alter table user add column totp_last_step integer;
+        alter table user add column totp_failure_count integer not null default 0;
+
+        alter table stream add column retain_max_age_sec integer
+            check (retain_max_age_sec > 0);
+        alter table stream add column retain_min_age_sec integer
+            check (retain_min_age_sec > 0);
+        alter table stream add column archive_sample_file_dir_id integer
+            references sample_file_dir (id);
+        alter table stream add column archive_age_sec integer
+            check (archive_age_sec > 0);
+        alter table recording add column sample_file_dir_id integer
+            references sample_file_dir (id);
+
+        create table recording_thumbnail (
+          composite_id integer primary key references recording (composite_id),
+          data blob not null check (length(data) > 0)
+        );
+
+        create table stream_motion (
+          stream_id integer primary key references stream (id),
+          signal_id integer not null references signal (id),
+          sensitivity integer not null check (sensitivity between 1 and 100),
+          zones text
+        );
+
+        create table hold (
+          id integer primary key,
+          stream_id integer not null references stream (id),
+          start_time_90k integer not null,
+          end_time_90k integer not null check (end_time_90k > start_time_90k),
+          reason text not null,
+          creator text,
+          creation_time_90k integer not null
+        );
+
+        drop index recording_cover;
+        create index recording_cover on recording (
+          stream_id,
+          start_time_90k,
+          open_id,
+          wall_duration_90k,
+          media_duration_delta_90k,
+          video_samples,
+          video_sync_samples,
+          video_sample_entry_id,
+          sample_file_bytes,
+          run_offset,
+          flags,
+          audio_sample_entry_id,
+          audio_samples,
+          audio_sample_file_bytes
+        );
+        "#,
+    )?;
+    let key = crate::db::generate_export_signing_key()?;
+    tx.execute("update meta set export_signing_key = ?", params![&key])?;
+    Ok(())
+}
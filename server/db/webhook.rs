@@ -0,0 +1,552 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Outbound webhooks: HTTP endpoints notified of signal changes, stream failures, and the like.
+//! See the `webhook` and `webhook_delivery` tables within `schema.sql` for more information.
+//!
+//! Unlike audit events, deliveries are written as soon as the event happens rather than on the
+//! next flush, so that notifications aren't delayed by a minute. `moonfire-nvr run` sends them
+//! and retries failures with exponential backoff.
+
+use crate::db::LockedDatabase;
+use crate::recording;
+use failure::{bail, format_err, Error};
+use ring::rand::{SecureRandom, SystemRandom};
+use rusqlite::{named_params, params, Connection};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Notify};
+
+/// The number of attempts to deliver an event before giving up on it.
+pub const MAX_ATTEMPTS: i32 = 10;
+
+/// The delay after the first failed attempt. Each later failure doubles it, up to
+/// `MAX_RETRY_DELAY_SEC`.
+const FIRST_RETRY_DELAY_SEC: i64 = 10;
+const MAX_RETRY_DELAY_SEC: i64 = 3600;
+
+/// The length of a generated secret in bytes, before base64 encoding.
+const SECRET_LEN: usize = 24;
+
+/// The number of events buffered for each subscriber (see `State::subscribe`).
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+
+/// The events a webhook receives.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    /// Signal changes, as limited by `signal_ids` and `signal_states`.
+    pub signals: bool,
+
+    /// If non-empty, only changes of these signals.
+    pub signal_ids: Vec<u32>,
+
+    /// If non-empty, only changes to these states.
+    pub signal_states: Vec<u16>,
+
+    /// Streams failing or recovering.
+    pub stream_health: bool,
+
+    /// Sample files which can't be written because the filesystem is full.
+    pub disk_full: bool,
+}
+
+impl Filter {
+    pub fn matches(&self, event: &Event) -> bool {
+        match event {
+            Event::Signal {
+                signal_id, state, ..
+            } => {
+                self.signals
+                    && (self.signal_ids.is_empty() || self.signal_ids.contains(signal_id))
+                    && (self.signal_states.is_empty() || self.signal_states.contains(state))
+            }
+            Event::StreamHealth { .. } => self.stream_health,
+            Event::DiskFull { .. } => self.disk_full,
+        }
+    }
+}
+
+/// Representation of a `webhook` row.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+
+    /// The key of each payload's HMAC-SHA256 signature, as given to the receiver.
+    pub secret: String,
+
+    pub filter: Filter,
+}
+
+/// An event which may be delivered to webhooks.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A signal changed to `state` for `time`, as via `LockedDatabase::update_signals`.
+    Signal {
+        signal_id: u32,
+        state: u16,
+        time: Range<recording::Time>,
+    },
+
+    /// A stream's connection failed (if `!healthy`) or was reestablished after a failure.
+    StreamHealth {
+        stream_id: i32,
+        healthy: bool,
+        error: Option<String>,
+        time: recording::Time,
+    },
+
+    /// A stream's sample file couldn't be written because its filesystem is full.
+    DiskFull {
+        stream_id: i32,
+        time: recording::Time,
+    },
+}
+
+/// A queued delivery of an event to a webhook; a `webhook_delivery` row.
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    pub id: i64,
+    pub webhook_id: i32,
+    pub url: String,
+    pub secret: String,
+
+    /// The JSON body to send.
+    pub payload: String,
+
+    /// The number of previous failed attempts.
+    pub attempts: i32,
+}
+
+/// Returns the JSON payload describing `event`.
+pub fn payload(db: &LockedDatabase, event: &Event) -> String {
+    let stream = |stream_id: i32| {
+        let s = db.streams_by_id().get(&stream_id);
+        let c = s.and_then(|s| db.cameras_by_id().get(&s.camera_id));
+        json!({
+            "cameraUuid": c.map(|c| c.uuid.to_string()),
+            "cameraShortName": c.map(|c| &c.short_name),
+            "streamType": s.map(|s| s.type_.as_str()),
+        })
+    };
+    let mut p = match event {
+        Event::Signal {
+            signal_id,
+            state,
+            time,
+        } => json!({
+            "type": "signal",
+            "signalId": signal_id,
+            "signalShortName": db.signals_by_id().get(signal_id).map(|s| &s.short_name),
+            "state": state,
+            "startTime90k": time.start.0,
+            "endTime90k": time.end.0,
+        }),
+        Event::StreamHealth {
+            stream_id,
+            healthy,
+            error,
+            time,
+        } => {
+            let mut p = stream(*stream_id);
+            p["type"] = json!("streamHealth");
+            p["healthy"] = json!(healthy);
+            p["error"] = json!(error);
+            p["time90k"] = json!(time.0);
+            p
+        }
+        Event::DiskFull { stream_id, time } => {
+            let mut p = stream(*stream_id);
+            let dir = db
+                .streams_by_id()
+                .get(stream_id)
+                .and_then(|s| s.sample_file_dir_id)
+                .and_then(|id| db.sample_file_dirs_by_id().get(&id));
+            p["type"] = json!("diskFull");
+            p["sampleFileDirId"] = json!(dir.map(|d| d.id));
+            p["sampleFileDirPath"] = json!(dir.map(|d| &d.path));
+            p["time90k"] = json!(time.0);
+            p
+        }
+    };
+    p["version"] = json!(1);
+    p.to_string()
+}
+
+/// Joins a list for a `webhook` column, or returns `None` if it's empty.
+fn join<T: ToString>(v: &[T]) -> Option<String> {
+    if v.is_empty() {
+        return None;
+    }
+    Some(v.iter().map(T::to_string).collect::<Vec<_>>().join(","))
+}
+
+/// Splits a list from a `webhook` column.
+fn split<T: FromStr>(s: Option<String>) -> Result<Vec<T>, Error> {
+    let s = match s {
+        None => return Ok(Vec::new()),
+        Some(s) => s,
+    };
+    s.split(',')
+        .map(|e| T::from_str(e).map_err(|_| format_err!("bad webhook list {:?}", s)))
+        .collect()
+}
+
+pub(crate) struct State {
+    webhooks_by_id: BTreeMap<i32, Webhook>,
+    rand: SystemRandom,
+
+    /// Notified when a delivery is queued.
+    notify: Arc<Notify>,
+
+    /// Receives every event, whether or not any webhook matches it.
+    events: broadcast::Sender<Event>,
+}
+
+impl State {
+    pub fn init(conn: &Connection) -> Result<Self, Error> {
+        let mut stmt = conn.prepare(
+            r#"
+            select
+              id,
+              url,
+              secret,
+              signals,
+              signal_ids,
+              signal_states,
+              stream_health,
+              disk_full
+            from
+              webhook
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        let mut webhooks_by_id = BTreeMap::new();
+        while let Some(row) = rows.next()? {
+            let id = row.get(0)?;
+            webhooks_by_id.insert(
+                id,
+                Webhook {
+                    id,
+                    url: row.get(1)?,
+                    secret: row.get(2)?,
+                    filter: Filter {
+                        signals: row.get(3)?,
+                        signal_ids: split(row.get(4)?)?,
+                        signal_states: split(row.get(5)?)?,
+                        stream_health: row.get(6)?,
+                        disk_full: row.get(7)?,
+                    },
+                },
+            );
+        }
+        Ok(State {
+            webhooks_by_id,
+            rand: SystemRandom::new(),
+            notify: Arc::new(Notify::new()),
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+        })
+    }
+
+    pub fn webhooks_by_id(&self) -> &BTreeMap<i32, Webhook> {
+        &self.webhooks_by_id
+    }
+
+    pub fn notify(&self) -> Arc<Notify> {
+        self.notify.clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Sends `event` to all current subscribers.
+    pub fn broadcast(&self, event: &Event) {
+        // An error just means there are no subscribers.
+        let _ = self.events.send(event.clone());
+    }
+
+    pub fn add(&mut self, conn: &Connection, url: String, filter: Filter) -> Result<i32, Error> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            bail!("webhook URL {:?} must be http or https", url);
+        }
+        let mut raw = [0u8; SECRET_LEN];
+        self.rand.fill(&mut raw).unwrap();
+        let secret = base64::encode_config(&raw, base64::URL_SAFE_NO_PAD);
+        conn.execute(
+            r#"
+            insert into webhook (url,  secret,  signals,  signal_ids,  signal_states,
+                                 stream_health,  disk_full)
+                         values (:url, :secret, :signals, :signal_ids, :signal_states,
+                                 :stream_health, :disk_full)
+            "#,
+            named_params! {
+                ":url": &url,
+                ":secret": &secret,
+                ":signals": &filter.signals,
+                ":signal_ids": &join(&filter.signal_ids),
+                ":signal_states": &join(&filter.signal_states),
+                ":stream_health": &filter.stream_health,
+                ":disk_full": &filter.disk_full,
+            },
+        )?;
+        let id = conn.last_insert_rowid() as i32;
+        self.webhooks_by_id.insert(
+            id,
+            Webhook {
+                id,
+                url,
+                secret,
+                filter,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Deletes a webhook along with its undelivered events.
+    pub fn delete(&mut self, conn: &mut Connection, id: i32) -> Result<(), Error> {
+        if !self.webhooks_by_id.contains_key(&id) {
+            bail!("no such webhook {}", id);
+        }
+        let tx = conn.transaction()?;
+        tx.execute(
+            "delete from webhook_delivery where webhook_id = ?",
+            params![id],
+        )?;
+        tx.execute("delete from webhook where id = ?", params![id])?;
+        tx.commit()?;
+        self.webhooks_by_id.remove(&id);
+        Ok(())
+    }
+
+    /// Queues a delivery of `event` to each matching webhook, returning the number queued.
+    pub fn enqueue(
+        &self,
+        conn: &Connection,
+        db: &LockedDatabase,
+        event: &Event,
+    ) -> Result<usize, Error> {
+        let mut matching = self
+            .webhooks_by_id
+            .values()
+            .filter(|w| w.filter.matches(event))
+            .peekable();
+        if matching.peek().is_none() {
+            return Ok(0);
+        }
+        let payload = payload(db, event);
+        let mut stmt = conn
+            .prepare_cached("insert into webhook_delivery (webhook_id, payload) values (?, ?)")?;
+        let mut n = 0;
+        for w in matching {
+            stmt.execute(params![w.id, &payload])?;
+            n += 1;
+        }
+        self.notify.notify_one();
+        Ok(n)
+    }
+
+    /// Lists up to `limit` deliveries which are due as of `now_sec`, oldest first.
+    pub fn list_due(
+        &self,
+        conn: &Connection,
+        now_sec: i64,
+        limit: usize,
+    ) -> Result<Vec<Delivery>, Error> {
+        let mut stmt = conn.prepare_cached(
+            r#"
+            select
+              id,
+              webhook_id,
+              payload,
+              attempts
+            from
+              webhook_delivery
+            where
+              next_attempt_time_sec is null or next_attempt_time_sec <= :now_sec
+            order by id
+            limit :limit
+            "#,
+        )?;
+        let mut rows = stmt.query(named_params! {
+            ":now_sec": &now_sec,
+            ":limit": &(limit as i64),
+        })?;
+        let mut deliveries = Vec::new();
+        while let Some(row) = rows.next()? {
+            let webhook_id = row.get(1)?;
+            let w = self
+                .webhooks_by_id
+                .get(&webhook_id)
+                .ok_or_else(|| format_err!("delivery for missing webhook {}", webhook_id))?;
+            deliveries.push(Delivery {
+                id: row.get(0)?,
+                webhook_id,
+                url: w.url.clone(),
+                secret: w.secret.clone(),
+                payload: row.get(2)?,
+                attempts: row.get(3)?,
+            });
+        }
+        Ok(deliveries)
+    }
+
+    /// Records the result of an attempt to send `delivery` at `now_sec`.
+    ///
+    /// On success or after `MAX_ATTEMPTS` failures, the delivery is removed. Otherwise it's
+    /// rescheduled. Returns true if it was removed.
+    pub fn finish(
+        &self,
+        conn: &Connection,
+        delivery: &Delivery,
+        now_sec: i64,
+        result: Result<(), String>,
+    ) -> Result<bool, Error> {
+        let attempts = delivery.attempts + 1;
+        let error = match result {
+            Err(e) if attempts < MAX_ATTEMPTS => e,
+            _ => {
+                conn.execute(
+                    "delete from webhook_delivery where id = ?",
+                    params![delivery.id],
+                )?;
+                return Ok(true);
+            }
+        };
+        let delay = std::cmp::min(
+            FIRST_RETRY_DELAY_SEC << std::cmp::min(delivery.attempts, 16),
+            MAX_RETRY_DELAY_SEC,
+        );
+        conn.execute(
+            r#"
+            update webhook_delivery
+            set attempts = ?, next_attempt_time_sec = ?, last_error = ?
+            where id = ?
+            "#,
+            params![attempts, now_sec + delay, &error, delivery.id],
+        )?;
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use base::clock;
+
+    #[test]
+    fn filter() {
+        let t = recording::Time(0)..recording::Time(1);
+        let motion = Event::Signal {
+            signal_id: 1,
+            state: 2,
+            time: t.clone(),
+        };
+        let still = Event::Signal {
+            signal_id: 1,
+            state: 1,
+            time: t.clone(),
+        };
+        let other = Event::Signal {
+            signal_id: 2,
+            state: 2,
+            time: t,
+        };
+        let disk_full = Event::DiskFull {
+            stream_id: 1,
+            time: recording::Time(0),
+        };
+        let f = Filter {
+            signals: true,
+            signal_ids: vec![1],
+            signal_states: vec![2],
+            ..Default::default()
+        };
+        assert!(f.matches(&motion));
+        assert!(!f.matches(&still));
+        assert!(!f.matches(&other));
+        assert!(!f.matches(&disk_full));
+        let f = Filter {
+            signals: true,
+            disk_full: true,
+            ..Default::default()
+        };
+        assert!(f.matches(&still) && f.matches(&other) && f.matches(&disk_full));
+        assert!(!Filter::default().matches(&motion));
+    }
+
+    #[test]
+    fn round_trip() {
+        testutil::init();
+        let clocks = clock::SimulatedClocks::new(time::Timespec::new(0, 0));
+        let tdb = testutil::TestDb::new(clocks);
+        let mut l = tdb.db.lock();
+        let filter = Filter {
+            signals: true,
+            signal_states: vec![1, 2],
+            stream_health: true,
+            ..Default::default()
+        };
+        assert!(l
+            .add_webhook("ftp://foo".to_owned(), filter.clone())
+            .is_err());
+        let id = l
+            .add_webhook("http://127.0.0.1/hook".to_owned(), filter.clone())
+            .unwrap();
+        let w = l.webhooks_by_id().get(&id).unwrap();
+        assert_eq!(w.filter, filter);
+        assert_eq!(w.secret.len(), 32);
+        l.enqueue_webhook_event(Event::DiskFull {
+            stream_id: testutil::TEST_STREAM_ID,
+            time: recording::Time(0),
+        });
+        l.enqueue_webhook_event(Event::StreamHealth {
+            stream_id: testutil::TEST_STREAM_ID,
+            healthy: false,
+            error: Some("connection refused".to_owned()),
+            time: recording::Time(90_000),
+        });
+        let due = l.list_due_webhook_deliveries(0, 10).unwrap();
+        assert_eq!(due.len(), 1);
+        let d = &due[0];
+        assert_eq!(d.url, "http://127.0.0.1/hook");
+        let p: serde_json::Value = serde_json::from_str(&d.payload).unwrap();
+        assert_eq!(p["type"], "streamHealth");
+        assert_eq!(p["healthy"], false);
+        assert_eq!(p["error"], "connection refused");
+        assert_eq!(p["streamType"], "main");
+
+        // A failure is retried later.
+        assert!(!l
+            .finish_webhook_delivery(d, 0, Err("timeout".to_owned()))
+            .unwrap());
+        assert!(l.list_due_webhook_deliveries(0, 10).unwrap().is_empty());
+        let due = l
+            .list_due_webhook_deliveries(FIRST_RETRY_DELAY_SEC, 10)
+            .unwrap();
+        assert_eq!(due[0].attempts, 1);
+        assert!(l
+            .finish_webhook_delivery(&due[0], FIRST_RETRY_DELAY_SEC, Ok(()))
+            .unwrap());
+        assert!(l
+            .list_due_webhook_deliveries(i64::max_value(), 10)
+            .unwrap()
+            .is_empty());
+
+        // Deleting the webhook deletes its pending deliveries.
+        l.enqueue_webhook_event(Event::StreamHealth {
+            stream_id: testutil::TEST_STREAM_ID,
+            healthy: true,
+            error: None,
+            time: recording::Time(180_000),
+        });
+        l.delete_webhook(id).unwrap();
+        assert!(l.webhooks_by_id().is_empty());
+        assert!(l.list_due_webhook_deliveries(0, 10).unwrap().is_empty());
+    }
+}
@@ -7,10 +7,12 @@
 use crate::db::{self, CompositeId};
 use crate::dir;
 use crate::recording::{self, MAX_RECORDING_WALL_DURATION};
+use crate::webhook;
 use base::clock::{self, Clocks};
+use base::metrics::{Gauge, Histogram, DURATION_BUCKETS, REGISTRY};
 use failure::{bail, format_err, Error};
 use fnv::FnvHashMap;
-use log::{debug, trace, warn};
+use log::{debug, info, trace, warn};
 use parking_lot::Mutex;
 use std::cmp::{self, Ordering};
 use std::convert::TryFrom;
@@ -28,6 +30,7 @@ pub trait DirWriter: 'static + Send {
     type File: FileWriter;
 
     fn create_file(&self, id: CompositeId) -> Result<Self::File, nix::Error>;
+    fn create_audio_file(&self, id: CompositeId) -> Result<Self::File, nix::Error>;
     fn sync(&self) -> Result<(), nix::Error>;
     fn unlink_file(&self, id: CompositeId) -> Result<(), nix::Error>;
 }
@@ -49,6 +52,9 @@ impl DirWriter for Arc<dir::SampleFileDir> {
     fn create_file(&self, id: CompositeId) -> Result<Self::File, nix::Error> {
         dir::SampleFileDir::create_file(self, id)
     }
+    fn create_audio_file(&self, id: CompositeId) -> Result<Self::File, nix::Error> {
+        dir::SampleFileDir::create_audio_file(self, id)
+    }
     fn sync(&self) -> Result<(), nix::Error> {
         dir::SampleFileDir::sync(self)
     }
@@ -69,7 +75,7 @@ impl FileWriter for ::std::fs::File {
 /// A command sent to a [Syncer].
 enum SyncerCommand<F> {
     /// Command sent by [SyncerChannel::async_save_recording].
-    AsyncSaveRecording(CompositeId, recording::Duration, F),
+    AsyncSaveRecording(CompositeId, recording::Duration, F, Option<F>),
 
     /// Notes that the database has been flushed and garbage collection should be attempted.
     /// [start_syncer] sets up a database callback to send this command.
@@ -77,15 +83,49 @@ enum SyncerCommand<F> {
 
     /// Command sent by [SyncerChannel::flush].
     Flush(mpsc::SyncSender<()>),
+
+    /// Command sent by [SyncerChannel::rotate].
+    Rotate,
 }
 
 /// A channel which can be used to send commands to the syncer.
 /// Can be cloned to allow multiple threads to send commands.
-pub struct SyncerChannel<F>(mpsc::Sender<SyncerCommand<F>>);
+///
+/// The gauge is the syncer's `pending_saves` metric, incremented here and decremented when the
+/// syncer handles the save.
+pub struct SyncerChannel<F>(mpsc::Sender<SyncerCommand<F>>, Arc<Gauge>);
 
 impl<F> ::std::clone::Clone for SyncerChannel<F> {
     fn clone(&self) -> Self {
-        SyncerChannel(self.0.clone())
+        SyncerChannel(self.0.clone(), self.1.clone())
+    }
+}
+
+/// Metrics of a [Syncer], labeled by its sample file directory's path.
+struct SyncerMetrics {
+    /// Recordings sent to the syncer which it hasn't yet saved; the depth of its queue.
+    pending_saves: Arc<Gauge>,
+
+    /// Time taken to sync and save each recording.
+    save_duration: Arc<Histogram>,
+}
+
+impl SyncerMetrics {
+    fn new(path: &str) -> Self {
+        let labels = [("dir", path)];
+        SyncerMetrics {
+            pending_saves: REGISTRY.gauge(
+                "moonfire_syncer_pending_saves",
+                "Recordings waiting for the syncer to save them.",
+                &labels,
+            ),
+            save_duration: REGISTRY.histogram(
+                "moonfire_syncer_save_duration_seconds",
+                "Time taken to sync a recording's files and mark it synced in the database.",
+                DURATION_BUCKETS,
+                &labels,
+            ),
+        }
     }
 }
 
@@ -95,6 +135,11 @@ struct Syncer<C: Clocks + Clone, D: DirWriter> {
     dir: D,
     db: Arc<db::Database<C>>,
     planned_flushes: std::collections::BinaryHeap<PlannedFlush>,
+
+    /// A channel to wake the thread created by [start_archiver] after each save, if any.
+    archiver: Option<mpsc::SyncSender<()>>,
+
+    metrics: SyncerMetrics,
 }
 
 /// A plan to flush at a given instant due to a recently-saved recording's `flush_if_sec` parameter.
@@ -142,10 +187,12 @@ impl Eq for PlannedFlush {}
 ///
 /// There should be only one syncer per directory, or 0 if operating in read-only mode.
 /// This function will perform the initial rotation synchronously, so that it is finished before
-/// file writing starts. Afterward the syncing happens in a background thread.
+/// file writing starts. Afterward the syncing happens in a background thread. Archiving happens
+/// in a second background thread, so that slow copies don't delay saves or flushes.
 ///
 /// Returns a `SyncerChannel` which can be used to send commands (and can be cloned freely) and
-/// a `JoinHandle` for the syncer thread. Commands sent on the channel will be executed or retried
+/// a `JoinHandle` for the syncer thread, which in turn joins the archiver thread when it exits.
+/// Commands sent on the channel will be executed or retried
 /// forever. (TODO: provide some manner of pushback during retry.) At program shutdown, all
 /// `SyncerChannel` clones should be dropped and then the handle joined to allow all recordings to
 /// be persisted.
@@ -172,15 +219,61 @@ where
             }
         }
     }));
+    let (archiver_snd, archiver) = start_archiver(db, dir_id, &path);
+    syncer.archiver = Some(archiver_snd);
+    let pending_saves = syncer.metrics.pending_saves.clone();
     Ok((
-        SyncerChannel(snd),
+        SyncerChannel(snd, pending_saves),
         thread::Builder::new()
             .name(format!("sync-{}", path))
-            .spawn(move || while syncer.iter(&rcv) {})
+            .spawn(move || {
+                while syncer.iter(&rcv) {}
+
+                // Dropping the syncer closes the archiver's channel, telling it to exit.
+                drop(syncer);
+                archiver.join().unwrap();
+            })
             .unwrap(),
     ))
 }
 
+/// Starts a thread which moves recordings of streams with primary directory `dir_id` to their
+/// archive directories via [archive_recordings] on startup and each time it's woken through the
+/// returned channel. It exits when the channel is closed.
+fn start_archiver<C>(
+    db: Arc<db::Database<C>>,
+    dir_id: i32,
+    path: &str,
+) -> (mpsc::SyncSender<()>, thread::JoinHandle<()>)
+where
+    C: Clocks + Clone,
+{
+    // A single slot is enough: a wakeup which arrives while one is already pending can be
+    // dropped, as the pending pass will see the same recordings.
+    let (snd, rcv) = mpsc::sync_channel(1);
+    snd.send(()).unwrap(); // archive anything left over from a previous run.
+    let join = thread::Builder::new()
+        .name(format!("archive-{}", path))
+        .spawn(move || {
+            // The lowest recording id of each stream which may still need archiving.
+            let mut next_ids = FnvHashMap::default();
+            while let Ok(()) = rcv.recv() {
+                // Archiving is best-effort; anything not moved now will be retried on the next
+                // wakeup.
+                let now = recording::Time::new(db.clocks().realtime());
+                if let Err(e) = archive_recordings(&db, dir_id, now, &mut next_ids) {
+                    warn!("Unable to archive recordings of dir {}: {}", dir_id, e);
+                }
+            }
+        })
+        .unwrap();
+    (snd, join)
+}
+
+/// The maximum number of recordings of each stream to move to its archive directory per pass.
+/// This bounds how long the archiver holds up shutdown.
+const MAX_ARCHIVE_PER_PASS: usize = 16;
+
 /// A new retention limit for use in [lower_retention].
 pub struct NewLimit {
     pub stream_id: i32,
@@ -190,6 +283,7 @@ pub struct NewLimit {
 /// Immediately deletes recordings if necessary to fit within the given new `retain_bytes` limit.
 /// Note this doesn't change the limit in the database; it only deletes files.
 /// Pass a limit of 0 to delete all recordings associated with a camera.
+/// As this is an explicit request from the operator, it ignores `retain_min_age_sec`.
 ///
 /// This is expected to be performed from `moonfire-nvr config` when no syncer is running.
 /// It potentially flushes the database twice (before and after the actual deletion).
@@ -199,6 +293,7 @@ pub fn lower_retention(
     limits: &[NewLimit],
 ) -> Result<(), Error> {
     let db2 = db.clone();
+    let now = recording::Time::new(db.clocks().realtime());
     let (mut syncer, _) = Syncer::new(&db.lock(), db2, dir_id)?;
     syncer.do_rotation(|db| {
         for l in limits {
@@ -215,27 +310,39 @@ pub fn lower_retention(
             if l.limit >= fs_bytes_before {
                 continue;
             }
-            delete_recordings(db, l.stream_id, extra)?;
+            delete_recordings(db, l.stream_id, extra, now, false)?;
         }
         Ok(())
     })
 }
 
-/// Enqueues deletion of recordings to bring a stream's disk usage within bounds.
+/// Enqueues deletion of recordings to bring a stream's disk usage within bounds and to delete
+/// recordings older than its `retain_max_age_sec` as of `now`. Unless `honor_min_age` is false,
+/// recordings younger than the stream's `retain_min_age_sec` are kept even if the stream exceeds
+/// its byte limit. Recordings overlapping a hold are never deleted, but their bytes still count
+/// toward the byte limit, so the stream's other recordings are deleted sooner to compensate.
 /// The next flush will mark the recordings as garbage in the SQLite database, and then they can
 /// be deleted from disk.
 fn delete_recordings(
     db: &mut db::LockedDatabase,
     stream_id: i32,
     extra_bytes_needed: i64,
+    now: recording::Time,
+    honor_min_age: bool,
 ) -> Result<(), Error> {
-    let fs_bytes_needed = {
+    let (fs_bytes_needed, max_age, min_age) = {
         let stream = match db.streams_by_id().get(&stream_id) {
             None => bail!("no stream {}", stream_id),
             Some(s) => s,
         };
-        stream.fs_bytes + stream.fs_bytes_to_add - stream.fs_bytes_to_delete + extra_bytes_needed
-            - stream.retain_bytes
+        let age = |sec| recording::Duration(sec * recording::TIME_UNITS_PER_SEC);
+        (
+            stream.fs_bytes + stream.fs_bytes_to_add - stream.fs_bytes_to_delete
+                + extra_bytes_needed
+                - stream.retain_bytes,
+            stream.retain_max_age_sec.map(age),
+            stream.retain_min_age_sec.filter(|_| honor_min_age).map(age),
+        )
     };
     let mut fs_bytes_to_delete = 0;
     if fs_bytes_needed <= 0 {
@@ -244,26 +351,138 @@ fn delete_recordings(
             stream_id,
             base::strutil::encode_size(-fs_bytes_needed)
         );
-        return Ok(());
+        if max_age.is_none() {
+            return note_retention_overage(db, stream_id, 0);
+        }
     }
     let mut n = 0;
+    let mut kept_young = false;
     db.delete_oldest_recordings(stream_id, &mut |row| {
-        if fs_bytes_needed >= fs_bytes_to_delete {
-            fs_bytes_to_delete += db::round_up(i64::from(row.sample_file_bytes));
-            n += 1;
-            return true;
+        let end = row.start + recording::Duration(i64::from(row.wall_duration_90k));
+        if max_age.map_or(false, |a| end < now - a) {
+            // Expired; delete regardless of space.
+        } else if fs_bytes_needed > 0 && fs_bytes_needed >= fs_bytes_to_delete {
+            if min_age.map_or(false, |a| end > now - a) {
+                kept_young = true;
+                return false;
+            }
+        } else {
+            return false;
         }
-        false
+        fs_bytes_to_delete += db::round_up(i64::from(row.sample_file_bytes));
+        n += 1;
+        true
     })?;
+    let overage = if kept_young {
+        fs_bytes_needed - fs_bytes_to_delete
+    } else {
+        0
+    };
+    note_retention_overage(db, stream_id, overage)
+}
+
+/// Records the bytes by which a stream exceeds its retention limit due to `retain_min_age_sec`.
+/// Rotation happens on every save, so this logs only when the stream starts or stops exceeding
+/// its limit. The current overage is available through the API.
+fn note_retention_overage(
+    db: &mut db::LockedDatabase,
+    stream_id: i32,
+    overage: i64,
+) -> Result<(), Error> {
+    let prev_overage = db.set_retention_overage(stream_id, overage)?;
+    if overage > 0 && prev_overage == 0 {
+        warn!(
+            "{}: exceeds its retention limit by {}, but its remaining recordings are younger \
+             than its minimum retention age, so they won't be deleted",
+            stream_id,
+            base::strutil::encode_size(overage)
+        );
+    } else if overage == 0 && prev_overage > 0 {
+        info!("{}: is back within its retention limit", stream_id);
+    }
+    Ok(())
+}
+
+/// Moves recordings of streams with primary directory `dir_id` which ended longer than their
+/// `archive_age_sec` before `now` to their archive directories. The original sample files become
+/// garbage, to be unlinked after the next flush. `next_ids` tracks the lowest recording id of each
+/// stream which may still need archiving, to avoid repeatedly scanning archived recordings.
+fn archive_recordings<C: Clocks + Clone>(
+    db: &db::Database<C>,
+    dir_id: i32,
+    now: recording::Time,
+    next_ids: &mut FnvHashMap<i32, i32>,
+) -> Result<(), Error> {
+    let mut todo = Vec::new();
+    let src = {
+        let l = db.lock();
+        for s in l.streams_by_id().values() {
+            let (archive_dir_id, age) = match (
+                s.sample_file_dir_id,
+                s.archive_sample_file_dir_id,
+                s.archive_age_sec,
+            ) {
+                (Some(d), Some(a), Some(age)) if d == dir_id => (a, age),
+                _ => continue,
+            };
+            let cutoff = now - recording::Duration(age * recording::TIME_UNITS_PER_SEC);
+            let start = next_ids.get(&s.id).copied().unwrap_or(0);
+            let (rows, next) =
+                l.list_recordings_to_archive(s.id, start, cutoff, MAX_ARCHIVE_PER_PASS)?;
+            if rows.is_empty() {
+                next_ids.insert(s.id, next);
+            } else {
+                let dest = l
+                    .sample_file_dirs_by_id()
+                    .get(&archive_dir_id)
+                    .ok_or_else(|| format_err!("no dir {}", archive_dir_id))?
+                    .get()?;
+                todo.push((s.id, archive_dir_id, dest, rows, next));
+            }
+        }
+        if todo.is_empty() {
+            return Ok(());
+        }
+        l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?
+    };
+    for (stream_id, archive_dir_id, dest, rows, next) in todo {
+        for row in &rows {
+            src.copy_file_to(row.id, &dest)?;
+        }
+        dest.sync()?;
+        let mut l = db.lock();
+        for row in &rows {
+            if let Err(e) = l.mark_archived(row.id, archive_dir_id) {
+                // Most likely the recording was deleted in the meantime; discard the copy.
+                warn!("Unable to mark {} as archived: {}", row.id, e);
+                if let Err(e) = dest.unlink_file(row.id) {
+                    warn!("Unable to unlink unused archive copy of {}: {}", row.id, e);
+                }
+            }
+        }
+        next_ids.insert(stream_id, next);
+    }
     Ok(())
 }
 
 impl<F: FileWriter> SyncerChannel<F> {
-    /// Asynchronously syncs the given writer, closes it, records it into the database, and
-    /// starts rotation.
-    fn async_save_recording(&self, id: CompositeId, wall_duration: recording::Duration, f: F) {
+    /// Asynchronously syncs the given writer (and audio writer, if any), closes it, records it
+    /// into the database, and starts rotation.
+    fn async_save_recording(
+        &self,
+        id: CompositeId,
+        wall_duration: recording::Duration,
+        f: F,
+        audio_f: Option<F>,
+    ) {
+        self.1.add(1.);
         self.0
-            .send(SyncerCommand::AsyncSaveRecording(id, wall_duration, f))
+            .send(SyncerCommand::AsyncSaveRecording(
+                id,
+                wall_duration,
+                f,
+                audio_f,
+            ))
             .unwrap();
     }
 
@@ -275,6 +494,13 @@ impl<F: FileWriter> SyncerChannel<F> {
         self.0.send(SyncerCommand::Flush(snd)).unwrap();
         rcv.recv().unwrap_err(); // syncer should just drop the channel, closing it.
     }
+
+    /// Asynchronously deletes recordings as needed to meet the current retention limits of the
+    /// directory's streams. Rotation normally happens only as new recordings are saved, so this
+    /// should be called after lowering a limit.
+    pub fn rotate(&self) {
+        self.0.send(SyncerCommand::Rotate).unwrap();
+    }
 }
 
 /// Lists files which should be "abandoned" (deleted without ever recording in the database)
@@ -349,6 +575,8 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
                 dir,
                 db,
                 planned_flushes: std::collections::BinaryHeap::new(),
+                archiver: None,
+                metrics: SyncerMetrics::new(&d.path),
             },
             d.path.clone(),
         ))
@@ -357,10 +585,11 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
     /// Rotates files for all streams and deletes stale files from previous runs.
     /// Called from main thread.
     fn initial_rotation(&mut self) -> Result<(), Error> {
+        let now = recording::Time::new(self.db.clocks().realtime());
         self.do_rotation(|db| {
             let streams: Vec<i32> = db.streams_by_id().keys().copied().collect();
             for &stream_id in &streams {
-                delete_recordings(db, stream_id, 0)?;
+                delete_recordings(db, stream_id, 0, now, true)?;
             }
             Ok(())
         })
@@ -438,7 +667,9 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
 
         // Have a command; handle it.
         match cmd {
-            SyncerCommand::AsyncSaveRecording(id, wall_dur, f) => self.save(id, wall_dur, f),
+            SyncerCommand::AsyncSaveRecording(id, wall_dur, f, audio_f) => {
+                self.save(id, wall_dur, f, audio_f)
+            }
             SyncerCommand::DatabaseFlushed => self.collect_garbage(),
             SyncerCommand::Flush(flush) => {
                 // The sender is waiting for the supplied writer to be dropped. If there's no
@@ -447,6 +678,7 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
                     f.senders.push(flush);
                 }
             }
+            SyncerCommand::Rotate => self.rotate(),
         };
 
         true
@@ -482,20 +714,63 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
         });
     }
 
-    /// Saves the given recording and prompts rotation. Called from worker thread.
+    /// Deletes recordings to meet the retention limits of the streams in this directory, flushing
+    /// immediately. Called from worker thread.
+    fn rotate(&mut self) {
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let mut db = self.db.lock();
+        let streams: Vec<i32> = db
+            .streams_by_id()
+            .iter()
+            .filter(|(_, s)| s.sample_file_dir_id == Some(self.dir_id))
+            .map(|(&id, _)| id)
+            .collect();
+        for &s in &streams {
+            if let Err(e) = delete_recordings(&mut db, s, 0, now, true) {
+                warn!("Unable to rotate stream {}: {}", s, e);
+            }
+        }
+        if let Err(e) = db.flush("retention change") {
+            warn!("Unable to flush after retention change: {}", e);
+        }
+    }
+
+    /// Saves the given recording and prompts rotation and archiving. Called from worker thread.
     /// Note that this doesn't flush immediately; SQLite transactions are batched to lower SSD
     /// wear. On the next flush, the old recordings will actually be marked as garbage in the
     /// database, and shortly afterward actually deleted from disk.
-    fn save(&mut self, id: CompositeId, wall_duration: recording::Duration, f: D::File) {
+    fn save(
+        &mut self,
+        id: CompositeId,
+        wall_duration: recording::Duration,
+        f: D::File,
+        audio_f: Option<D::File>,
+    ) {
         trace!("Processing save for {}", id);
+        let start = self.db.clocks().monotonic();
         let stream_id = id.stream();
 
         // Free up a like number of bytes.
         clock::retry_forever(&self.db.clocks(), &mut || f.sync_all());
+        if let Some(ref a) = audio_f {
+            clock::retry_forever(&self.db.clocks(), &mut || a.sync_all());
+        }
         clock::retry_forever(&self.db.clocks(), &mut || self.dir.sync());
+        let now = recording::Time::new(self.db.clocks().realtime());
         let mut db = self.db.lock();
         db.mark_synced(id).unwrap();
-        delete_recordings(&mut db, stream_id, 0).unwrap();
+
+        // Rotate every stream in this directory, not just this one, so that streams which are no
+        // longer recording still expire recordings older than their `retain_max_age_sec`.
+        let streams: Vec<i32> = db
+            .streams_by_id()
+            .iter()
+            .filter(|(_, s)| s.sample_file_dir_id == Some(self.dir_id))
+            .map(|(&id, _)| id)
+            .collect();
+        for &s in &streams {
+            delete_recordings(&mut db, s, 0, now, true).unwrap();
+        }
         let s = db.streams_by_id().get(&stream_id).unwrap();
         let c = db.cameras_by_id().get(&s.camera_id).unwrap();
 
@@ -512,12 +787,23 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
             id
         );
         trace!("scheduling flush in {} because {}", how_soon, &reason);
+        drop(db);
+        self.metrics.pending_saves.add(-1.);
+        self.metrics
+            .save_duration
+            .observe_duration(self.db.clocks().monotonic() - start);
         self.planned_flushes.push(PlannedFlush {
             when,
             reason,
             recording: id,
             senders: Vec::new(),
         });
+
+        if let Some(ref a) = self.archiver {
+            if let Err(mpsc::TrySendError::Disconnected(())) = a.try_send(()) {
+                warn!("Archiver for dir {} has exited", self.dir_id);
+            }
+        }
     }
 
     /// Flushes the database if necessary to honor `flush_if_sec` for some recording.
@@ -581,6 +867,33 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
     }
 }
 
+/// Writes all of `buf` to `f`, retrying errors forever. The first `ENOSPC` is reported to
+/// webhooks as a full disk.
+fn write_all<C: Clocks + Clone, F: FileWriter>(
+    db: &db::Database<C>,
+    stream_id: i32,
+    f: &mut F,
+    buf: &[u8],
+) {
+    let mut reported = false;
+    let mut remaining = buf;
+    while !remaining.is_empty() {
+        let written = clock::retry_forever(&db.clocks(), &mut || {
+            let r = f.write(remaining);
+            if let Err(ref e) = r {
+                if e.raw_os_error() == Some(libc::ENOSPC) && !reported {
+                    reported = true;
+                    let time = recording::Time::new(db.clocks().realtime());
+                    db.lock()
+                        .enqueue_webhook_event(webhook::Event::DiskFull { stream_id, time });
+                }
+            }
+            r
+        });
+        remaining = &remaining[written..];
+    }
+}
+
 /// Struct for writing a single run (of potentially several recordings) to disk and committing its
 /// metadata to the database. `Writer` hands off each recording's state to the syncer when done. It
 /// saves the recording to the database (if I/O errors do not prevent this), retries forever,
@@ -591,6 +904,7 @@ pub struct Writer<'a, C: Clocks + Clone, D: DirWriter> {
     channel: &'a SyncerChannel<D::File>,
     stream_id: i32,
     video_sample_entry_id: i32,
+    audio_sample_entry_id: Option<i32>,
     state: WriterState<D::File>,
 }
 
@@ -627,6 +941,13 @@ struct InnerWriter<F: FileWriter> {
     ///
     /// Invariant: this should always be `Some` (briefly violated during `write` call only).
     unindexed_sample: Option<UnindexedSample>,
+
+    /// The audio sample file and index encoder, created on the first audio sample.
+    audio: Option<(F, recording::AudioIndexEncoder)>,
+
+    /// The pts of the first video frame, set on the first `write`. The first audio sample's
+    /// offset is relative to this.
+    start_pts_90k: Option<i64>,
 }
 
 /// A sample which has been written to disk but not included in the index yet.
@@ -655,6 +976,7 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
         channel: &'a SyncerChannel<D::File>,
         stream_id: i32,
         video_sample_entry_id: i32,
+        audio_sample_entry_id: Option<i32>,
     ) -> Self {
         Writer {
             dir,
@@ -662,6 +984,7 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             channel,
             stream_id,
             video_sample_entry_id,
+            audio_sample_entry_id,
             state: WriterState::Unopened,
         }
     }
@@ -698,6 +1021,8 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             hasher: blake3::Hasher::new(),
             local_start: recording::Time(i64::max_value()),
             unindexed_sample: None,
+            audio: None,
+            start_pts_90k: None,
         });
         Ok(())
     }
@@ -728,6 +1053,8 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
         // Note w's invariant that `unindexed_sample` is `None` may currently be violated.
         // We must restore it on all success or error paths.
 
+        w.start_pts_90k.get_or_insert(pts_90k);
+
         if let Some(unindexed) = w.unindexed_sample.take() {
             let duration = pts_90k - unindexed.pts_90k;
             if duration <= 0 {
@@ -761,11 +1088,7 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
                 return Err(e);
             }
         }
-        let mut remaining = pkt;
-        while !remaining.is_empty() {
-            let written = clock::retry_forever(&self.db.clocks(), &mut || w.f.write(remaining));
-            remaining = &remaining[written..];
-        }
+        write_all(self.db, self.stream_id, &mut w.f, pkt);
         w.unindexed_sample = Some(UnindexedSample {
             local_time,
             pts_90k,
@@ -776,6 +1099,49 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
         Ok(())
     }
 
+    /// Writes a new audio frame to the current recording.
+    /// `pts_90k` is on the same timeline as video frames' pts; `duration` is in units of the
+    /// audio sample entry's sample rate.
+    ///
+    /// Frames received while no recording is open (before the first video frame) are discarded.
+    /// The first frame's pts relative to the recording's first video frame is saved so that
+    /// playback can keep audio and video in sync.
+    pub fn write_audio(&mut self, pkt: &[u8], pts_90k: i64, duration: i32) -> Result<(), Error> {
+        let audio_sample_entry_id = match self.audio_sample_entry_id {
+            None => bail!("write_audio on writer with no audio sample entry"),
+            Some(id) => id,
+        };
+        let w = match self.state {
+            WriterState::Open(ref mut w) => w,
+            _ => return Ok(()),
+        };
+        if duration <= 0 {
+            bail!("non-positive audio duration {}", duration);
+        }
+        if w.audio.is_none() {
+            let dir = &self.dir;
+            let id = w.id;
+            let start_pts_90k = w.start_pts_90k.expect("open writer has video");
+            let offset = i32::try_from(pts_90k - start_pts_90k).map_err(|_| {
+                format_err!(
+                    "excessive gap between video pts {} and audio pts {}",
+                    start_pts_90k,
+                    pts_90k
+                )
+            })?;
+            let f = clock::retry_forever(&self.db.clocks(), &mut || dir.create_audio_file(id));
+            let mut r = w.r.lock();
+            r.audio_sample_entry_id = Some(audio_sample_entry_id);
+            r.audio_start_offset_90k = offset;
+            drop(r);
+            w.audio = Some((f, recording::AudioIndexEncoder::default()));
+        }
+        let (f, e) = w.audio.as_mut().unwrap();
+        write_all(self.db, self.stream_id, f, pkt);
+        e.add_sample(duration, i32::try_from(pkt.len())?, &mut w.r.lock());
+        Ok(())
+    }
+
     /// Cleanly closes the writer, using a supplied pts of the next sample for the last sample's
     /// duration (if known). If `close` is not called, the `Drop` trait impl will close the trait,
     /// swallowing errors and using a zero duration for the last sample.
@@ -886,7 +1252,7 @@ impl<F: FileWriter> InnerWriter<F> {
             end = l.start + wall_duration;
         }
         drop(self.r);
-        channel.async_save_recording(self.id, wall_duration, self.f);
+        channel.async_save_recording(self.id, wall_duration, self.f, self.audio.map(|(f, _)| f));
         Ok(PreviousWriter { end, run_offset })
     }
 }
@@ -913,6 +1279,7 @@ mod tests {
     use crate::recording;
     use crate::testutil;
     use base::clock::{Clocks, SimulatedClocks};
+    use fnv::FnvHashMap;
     use log::{trace, warn};
     use parking_lot::Mutex;
     use std::collections::VecDeque;
@@ -928,6 +1295,10 @@ mod tests {
             CompositeId,
             Box<dyn Fn(CompositeId) -> Result<MockFile, nix::Error> + Send>,
         ),
+        CreateAudio(
+            CompositeId,
+            Box<dyn Fn(CompositeId) -> Result<MockFile, nix::Error> + Send>,
+        ),
         Sync(Box<dyn Fn() -> Result<(), nix::Error> + Send>),
         Unlink(
             CompositeId,
@@ -964,6 +1335,20 @@ mod tests {
                 _ => panic!("got create_file({}), expected something else", id),
             }
         }
+        fn create_audio_file(&self, id: CompositeId) -> Result<Self::File, nix::Error> {
+            match self
+                .0
+                .lock()
+                .pop_front()
+                .expect("got create_audio_file with no expectation")
+            {
+                MockDirAction::CreateAudio(expected_id, ref f) => {
+                    assert_eq!(id, expected_id);
+                    f(id)
+                }
+                _ => panic!("got create_audio_file({}), expected something else", id),
+            }
+        }
         fn sync(&self) -> Result<(), nix::Error> {
             match self
                 .0
@@ -1083,6 +1468,8 @@ mod tests {
             dir: dir.clone(),
             db: tdb.db.clone(),
             planned_flushes: std::collections::BinaryHeap::new(),
+            archiver: None,
+            metrics: super::SyncerMetrics::new("test"),
         };
         let (syncer_snd, syncer_rcv) = mpsc::channel();
         tdb.db.lock().on_flush(Box::new({
@@ -1098,7 +1485,7 @@ mod tests {
             dir,
             db: tdb.db,
             _tmpdir: tdb.tmpdir,
-            channel: super::SyncerChannel(syncer_snd),
+            channel: super::SyncerChannel(syncer_snd, syncer.metrics.pending_saves.clone()),
             syncer,
             syncer_rcv,
         }
@@ -1132,6 +1519,7 @@ mod tests {
             &h.channel,
             testutil::TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
         h.dir.expect(MockDirAction::Create(
             CompositeId::new(1, 0),
@@ -1164,6 +1552,110 @@ mod tests {
         h.dir.ensure_done();
     }
 
+    /// Tests writing audio alongside video, including discarding audio before the first video
+    /// frame and syncing the audio file on save.
+    #[test]
+    fn audio() {
+        testutil::init();
+        let mut h = new_harness(0);
+        let (video_sample_entry_id, audio_sample_entry_id) = {
+            let mut l = h.db.lock();
+            let v = l
+                .insert_video_sample_entry(VideoSampleEntryToInsert {
+                    width: 1920,
+                    height: 1080,
+                    pasp_h_spacing: 1,
+                    pasp_v_spacing: 1,
+                    data: [0u8; 100].to_vec(),
+                    rfc6381_codec: "avc1.000000".to_owned(),
+                })
+                .unwrap();
+            let a = l
+                .insert_audio_sample_entry(db::AudioSampleEntryToInsert {
+                    data: [0u8; 50].to_vec(),
+                    rfc6381_codec: "mp4a.40.2".to_owned(),
+                    sample_rate: 8_000,
+                    channels: 1,
+                })
+                .unwrap();
+            (v, a)
+        };
+        let mut w = Writer::new(
+            &h.dir,
+            &h.db,
+            &h.channel,
+            testutil::TEST_STREAM_ID,
+            video_sample_entry_id,
+            Some(audio_sample_entry_id),
+        );
+
+        // Audio before the first video frame is discarded without touching the directory.
+        w.write_audio(b"x", -900, 1024).unwrap();
+
+        let f = MockFile::new();
+        h.dir.expect(MockDirAction::Create(
+            CompositeId::new(1, 0),
+            Box::new({
+                let f = f.clone();
+                move |_id| Ok(f.clone())
+            }),
+        ));
+        let a = MockFile::new();
+        h.dir.expect(MockDirAction::CreateAudio(
+            CompositeId::new(1, 0),
+            Box::new({
+                let a = a.clone();
+                move |_id| Ok(a.clone())
+            }),
+        ));
+        f.expect(MockFileAction::Write(Box::new(|buf| {
+            assert_eq!(buf, b"123");
+            Ok(3)
+        })));
+        a.expect(MockFileAction::Write(Box::new(|buf| {
+            assert_eq!(buf, b"ab");
+            Ok(2)
+        })));
+        a.expect(MockFileAction::Write(Box::new(|buf| {
+            assert_eq!(buf, b"cde");
+            Ok(3)
+        })));
+        w.write(b"123", recording::Time(2), 0, true).unwrap();
+        w.write_audio(b"ab", 900, 1024).unwrap();
+        w.write_audio(b"cde", 900 + 11_520, 1024).unwrap();
+        f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+        a.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        w.close(Some(1)).unwrap();
+        drop(w);
+        assert!(h.syncer.iter(&h.syncer_rcv)); // AsyncSave
+        assert_eq!(h.syncer.planned_flushes.len(), 1);
+        assert!(h.syncer.iter(&h.syncer_rcv)); // planned flush
+        assert!(h.syncer.iter(&h.syncer_rcv)); // DatabaseFlushed
+        f.ensure_done();
+        a.ensure_done();
+        h.dir.ensure_done();
+
+        let mut rows = 0;
+        h.db.lock()
+            .list_recordings_by_id(testutil::TEST_STREAM_ID, 0..1, &mut |r| {
+                rows += 1;
+                assert_eq!(r.audio_sample_entry_id, Some(audio_sample_entry_id));
+                assert_eq!(r.audio_samples, 2);
+                assert_eq!(r.audio_sample_file_bytes, 5);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(rows, 1);
+        let offset =
+            h.db.lock()
+                .with_recording_playback(CompositeId::new(1, 0), &mut |p| {
+                    Ok(p.audio_start_offset_90k)
+                })
+                .unwrap();
+        assert_eq!(offset, 900);
+    }
+
     /// Tests the database flushing while a syncer is still processing a previous flush event.
     #[test]
     fn double_flush() {
@@ -1195,6 +1687,7 @@ mod tests {
             &h.channel,
             testutil::TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
         let f = MockFile::new();
         h.dir.expect(MockDirAction::Create(
@@ -1313,6 +1806,7 @@ mod tests {
             &h.channel,
             testutil::TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
         h.dir.expect(MockDirAction::Create(
             CompositeId::new(1, 0),
@@ -1404,6 +1898,7 @@ mod tests {
             &h.channel,
             testutil::TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
         let f = MockFile::new();
         h.dir.expect(MockDirAction::Create(
@@ -1534,6 +2029,7 @@ mod tests {
             &h.channel,
             testutil::TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
         let f1 = MockFile::new();
         h.dir.expect(MockDirAction::Create(
@@ -1574,6 +2070,7 @@ mod tests {
             &h.channel,
             testutil::TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
         let f2 = MockFile::new();
         h.dir.expect(MockDirAction::Create(
@@ -1628,4 +2125,286 @@ mod tests {
         );
         assert!(h.syncer.planned_flushes.is_empty());
     }
+
+    /// Sets the test stream's retention ages.
+    fn set_retain_ages<C: Clocks + Clone>(
+        db: &db::Database<C>,
+        max_sec: Option<i64>,
+        min_sec: Option<i64>,
+    ) {
+        update_test_stream(db, |sc| {
+            sc.retain_max_age_sec = max_sec;
+            sc.retain_min_age_sec = min_sec;
+        });
+    }
+
+    /// Updates the test stream's configuration via `f`.
+    fn update_test_stream<C: Clocks + Clone>(
+        db: &db::Database<C>,
+        f: impl FnOnce(&mut db::StreamChange),
+    ) {
+        let mut l = db.lock();
+        let c = l.cameras_by_id().get(&testutil::TEST_CAMERA_ID).unwrap();
+        let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+        let mut sc = db::StreamChange {
+            sample_file_dir_id: s.sample_file_dir_id,
+            rtsp_url: s.rtsp_url.clone(),
+            record: s.record,
+            flush_if_sec: s.flush_if_sec,
+            retain_max_age_sec: s.retain_max_age_sec,
+            retain_min_age_sec: s.retain_min_age_sec,
+            archive_sample_file_dir_id: s.archive_sample_file_dir_id,
+            archive_age_sec: s.archive_age_sec,
+        };
+        f(&mut sc);
+        let change = db::CameraChange {
+            short_name: c.short_name.clone(),
+            description: c.description.clone(),
+            onvif_host: c.onvif_host.clone(),
+            username: c.username.clone(),
+            password: c.password.clone(),
+            streams: [sc, Default::default()],
+        };
+        l.update_camera(testutil::TEST_CAMERA_ID, change).unwrap();
+    }
+
+    fn bytes_to_delete<C: Clocks + Clone>(db: &db::Database<C>) -> i64 {
+        db.lock()
+            .streams_by_id()
+            .get(&testutil::TEST_STREAM_ID)
+            .unwrap()
+            .bytes_to_delete
+    }
+
+    fn retention_overage_bytes<C: Clocks + Clone>(db: &db::Database<C>) -> i64 {
+        db.lock()
+            .streams_by_id()
+            .get(&testutil::TEST_STREAM_ID)
+            .unwrap()
+            .retention_overage_bytes
+    }
+
+    /// Tests deletion of recordings older than `retain_max_age_sec`, even when within the byte
+    /// limit.
+    #[test]
+    fn retain_max_age() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(SimulatedClocks::new(::time::Timespec::new(0, 0)));
+        let mut encoder = recording::SampleIndexEncoder::default();
+        let mut r = db::RecordingToInsert::default();
+        encoder.add_sample(90_000, 42, true, &mut r);
+        let row = tdb.insert_recording_from_encoder(r);
+        let end = row.start + recording::Duration(90_000);
+        set_retain_ages(&tdb.db, Some(100), None);
+        tdb.db
+            .lock()
+            .update_retention(&[db::RetentionChange {
+                stream_id: testutil::TEST_STREAM_ID,
+                new_record: true,
+                new_limit: 1 << 30,
+            }])
+            .unwrap();
+
+        let now = end + recording::Duration(99 * 90_000);
+        super::delete_recordings(&mut tdb.db.lock(), testutil::TEST_STREAM_ID, 0, now, true)
+            .unwrap();
+        assert_eq!(bytes_to_delete(&tdb.db), 0);
+
+        let now = end + recording::Duration(101 * 90_000);
+        super::delete_recordings(&mut tdb.db.lock(), testutil::TEST_STREAM_ID, 0, now, true)
+            .unwrap();
+        assert_eq!(bytes_to_delete(&tdb.db), 42);
+    }
+
+    /// Tests that `retain_min_age_sec` keeps young recordings even when over the byte limit,
+    /// unless the caller (the config UI lowering retention) overrides it.
+    #[test]
+    fn retain_min_age() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(SimulatedClocks::new(::time::Timespec::new(0, 0)));
+        let mut encoder = recording::SampleIndexEncoder::default();
+        let mut r = db::RecordingToInsert::default();
+        encoder.add_sample(90_000, 42, true, &mut r);
+        let row = tdb.insert_recording_from_encoder(r);
+        let end = row.start + recording::Duration(90_000);
+        set_retain_ages(&tdb.db, None, Some(100));
+        tdb.db
+            .lock()
+            .update_retention(&[db::RetentionChange {
+                stream_id: testutil::TEST_STREAM_ID,
+                new_record: true,
+                new_limit: 0,
+            }])
+            .unwrap();
+
+        let now = end + recording::Duration(99 * 90_000);
+        super::delete_recordings(&mut tdb.db.lock(), testutil::TEST_STREAM_ID, 0, now, true)
+            .unwrap();
+        assert_eq!(bytes_to_delete(&tdb.db), 0);
+        assert_eq!(retention_overage_bytes(&tdb.db), 4096);
+        super::delete_recordings(&mut tdb.db.lock(), testutil::TEST_STREAM_ID, 0, now, false)
+            .unwrap();
+        assert_eq!(bytes_to_delete(&tdb.db), 42);
+        assert_eq!(retention_overage_bytes(&tdb.db), 0);
+    }
+
+    /// Tests that rotation skips held recordings, deleting newer ones in their place.
+    #[test]
+    fn hold() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(SimulatedClocks::new(::time::Timespec::new(0, 0)));
+        let start = recording::Time(1430006400 * recording::TIME_UNITS_PER_SEC);
+        let mut ids = Vec::new();
+        for i in 0..3 {
+            let mut encoder = recording::SampleIndexEncoder::default();
+            let mut r = db::RecordingToInsert::default();
+            encoder.add_sample(90_000, 1000 + i, true, &mut r);
+            let t = start + recording::Duration(i64::from(i) * 90_000);
+            ids.push(tdb.insert_recording_from_encoder_at(t, r).id);
+        }
+        let now = start + recording::Duration(3 * 90_000);
+        let hold_id = {
+            let mut l = tdb.db.lock();
+            l.update_retention(&[db::RetentionChange {
+                stream_id: testutil::TEST_STREAM_ID,
+                new_record: true,
+                new_limit: 4096,
+            }])
+            .unwrap();
+            l.add_hold(db::HoldToInsert {
+                stream_id: testutil::TEST_STREAM_ID,
+                time: start + recording::Duration(90_000)..start + recording::Duration(2 * 90_000),
+                reason: "incident".to_owned(),
+                creator: None,
+                creation_time: now,
+            })
+            .unwrap()
+        };
+
+        // The held recording's bytes count toward the limit, so both others are deleted.
+        super::delete_recordings(&mut tdb.db.lock(), testutil::TEST_STREAM_ID, 0, now, true)
+            .unwrap();
+        assert_eq!(bytes_to_delete(&tdb.db), 1000 + 1002);
+        tdb.db.lock().flush("test").unwrap();
+        let mut left = Vec::new();
+        tdb.db
+            .lock()
+            .list_recordings_by_id(testutil::TEST_STREAM_ID, 0..3, &mut |r| {
+                left.push(r.id);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(&left, &ids[1..2]);
+
+        // Once released, the recording can be deleted.
+        tdb.db.lock().release_hold(hold_id).unwrap();
+        super::delete_recordings(
+            &mut tdb.db.lock(),
+            testutil::TEST_STREAM_ID,
+            4096,
+            now,
+            true,
+        )
+        .unwrap();
+        assert_eq!(bytes_to_delete(&tdb.db), 1001);
+    }
+
+    /// Tests applying a lowered retention limit without saving a new recording.
+    #[test]
+    fn rotate() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(SimulatedClocks::new(::time::Timespec::new(0, 0)));
+        let mut encoder = recording::SampleIndexEncoder::default();
+        let mut r = db::RecordingToInsert::default();
+        encoder.add_sample(90_000, 4, true, &mut r);
+        tdb.insert_recording_from_encoder(r);
+        tdb.db
+            .lock()
+            .update_retention(&[db::RetentionChange {
+                stream_id: testutil::TEST_STREAM_ID,
+                new_record: false,
+                new_limit: 0,
+            }])
+            .unwrap();
+        tdb.syncer_channel.rotate();
+        tdb.syncer_channel.flush(); // waits for the rotation, which was queued first.
+        let l = tdb.db.lock();
+        let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+        assert!(s.range.is_none());
+    }
+
+    /// Tests moving a recording to the stream's archive directory.
+    #[test]
+    fn archive() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(SimulatedClocks::new(::time::Timespec::new(0, 0)));
+        let archive_tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test-archive")
+            .tempdir()
+            .unwrap();
+        let (dir_id, archive_dir_id) = {
+            let mut l = tdb.db.lock();
+            let archive_dir_id = l
+                .add_sample_file_dir(archive_tmpdir.path().to_str().unwrap().to_owned())
+                .unwrap();
+            let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+            (s.sample_file_dir_id.unwrap(), archive_dir_id)
+        };
+        update_test_stream(&tdb.db, |sc| {
+            sc.archive_sample_file_dir_id = Some(archive_dir_id);
+            sc.archive_age_sec = Some(3600);
+        });
+        let start = recording::Time(1430006400 * recording::TIME_UNITS_PER_SEC);
+        let mut ids = Vec::new();
+        for i in 0..2 {
+            let mut encoder = recording::SampleIndexEncoder::default();
+            let mut r = db::RecordingToInsert::default();
+            encoder.add_sample(90_000, 4, true, &mut r);
+            let t = start + recording::Duration(i * 3600 * recording::TIME_UNITS_PER_SEC);
+            let id = tdb.insert_recording_from_encoder_at(t, r).id;
+            let mut f = tdb.dirs_by_stream_id[&testutil::TEST_STREAM_ID]
+                .create_file(id)
+                .unwrap();
+            io::Write::write_all(&mut f, b"blah").unwrap();
+            ids.push(id);
+        }
+
+        // Only the first recording ended over an hour ago.
+        let now = start + recording::Duration(3600 * recording::TIME_UNITS_PER_SEC + 90_000);
+        let mut next_ids = FnvHashMap::default();
+        super::archive_recordings(&tdb.db, dir_id, now, &mut next_ids).unwrap();
+        let name = |id: CompositeId| format!("{:016x}", id.0);
+        assert_eq!(
+            std::fs::read(archive_tmpdir.path().join(name(ids[0]))).unwrap(),
+            b"blah"
+        );
+        assert!(!archive_tmpdir.path().join(name(ids[1])).exists());
+        assert_eq!(
+            next_ids.get(&testutil::TEST_STREAM_ID),
+            Some(&(ids[0].recording() + 1))
+        );
+        {
+            let l = tdb.db.lock();
+            let dir = l.sample_file_dirs_by_id().get(&dir_id).unwrap();
+            assert!(dir.garbage_needs_unlink.contains(&ids[0]));
+            let (rows, _) = l
+                .list_recordings_to_archive(testutil::TEST_STREAM_ID, 0, now, 16)
+                .unwrap();
+            assert!(rows.is_empty());
+        }
+
+        // Once the primary copy is gone, deleting the recording leaves garbage in the archive.
+        tdb.db.lock().flush("test").unwrap();
+        tdb.syncer_channel.flush();
+        assert!(!tdb.tmpdir.path().join(name(ids[0])).exists());
+        let mut l = tdb.db.lock();
+        l.delete_oldest_recordings(testutil::TEST_STREAM_ID, &mut |_| true)
+            .unwrap();
+        l.flush("test").unwrap();
+        let dir = l.sample_file_dirs_by_id().get(&archive_dir_id).unwrap();
+        assert!(dir.garbage_needs_unlink.contains(&ids[0]));
+        assert!(!dir.garbage_needs_unlink.contains(&ids[1]));
+        let dir = l.sample_file_dirs_by_id().get(&dir_id).unwrap();
+        assert!(dir.garbage_needs_unlink.contains(&ids[1]));
+    }
 }
@@ -0,0 +1,118 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Subcommand to dump the audit log.
+
+use db::audit;
+use db::recording::{self, TIME_UNITS_PER_SEC};
+use failure::{format_err, Error};
+use rusqlite::params;
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+use uuid::Uuid;
+
+#[derive(StructOpt)]
+pub struct Args {
+    /// Directory holding the SQLite3 index database.
+    #[structopt(
+        long,
+        default_value = "/var/lib/moonfire-nvr/db",
+        value_name = "path",
+        parse(from_os_str)
+    )]
+    db_dir: PathBuf,
+
+    /// Only events at or after this time, as an integer or RFC-3339-like string
+    /// (see `moonfire-nvr ts --help`).
+    #[structopt(long)]
+    start: Option<String>,
+
+    /// Only events before this time, as with `--start`.
+    #[structopt(long)]
+    end: Option<String>,
+
+    /// Only events of this kind: `login`, `login_failed`, `logout`, `view`, `export`,
+    /// `post_signals`, or `config`.
+    #[structopt(long)]
+    kind: Option<String>,
+
+    /// Only events attributed to this username.
+    #[structopt(long)]
+    username: Option<String>,
+
+    /// Only events for this camera, given by its short name or uuid.
+    #[structopt(long)]
+    camera: Option<String>,
+
+    /// Only the most recent events, up to this many.
+    #[structopt(long)]
+    limit: Option<usize>,
+}
+
+fn parse_time_sec(t: &Option<String>) -> Result<Option<i64>, Error> {
+    Ok(match t {
+        None => None,
+        Some(t) => Some(recording::Time::parse(t)?.unix_seconds()),
+    })
+}
+
+fn lookup_camera(conn: &rusqlite::Connection, camera: &str) -> Result<Uuid, Error> {
+    if let Ok(u) = Uuid::parse_str(camera) {
+        return Ok(u);
+    }
+    let uuid: db::FromSqlUuid = conn
+        .query_row(
+            "select uuid from camera where short_name = ?",
+            params![camera],
+            |row| row.get(0),
+        )
+        .map_err(|_| format_err!("no such camera {:?}", camera))?;
+    Ok(uuid.0)
+}
+
+pub fn run(args: &Args) -> Result<i32, Error> {
+    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadOnly)?;
+    let filter = audit::Filter {
+        start_time_sec: parse_time_sec(&args.start)?,
+        end_time_sec: parse_time_sec(&args.end)?,
+        kind: args
+            .kind
+            .as_deref()
+            .map(audit::Kind::from_str)
+            .transpose()?,
+        username: args.username.clone(),
+        camera_uuid: match args.camera {
+            None => None,
+            Some(ref c) => Some(lookup_camera(&conn, c)?),
+        },
+        limit: args.limit,
+    };
+
+    // Print the oldest first, as in a log file.
+    for e in audit::list(&conn, &filter)?.iter().rev() {
+        let mut line = format!(
+            "{} {}",
+            recording::Time(e.time_sec * TIME_UNITS_PER_SEC),
+            e.kind.as_str()
+        );
+        if let Some(ref u) = e.username {
+            line.push_str(&format!(" user={:?}", u));
+        }
+        if let Some(a) = e.peer_addr {
+            line.push_str(&format!(" addr={}", a));
+        }
+        if let Some(c) = e.camera_uuid {
+            line.push_str(&format!(" camera={}", c));
+        }
+        if let Some(ref r) = e.time_90k {
+            line.push_str(&format!(" range={}-{}", r.start, r.end));
+        }
+        if let Some(ref d) = e.detail {
+            line.push_str(&format!(" detail={:?}", d));
+        }
+        println!("{}", line);
+    }
+    Ok(0)
+}
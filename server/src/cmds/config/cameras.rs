@@ -14,6 +14,25 @@ use std::str::FromStr;
 use std::sync::Arc;
 use url::Url;
 
+const SEC_PER_DAY: f64 = 86_400.;
+
+/// Parses a retention age in (possibly fractional) days, as entered in `edit_camera_dialog`.
+/// Returns `None` if empty or invalid.
+fn parse_age_days(s: &str) -> Option<i64> {
+    f64::from_str(s.trim())
+        .ok()
+        .filter(|&d| d > 0.)
+        .map(|d| (d * SEC_PER_DAY).round() as i64)
+}
+
+/// Formats a retention age for display in `edit_camera_dialog`.
+fn encode_age_days(sec: Option<i64>) -> String {
+    match sec {
+        None => String::new(),
+        Some(s) => (s as f64 / SEC_PER_DAY).to_string(),
+    }
+}
+
 /// Builds a `CameraChange` from an active `edit_camera_dialog`.
 fn get_change(siv: &mut Cursive) -> db::CameraChange {
     // Note: these find_name calls are separate statements, which seems to be important:
@@ -79,16 +98,46 @@ fn get_change(siv: &mut Cursive) -> db::CameraChange {
                 .as_str(),
         )
         .unwrap_or(0);
+        let max_age = parse_age_days(
+            siv.find_name::<views::EditView>(&format!("{}_retain_max_age", t.as_str()))
+                .unwrap()
+                .get_content()
+                .as_str(),
+        );
+        let min_age = parse_age_days(
+            siv.find_name::<views::EditView>(&format!("{}_retain_min_age", t.as_str()))
+                .unwrap()
+                .get_content()
+                .as_str(),
+        );
         let d = *siv
             .find_name::<views::SelectView<Option<i32>>>(&format!("{}_sample_file_dir", t.as_str()))
             .unwrap()
             .selection()
             .unwrap();
+        let archive_d = *siv
+            .find_name::<views::SelectView<Option<i32>>>(&format!(
+                "{}_archive_sample_file_dir",
+                t.as_str()
+            ))
+            .unwrap()
+            .selection()
+            .unwrap();
+        let archive_age = parse_age_days(
+            siv.find_name::<views::EditView>(&format!("{}_archive_age", t.as_str()))
+                .unwrap()
+                .get_content()
+                .as_str(),
+        );
         c.streams[t.index()] = db::StreamChange {
             rtsp_url: u,
             sample_file_dir_id: d,
             record: r,
             flush_if_sec: f,
+            retain_max_age_sec: max_age,
+            retain_min_age_sec: min_age,
+            archive_sample_file_dir_id: archive_d,
+            archive_age_sec: archive_age,
         };
     }
     c
@@ -381,6 +430,25 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                 "flush_if_sec",
                 views::EditView::new().with_name(format!("{}_flush_if_sec", type_.as_str())),
             )
+            .child(
+                "retain max age (days)",
+                views::EditView::new().with_name(format!("{}_retain_max_age", type_.as_str())),
+            )
+            .child(
+                "retain min age (days)",
+                views::EditView::new().with_name(format!("{}_retain_min_age", type_.as_str())),
+            )
+            .child(
+                "archive dir",
+                views::SelectView::<Option<i32>>::new()
+                    .with_all(dirs.iter().cloned())
+                    .popup()
+                    .with_name(format!("{}_archive_sample_file_dir", type_.as_str())),
+            )
+            .child(
+                "archive age (days)",
+                views::EditView::new().with_name(format!("{}_archive_age", type_.as_str())),
+            )
             .child(
                 "usage/capacity",
                 views::TextView::new("").with_name(format!("{}_usage_cap", type_.as_str())),
@@ -407,6 +475,7 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
 
             // Find the index into dirs of the stored sample file dir.
             let mut selected_dir = 0;
+            let mut selected_archive_dir = 0;
             if let Some(s) = sid.map(|sid| l.streams_by_id().get(&sid).unwrap()) {
                 if let Some(id) = s.sample_file_dir_id {
                     for (i, &(_, d_id)) in dirs.iter().skip(1).enumerate() {
@@ -416,6 +485,12 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                         }
                     }
                 }
+                if let Some(id) = s.archive_sample_file_dir_id {
+                    selected_archive_dir = dirs
+                        .iter()
+                        .position(|&(_, d_id)| d_id == Some(id))
+                        .unwrap_or(0);
+                }
                 bytes += s.sample_file_bytes;
                 let u = if s.retain_bytes == 0 {
                     "0 / 0 (0.0%)".to_owned()
@@ -443,11 +518,27 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                     &format!("{}_flush_if_sec", t.as_str()),
                     |v: &mut views::EditView| v.set_content(s.flush_if_sec.to_string()),
                 );
+                dialog.call_on_name(
+                    &format!("{}_retain_max_age", t.as_str()),
+                    |v: &mut views::EditView| v.set_content(encode_age_days(s.retain_max_age_sec)),
+                );
+                dialog.call_on_name(
+                    &format!("{}_retain_min_age", t.as_str()),
+                    |v: &mut views::EditView| v.set_content(encode_age_days(s.retain_min_age_sec)),
+                );
+                dialog.call_on_name(
+                    &format!("{}_archive_age", t.as_str()),
+                    |v: &mut views::EditView| v.set_content(encode_age_days(s.archive_age_sec)),
+                );
             }
             dialog.call_on_name(
                 &format!("{}_sample_file_dir", t.as_str()),
                 |v: &mut views::SelectView<Option<i32>>| v.set_selection(selected_dir),
             );
+            dialog.call_on_name(
+                &format!("{}_archive_sample_file_dir", t.as_str()),
+                |v: &mut views::SelectView<Option<i32>>| v.set_selection(selected_archive_dir),
+            );
         }
         let name = camera.short_name.clone();
         for &(view_id, content) in &[
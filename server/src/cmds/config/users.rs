@@ -46,10 +46,18 @@ fn get_change(
             "perm_update_signals",
             &mut change.permissions.update_signals,
         ),
+        ("perm_manage_holds", &mut change.permissions.manage_holds),
+        ("perm_update_config", &mut change.permissions.update_config),
+        ("perm_admin_users", &mut change.permissions.admin_users),
     ] {
         **b = siv.find_name::<views::Checkbox>(id).unwrap().is_checked();
         info!("{}: {}", id, **b);
     }
+    if let Some(c) = siv.find_name::<views::Checkbox>("clear_totp") {
+        if c.is_checked() {
+            change.clear_totp();
+        }
+    }
     change
 }
 
@@ -121,7 +129,7 @@ fn select_set(siv: &mut Cursive) {
 /// Adds or updates a user.
 /// (The former if `item` is None; the latter otherwise.)
 fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>) {
-    let (username, id_str, has_password, permissions);
+    let (username, id_str, has_password, totp_enrolled, permissions);
     let mut pw_group = views::RadioGroup::new();
     {
         let l = db.lock();
@@ -131,6 +139,7 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
             .map(|id| id.to_string())
             .unwrap_or_else(|| "<new>".to_string());
         has_password = u.map(|u| u.has_password()).unwrap_or(false);
+        totp_enrolled = u.map(|u| u.totp_enrolled()).unwrap_or(false);
         permissions = u.map(|u| u.permissions.clone()).unwrap_or_default();
     }
     let top_list = views::ListView::new()
@@ -182,6 +191,12 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
                 ),
         );
     }
+    if totp_enrolled {
+        layout.add_child(views::ListView::new().child(
+            "clear TOTP enrollment",
+            views::Checkbox::new().with_name("clear_totp"),
+        ));
+    }
 
     layout.add_child(views::DummyView);
     layout.add_child(views::TextView::new("permissions"));
@@ -190,6 +205,9 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
         ("view_video", permissions.view_video),
         ("read_camera_configs", permissions.read_camera_configs),
         ("update_signals", permissions.update_signals),
+        ("manage_holds", permissions.manage_holds),
+        ("update_config", permissions.update_config),
+        ("admin_users", permissions.admin_users),
     ] {
         let mut checkbox = views::Checkbox::new();
         checkbox.set_checked(*b);
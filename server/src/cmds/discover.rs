@@ -0,0 +1,161 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Subcommand to find ONVIF cameras on the local network and optionally add one.
+
+use crate::onvif;
+use base::clock;
+use failure::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Args {
+    /// Directory holding the SQLite3 index database.
+    #[structopt(
+        long,
+        default_value = "/var/lib/moonfire-nvr/db",
+        value_name = "path",
+        parse(from_os_str)
+    )]
+    db_dir: PathBuf,
+
+    /// How long to wait for cameras to respond.
+    #[structopt(long, default_value = "3", value_name = "secs")]
+    timeout_sec: u64,
+
+    /// ONVIF username, used to list each camera's profiles or to add a camera.
+    #[structopt(long)]
+    username: Option<String>,
+
+    /// ONVIF password, used to list each camera's profiles or to add a camera.
+    #[structopt(long)]
+    password: Option<String>,
+
+    /// Rather than probing the network, adds the camera at this ONVIF host (`host` or
+    /// `host:port`, as listed by a previous run).
+    ///
+    /// The camera's main and sub streams use the RTSP URLs of the chosen profiles. They aren't
+    /// recorded until configured with "moonfire-nvr config".
+    #[structopt(
+        long,
+        value_name = "host",
+        requires_all(&["short_name", "username", "password"])
+    )]
+    add: Option<String>,
+
+    /// Short name of the camera to add.
+    #[structopt(long)]
+    short_name: Option<String>,
+
+    /// Description of the camera to add.
+    #[structopt(long, default_value = "")]
+    description: String,
+
+    /// Token of the profile to use for the main stream, rather than the highest-resolution one.
+    #[structopt(long, value_name = "token")]
+    main_profile: Option<String>,
+
+    /// Token of the profile to use for the sub stream, rather than the lowest-resolution one.
+    #[structopt(long, value_name = "token")]
+    sub_profile: Option<String>,
+}
+
+pub fn run(args: &Args) -> Result<i32, Error> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    match args.add {
+        Some(ref host) => rt.block_on(add(args, host)),
+        None => rt.block_on(list(args)),
+    }
+}
+
+async fn list(args: &Args) -> Result<i32, Error> {
+    let devices = onvif::discover(Duration::from_secs(args.timeout_sec)).await?;
+    if devices.is_empty() {
+        println!("No ONVIF cameras found.");
+    }
+    for d in &devices {
+        let host = d.onvif_host();
+        println!(
+            "{} at {}: {} ({})",
+            &d.endpoint,
+            host.as_deref().unwrap_or("(no HTTP address)"),
+            d.scope("name").as_deref().unwrap_or("(unnamed)"),
+            d.scope("hardware").as_deref().unwrap_or("unknown hardware"),
+        );
+        let (host, username, password) = match (&host, &args.username, &args.password) {
+            (Some(h), Some(u), Some(p)) => (h, u, p),
+            _ => continue,
+        };
+        let profiles = match onvif::get_profiles(host, username, password).await {
+            Ok(p) => p,
+            Err(e) => {
+                println!("    unable to get profiles: {}", e);
+                continue;
+            }
+        };
+        let (main, sub) = onvif::choose_profiles(&profiles);
+        for p in &profiles {
+            let default = if main == Some(p) {
+                " [main]"
+            } else if sub == Some(p) {
+                " [sub]"
+            } else {
+                ""
+            };
+            let resolution = match p.resolution {
+                Some((w, h)) => format!("{}x{}", w, h),
+                None => "no video".to_owned(),
+            };
+            println!(
+                "    {} ({}): {} {} {}{}",
+                &p.token,
+                &p.name,
+                p.encoding.as_deref().unwrap_or("?"),
+                resolution,
+                &p.rtsp_url,
+                default
+            );
+        }
+    }
+    Ok(0)
+}
+
+async fn add(args: &Args, host: &str) -> Result<i32, Error> {
+    // structopt ensures these are present with --add.
+    let short_name = args.short_name.clone().unwrap();
+    let username = args.username.clone().unwrap();
+    let password = args.password.clone().unwrap();
+
+    let profiles = onvif::get_profiles(host, &username, &password).await?;
+    let (main, sub) = onvif::select_profiles(
+        &profiles,
+        args.main_profile.as_deref(),
+        args.sub_profile.as_deref(),
+    )?;
+    let change = onvif::camera_change(
+        short_name,
+        args.description.clone(),
+        host.to_owned(),
+        username,
+        password,
+        main,
+        sub,
+    );
+    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
+    let db = db::Database::new(clock::RealClocks {}, conn, true)?;
+    let mut l = db.lock();
+    let id = l.add_camera(change)?;
+    let c = &l.cameras_by_id()[&id];
+    println!("Added camera {} ({}).", &c.short_name, c.uuid);
+    println!("    main: {}", &main.rtsp_url);
+    if let Some(s) = sub {
+        println!("    sub: {}", &s.rtsp_url);
+    }
+    Ok(0)
+}
@@ -0,0 +1,101 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+use crate::export::{Export, Progress};
+use base::clock;
+use db::recording;
+use failure::{bail, format_err, Error};
+use fnv::FnvHashMap;
+use log::info;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Args {
+    /// Directory holding the SQLite3 index database.
+    #[structopt(
+        long,
+        default_value = "/var/lib/moonfire-nvr/db",
+        value_name = "path",
+        parse(from_os_str)
+    )]
+    db_dir: PathBuf,
+
+    /// Start of the time range to export, as an integer or RFC-3339-like string
+    /// (see `moonfire-nvr ts --help`).
+    #[structopt(long)]
+    start: String,
+
+    /// End of the time range to export, as with `--start`.
+    #[structopt(long)]
+    end: String,
+
+    /// Stream(s) to export, as `<camera>/<main|sub>`. The camera may be given by its short name
+    /// or uuid.
+    #[structopt(long = "stream", value_name = "camera/type", required = true)]
+    streams: Vec<String>,
+
+    /// Path of the tar archive to write.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    output: PathBuf,
+}
+
+fn parse_stream(l: &db::LockedDatabase, spec: &str) -> Result<i32, Error> {
+    let slash = spec
+        .rfind('/')
+        .ok_or_else(|| format_err!("stream {:?} should be <camera>/<main|sub>", spec))?;
+    let (camera, type_) = (&spec[..slash], &spec[slash + 1..]);
+    let type_ = db::StreamType::parse(type_)
+        .ok_or_else(|| format_err!("stream {:?} has unknown type {:?}", spec, type_))?;
+    let camera = l
+        .cameras_by_id()
+        .values()
+        .find(|c| c.short_name == camera || c.uuid.to_string() == camera)
+        .ok_or_else(|| format_err!("no such camera {:?}", camera))?;
+    camera.streams[type_.index()].ok_or_else(|| format_err!("no such stream {:?}", spec))
+}
+
+pub fn run(args: &Args) -> Result<i32, Error> {
+    let start = recording::Time::parse(&args.start)?;
+    let end = recording::Time::parse(&args.end)?;
+    if start >= end {
+        bail!("--start must be before --end");
+    }
+    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadOnly)?;
+    let db = Arc::new(db::Database::new(clock::RealClocks {}, conn, false)?);
+    let (stream_ids, dirs_by_stream_id) = {
+        let mut l = db.lock();
+        let stream_ids = args
+            .streams
+            .iter()
+            .map(|s| parse_stream(&l, s))
+            .collect::<Result<Vec<_>, _>>()?;
+        let dirs_to_open: Vec<_> = stream_ids
+            .iter()
+            .flat_map(|id| {
+                let s = l.streams_by_id().get(id).unwrap();
+                s.sample_file_dir_id
+                    .into_iter()
+                    .chain(s.archive_sample_file_dir_id)
+            })
+            .collect();
+        l.open_sample_file_dirs(&dirs_to_open)?;
+        let mut d = FnvHashMap::with_capacity_and_hasher(stream_ids.len(), Default::default());
+        for &id in &stream_ids {
+            let dir_id = match l.streams_by_id().get(&id).unwrap().sample_file_dir_id {
+                Some(d) => d,
+                None => continue,
+            };
+            d.insert(id, l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?);
+        }
+        (stream_ids, Arc::new(d))
+    };
+    let export = Export::plan(&db, &dirs_by_stream_id, &stream_ids, start..end)?;
+    let f = std::fs::File::create(&args.output)
+        .map_err(|e| format_err!("unable to create {}: {}", args.output.display(), e))?;
+    export.write(&mut std::io::BufWriter::new(f), &Progress::default())?;
+    info!("Wrote {}.", args.output.display());
+    Ok(0)
+}
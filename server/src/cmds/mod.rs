@@ -8,8 +8,11 @@ use log::info;
 use nix::fcntl::FlockArg;
 use std::path::Path;
 
+pub mod audit;
 pub mod check;
 pub mod config;
+pub mod discover;
+pub mod export;
 pub mod init;
 pub mod login;
 pub mod run;
@@ -2,20 +2,21 @@
 // Copyright (C) 2020 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
-use crate::streamer;
+use crate::motion;
+use crate::mqtt;
+use crate::rtsp_server;
+use crate::supervisor;
+use crate::thumbnails;
 use crate::web;
+use crate::webhook;
 use crate::onvif;
 use base::clock;
-use db::{dir, writer};
-use failure::{bail, Error};
-use fnv::FnvHashMap;
+use failure::{bail, format_err, Error};
 use futures::future::FutureExt;
 use hyper::service::{make_service_fn, service_fn};
-use log::{info, warn};
+use log::info;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread;
 use structopt::StructOpt;
 use tokio::signal::unix::{signal, SignalKind};
 
@@ -44,10 +45,66 @@ pub struct Args {
     )]
     ui_dir: std::path::PathBuf,
 
+    /// Directory in which to write exports started through the web API.
+    #[structopt(
+        long,
+        default_value = "/var/lib/moonfire-nvr/exports",
+        value_name = "path",
+        parse(from_os_str)
+    )]
+    export_dir: std::path::PathBuf,
+
     /// Bind address for unencrypted HTTP server.
     #[structopt(long, default_value = "0.0.0.0:8080", parse(try_from_str))]
     http_addr: std::net::SocketAddr,
 
+    /// Bind address for the RTSP server, which re-streams the cameras' live and recorded video
+    /// (e.g. 0.0.0.0:8554). If absent, the RTSP server is disabled.
+    #[structopt(long, parse(try_from_str))]
+    rtsp_addr: Option<std::net::SocketAddr>,
+
+    /// Address of an MQTT broker (e.g. localhost:1883) to which to publish signal changes and
+    /// stream health. If absent, MQTT is disabled.
+    #[structopt(long)]
+    mqtt_addr: Option<String>,
+
+    /// Username to present to the MQTT broker.
+    #[structopt(long, requires("mqtt_password_file"))]
+    mqtt_username: Option<String>,
+
+    /// File holding the password to present to the MQTT broker.
+    #[structopt(
+        long,
+        value_name = "path",
+        requires("mqtt_username"),
+        parse(from_os_str)
+    )]
+    mqtt_password_file: Option<PathBuf>,
+
+    /// PEM file of CA certificates with which to verify the MQTT broker. If present, the
+    /// connection uses TLS.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    mqtt_tls_ca: Option<PathBuf>,
+
+    /// Accept signal commands on the MQTT topics `<prefix>/signals/<id>/set`. Anyone able to
+    /// publish to them can set signals, so use this only with an authenticated broker.
+    #[structopt(long)]
+    mqtt_commands: bool,
+
+    /// Client id to send to the MQTT broker.
+    #[structopt(long, default_value = "moonfire-nvr")]
+    mqtt_client_id: String,
+
+    /// Prefix of all MQTT topics published and subscribed to.
+    #[structopt(long, default_value = "moonfire-nvr")]
+    mqtt_topic_prefix: String,
+
+    /// Base URL of this server as reachable by cameras (e.g. http://192.168.1.90:8080/), to
+    /// which cameras with ONVIF event mappings send their events. If absent, only cameras in
+    /// PullPoint mode receive ONVIF events.
+    #[structopt(long, parse(try_from_str = url::Url::parse))]
+    onvif_notify_url: Option<url::Url>,
+
     /// Open the database in read-only mode and disables recording.
     ///
     /// Note this is incompatible with authentication, so you'll likely want to specify
@@ -74,7 +131,8 @@ pub struct Args {
     /// RTSP library to use for fetching the cameras' video stream.
     /// Moonfire NVR is in the process of switching from `ffmpeg` (used since
     /// the beginning of the project) to `retina` (a pure-Rust RTSP library
-    /// developed by Moonfire NVR's author).
+    /// developed by Moonfire NVR's author). H.265 streams currently require
+    /// `ffmpeg`.
     #[structopt(long, default_value = "retina", parse(try_from_str))]
     rtsp_library: crate::stream::RtspLibrary,
 }
@@ -157,12 +215,6 @@ fn resolve_zone() -> Result<String, Error> {
     }
 }
 
-struct Syncer {
-    dir: Arc<dir::SampleFileDir>,
-    channel: writer::SyncerChannel<::std::fs::File>,
-    join: thread::JoinHandle<()>,
-}
-
 pub fn run(args: &Args) -> Result<i32, Error> {
     let mut builder = tokio::runtime::Builder::new_multi_thread();
     builder.enable_all();
@@ -190,7 +242,11 @@ async fn async_run(args: &Args) -> Result<i32, Error> {
         let dirs_to_open: Vec<_> = l
             .streams_by_id()
             .values()
-            .filter_map(|s| s.sample_file_dir_id)
+            .flat_map(|s| {
+                s.sample_file_dir_id
+                    .into_iter()
+                    .chain(s.archive_sample_file_dir_id)
+            })
             .collect();
         l.open_sample_file_dirs(&dirs_to_open)?;
     }
@@ -198,100 +254,70 @@ async fn async_run(args: &Args) -> Result<i32, Error> {
 
     let time_zone_name = resolve_zone()?;
     info!("Resolved timezone: {}", &time_zone_name);
-    let svc = Arc::new(web::Service::new(web::Config {
-        db: db.clone(),
-        ui_dir: Some(&args.ui_dir),
-        allow_unauthenticated_permissions: args.allow_unauthenticated_permissions.clone(),
-        trust_forward_hdrs: args.trust_forward_hdrs,
-        time_zone_name,
-    })?);
-
-    // Start a streamer for each stream.
-    let shutdown_streamers = Arc::new(AtomicBool::new(false));
-    let mut streamers = Vec::new();
-    let syncers = if !args.read_only {
-        let l = db.lock();
-        let mut dirs = FnvHashMap::with_capacity_and_hasher(
-            l.sample_file_dirs_by_id().len(),
-            Default::default(),
-        );
-        let streams = l.streams_by_id().len();
-        let env = streamer::Environment {
-            db: &db,
-            opener: args.rtsp_library.opener(),
-            shutdown: &shutdown_streamers,
-        };
-
-        // Get the directories that need syncers.
-        for stream in l.streams_by_id().values() {
-            if let (Some(id), true) = (stream.sample_file_dir_id, stream.record) {
-                dirs.entry(id).or_insert_with(|| {
-                    let d = l.sample_file_dirs_by_id().get(&id).unwrap();
-                    info!("Starting syncer for path {}", d.path);
-                    d.get().unwrap()
-                });
-            }
-        }
-
-        // Then, with the lock dropped, create syncers.
-        drop(l);
-        let mut syncers = FnvHashMap::with_capacity_and_hasher(dirs.len(), Default::default());
-        for (id, dir) in dirs.drain() {
-            let (channel, join) = writer::start_syncer(db.clone(), id)?;
-            syncers.insert(id, Syncer { dir, channel, join });
-        }
 
-        // Then start up streams.
-        let handle = tokio::runtime::Handle::current();
-        let l = db.lock();
-        for (i, (id, stream)) in l.streams_by_id().iter().enumerate() {
-            if !stream.record {
-                continue;
-            }
-            let camera = l.cameras_by_id().get(&stream.camera_id).unwrap();
-            let sample_file_dir_id = match stream.sample_file_dir_id {
-                Some(s) => s,
-                None => {
-                    warn!(
-                        "Can't record stream {} ({}/{}) because it has no sample file dir",
-                        id,
-                        camera.short_name,
-                        stream.type_.as_str()
-                    );
-                    continue;
+    let rtsp_hub = args
+        .rtsp_addr
+        .map(|_| Arc::new(rtsp_server::Hub::default()));
+
+    // Start motion detection, which is fed by the streamers, then the streamers themselves.
+    let supervisor = if !args.read_only {
+        let motion = motion::Workers::start(&db)?;
+        let s = supervisor::Supervisor::start(
+            db.clone(),
+            args.rtsp_library.opener(),
+            rtsp_hub.clone(),
+            Some(motion),
+        )?;
+
+        // Generate thumbnails of recordings in the background as they're committed.
+        thumbnails::start(db.clone())?;
+
+        // Deliver webhook events, including any left undelivered at the last shutdown.
+        webhook::start(db.clone())?;
+
+        if let Some(addr) = args.mqtt_addr.clone() {
+            let credentials = match (&args.mqtt_username, &args.mqtt_password_file) {
+                (Some(u), Some(p)) => {
+                    let password = std::fs::read_to_string(p).map_err(|e| {
+                        format_err!("unable to read MQTT password file {}: {}", p.display(), e)
+                    })?;
+                    Some((u.clone(), password.trim_end_matches('\n').to_owned()))
                 }
+                _ => None,
             };
-            let rotate_offset_sec = streamer::ROTATE_INTERVAL_SEC * i as i64 / streams as i64;
-            let syncer = syncers.get(&sample_file_dir_id).unwrap();
-            let mut streamer = streamer::Streamer::new(
-                &env,
-                syncer.dir.clone(),
-                syncer.channel.clone(),
-                *id,
-                camera,
-                stream,
-                rotate_offset_sec,
-                streamer::ROTATE_INTERVAL_SEC,
+            let tls_ca = match args.mqtt_tls_ca {
+                None => None,
+                Some(ref p) => Some(std::fs::read(p).map_err(|e| {
+                    format_err!("unable to read MQTT CA file {}: {}", p.display(), e)
+                })?),
+            };
+            mqtt::start(
+                db.clone(),
+                mqtt::Config {
+                    addr,
+                    client_id: args.mqtt_client_id.clone(),
+                    topic_prefix: args.mqtt_topic_prefix.clone(),
+                    credentials,
+                    tls_ca,
+                    accept_commands: args.mqtt_commands,
+                },
             )?;
-            info!("Starting streamer for {}", streamer.short_name());
-            let name = format!("s-{}", streamer.short_name());
-            let handle = handle.clone();
-            streamers.push(
-                thread::Builder::new()
-                    .name(name)
-                    .spawn(move || {
-                        let _enter = handle.enter();
-                        streamer.run();
-                    })
-                    .expect("can't create thread"),
-            );
         }
-        drop(l);
-        Some(syncers)
+        Some(s)
     } else {
         None
     };
 
+    let svc = Arc::new(web::Service::new(web::Config {
+        db: db.clone(),
+        ui_dir: Some(&args.ui_dir),
+        export_dir: &args.export_dir,
+        allow_unauthenticated_permissions: args.allow_unauthenticated_permissions.clone(),
+        trust_forward_hdrs: args.trust_forward_hdrs,
+        time_zone_name,
+        supervisor: supervisor.clone(),
+    })?);
+
     // Start the web interface.
     let make_svc = make_service_fn(move |_conn| {
         futures::future::ok::<_, std::convert::Infallible>(service_fn({
@@ -313,44 +339,27 @@ async fn async_run(args: &Args) -> Result<i32, Error> {
 
     info!("Ready to serve HTTP requests");
 
-    // subscribe for onvif events
-    {
-        let l = db.lock();
-        for camera in l.cameras_by_id().values() {
-            if let (Some(user), Some(passwd)) = (&camera.username, &camera.password) {
-                let uri = "http://".to_owned() + &camera.onvif_host + ":8000";
-                println!("Trying to subscribe to onvif host: '{}'", uri);
-                match url::Url::parse(uri.as_str()) {
-                    Ok(base_uri) => {
-                        let client = onvif::OnvifClient::new(base_uri, user.as_str(), passwd.as_str()).await;
-                        println!("got client: {:?}", client.is_ok());
-
-                    }
-                    Err(err) => println!("{}", err),
-                }
-            }
-        }
-        drop(l);
+    if let (Some(addr), Some(hub)) = (args.rtsp_addr, rtsp_hub) {
+        let rtsp = Arc::new(rtsp_server::Server::new(rtsp_server::Config {
+            db: db.clone(),
+            hub,
+            allow_unauthenticated_permissions: args.allow_unauthenticated_permissions.clone(),
+        }));
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        tokio::spawn(rtsp.serve(listener));
+        info!("Ready to serve RTSP requests on {}", addr);
     }
 
+    // Subscribe to cameras' ONVIF events now that the HTTP server is ready to receive them.
+    if !args.read_only {
+        onvif::start(db.clone(), args.onvif_notify_url.clone())?;
+    }
 
     shutdown.await;
     shutdown_tx.send(()).unwrap();
 
-    info!("Shutting down streamers.");
-    shutdown_streamers.store(true, Ordering::SeqCst);
-    for streamer in streamers.drain(..) {
-        streamer.join().unwrap();
-    }
-
-    if let Some(mut ss) = syncers {
-        // The syncers shut down when all channels to them have been dropped.
-        // The database maintains one; and `ss` holds one. Drop both.
-        db.lock().clear_on_flush();
-        for (_, s) in ss.drain() {
-            drop(s.channel);
-            s.join.join().unwrap();
-        }
+    if let Some(s) = supervisor {
+        s.shutdown();
     }
 
     db.lock().clear_watches();
@@ -0,0 +1,535 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Exports of a time range of one or more streams, for handing video to a third party.
+//!
+//! An export is an uncompressed tar archive holding:
+//!
+//! *   a `.mp4` file with a timestamp subtitle track for each run of recordings of each
+//!     requested stream within the time range.
+//! *   `manifest.json`, describing the time range and each `.mp4` file: its blake3 hash and
+//!     the recordings it was built from, including their sample files' blake3 hashes as noted
+//!     in the `recording_integrity` table at recording time.
+//! *   `manifest.json.sig`, an Ed25519 signature of `manifest.json` made with the database's
+//!     export signing key, along with the public key.
+//!
+//! Exports can be written by `moonfire-nvr export` or started as background jobs through the
+//! web API (see `Jobs`).
+
+use crate::mp4;
+use db::dir::SampleFileDir;
+use db::recording::{self, rescale};
+use failure::{bail, format_err, Error};
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::stream::StreamExt;
+use hyper::body::Buf;
+use log::{info, warn};
+use parking_lot::Mutex;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use std::cmp;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use uuid::Uuid;
+
+const TAR_BLOCK_SIZE: usize = 512;
+
+/// A planned export, ready to be written by `Export::write`.
+pub struct Export {
+    files: Vec<(ManifestFile, mp4::File)>,
+    manifest: Manifest,
+    key: Ed25519KeyPair,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    start_time_90k: i64,
+    end_time_90k: i64,
+    creation_time_90k: i64,
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestFile {
+    name: String,
+    camera_uuid: Uuid,
+    camera_short_name: String,
+    stream_type: &'static str,
+    bytes: u64,
+
+    /// The hex-encoded blake3 hash of the `.mp4` file, filled in as it's written.
+    blake3: String,
+    recordings: Vec<ManifestRecording>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestRecording {
+    stream_id: i32,
+    recording_id: i32,
+    start_time_90k: i64,
+    end_time_90k: i64,
+    sample_file_bytes: i32,
+
+    /// The hex-encoded (possibly truncated) blake3 hash of the sample file, if known.
+    sample_file_blake3: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Signature {
+    algorithm: &'static str,
+    public_key: String,
+    signature: String,
+}
+
+/// Progress of an export being written, in bytes of `.mp4` files.
+#[derive(Default)]
+pub struct Progress {
+    pub written: AtomicU64,
+    pub total: AtomicU64,
+}
+
+impl Export {
+    /// Plans an export of the given streams within `range`.
+    /// Fails if there are no committed recordings in the range.
+    pub fn plan(
+        db: &Arc<db::Database>,
+        dirs_by_stream_id: &Arc<FnvHashMap<i32, Arc<SampleFileDir>>>,
+        stream_ids: &[i32],
+        range: Range<recording::Time>,
+    ) -> Result<Self, Error> {
+        if range.start >= range.end {
+            bail!("export start time must be before end time");
+        }
+        let mut runs = Vec::new();
+        let key;
+        {
+            let l = db.lock();
+            key = l.export_signing_key()?;
+            for &stream_id in stream_ids {
+                let stream = l
+                    .streams_by_id()
+                    .get(&stream_id)
+                    .ok_or_else(|| format_err!("no such stream {}", stream_id))?;
+                let camera = l.cameras_by_id().get(&stream.camera_id).unwrap();
+                let mut rows = Vec::new();
+                l.list_recordings_by_time(stream_id, range.clone(), &mut |r| {
+                    if r.flags & db::RecordingFlags::Uncommitted as i32 == 0 {
+                        rows.push(r);
+                    }
+                    Ok(())
+                })?;
+                let mut cur: Option<(mp4::FileBuilder, ManifestFile, bool)> = None;
+                let mut prev_id = None;
+                for r in rows {
+                    let start = r.start;
+                    let wd = i64::from(r.wall_duration_90k);
+                    let wr = i32::try_from(cmp::max(0, (range.start - start).0)).unwrap()
+                        ..i32::try_from(cmp::min(wd, (range.end - start).0)).unwrap();
+                    if wr.start >= wr.end {
+                        continue;
+                    }
+                    let mr = rescale(wr.start, r.wall_duration_90k, r.media_duration_90k)
+                        ..rescale(wr.end, r.wall_duration_90k, r.media_duration_90k);
+
+                    // Start a new file at each gap in the recordings, including a new run.
+                    let contiguous = r.run_offset > 0 && prev_id == Some(r.id.recording() - 1);
+                    match cur {
+                        Some((_, _, trailing_zero)) if contiguous && !trailing_zero => {}
+                        _ => {
+                            if let Some(c) = cur.take() {
+                                runs.push((c.0, c.1));
+                            }
+                            let mut builder = mp4::FileBuilder::new(mp4::Type::Normal);
+                            builder.include_timestamp_subtitle_track(true)?;
+                            let tm = time::at(time::Timespec {
+                                sec: (start + recording::Duration(i64::from(wr.start)))
+                                    .unix_seconds(),
+                                nsec: 0,
+                            });
+                            let name = format!(
+                                "{}-{}-{}.mp4",
+                                tm.strftime("%Y%m%d%H%M%S").unwrap(),
+                                sanitize(&camera.short_name),
+                                stream.type_.as_str()
+                            );
+                            let file = ManifestFile {
+                                name,
+                                camera_uuid: camera.uuid,
+                                camera_short_name: camera.short_name.clone(),
+                                stream_type: stream.type_.as_str(),
+                                bytes: 0,
+                                blake3: String::new(),
+                                recordings: Vec::new(),
+                            };
+                            cur = Some((builder, file, false));
+                        }
+                    }
+                    let c = cur.as_mut().unwrap();
+                    c.1.recordings.push(ManifestRecording {
+                        stream_id,
+                        recording_id: r.id.recording(),
+                        start_time_90k: (start + recording::Duration(i64::from(wr.start))).0,
+                        end_time_90k: (start + recording::Duration(i64::from(wr.end))).0,
+                        sample_file_bytes: r.sample_file_bytes,
+                        sample_file_blake3: l
+                            .get_sample_file_blake3(r.id)?
+                            .map(|h| base::strutil::hex(&h)),
+                    });
+                    c.2 = r.flags & db::RecordingFlags::TrailingZero as i32 != 0;
+                    prev_id = Some(r.id.recording());
+                    c.0.append(&l, r, mr, true)?;
+                }
+                if let Some(c) = cur {
+                    runs.push((c.0, c.1));
+                }
+            }
+        }
+        if runs.is_empty() {
+            bail!("no recordings in the requested time range");
+        }
+
+        let mut names = FnvHashSet::default();
+        let mut files = Vec::with_capacity(runs.len());
+        for (builder, mut file) in runs {
+            // Two runs may start within the same second; keep the names unique.
+            if !names.insert(file.name.clone()) {
+                let base = file.name[..file.name.len() - ".mp4".len()].to_owned();
+                let mut i = 2;
+                loop {
+                    let name = format!("{}-{}.mp4", base, i);
+                    if names.insert(name.clone()) {
+                        file.name = name;
+                        break;
+                    }
+                    i += 1;
+                }
+            }
+            let mp4 = builder.build(db.clone(), dirs_by_stream_id.clone())?;
+            file.bytes = http_serve::Entity::len(&mp4);
+            files.push((file, mp4));
+        }
+        Ok(Export {
+            files,
+            manifest: Manifest {
+                start_time_90k: range.start.0,
+                end_time_90k: range.end.0,
+                creation_time_90k: recording::Time::new(db.clocks().realtime()).0,
+                files: Vec::new(),
+            },
+            key,
+        })
+    }
+
+    /// Returns the cameras whose video is included, each once.
+    pub fn camera_uuids(&self) -> Vec<Uuid> {
+        let mut uuids = Vec::new();
+        for (f, _) in &self.files {
+            if !uuids.contains(&f.camera_uuid) {
+                uuids.push(f.camera_uuid);
+            }
+        }
+        uuids
+    }
+
+    /// Returns the total length of the `.mp4` files to be written.
+    pub fn mp4_bytes(&self) -> u64 {
+        self.files.iter().map(|f| f.0.bytes).sum()
+    }
+
+    /// Writes the export as a tar archive to `out`, updating `progress` along the way.
+    /// This blocks on reading sample files; call it from a blocking context.
+    pub fn write(mut self, out: &mut dyn Write, progress: &Progress) -> Result<(), Error> {
+        progress.total.store(self.mp4_bytes(), Ordering::Relaxed);
+        let mtime = self.manifest.creation_time_90k / recording::TIME_UNITS_PER_SEC;
+        for (mut file, mp4) in self.files.drain(..) {
+            info!("Writing {} ({} bytes)", &file.name, file.bytes);
+            write_tar_header(out, &file.name, file.bytes, mtime)?;
+            let mut hasher = blake3::Hasher::new();
+            let mut stream =
+                std::pin::Pin::from(http_serve::Entity::get_range(&mp4, 0..file.bytes));
+            let mut written = 0;
+            while let Some(chunk) = futures::executor::block_on(stream.next()) {
+                let mut chunk = chunk.map_err(Error::from_boxed_compat)?;
+                while chunk.has_remaining() {
+                    let c = chunk.chunk();
+                    hasher.update(c);
+                    out.write_all(c)?;
+                    let len = c.len();
+                    written += len as u64;
+                    progress.written.fetch_add(len as u64, Ordering::Relaxed);
+                    chunk.advance(len);
+                }
+            }
+            if written != file.bytes {
+                bail!(
+                    "{}: wrote {} bytes; expected {}",
+                    &file.name,
+                    written,
+                    file.bytes
+                );
+            }
+            write_tar_padding(out, written)?;
+            file.blake3 = hasher.finalize().to_hex().to_string();
+            self.manifest.files.push(file);
+        }
+
+        let manifest = serde_json::to_vec_pretty(&self.manifest)?;
+        let sig = serde_json::to_vec_pretty(&Signature {
+            algorithm: "Ed25519",
+            public_key: base64::encode(self.key.public_key().as_ref()),
+            signature: base64::encode(self.key.sign(&manifest).as_ref()),
+        })?;
+        for (name, data) in &[("manifest.json", &manifest), ("manifest.json.sig", &sig)] {
+            write_tar_header(out, name, data.len() as u64, mtime)?;
+            out.write_all(data)?;
+            write_tar_padding(out, data.len() as u64)?;
+        }
+
+        // The archive ends with two zero-filled blocks.
+        out.write_all(&[0u8; 2 * TAR_BLOCK_SIZE])?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Returns a version of a camera's short name suitable for use in a file name.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Writes `v` as a NUL-terminated octal number filling `field`, or in the GNU base-256
+/// extension if it doesn't fit.
+fn write_tar_number(field: &mut [u8], v: u64) {
+    let digits = field.len() - 1;
+    if digits < 22 && v >= 1 << (3 * digits) {
+        field[0] = 0x80;
+        for (i, b) in field[1..].iter_mut().rev().enumerate() {
+            *b = v.checked_shr(8 * i as u32).unwrap_or(0) as u8;
+        }
+        return;
+    }
+    field[..digits].copy_from_slice(format!("{:0w$o}", v, w = digits).as_bytes());
+    field[digits] = 0;
+}
+
+/// Writes a ustar header for a regular file.
+fn write_tar_header(out: &mut dyn Write, name: &str, len: u64, mtime: i64) -> Result<(), Error> {
+    let mut h = [0u8; TAR_BLOCK_SIZE];
+    if name.len() > 100 {
+        bail!("file name {:?} is too long for a tar archive", name);
+    }
+    h[..name.len()].copy_from_slice(name.as_bytes());
+    write_tar_number(&mut h[100..108], 0o644); // mode
+    write_tar_number(&mut h[108..116], 0); // uid
+    write_tar_number(&mut h[116..124], 0); // gid
+    write_tar_number(&mut h[124..136], len);
+    write_tar_number(&mut h[136..148], u64::try_from(mtime).unwrap_or(0));
+    h[148..156].copy_from_slice(b"        "); // checksum, as spaces while summing.
+    h[156] = b'0'; // regular file
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    let sum: u32 = h.iter().map(|&b| u32::from(b)).sum();
+    h[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    out.write_all(&h[..])?;
+    Ok(())
+}
+
+/// Pads a file of the given length to a whole number of tar blocks.
+fn write_tar_padding(out: &mut dyn Write, len: u64) -> Result<(), io::Error> {
+    let rem = (len % TAR_BLOCK_SIZE as u64) as usize;
+    if rem != 0 {
+        out.write_all(&[0u8; TAR_BLOCK_SIZE][rem..])?;
+    }
+    Ok(())
+}
+
+/// The state of an export job.
+#[derive(Clone)]
+pub enum Status {
+    Running,
+    Done,
+    Failed(String),
+}
+
+/// An export started through the web API.
+pub struct Job {
+    pub id: Uuid,
+    pub creator: Option<String>,
+
+    /// The id of the user who started the export, or `None` if unauthenticated.
+    /// Only this user may see or delete the job.
+    pub creator_id: Option<i32>,
+
+    /// The cameras whose video is included; the caller must still have permission to view each.
+    pub camera_uuids: Vec<Uuid>,
+    pub creation_time: recording::Time,
+    pub progress: Progress,
+    status: Mutex<Status>,
+}
+
+impl Job {
+    pub fn status(&self) -> Status {
+        self.status.lock().clone()
+    }
+}
+
+/// Export jobs started through the web API, written to `<dir>/<id>.tar`.
+///
+/// Jobs are kept in memory only; a job's archive remains until the job is deleted.
+pub struct Jobs {
+    dir: PathBuf,
+    jobs: Mutex<FnvHashMap<Uuid, Arc<Job>>>,
+}
+
+impl Jobs {
+    pub fn new(dir: PathBuf) -> Self {
+        Jobs {
+            dir,
+            jobs: Mutex::new(FnvHashMap::default()),
+        }
+    }
+
+    /// Returns the path of the given job's archive, which is complete once its status is `Done`.
+    pub fn path(&self, id: Uuid) -> PathBuf {
+        self.dir.join(format!("{}.tar", id))
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Arc<Job>> {
+        self.jobs.lock().get(&id).cloned()
+    }
+
+    /// Starts writing `export` in the background. Must be called from within a tokio runtime.
+    pub fn start(
+        &self,
+        export: Export,
+        creator: Option<String>,
+        creator_id: Option<i32>,
+        creation_time: recording::Time,
+    ) -> Result<Arc<Job>, Error> {
+        std::fs::create_dir_all(&self.dir).map_err(|e| {
+            format_err!("unable to create export dir {}: {}", self.dir.display(), e)
+        })?;
+        let job = Arc::new(Job {
+            id: Uuid::new_v4(),
+            creator,
+            creator_id,
+            camera_uuids: export.camera_uuids(),
+            creation_time,
+            progress: Progress::default(),
+            status: Mutex::new(Status::Running),
+        });
+        self.jobs.lock().insert(job.id, job.clone());
+        let path = self.path(job.id);
+        let tmp_path = self.dir.join(format!("{}.tar.tmp", job.id));
+        let j = job.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = std::fs::File::create(&tmp_path)
+                .map_err(Error::from)
+                .and_then(|f| {
+                    let mut out = io::BufWriter::new(f);
+                    export.write(&mut out, &j.progress)?;
+                    out.into_inner()
+                        .map_err(|e| format_err!("{}", e))?
+                        .sync_all()?;
+                    std::fs::rename(&tmp_path, &path)?;
+                    Ok(())
+                });
+            *j.status.lock() = match result {
+                Ok(()) => {
+                    info!("export {} done", j.id);
+                    Status::Done
+                }
+                Err(e) => {
+                    warn!("export {} failed: {}", j.id, e);
+                    let _ = std::fs::remove_file(&tmp_path);
+                    Status::Failed(e.to_string())
+                }
+            };
+        });
+        Ok(job)
+    }
+
+    /// Removes the given job and its archive, returning false if there's no such job.
+    /// Running jobs can't be removed.
+    pub fn remove(&self, id: Uuid) -> Result<bool, Error> {
+        let mut l = self.jobs.lock();
+        match l.get(&id) {
+            None => return Ok(false),
+            Some(j) if matches!(*j.status.lock(), Status::Running) => {
+                bail!("export {} is still running", id)
+            }
+            Some(_) => {}
+        }
+        l.remove(&id);
+        match std::fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tar_header() {
+        let mut h = Vec::new();
+        write_tar_header(&mut h, "foo.mp4", 1234, 1_600_000_000).unwrap();
+        assert_eq!(h.len(), TAR_BLOCK_SIZE);
+        assert_eq!(&h[..8], b"foo.mp4\0");
+        assert_eq!(&h[124..136], b"00000002322\0");
+        assert_eq!(&h[257..265], b"ustar\x0000");
+        let sum: u32 = h
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    u32::from(b)
+                }
+            })
+            .sum();
+        assert_eq!(&h[148..156], format!("{:06o}\0 ", sum).as_bytes());
+    }
+
+    #[test]
+    fn tar_big_number() {
+        let mut f = [0u8; 12];
+        write_tar_number(&mut f, 1 << 33);
+        assert_eq!(f, [0x80, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn tar_padding() {
+        let mut v = Vec::new();
+        write_tar_padding(&mut v, 1024).unwrap();
+        assert!(v.is_empty());
+        write_tar_padding(&mut v, 1000).unwrap();
+        assert_eq!(v.len(), 24);
+    }
+
+    #[test]
+    fn sanitize_name() {
+        assert_eq!(sanitize("back yard/2"), "back_yard_2");
+    }
+}
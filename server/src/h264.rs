@@ -50,7 +50,7 @@ const PIXEL_ASPECT_RATIOS: [((u16, u16), (u16, u16)); 6] = [
 ///
 /// Note that at least in the case of .mp4 muxing, we don't need to fix up the underlying SPS.
 /// SPS; PixelAspectRatioBox's definition says that it overrides the H.264-level declaration.
-fn default_pixel_aspect_ratio(width: u16, height: u16) -> (u16, u16) {
+pub(crate) fn default_pixel_aspect_ratio(width: u16, height: u16) -> (u16, u16) {
     if width >= height {
         PIXEL_ASPECT_RATIOS
             .iter()
@@ -77,7 +77,7 @@ fn default_pixel_aspect_ratio(width: u16, height: u16) -> (u16, u16) {
 ///
 /// TODO: detect invalid byte streams. For example, several 0x00s not followed by a 0x01, a stream
 /// stream not starting with 0x00 0x00 0x00 0x01, or an empty NAL unit.
-fn decode_h264_annex_b<'a, F>(mut data: &'a [u8], mut f: F) -> Result<(), Error>
+pub(crate) fn decode_h264_annex_b<'a, F>(mut data: &'a [u8], mut f: F) -> Result<(), Error>
 where
     F: FnMut(&'a [u8]) -> Result<(), Error>,
 {
@@ -126,6 +126,41 @@ fn parse_annex_b_extra_data(data: &[u8]) -> Result<(&[u8], &[u8]), Error> {
     }
 }
 
+/// Appends a `SampleEntry` (ISO/IEC 14496-12 section 8.5.2) and `VisualSampleEntry` (section
+/// 12.1.3) with the given box type and a length placeholder. The caller is responsible for
+/// appending the codec-specific configuration box and fixing up the length.
+pub(crate) fn append_visual_sample_entry(
+    sample_entry: &mut Vec<u8>,
+    type_: &[u8; 4],
+    width: u16,
+    height: u16,
+) -> Result<(), Error> {
+    // length placeholder + type + reserved + data_reference_index = 1
+    sample_entry.extend_from_slice(b"\x00\x00\x00\x00");
+    sample_entry.extend_from_slice(&type_[..]);
+    sample_entry.extend_from_slice(b"\x00\x00\x00\x00\x00\x00\x00\x01");
+
+    sample_entry.extend_from_slice(&[0; 16]); // pre-defined + reserved
+    sample_entry.write_u16::<BigEndian>(width)?;
+    sample_entry.write_u16::<BigEndian>(height)?;
+    sample_entry.extend_from_slice(&[
+        0x00, 0x48, 0x00, 0x00, // horizresolution
+        0x00, 0x48, 0x00, 0x00, // vertresolution
+        0x00, 0x00, 0x00, 0x00, // reserved
+        0x00, 0x01, // frame count
+        0x00, 0x00, 0x00, 0x00, // compressorname
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x18, 0xff, 0xff, // depth + pre_defined
+    ]);
+    Ok(())
+}
+
 /// Parsed representation of ffmpeg's "extradata".
 #[derive(Debug, PartialEq, Eq)]
 pub struct ExtraData {
@@ -175,30 +210,8 @@ impl ExtraData {
 
         // This is a concatenation of the following boxes/classes.
 
-        // SampleEntry, ISO/IEC 14496-12 section 8.5.2.
         let avc1_len_pos = sample_entry.len();
-        // length placeholder + type + reserved + data_reference_index = 1
-        sample_entry.extend_from_slice(b"\x00\x00\x00\x00avc1\x00\x00\x00\x00\x00\x00\x00\x01");
-
-        // VisualSampleEntry, ISO/IEC 14496-12 section 12.1.3.
-        sample_entry.extend_from_slice(&[0; 16]); // pre-defined + reserved
-        sample_entry.write_u16::<BigEndian>(width)?;
-        sample_entry.write_u16::<BigEndian>(height)?;
-        sample_entry.extend_from_slice(&[
-            0x00, 0x48, 0x00, 0x00, // horizresolution
-            0x00, 0x48, 0x00, 0x00, // vertresolution
-            0x00, 0x00, 0x00, 0x00, // reserved
-            0x00, 0x01, // frame count
-            0x00, 0x00, 0x00, 0x00, // compressorname
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x00, 0x00, 0x00, //
-            0x00, 0x18, 0xff, 0xff, // depth + pre_defined
-        ]);
+        append_visual_sample_entry(&mut sample_entry, b"avc1", width, height)?;
 
         // AVCSampleEntry, ISO/IEC 14496-15 section 5.3.4.1.
         // AVCConfigurationBox, ISO/IEC 14496-15 section 5.3.4.1.
@@ -288,6 +301,9 @@ impl ExtraData {
 /// Transforms sample data from Annex B format to AVC format. Should be called on samples iff
 /// `ExtraData::need_transform` is true. Uses an out parameter `avc_sample` rather than a return
 /// so that memory allocations can be reused from sample to sample.
+///
+/// H.265's Annex B byte stream and ISO/IEC 14496-15 section 8.3.2 sample format frame NAL units
+/// the same way, so this is also used for H.265 streams.
 pub fn transform_sample_data(annexb_sample: &[u8], avc_sample: &mut Vec<u8>) -> Result<(), Error> {
     // See AVCParameterSamples, ISO/IEC 14496-15 section 5.3.2.
     avc_sample.clear();
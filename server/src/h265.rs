@@ -0,0 +1,447 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! H.265 (HEVC) decoding
+//!
+//! As with H.264 (see `h264.rs`), Moonfire NVR mostly doesn't try to understand the video codec.
+//! It needs to turn the parameter sets (VPS, SPS, and PPS) into the ISO/IEC 14496-15 section
+//! 8.3.3.1 `HEVCDecoderConfigurationRecord` and wrap that in a section 8.4.1 `hvc1` or `hev1`
+//! sample entry. The sample data uses the same framing as H.264, so
+//! `h264::transform_sample_data` converts it from Annex B format when necessary.
+//!
+//! The relevant syntax of the parameter sets is in ISO/IEC 23008-2 (aka ITU-T H.265) section
+//! 7.3.
+
+use crate::h264::{self, ExtraData};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use failure::{bail, format_err, Error};
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+// See ISO/IEC 23008-2 table 7-1 - NAL unit type codes and NAL unit type classes.
+const NAL_UNIT_VPS: u8 = 32;
+const NAL_UNIT_SPS: u8 = 33;
+const NAL_UNIT_PPS: u8 = 34;
+const NAL_UNIT_PREFIX_SEI: u8 = 39;
+const NAL_UNIT_SUFFIX_SEI: u8 = 40;
+
+/// Returns the `nal_unit_type` from the two-byte NAL unit header.
+fn nal_unit_type(unit: &[u8]) -> Result<u8, Error> {
+    if unit.len() < 2 {
+        bail!("NAL unit of length {} is too short for header", unit.len());
+    }
+    Ok((unit[0] >> 1) & 0x3F)
+}
+
+/// Parses Annex B extra data, returning a tuple holding the `vps`, `sps`, and `pps` substrings.
+fn parse_annex_b_extra_data(data: &[u8]) -> Result<(&[u8], &[u8], &[u8]), Error> {
+    let mut vps = None;
+    let mut sps = None;
+    let mut pps = None;
+    h264::decode_h264_annex_b(data, |unit| {
+        let nal_type = nal_unit_type(unit)?;
+        match nal_type {
+            NAL_UNIT_VPS => vps = Some(unit),
+            NAL_UNIT_SPS => sps = Some(unit),
+            NAL_UNIT_PPS => pps = Some(unit),
+            NAL_UNIT_PREFIX_SEI | NAL_UNIT_SUFFIX_SEI => {}
+            _ => bail!("Expected VPS, SPS, and PPS; got type {}", nal_type),
+        };
+        Ok(())
+    })?;
+    match (vps, sps, pps) {
+        (Some(v), Some(s), Some(p)) => Ok((v, s, p)),
+        _ => bail!("VPS, SPS, and PPS must be specified"),
+    }
+}
+
+/// A minimal MSB-first bit reader over RBSP data, sufficient for the SPS fields needed here.
+struct BitReader<'a> {
+    data: &'a [u8],
+
+    /// The position of the next bit to read, in bits from the start of `data`.
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read_bit(&mut self) -> Result<bool, Error> {
+        let byte = self
+            .data
+            .get(self.pos >> 3)
+            .ok_or_else(|| format_err!("SPS is truncated"))?;
+        let bit = (byte >> (7 - (self.pos & 7))) & 1;
+        self.pos += 1;
+        Ok(bit == 1)
+    }
+
+    fn read_bits(&mut self, n: u32) -> Result<u32, Error> {
+        debug_assert!(n <= 32);
+        let mut v = 0u64;
+        for _ in 0..n {
+            v = (v << 1) | u64::from(self.read_bit()?);
+        }
+        Ok(v as u32)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), Error> {
+        if self.pos + n > self.data.len() * 8 {
+            bail!("SPS is truncated");
+        }
+        self.pos += n;
+        Ok(())
+    }
+
+    /// Reads an `ue(v)`, as described in ISO/IEC 23008-2 section 9.2.
+    fn read_ue(&mut self) -> Result<u32, Error> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                bail!("ue(v) value is too large");
+            }
+        }
+        Ok((1u32 << leading_zeros) - 1 + self.read_bits(leading_zeros)?)
+    }
+}
+
+/// The fields of the SPS which are needed to construct a `HEVCDecoderConfigurationRecord`.
+#[derive(Debug)]
+struct Sps {
+    /// The general part of `profile_tier_level`, from `general_profile_space` through
+    /// `general_level_idc`. This is byte-aligned and laid out exactly as in the
+    /// `HEVCDecoderConfigurationRecord`.
+    general_profile_tier_level: [u8; 12],
+
+    max_sub_layers_minus1: u8,
+    temporal_id_nesting: bool,
+    chroma_format_idc: u8,
+    bit_depth_luma_minus8: u8,
+    bit_depth_chroma_minus8: u8,
+}
+
+impl Sps {
+    /// Parses the SPS from its NAL form (including header and emulation prevention bytes).
+    fn parse(nal: &[u8]) -> Result<Self, Error> {
+        let rbsp = h264_reader::rbsp::decode_nal(&nal[2..]);
+        let rbsp = &rbsp[..];
+        if rbsp.len() < 13 {
+            bail!("SPS of length {} is too short", rbsp.len());
+        }
+
+        // seq_parameter_set_rbsp, ISO/IEC 23008-2 section 7.3.2.2.1. The first byte holds
+        // sps_video_parameter_set_id (4 bits), sps_max_sub_layers_minus1 (3 bits), and
+        // sps_temporal_id_nesting_flag (1 bit). The general profile_tier_level follows.
+        let max_sub_layers_minus1 = (rbsp[0] >> 1) & 0x7;
+        let temporal_id_nesting = (rbsp[0] & 1) != 0;
+        let mut general_profile_tier_level = [0u8; 12];
+        general_profile_tier_level.copy_from_slice(&rbsp[1..13]);
+
+        // The remainder of profile_tier_level, ISO/IEC 23008-2 section 7.3.3.
+        let mut r = BitReader {
+            data: rbsp,
+            pos: 13 * 8,
+        };
+        let mut sub_layer_bits = 0;
+        for _ in 0..max_sub_layers_minus1 {
+            if r.read_bit()? {
+                sub_layer_bits += 88; // sub_layer_profile_space through sub_layer_reserved_zero_bit
+            }
+            if r.read_bit()? {
+                sub_layer_bits += 8; // sub_layer_level_idc
+            }
+        }
+        if max_sub_layers_minus1 > 0 {
+            r.skip(2 * (8 - usize::from(max_sub_layers_minus1)))?; // reserved_zero_2bits
+        }
+        r.skip(sub_layer_bits)?;
+
+        r.read_ue()?; // sps_seq_parameter_set_id
+        let chroma_format_idc = r.read_ue()?;
+        if chroma_format_idc == 3 {
+            r.skip(1)?; // separate_colour_plane_flag
+        }
+        r.read_ue()?; // pic_width_in_luma_samples
+        r.read_ue()?; // pic_height_in_luma_samples
+        if r.read_bit()? {
+            // conformance_window_flag
+            for _ in 0..4 {
+                r.read_ue()?; // conf_win_{left,right,top,bottom}_offset
+            }
+        }
+        let bit_depth_luma_minus8 = r.read_ue()?;
+        let bit_depth_chroma_minus8 = r.read_ue()?;
+        if chroma_format_idc > 3 || bit_depth_luma_minus8 > 7 || bit_depth_chroma_minus8 > 7 {
+            bail!(
+                "Bad SPS: chroma_format_idc={} bit_depth_luma_minus8={} \
+                 bit_depth_chroma_minus8={}",
+                chroma_format_idc,
+                bit_depth_luma_minus8,
+                bit_depth_chroma_minus8
+            );
+        }
+        Ok(Sps {
+            general_profile_tier_level,
+            max_sub_layers_minus1,
+            temporal_id_nesting,
+            chroma_format_idc: chroma_format_idc as u8,
+            bit_depth_luma_minus8: bit_depth_luma_minus8 as u8,
+            bit_depth_chroma_minus8: bit_depth_chroma_minus8 as u8,
+        })
+    }
+}
+
+/// Appends a `HEVCDecoderConfigurationRecord` (ISO/IEC 14496-15 section 8.3.3.1) built from the
+/// given parameter sets.
+fn append_hvcc(
+    out: &mut Vec<u8>,
+    vps: &[u8],
+    raw_sps: &[u8],
+    sps: &Sps,
+    pps: &[u8],
+) -> Result<(), Error> {
+    out.push(1); // configurationVersion
+    out.extend_from_slice(&sps.general_profile_tier_level[..]);
+    out.write_u16::<BigEndian>(0xF000)?; // reserved + min_spatial_segmentation_idc (unknown)
+    out.push(0xFC); // reserved + parallelismType (unknown)
+    out.push(0xFC | sps.chroma_format_idc); // reserved + chromaFormat
+    out.push(0xF8 | sps.bit_depth_luma_minus8); // reserved + bitDepthLumaMinus8
+    out.push(0xF8 | sps.bit_depth_chroma_minus8); // reserved + bitDepthChromaMinus8
+    out.write_u16::<BigEndian>(0)?; // avgFrameRate (unspecified)
+
+    // constantFrameRate (unknown) + numTemporalLayers + temporalIdNested +
+    // lengthSizeMinusOne. Hardcode lengthSizeMinusOne to 3, matching
+    // h264::transform_sample_data's 4-byte lengths.
+    out.push(
+        ((sps.max_sub_layers_minus1 + 1) << 3)
+            | if sps.temporal_id_nesting { 1 << 2 } else { 0 }
+            | 0x03,
+    );
+
+    // Only support one of each parameter set, as with H.264. Each array is complete; that is,
+    // the decoder doesn't need to look in the samples for additional parameter sets.
+    out.push(3); // numOfArrays
+    for &(nal_type, unit) in &[
+        (NAL_UNIT_VPS, vps),
+        (NAL_UNIT_SPS, raw_sps),
+        (NAL_UNIT_PPS, pps),
+    ] {
+        out.push(0x80 | nal_type); // array_completeness + reserved + NAL_unit_type
+        out.write_u16::<BigEndian>(1)?; // numNalus
+        out.write_u16::<BigEndian>(u16::try_from(unit.len())?)?;
+        out.extend_from_slice(unit);
+    }
+    Ok(())
+}
+
+/// Validates the framing of a `HEVCDecoderConfigurationRecord`, returning true iff all of its
+/// parameter set arrays are marked complete (and thus it is suitable for an `hvc1` rather than
+/// an `hev1` sample entry).
+fn check_hvcc(hvcc: &[u8]) -> Result<bool, Error> {
+    if hvcc.len() < 23 || hvcc[0] != 1 {
+        bail!("Bad HEVCDecoderConfigurationRecord");
+    }
+    let num_of_arrays = hvcc[22];
+    let mut pos = 23;
+    let mut complete = true;
+    for _ in 0..num_of_arrays {
+        let hdr = hvcc
+            .get(pos..pos + 3)
+            .ok_or_else(|| format_err!("HEVCDecoderConfigurationRecord is truncated"))?;
+        if (hdr[0] & 0x80) == 0 {
+            complete = false;
+        }
+        let num_nalus = BigEndian::read_u16(&hdr[1..3]);
+        pos += 3;
+        for _ in 0..num_nalus {
+            let len = hvcc
+                .get(pos..pos + 2)
+                .ok_or_else(|| format_err!("HEVCDecoderConfigurationRecord is truncated"))?;
+            pos += 2 + usize::from(BigEndian::read_u16(len));
+        }
+    }
+    if pos != hvcc.len() {
+        bail!(
+            "HEVCDecoderConfigurationRecord has length {}; expected {}",
+            hvcc.len(),
+            pos
+        );
+    }
+    Ok(complete)
+}
+
+/// Returns the RFC 6381 codec string, as described in ISO/IEC 14496-15 annex E.3.
+/// `general_profile_tier_level` is as in `Sps`.
+fn rfc6381_codec(type_: &str, general_profile_tier_level: &[u8]) -> String {
+    let ptl = general_profile_tier_level;
+    let profile_space = ["", "A", "B", "C"][usize::from(ptl[0] >> 6)];
+    let tier = if (ptl[0] & 0x20) != 0 { 'H' } else { 'L' };
+    let profile_idc = ptl[0] & 0x1F;
+
+    // general_profile_compatibility_flags in reverse bit order, in hex with no leading zeros.
+    let compatibility = BigEndian::read_u32(&ptl[1..5]).reverse_bits();
+
+    let mut codec = format!(
+        "{}.{}{}.{:X}.{}{}",
+        type_, profile_space, profile_idc, compatibility, tier, ptl[11]
+    );
+
+    // Each byte of the constraint flags, omitting trailing zero bytes.
+    let constraints = &ptl[5..11];
+    let len = constraints
+        .iter()
+        .rposition(|&b| b != 0)
+        .map(|p| p + 1)
+        .unwrap_or(0);
+    for b in &constraints[..len] {
+        write!(&mut codec, ".{:X}", b).unwrap();
+    }
+    codec
+}
+
+/// Parses "extradata" from ffmpeg or Retina for an H.265 stream. This data may be in either Annex
+/// B format or `HEVCDecoderConfigurationRecord` format.
+///
+/// Unlike `h264::ExtraData::parse`, this doesn't look at the VUI for a pixel aspect ratio;
+/// it uses only the default for the given dimensions.
+pub fn parse_extra_data(extradata: &[u8], width: u16, height: u16) -> Result<ExtraData, Error> {
+    let mut sample_entry = Vec::with_capacity(256);
+    let need_transform;
+    let type_;
+    let mut hvcc_box = Vec::with_capacity(128);
+    hvcc_box.extend_from_slice(b"\x00\x00\x00\x00hvcC");
+    if extradata.starts_with(b"\x00\x00\x00\x01") || extradata.starts_with(b"\x00\x00\x01") {
+        // Annex B format.
+        let (vps, raw_sps, pps) = parse_annex_b_extra_data(extradata)?;
+        let sps = Sps::parse(raw_sps)?;
+        append_hvcc(&mut hvcc_box, vps, raw_sps, &sps, pps)?;
+        need_transform = true;
+        type_ = "hvc1";
+    } else {
+        // Assume "extradata" holds a HEVCDecoderConfigurationRecord.
+        let complete = check_hvcc(extradata)?;
+        hvcc_box.extend_from_slice(extradata);
+        need_transform = false;
+        type_ = if complete { "hvc1" } else { "hev1" };
+    }
+    let hvcc_len = u32::try_from(hvcc_box.len())?;
+    BigEndian::write_u32(&mut hvcc_box[0..4], hvcc_len);
+
+    // HEVCSampleEntry, ISO/IEC 14496-15 section 8.4.1.1.
+    let len_pos = sample_entry.len();
+    h264::append_visual_sample_entry(
+        &mut sample_entry,
+        if type_ == "hvc1" { b"hvc1" } else { b"hev1" },
+        width,
+        height,
+    )?;
+    let hvcc_pos = sample_entry.len();
+    sample_entry.extend_from_slice(&hvcc_box);
+
+    // PixelAspectRatioBox, ISO/IEC 14496-12 section 12.1.4.2.
+    let pasp = h264::default_pixel_aspect_ratio(width, height);
+    if pasp != (1, 1) {
+        sample_entry.extend_from_slice(b"\x00\x00\x00\x10pasp"); // length + box name
+        sample_entry.write_u32::<BigEndian>(pasp.0.into())?;
+        sample_entry.write_u32::<BigEndian>(pasp.1.into())?;
+    }
+
+    let cur_pos = sample_entry.len();
+    BigEndian::write_u32(
+        &mut sample_entry[len_pos..len_pos + 4],
+        u32::try_from(cur_pos - len_pos)?,
+    );
+
+    // The general profile_tier_level follows the hvcC box header and configurationVersion.
+    let ptl_pos = hvcc_pos + 9;
+    let rfc6381_codec = rfc6381_codec(type_, &sample_entry[ptl_pos..ptl_pos + 12]);
+    Ok(ExtraData {
+        entry: db::VideoSampleEntryToInsert {
+            data: sample_entry,
+            rfc6381_codec,
+            width,
+            height,
+            pasp_h_spacing: pasp.0,
+            pasp_v_spacing: pasp.1,
+        },
+        need_transform,
+    })
+}
+
+/// Returns true iff the given sample entry data is for H.265.
+pub fn is_h265_sample_entry(data: &[u8]) -> bool {
+    data.len() >= 8 && (&data[4..8] == b"hvc1" || &data[4..8] == b"hev1")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use byteorder::{BigEndian, ByteOrder};
+    use db::testutil;
+
+    /// A VPS, SPS, and PPS for 1920x1080 Main profile, level 3.1, 8-bit 4:2:0 video.
+    #[rustfmt::skip]
+    pub(crate) const ANNEX_B_TEST_INPUT: [u8; 67] = [
+        0x00, 0x00, 0x00, 0x01, 0x40, 0x01, 0x0c, 0x01,
+        0xff, 0xff, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00,
+        0x90, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00,
+        0x5d, 0x70, 0x24, 0x00, 0x00, 0x00, 0x01, 0x42,
+        0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00,
+        0x90, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00,
+        0x5d, 0xa0, 0x03, 0xc0, 0x80, 0x11, 0x07, 0xcb,
+        0x94, 0xb8, 0x00, 0x00, 0x00, 0x01, 0x44, 0x01,
+        0xc0, 0x60, 0x20,
+    ];
+
+    #[test]
+    fn test_sample_entry_from_annex_b() {
+        testutil::init();
+        let e = super::parse_extra_data(&ANNEX_B_TEST_INPUT, 1920, 1080).unwrap();
+        assert_eq!(&e.entry.data[4..8], b"hvc1");
+        assert_eq!(&e.entry.data[90..94], b"hvcC");
+        assert_eq!(e.entry.width, 1920);
+        assert_eq!(e.entry.height, 1080);
+        assert_eq!(e.entry.rfc6381_codec, "hvc1.1.6.L93.90");
+        assert_eq!(e.need_transform, true);
+
+        // chromaFormat, bitDepthLumaMinus8, bitDepthChromaMinus8.
+        assert_eq!(&e.entry.data[94 + 16..94 + 19], &[0xfd, 0xf8, 0xf8]);
+
+        // numTemporalLayers = 1, temporalIdNested = 1, lengthSizeMinusOne = 3.
+        assert_eq!(e.entry.data[94 + 21], 0x0f);
+        assert!(super::is_h265_sample_entry(&e.entry.data));
+    }
+
+    #[test]
+    fn test_sample_entry_from_hvcc() {
+        testutil::init();
+        let from_annex_b = super::parse_extra_data(&ANNEX_B_TEST_INPUT, 1920, 1080).unwrap();
+        let hvcc_len = BigEndian::read_u32(&from_annex_b.entry.data[86..90]) as usize;
+        let hvcc = &from_annex_b.entry.data[94..86 + hvcc_len];
+        let e = super::parse_extra_data(hvcc, 1920, 1080).unwrap();
+        assert_eq!(e.entry, from_annex_b.entry);
+        assert_eq!(e.need_transform, false);
+
+        // Without array_completeness, it should be an hev1 sample entry instead.
+        let mut hvcc = hvcc.to_vec();
+        hvcc[23] &= 0x7f;
+        let e = super::parse_extra_data(&hvcc, 1920, 1080).unwrap();
+        assert_eq!(&e.entry.data[4..8], b"hev1");
+        assert_eq!(e.entry.rfc6381_codec, "hev1.1.6.L93.90");
+
+        // Truncated records should be rejected.
+        super::parse_extra_data(&hvcc[..hvcc.len() - 1], 1920, 1080).unwrap_err();
+    }
+
+    #[test]
+    fn test_rfc6381_codec() {
+        testutil::init();
+
+        // Main 10 profile, high tier, level 5.1, with no constraint flags.
+        #[rustfmt::skip]
+        let ptl = [
+            0x22, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x99,
+        ];
+        assert_eq!(super::rfc6381_codec("hvc1", &ptl), "hvc1.2.4.H153");
+    }
+}
@@ -0,0 +1,414 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! HTTP Live Streaming (HLS) playlists, as described in RFC 8216.
+//!
+//! Playlists use fragmented `.mp4` media segments (`hls.m4s`), each of which starts at a key
+//! frame and covers part of a single recording. Recordings are split into segments
+//! deterministically from their video index, so a given segment keeps the same URL and (in live
+//! playlists) the same media sequence number as the recording grows and the playlist slides
+//! forward.
+
+use base::time::TIME_UNITS_PER_SEC;
+use db::recording::{self, SampleIndexIterator};
+use db::CompositeId;
+use failure::Error;
+use fnv::FnvHashMap;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::ops::Range;
+
+/// The minimum duration of a segment, other than the last of a recording.
+/// Recordings are split at the first key frame at least this long after the previous split.
+const MIN_SEGMENT_DURATION_90K: i32 = 2 * TIME_UNITS_PER_SEC as i32;
+
+/// How far back to look for recordings to include in a live playlist. This should cover at
+/// least `LIVE_SEGMENTS` segments of the current and previous recordings.
+pub const LIVE_LOOKBACK: recording::Duration = recording::Duration(120 * TIME_UNITS_PER_SEC);
+
+/// The number of segments to include in a live playlist.
+const LIVE_SEGMENTS: usize = 6;
+
+/// A media segment within a playlist.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Segment {
+    pub id: CompositeId,
+    pub open_id: u32,
+    pub run_offset: i32,
+    pub video_sample_entry_id: i32,
+    pub audio_sample_entry_id: Option<i32>,
+
+    /// The media time range within the recording. This always starts at a key frame.
+    pub media_range_90k: Range<i32>,
+
+    /// The wall time at which this segment starts.
+    pub start: recording::Time,
+
+    /// True iff this segment runs through the end of a complete recording.
+    pub ends_recording: bool,
+}
+
+impl Segment {
+    /// Returns true if `self` doesn't directly follow `prev` in the same run with the same
+    /// sample entries, in which case an `EXT-X-DISCONTINUITY` tag is necessary.
+    fn is_discontinuity_after(&self, prev: &Segment) -> bool {
+        if (self.video_sample_entry_id, self.audio_sample_entry_id)
+            != (prev.video_sample_entry_id, prev.audio_sample_entry_id)
+        {
+            return true;
+        }
+        if self.id == prev.id {
+            return self.media_range_90k.start != prev.media_range_90k.end;
+        }
+        !(prev.ends_recording
+            && self.id.stream() == prev.id.stream()
+            && self.id.recording() == prev.id.recording() + 1
+            && self.open_id == prev.open_id
+            && self.run_offset != 0
+            && self.media_range_90k.start == 0)
+    }
+}
+
+/// Returns the media time offsets within a recording at which its segments start: the first
+/// frame, then each key frame at least `MIN_SEGMENT_DURATION_90K` after the previous start.
+fn split_points(video_index: &[u8]) -> Result<Vec<i32>, Error> {
+    let mut it = SampleIndexIterator::default();
+    let mut points = Vec::new();
+    while it.next(video_index)? {
+        if !it.is_key() {
+            continue;
+        }
+        match points.last() {
+            Some(&p) if it.start_90k < p + MIN_SEGMENT_DURATION_90K => {}
+            _ => points.push(it.start_90k),
+        }
+    }
+    Ok(points)
+}
+
+/// Appends the segments of `row` which overlap `desired_media_range_90k`.
+///
+/// If the recording is still growing, its final segment is omitted, as it may gain more frames.
+pub fn append_segments(
+    db: &db::LockedDatabase,
+    row: &db::ListRecordingsRow,
+    desired_media_range_90k: Range<i32>,
+    out: &mut Vec<Segment>,
+) -> Result<(), Error> {
+    let growing = (row.flags & db::RecordingFlags::Growing as i32) != 0;
+    let points = db.with_recording_playback(row.id, &mut |p| split_points(p.video_index))?;
+    for (i, &start) in points.iter().enumerate() {
+        let end = match points.get(i + 1) {
+            Some(&e) => e,
+            None if growing => break,
+            None => row.media_duration_90k,
+        };
+        if start >= end
+            || end <= desired_media_range_90k.start
+            || start >= desired_media_range_90k.end
+        {
+            continue;
+        }
+        let wall_start = recording::rescale(start, row.media_duration_90k, row.wall_duration_90k);
+        out.push(Segment {
+            id: row.id,
+            open_id: row.open_id,
+            run_offset: row.run_offset,
+            video_sample_entry_id: row.video_sample_entry_id,
+            audio_sample_entry_id: row.audio_sample_entry_id,
+            media_range_90k: start..end,
+            start: row.start + recording::Duration(i64::from(wall_start)),
+            ends_recording: !growing && end == row.media_duration_90k,
+        });
+    }
+    Ok(())
+}
+
+/// Writes a `#EXT-X-PROGRAM-DATE-TIME` tag, which has the format of RFC 3339 in UTC.
+fn write_program_date_time(out: &mut String, t: recording::Time) {
+    let sec = t.0.div_euclid(TIME_UNITS_PER_SEC);
+    let millis = t.0.rem_euclid(TIME_UNITS_PER_SEC) * 1000 / TIME_UNITS_PER_SEC;
+    let tm = time::at_utc(time::Timespec { sec, nsec: 0 });
+    writeln!(
+        out,
+        "#EXT-X-PROGRAM-DATE-TIME:{}.{:03}Z",
+        tm.strftime("%FT%T").unwrap(),
+        millis
+    )
+    .unwrap();
+}
+
+/// Returns a media playlist for the given segments.
+///
+/// URIs are relative to the playlist's own URL, `/api/cameras/<uuid>/<type>/<name>.m3u8`.
+fn playlist(
+    segments: &[Segment],
+    media_sequence: u64,
+    discontinuity_sequence: u64,
+    vod: bool,
+) -> String {
+    let max_duration_90k = segments
+        .iter()
+        .map(|s| s.media_range_90k.end - s.media_range_90k.start)
+        .max()
+        .unwrap_or(0);
+    let target_duration = cmp::max(
+        1,
+        (i64::from(max_duration_90k) + TIME_UNITS_PER_SEC - 1) / TIME_UNITS_PER_SEC,
+    );
+    let mut out = String::new();
+    out.push_str("#EXTM3U\n#EXT-X-VERSION:7\n");
+    writeln!(out, "#EXT-X-TARGETDURATION:{}", target_duration).unwrap();
+    writeln!(out, "#EXT-X-MEDIA-SEQUENCE:{}", media_sequence).unwrap();
+    if discontinuity_sequence != 0 {
+        writeln!(
+            out,
+            "#EXT-X-DISCONTINUITY-SEQUENCE:{}",
+            discontinuity_sequence
+        )
+        .unwrap();
+    }
+    if vod {
+        out.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
+    }
+    out.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
+    let mut prev: Option<&Segment> = None;
+    for s in segments {
+        let discontinuity = match prev {
+            None => true,
+            Some(p) => {
+                let d = s.is_discontinuity_after(p);
+                if d {
+                    out.push_str("#EXT-X-DISCONTINUITY\n");
+                }
+                d
+            }
+        };
+        let new_map = prev
+            .map(|p| {
+                (p.video_sample_entry_id, p.audio_sample_entry_id)
+                    != (s.video_sample_entry_id, s.audio_sample_entry_id)
+            })
+            .unwrap_or(true);
+        if new_map {
+            write!(
+                out,
+                "#EXT-X-MAP:URI=\"../../../init/{}.mp4",
+                s.video_sample_entry_id
+            )
+            .unwrap();
+            if let Some(id) = s.audio_sample_entry_id {
+                write!(out, "?audioSampleEntryId={}", id).unwrap();
+            }
+            out.push_str("\"\n");
+        }
+        if discontinuity {
+            write_program_date_time(&mut out, s.start);
+        }
+        let duration_90k = i64::from(s.media_range_90k.end - s.media_range_90k.start);
+        writeln!(
+            out,
+            "#EXTINF:{}.{:03},\nhls.m4s?s={}@{}.{}-{}",
+            duration_90k / TIME_UNITS_PER_SEC,
+            duration_90k % TIME_UNITS_PER_SEC * 1000 / TIME_UNITS_PER_SEC,
+            s.id.recording(),
+            s.open_id,
+            s.media_range_90k.start,
+            s.media_range_90k.end
+        )
+        .unwrap();
+        prev = Some(s);
+    }
+    if vod {
+        out.push_str("#EXT-X-ENDLIST\n");
+    }
+    out
+}
+
+/// Returns a video-on-demand playlist for the given segments.
+pub fn vod_playlist(segments: &[Segment]) -> String {
+    playlist(segments, 0, 0, true)
+}
+
+/// Sequence numbers (RFC 8216 section 6.2.1) of the first segment of a recording.
+#[derive(Copy, Clone, Debug, Default)]
+struct RecordingSequences {
+    media: u64,
+    discontinuity: u64,
+
+    /// The number of segments in the recording as of the latest request.
+    segments: usize,
+}
+
+/// Tracks the sequence numbers of live playlists.
+///
+/// Clients expect a segment to keep its media and discontinuity sequence numbers as the playlist
+/// slides forward. Segments are split deterministically, so it suffices to remember the numbers
+/// of the first segment of each recent recording. These are only kept in RAM; after a restart,
+/// numbering begins again at zero.
+#[derive(Default)]
+pub struct LiveSequences(FnvHashMap<i32, BTreeMap<i32, RecordingSequences>>);
+
+impl LiveSequences {
+    /// Returns a live playlist from the tail of `segments`, which should hold all the segments
+    /// of the latest recordings of `stream_id` in order.
+    pub fn playlist(&mut self, stream_id: i32, segments: &[Segment]) -> String {
+        let recordings = self.0.entry(stream_id).or_default();
+        let mut prev: Option<(&Segment, RecordingSequences)> = None;
+        for s in segments {
+            let id = s.id.recording();
+            let seqs = match prev {
+                Some((p, mut seqs)) if p.id == s.id => {
+                    seqs.segments += 1;
+                    seqs
+                }
+                _ => {
+                    let mut seqs = match (recordings.get(&id), prev) {
+                        (Some(&seqs), _) => seqs,
+                        (None, Some((p, p_seqs))) => RecordingSequences {
+                            media: p_seqs.media + p_seqs.segments as u64,
+                            discontinuity: p_seqs.discontinuity
+                                + s.is_discontinuity_after(p) as u64,
+                            segments: 0,
+                        },
+                        (None, None) => match recordings.values().next_back() {
+                            Some(l) => RecordingSequences {
+                                media: l.media + l.segments as u64,
+                                discontinuity: l.discontinuity + 1,
+                                segments: 0,
+                            },
+                            None => RecordingSequences::default(),
+                        },
+                    };
+                    seqs.segments = 1;
+                    seqs
+                }
+            };
+            recordings.insert(id, seqs);
+            prev = Some((s, seqs));
+        }
+
+        // Forget recordings which have slid out of the window.
+        let skip = segments.len().saturating_sub(LIVE_SEGMENTS);
+        let window = &segments[skip..];
+        let (media, discontinuity) = match window.first() {
+            None => (
+                recordings
+                    .values()
+                    .next_back()
+                    .map(|l| l.media + l.segments as u64)
+                    .unwrap_or(0),
+                0,
+            ),
+            Some(first) => {
+                *recordings = recordings.split_off(&first.id.recording());
+                let seqs = recordings[&first.id.recording()];
+                let index = segments[..skip].iter().filter(|s| s.id == first.id).count();
+                (seqs.media + index as u64, seqs.discontinuity)
+            }
+        };
+        playlist(window, media, discontinuity, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    fn segment(recording_id: i32, run_offset: i32, media_range_90k: Range<i32>) -> Segment {
+        Segment {
+            id: CompositeId::new(1, recording_id),
+            open_id: 1,
+            run_offset,
+            video_sample_entry_id: 1,
+            audio_sample_entry_id: None,
+            start: recording::Time(1430006400i64 * TIME_UNITS_PER_SEC)
+                + recording::Duration(i64::from(recording_id) * 60 * TIME_UNITS_PER_SEC)
+                + recording::Duration(i64::from(media_range_90k.start)),
+            ends_recording: media_range_90k.end == 180_000 * 3,
+            media_range_90k,
+        }
+    }
+
+    #[test]
+    fn test_split_points() {
+        testutil::init();
+        let mut r = db::RecordingToInsert::default();
+        let mut e = recording::SampleIndexEncoder::default();
+
+        // Key frames every 1.5 seconds; splits happen at the first one at least 2 seconds after
+        // the previous split.
+        for i in 0..12 {
+            e.add_sample(45_000, 1, i % 3 == 0, &mut r);
+        }
+        assert_eq!(split_points(&r.video_index).unwrap(), &[0, 270_000][..]);
+    }
+
+    #[test]
+    fn test_vod_playlist() {
+        testutil::init();
+        let mut segments = vec![
+            segment(1, 0, 0..180_000),
+            segment(1, 0, 180_000..540_000),
+            segment(2, 1, 0..180_000),
+            segment(4, 0, 0..90_000),
+        ];
+        segments[3].audio_sample_entry_id = Some(2);
+        let p = vod_playlist(&segments);
+        assert!(
+            p.starts_with(
+                "#EXTM3U\n\
+                 #EXT-X-VERSION:7\n\
+                 #EXT-X-TARGETDURATION:4\n\
+                 #EXT-X-MEDIA-SEQUENCE:0\n\
+                 #EXT-X-PLAYLIST-TYPE:VOD\n\
+                 #EXT-X-INDEPENDENT-SEGMENTS\n\
+                 #EXT-X-MAP:URI=\"../../../init/1.mp4\"\n\
+                 #EXT-X-PROGRAM-DATE-TIME:2015-04-26T00:01:00.000Z\n\
+                 #EXTINF:2.000,\n\
+                 hls.m4s?s=1@1.0-180000\n\
+                 #EXTINF:4.000,\n\
+                 hls.m4s?s=1@1.180000-540000\n\
+                 #EXTINF:2.000,\n\
+                 hls.m4s?s=2@1.0-180000\n\
+                 #EXT-X-DISCONTINUITY\n\
+                 #EXT-X-MAP:URI=\"../../../init/1.mp4?audioSampleEntryId=2\"\n\
+                 #EXT-X-PROGRAM-DATE-TIME:2015-04-26T00:04:00.000Z\n\
+                 #EXTINF:1.000,\n\
+                 hls.m4s?s=4@1.0-90000\n\
+                 #EXT-X-ENDLIST\n"
+            ),
+            "{}",
+            p
+        );
+    }
+
+    #[test]
+    fn test_live_sequences() {
+        testutil::init();
+        let mut seqs = LiveSequences::default();
+
+        // Recording 1 starts a run and is growing.
+        let mut segments: Vec<_> = (0..4)
+            .map(|i| segment(1, 0, i * 90_000..(i + 1) * 90_000))
+            .collect();
+        let p = seqs.playlist(1, &segments);
+        assert!(p.contains("#EXT-X-MEDIA-SEQUENCE:0\n"), "{}", p);
+
+        // Recording 1 continues to grow beyond the window.
+        segments.extend((4..8).map(|i| segment(1, 0, i * 90_000..(i + 1) * 90_000)));
+        let p = seqs.playlist(1, &segments);
+        assert!(p.contains("#EXT-X-MEDIA-SEQUENCE:2\n"), "{}", p);
+        assert!(p.contains("hls.m4s?s=1@1.180000-270000\n"), "{}", p);
+
+        // Recording 2 starts a new run; recording 1 has slid out of the window.
+        segments.clear();
+        segments.extend((0..6).map(|i| segment(2, 0, i * 90_000..(i + 1) * 90_000)));
+        let p = seqs.playlist(1, &segments);
+        assert!(p.contains("#EXT-X-MEDIA-SEQUENCE:8\n"), "{}", p);
+        assert!(p.contains("#EXT-X-DISCONTINUITY-SEQUENCE:1\n"), "{}", p);
+    }
+}
@@ -16,9 +16,15 @@ pub struct TopLevel<'a> {
     pub time_zone_name: &'a str,
 
     // Use a custom serializer which presents the map's values as a sequence and includes the
-    // "days" and "camera_configs" attributes or not, according to the respective bools.
+    // "days" and "camera_configs" attributes or not, according to the respective bools. Only
+    // cameras matching the predicate are included.
     #[serde(serialize_with = "TopLevel::serialize_cameras")]
-    pub cameras: (&'a db::LockedDatabase, bool, bool),
+    pub cameras: (
+        &'a db::LockedDatabase,
+        bool,
+        bool,
+        &'a dyn Fn(&db::Camera) -> bool,
+    ),
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub session: Option<Session>,
@@ -30,7 +36,7 @@ pub struct TopLevel<'a> {
     pub signal_types: &'a db::LockedDatabase,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Session {
     pub username: String,
@@ -62,6 +68,8 @@ pub struct Camera<'a> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub config: Option<CameraConfig<'a>>,
 
+    pub onvif_status: OnvifStatus,
+
     #[serde(serialize_with = "Camera::serialize_streams")]
     pub streams: [Option<Stream<'a>>; 2],
 }
@@ -74,15 +82,51 @@ pub struct CameraConfig<'a> {
     pub password: Option<&'a str>,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OnvifStatus {
+    pub state: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+
+    pub failures: u32,
+    pub expiration_time_90k: Option<Time>,
+    pub last_event_time_90k: Option<Time>,
+}
+
+impl OnvifStatus {
+    pub fn wrap(s: &db::OnvifStatus) -> Self {
+        OnvifStatus {
+            state: s.state.as_str(),
+            last_error: s.last_error.clone(),
+            failures: s.failures,
+            expiration_time_90k: s.expiration,
+            last_event_time_90k: s.last_event,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Stream<'a> {
     pub retain_bytes: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retain_max_age_sec: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retain_min_age_sec: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive_age_sec: Option<i64>,
+
     pub min_start_time_90k: Option<Time>,
     pub max_end_time_90k: Option<Time>,
     pub total_duration_90k: Duration,
     pub total_sample_file_bytes: i64,
     pub fs_bytes: i64,
+    pub retention_overage_bytes: i64,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(serialize_with = "Stream::serialize_days")]
@@ -90,12 +134,84 @@ pub struct Stream<'a> {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub config: Option<StreamConfig<'a>>,
+
+    pub status: StreamStatus,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamStatus {
+    pub state: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+
+    pub session_start_time_90k: Option<Time>,
+    pub last_frame_time_90k: Option<Time>,
+    pub bits_per_sec: f64,
+    pub frames_per_sec: f64,
+}
+
+impl StreamStatus {
+    pub fn wrap(s: &db::StreamStatus) -> Self {
+        StreamStatus {
+            state: s.state.as_str(),
+            last_error: s.last_error.clone(),
+            session_start_time_90k: s.session_start,
+            last_frame_time_90k: s.last_frame,
+            bits_per_sec: s.bits_per_sec,
+            frames_per_sec: s.frames_per_sec,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Health<'a> {
+    pub healthy: bool,
+    pub stalled_streams: Vec<StalledStream<'a>>,
+    pub unopened_dirs: Vec<UnopenedDir<'a>>,
+    pub over_retention_streams: Vec<OverRetentionStream<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StalledStream<'a> {
+    pub camera_uuid: Uuid,
+    pub camera_short_name: &'a str,
+    pub stream_type: &'static str,
+    pub status: StreamStatus,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OverRetentionStream<'a> {
+    pub camera_uuid: Uuid,
+    pub camera_short_name: &'a str,
+    pub stream_type: &'static str,
+    pub retain_bytes: i64,
+    pub retention_overage_bytes: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnopenedDir<'a> {
+    pub id: i32,
+    pub path: &'a str,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StreamConfig<'a> {
     pub rtsp_url: &'a str,
+    pub record: bool,
+    pub flush_if_sec: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_file_dir_id: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive_sample_file_dir_id: Option<i32>,
 }
 
 #[derive(Serialize)]
@@ -125,6 +241,9 @@ pub enum PostSignalsTimeBase {
 pub struct LoginRequest<'a> {
     pub username: &'a str,
     pub password: String,
+
+    /// A TOTP code or recovery code, required iff the user is enrolled in TOTP.
+    pub totp_code: Option<&'a str>,
 }
 
 #[derive(Deserialize)]
@@ -156,6 +275,664 @@ pub struct Signals {
     pub states: Vec<u16>,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Holds<'a> {
+    pub holds: Vec<Hold<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hold<'a> {
+    pub id: i32,
+    pub start_time_90k: Time,
+    pub end_time_90k: Time,
+    pub reason: &'a str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creator: Option<&'a str>,
+    pub creation_time_90k: Time,
+}
+
+impl<'a> Hold<'a> {
+    pub fn wrap(h: &'a db::Hold) -> Self {
+        Hold {
+            id: h.id,
+            start_time_90k: h.time.start,
+            end_time_90k: h.time.end,
+            reason: &h.reason,
+            creator: h.creator.as_deref(),
+            creation_time_90k: h.creation_time,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostHoldRequest {
+    pub start_time_90k: Time,
+    pub end_time_90k: Time,
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostHoldResponse {
+    pub id: i32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Webhooks<'a> {
+    pub webhooks: Vec<Webhook<'a>>,
+}
+
+/// A webhook as returned by `GET /api/webhooks`. The secret is only returned on creation.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Webhook<'a> {
+    pub id: i32,
+    pub url: &'a str,
+    pub signals: bool,
+    pub signal_ids: &'a [u32],
+    pub signal_states: &'a [u16],
+    pub stream_health: bool,
+    pub disk_full: bool,
+}
+
+impl<'a> Webhook<'a> {
+    pub fn wrap(w: &'a db::webhook::Webhook) -> Self {
+        Webhook {
+            id: w.id,
+            url: &w.url,
+            signals: w.filter.signals,
+            signal_ids: &w.filter.signal_ids,
+            signal_states: &w.filter.signal_states,
+            stream_health: w.filter.stream_health,
+            disk_full: w.filter.disk_full,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostWebhookRequest {
+    pub url: String,
+
+    #[serde(default)]
+    pub signals: bool,
+
+    #[serde(default)]
+    pub signal_ids: Vec<u32>,
+
+    #[serde(default)]
+    pub signal_states: Vec<u16>,
+
+    #[serde(default)]
+    pub stream_health: bool,
+
+    #[serde(default)]
+    pub disk_full: bool,
+}
+
+impl PostWebhookRequest {
+    pub fn filter(self) -> (String, db::webhook::Filter) {
+        (
+            self.url,
+            db::webhook::Filter {
+                signals: self.signals,
+                signal_ids: self.signal_ids,
+                signal_states: self.signal_states,
+                stream_health: self.stream_health,
+                disk_full: self.disk_full,
+            },
+        )
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostWebhookResponse {
+    pub id: i32,
+    pub secret: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostDiscoveryRequest {
+    /// Credentials with which to query each device's profiles, if any.
+    pub username: Option<String>,
+    pub password: Option<String>,
+
+    pub timeout_sec: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Discovery {
+    pub devices: Vec<DiscoveredDevice>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiscoveredDevice {
+    pub endpoint: String,
+    pub onvif_host: Option<String>,
+    pub name: Option<String>,
+    pub hardware: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profiles: Option<Vec<Profile>>,
+
+    /// The error from querying the device's profiles, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// An ONVIF media profile, as returned by `POST /api/discovery`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub token: String,
+    pub name: String,
+    pub encoding: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub rtsp_url: String,
+
+    /// The stream type this profile would be used for by default, if any.
+    pub default_stream_type: Option<&'static str>,
+}
+
+impl Profile {
+    pub fn wrap_all(profiles: Vec<crate::onvif::Profile>) -> Vec<Self> {
+        let (main, sub) = crate::onvif::choose_profiles(&profiles);
+        let (main, sub) = (main.map(|p| p.token.clone()), sub.map(|p| p.token.clone()));
+        profiles
+            .into_iter()
+            .map(|p| Profile {
+                default_stream_type: if main.as_ref() == Some(&p.token) {
+                    Some("main")
+                } else if sub.as_ref() == Some(&p.token) {
+                    Some("sub")
+                } else {
+                    None
+                },
+                width: p.resolution.map(|r| r.0),
+                height: p.resolution.map(|r| r.1),
+                token: p.token,
+                name: p.name,
+                encoding: p.encoding,
+                rtsp_url: p.rtsp_url,
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostDiscoveryCameraRequest {
+    pub onvif_host: String,
+    pub username: String,
+    pub password: String,
+    pub short_name: String,
+
+    #[serde(default)]
+    pub description: String,
+
+    /// The tokens of the profiles to use; by default, as in `Profile::default_stream_type`.
+    pub main_profile: Option<String>,
+    pub sub_profile: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostExportRequest {
+    pub streams: Vec<ExportStream>,
+    pub start_time_90k: Time,
+    pub end_time_90k: Time,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportStream {
+    pub camera_uuid: Uuid,
+    pub stream_type: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostExportResponse {
+    pub id: Uuid,
+}
+
+/// A camera's full configuration, as supplied to `POST /api/cameras` or
+/// `PUT /api/cameras/<uuid>/`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CameraChange {
+    pub short_name: String,
+
+    #[serde(default)]
+    pub description: String,
+
+    #[serde(default)]
+    pub onvif_host: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+
+    #[serde(default)]
+    pub streams: StreamChanges,
+}
+
+#[derive(Default, Deserialize)]
+pub struct StreamChanges {
+    pub main: Option<StreamChange>,
+    pub sub: Option<StreamChange>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamChange {
+    pub sample_file_dir_id: Option<i32>,
+    pub rtsp_url: String,
+
+    #[serde(default)]
+    pub record: bool,
+
+    #[serde(default)]
+    pub flush_if_sec: i64,
+
+    #[serde(default)]
+    pub retain_bytes: i64,
+    pub retain_max_age_sec: Option<i64>,
+    pub retain_min_age_sec: Option<i64>,
+    pub archive_sample_file_dir_id: Option<i32>,
+    pub archive_age_sec: Option<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostCameraResponse {
+    pub uuid: Uuid,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Dirs<'a> {
+    pub dirs: Vec<Dir<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Dir<'a> {
+    pub id: i32,
+    pub path: &'a str,
+    pub uuid: Uuid,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostDirRequest {
+    pub path: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostDirResponse {
+    pub id: i32,
+}
+
+/// A user's or session's permissions, as in the `Permissions` protobuf message.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Permissions {
+    pub view_video: bool,
+    pub read_camera_configs: bool,
+    pub update_signals: bool,
+    pub manage_holds: bool,
+    pub update_config: bool,
+    pub admin_users: bool,
+
+    /// Cameras whose video may be viewed without `view_video`, which covers all cameras.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub view_video_cameras: Vec<Uuid>,
+}
+
+impl From<&db::Permissions> for Permissions {
+    fn from(p: &db::Permissions) -> Self {
+        Permissions {
+            view_video: p.view_video,
+            read_camera_configs: p.read_camera_configs,
+            update_signals: p.update_signals,
+            manage_holds: p.manage_holds,
+            update_config: p.update_config,
+            admin_users: p.admin_users,
+            view_video_cameras: p
+                .view_video_cameras
+                .iter()
+                .filter_map(|u| Uuid::from_slice(u).ok())
+                .collect(),
+        }
+    }
+}
+
+impl Permissions {
+    /// Returns true iff every permission granted by `self` is also granted by `other`.
+    pub fn is_subset_of(&self, other: &Permissions) -> bool {
+        (!self.view_video || other.view_video)
+            && (!self.read_camera_configs || other.read_camera_configs)
+            && (!self.update_signals || other.update_signals)
+            && (!self.manage_holds || other.manage_holds)
+            && (!self.update_config || other.update_config)
+            && (!self.admin_users || other.admin_users)
+            && (other.view_video
+                || self
+                    .view_video_cameras
+                    .iter()
+                    .all(|c| other.view_video_cameras.contains(c)))
+    }
+}
+
+impl From<Permissions> for db::Permissions {
+    fn from(p: Permissions) -> Self {
+        let mut out = db::Permissions::new();
+        out.view_video = p.view_video;
+        out.read_camera_configs = p.read_camera_configs;
+        out.update_signals = p.update_signals;
+        out.manage_holds = p.manage_holds;
+        out.update_config = p.update_config;
+        out.admin_users = p.admin_users;
+        out.view_video_cameras = p
+            .view_video_cameras
+            .iter()
+            .map(|u| u.as_bytes().to_vec())
+            .collect();
+        out
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Users<'a> {
+    pub users: Vec<User<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct User<'a> {
+    pub id: i32,
+    pub username: &'a str,
+    pub disabled: bool,
+    pub has_password: bool,
+    pub permissions: Permissions,
+    pub totp_enrolled: bool,
+}
+
+impl<'a> User<'a> {
+    pub fn wrap(u: &'a db::User) -> Self {
+        User {
+            id: u.id,
+            username: &u.username,
+            disabled: u.disabled(),
+            has_password: u.has_password(),
+            permissions: Permissions::from(&u.permissions),
+            totp_enrolled: u.totp_enrolled(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostUserRequest {
+    pub username: String,
+    pub password: Option<String>,
+
+    #[serde(default)]
+    pub disabled: bool,
+
+    #[serde(default)]
+    pub permissions: Permissions,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostUserResponse {
+    pub id: i32,
+}
+
+/// A change to a user, as supplied to `PATCH /api/users/<id>`. Omitted fields are unchanged.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatchUserRequest {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub disabled: Option<bool>,
+    pub permissions: Option<Permissions>,
+
+    /// If true, removes the user's TOTP enrollment, as when they've lost their authenticator and
+    /// recovery codes.
+    #[serde(default)]
+    pub clear_totp: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSessions<'a> {
+    pub sessions: Vec<UserSession<'a>>,
+}
+
+/// A session as listed by `GET /api/sessions`. Unlike `Session`, this describes someone else's
+/// session and so omits the CSRF token.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSession<'a> {
+    #[serde(serialize_with = "serialize_session_id")]
+    pub id: SessionHash,
+    pub user_id: i32,
+    pub username: &'a str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<&'a str>,
+    pub creation: SessionRequest<'a>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_use: Option<SessionRequest<'a>>,
+    pub use_count: i32,
+    pub permissions: Permissions,
+
+    #[serde(skip_serializing_if = "Not::not")]
+    pub api_token: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiration_time_sec: Option<i64>,
+}
+
+impl<'a> UserSession<'a> {
+    pub fn wrap(id: SessionHash, s: &'a db::Session, u: &'a db::User) -> Self {
+        UserSession {
+            id,
+            user_id: s.user_id,
+            username: &u.username,
+            description: s.description.as_deref(),
+            creation: SessionRequest::wrap(&s.creation),
+            last_use: match s.last_use.when_sec {
+                None => None,
+                Some(_) => Some(SessionRequest::wrap(&s.last_use)),
+            },
+            use_count: s.use_count,
+            permissions: Permissions::from(&s.permissions),
+            api_token: s.api_token(),
+            expiration_time_sec: s.expiration_time_sec,
+        }
+    }
+}
+
+/// Serializes a session id hash in URL-safe base64, for use in `/api/sessions/<id>` and
+/// `/api/tokens/<id>`.
+fn serialize_session_id<S>(id: &SessionHash, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&base64::encode_config(&id.0, base64::URL_SAFE_NO_PAD))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostTokenRequest {
+    pub name: String,
+
+    #[serde(default)]
+    pub permissions: Permissions,
+    pub expiration_time_sec: Option<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostTokenResponse {
+    #[serde(serialize_with = "serialize_session_id")]
+    pub id: SessionHash,
+
+    /// The token itself, in standard base64. This is the only time it's revealed.
+    pub token: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostTotpResponse {
+    /// An `otpauth://` URI with the new secret, suitable for display as a QR code.
+    pub uri: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PutTotpRequest<'a> {
+    pub code: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PutTotpResponse {
+    /// Single-use codes which can be supplied in place of a TOTP code. They can't be retrieved
+    /// again.
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteTotpRequest<'a> {
+    /// A current TOTP code or an unused recovery code.
+    pub code: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionRequest<'a> {
+    pub time_sec: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<std::borrow::Cow<'a, str>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_addr: Option<std::net::IpAddr>,
+}
+
+impl<'a> SessionRequest<'a> {
+    fn wrap(r: &'a db::Request) -> Self {
+        SessionRequest {
+            time_sec: r.when_sec,
+            user_agent: r.user_agent.as_deref().map(String::from_utf8_lossy),
+            peer_addr: r.addr,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEvents<'a> {
+    pub events: Vec<AuditEvent<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEvent<'a> {
+    pub time_sec: i64,
+    pub kind: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<&'a str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_addr: Option<std::net::IpAddr>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_uuid: Option<Uuid>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time_90k: Option<Time>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time_90k: Option<Time>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<&'a str>,
+}
+
+impl<'a> AuditEvent<'a> {
+    pub fn wrap(e: &'a db::audit::Event) -> Self {
+        AuditEvent {
+            time_sec: e.time_sec,
+            kind: e.kind.as_str(),
+            user_id: e.user_id,
+            username: e.username.as_deref(),
+            peer_addr: e.peer_addr,
+            camera_uuid: e.camera_uuid,
+            start_time_90k: e.time_90k.as_ref().map(|r| r.start),
+            end_time_90k: e.time_90k.as_ref().map(|r| r.end),
+            detail: e.detail.as_deref(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Export<'a> {
+    pub id: Uuid,
+    pub status: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub bytes_written: u64,
+    pub total_bytes: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creator: Option<&'a str>,
+    pub creation_time_90k: Time,
+}
+
+impl<'a> Export<'a> {
+    pub fn wrap(j: &'a crate::export::Job) -> Self {
+        use std::sync::atomic::Ordering;
+        let (status, error) = match j.status() {
+            crate::export::Status::Running => ("running", None),
+            crate::export::Status::Done => ("done", None),
+            crate::export::Status::Failed(e) => ("failed", Some(e)),
+        };
+        Export {
+            id: j.id,
+            status,
+            error,
+            bytes_written: j.progress.written.load(Ordering::Relaxed),
+            total_bytes: j.progress.total.load(Ordering::Relaxed),
+            creator: j.creator.as_deref(),
+            creation_time_90k: j.creation_time,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SignalType<'a> {
@@ -195,6 +972,7 @@ impl<'a> Camera<'a> {
                     password: c.password.as_deref(),
                 }),
             },
+            onvif_status: OnvifStatus::wrap(&c.onvif_status.lock()),
             streams: [
                 Stream::wrap(db, c.streams[0], include_days, include_config)?,
                 Stream::wrap(db, c.streams[1], include_days, include_config)?,
@@ -238,18 +1016,27 @@ impl<'a> Stream<'a> {
             .ok_or_else(|| format_err!("missing stream {}", id))?;
         Ok(Some(Stream {
             retain_bytes: s.retain_bytes,
+            retain_max_age_sec: s.retain_max_age_sec,
+            retain_min_age_sec: s.retain_min_age_sec,
+            archive_age_sec: s.archive_age_sec,
             min_start_time_90k: s.range.as_ref().map(|r| r.start),
             max_end_time_90k: s.range.as_ref().map(|r| r.end),
             total_duration_90k: s.duration,
             total_sample_file_bytes: s.sample_file_bytes,
             fs_bytes: s.fs_bytes,
+            retention_overage_bytes: s.retention_overage_bytes,
             days: if include_days { Some(s.days()) } else { None },
             config: match include_config {
                 false => None,
                 true => Some(StreamConfig {
                     rtsp_url: &s.rtsp_url,
+                    record: s.record,
+                    flush_if_sec: s.flush_if_sec,
+                    sample_file_dir_id: s.sample_file_dir_id,
+                    archive_sample_file_dir_id: s.archive_sample_file_dir_id,
                 }),
             },
+            status: StreamStatus::wrap(&s.status.lock()),
         }))
     }
 
@@ -388,16 +1175,21 @@ impl<'a> TopLevel<'a> {
     /// Serializes cameras as a list (rather than a map), optionally including the `days` and
     /// `cameras` fields.
     fn serialize_cameras<S>(
-        cameras: &(&db::LockedDatabase, bool, bool),
+        cameras: &(
+            &db::LockedDatabase,
+            bool,
+            bool,
+            &dyn Fn(&db::Camera) -> bool,
+        ),
         serializer: S,
     ) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let (db, include_days, include_config) = *cameras;
-        let cs = db.cameras_by_id();
+        let (db, include_days, include_config, filter) = *cameras;
+        let cs: Vec<_> = db.cameras_by_id().values().filter(|c| filter(c)).collect();
         let mut seq = serializer.serialize_seq(Some(cs.len()))?;
-        for c in cs.values() {
+        for c in cs {
             seq.serialize_element(
                 &Camera::wrap(c, db, include_days, include_config).map_err(S::Error::custom)?,
             )?;
@@ -446,6 +1238,10 @@ pub struct ListRecordings<'a> {
     // than dealing with a HashSet's code bloat.
     #[serde(serialize_with = "ListRecordings::serialize_video_sample_entries")]
     pub video_sample_entries: (&'a db::LockedDatabase, Vec<i32>),
+
+    // As above.
+    #[serde(serialize_with = "ListRecordings::serialize_audio_sample_entries")]
+    pub audio_sample_entries: (&'a db::LockedDatabase, Vec<i32>),
 }
 
 impl<'a> ListRecordings<'a> {
@@ -466,6 +1262,24 @@ impl<'a> ListRecordings<'a> {
         }
         map.end()
     }
+
+    fn serialize_audio_sample_entries<S>(
+        audio_sample_entries: &(&db::LockedDatabase, Vec<i32>),
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let (db, ref v) = *audio_sample_entries;
+        let mut map = serializer.serialize_map(Some(v.len()))?;
+        for id in v {
+            map.serialize_entry(
+                id,
+                &AudioSampleEntry::from(&db.audio_sample_entries_by_id().get(id).unwrap()),
+            )?;
+        }
+        map.end()
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -479,6 +1293,9 @@ pub struct Recording {
     pub start_id: i32,
     pub open_id: u32,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_sample_entry_id: Option<i32>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub first_uncommitted: Option<i32>,
 
@@ -487,6 +1304,17 @@ pub struct Recording {
 
     #[serde(skip_serializing_if = "Not::not")]
     pub growing: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnails: Option<Vec<Thumbnail>>,
+}
+
+/// A recording's thumbnail, as fetched via `/api/cameras/<uuid>/<type>/thumbnail.jpg`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Thumbnail {
+    pub recording_id: i32,
+    pub start_time_90k: i64,
 }
 
 #[derive(Debug, Serialize)]
@@ -508,3 +1336,21 @@ impl VideoSampleEntry {
         }
     }
 }
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioSampleEntry {
+    pub rfc6381_codec: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl AudioSampleEntry {
+    fn from(e: &db::AudioSampleEntry) -> Self {
+        Self {
+            rfc6381_codec: e.rfc6381_codec.clone(),
+            sample_rate: e.sample_rate,
+            channels: e.channels,
+        }
+    }
+}
@@ -11,14 +11,24 @@ use structopt::StructOpt;
 
 mod body;
 mod cmds;
+mod export;
 mod h264;
+mod h265;
+mod hls;
 mod json;
+mod motion;
 mod mp4;
+mod mqtt;
 mod onvif;
+mod rtsp_server;
 mod slices;
+mod snapshot;
 mod stream;
 mod streamer;
+mod supervisor;
+mod thumbnails;
 mod web;
+mod webhook;
 
 #[derive(StructOpt)]
 #[structopt(
@@ -27,12 +37,30 @@ mod web;
     global_settings(&[clap::AppSettings::ColoredHelp])
 )]
 enum Args {
+    /// Dumps the audit log of logins, video access, and configuration changes.
+    ///
+    /// This locks the database for shared access, so it can't run alongside a read-write server.
+    /// While the server is running, use `/api/audit` instead.
+    Audit(cmds::audit::Args),
+
     /// Checks database integrity (like fsck).
     Check(cmds::check::Args),
 
     /// Interactively edits configuration.
     Config(cmds::config::Args),
 
+    /// Finds ONVIF cameras on the local network and optionally adds one.
+    ///
+    /// This probes with WS-Discovery. Given credentials, it also lists each camera's media
+    /// profiles and the RTSP URLs a new camera would use.
+    Discover(cmds::discover::Args),
+
+    /// Exports a time range of one or more streams as a signed tar archive.
+    ///
+    /// The archive holds a `.mp4` file per run of recordings and a manifest of their hashes,
+    /// signed with the database's export signing key.
+    Export(cmds::export::Args),
+
     /// Initializes a database.
     Init(cmds::init::Args),
 
@@ -62,8 +90,11 @@ enum Args {
 impl Args {
     fn run(&self) -> Result<i32, failure::Error> {
         match self {
+            Args::Audit(ref a) => cmds::audit::run(a),
             Args::Check(ref a) => cmds::check::run(a),
             Args::Config(ref a) => cmds::config::run(a),
+            Args::Discover(ref a) => cmds::discover::run(a),
+            Args::Export(ref a) => cmds::export::run(a),
             Args::Init(ref a) => cmds::init::run(a),
             Args::Login(ref a) => cmds::login::run(a),
             Args::Run(ref a) => cmds::run::run(a),
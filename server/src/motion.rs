@@ -0,0 +1,395 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Built-in motion detection.
+//!
+//! Each stream configured in the `stream_motion` table has a worker thread. The stream's
+//! `Streamer` sends it each key frame (along with a few following frames, which some decoders
+//! need before producing output). The worker decodes a downscaled copy, compares its luma against
+//! a rolling background within the configured zones, and records the result as a state change
+//! of the configured signal.
+
+use crate::snapshot;
+use db::recording;
+use failure::{bail, format_err, Error};
+use fnv::FnvHashMap;
+use log::{info, warn};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+/// The width of the analyzed image, in pixels. The height preserves the aspect ratio.
+const ANALYSIS_WIDTH: u16 = 64;
+
+/// The minimum difference in luma (on a 0-255 scale) between a pixel and its background for the
+/// pixel to be considered changed.
+const PIXEL_THRESHOLD: f32 = 25.0;
+
+/// How quickly the background adapts to each new key frame, from 0 (never) to 1 (immediately).
+const BACKGROUND_ALPHA: f32 = 0.1;
+
+/// How long each observation is considered valid. If the stream stops, the signal reverts to
+/// unknown after this long.
+const HOLD: recording::Duration = recording::Duration(10 * recording::TIME_UNITS_PER_SEC);
+
+/// A key frame (and some of the frames following it) to analyze.
+struct Frames {
+    video_sample_entry_id: i32,
+    time: recording::Time,
+    lens: Vec<usize>,
+    data: Vec<u8>,
+}
+
+/// Sends a stream's key frames to its motion detection worker.
+pub struct Sender {
+    tx: mpsc::SyncSender<Frames>,
+    pending: Option<Frames>,
+}
+
+impl Sender {
+    /// Handles a video frame of the stream. Frames are dropped if the worker is still busy.
+    pub fn push(
+        &mut self,
+        video_sample_entry_id: i32,
+        time: recording::Time,
+        is_key: bool,
+        data: &[u8],
+    ) {
+        if is_key {
+            self.send();
+            self.pending = Some(Frames {
+                video_sample_entry_id,
+                time,
+                lens: Vec::with_capacity(snapshot::MAX_FRAMES),
+                data: Vec::new(),
+            });
+        }
+        let p = match self.pending.as_mut() {
+            Some(p) => p,
+            None => return,
+        };
+        p.lens.push(data.len());
+        p.data.extend_from_slice(data);
+        if p.lens.len() == snapshot::MAX_FRAMES {
+            self.send();
+        }
+    }
+
+    fn send(&mut self) {
+        if let Some(p) = self.pending.take() {
+            let _ = self.tx.try_send(p);
+        }
+    }
+}
+
+/// The set of running motion detection workers.
+pub struct Workers {
+    txs: FnvHashMap<i32, mpsc::SyncSender<Frames>>,
+    joins: Vec<thread::JoinHandle<()>>,
+}
+
+impl Workers {
+    /// Starts a worker for each stream with a valid motion detection configuration.
+    pub fn start(db: &Arc<db::Database>) -> Result<Self, Error> {
+        let l = db.lock();
+        let mut txs = FnvHashMap::default();
+        let mut joins = Vec::new();
+        for m in l.list_stream_motion()? {
+            let stream = l.streams_by_id().get(&m.stream_id).unwrap();
+            let camera = l.cameras_by_id().get(&stream.camera_id).unwrap();
+            let short_name = format!("{}-{}", camera.short_name, stream.type_.as_str());
+            let config = m
+                .zones
+                .as_deref()
+                .map_or(Ok(Vec::new()), parse_zones)
+                .and_then(|zones| Ok((zones, motion_states(&l, m.signal_id)?)));
+            let (zones, (moving, still)) = match config {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("{}: not starting motion detection: {}", short_name, e);
+                    continue;
+                }
+            };
+            let (tx, rx) = mpsc::sync_channel(1);
+            let mut worker = Worker {
+                db: db.clone(),
+                short_name,
+                signal_id: m.signal_id,
+                moving,
+                still,
+                detector: Detector::new(zones, m.sensitivity),
+                sample_entry: None,
+            };
+            info!("{}: starting motion detection", worker.short_name);
+            joins.push(
+                thread::Builder::new()
+                    .name(format!("m-{}", worker.short_name))
+                    .spawn(move || worker.run(rx))
+                    .expect("can't create thread"),
+            );
+            txs.insert(m.stream_id, tx);
+        }
+        Ok(Workers { txs, joins })
+    }
+
+    /// Returns a sender for the given stream, if it has motion detection.
+    pub fn sender(&self, stream_id: i32) -> Option<Sender> {
+        self.txs.get(&stream_id).map(|tx| Sender {
+            tx: tx.clone(),
+            pending: None,
+        })
+    }
+
+    /// Waits for all workers to finish. The workers stop once all their senders are dropped.
+    pub fn join(self) {
+        drop(self.txs);
+        for j in self.joins {
+            j.join().unwrap();
+        }
+    }
+}
+
+/// Returns the (moving, still) states of the given signal's type.
+fn motion_states(l: &db::LockedDatabase, signal_id: u32) -> Result<(u16, u16), Error> {
+    let signal = l
+        .signals_by_id()
+        .get(&signal_id)
+        .ok_or_else(|| format_err!("no such signal {}", signal_id))?;
+    let states = l
+        .signal_types_by_uuid()
+        .get(&signal.type_)
+        .map(|t| &t.states[..])
+        .unwrap_or(&[]);
+    let moving = states.iter().find(|s| s.motion);
+    let still = states.iter().find(|s| !s.motion);
+    match (moving, still) {
+        (Some(m), Some(s)) => Ok((m.value, s.value)),
+        _ => bail!(
+            "signal {}'s type {} lacks a moving and a still state",
+            signal_id,
+            signal.type_
+        ),
+    }
+}
+
+struct Worker {
+    db: Arc<db::Database>,
+    short_name: String,
+    signal_id: u32,
+    moving: u16,
+    still: u16,
+    detector: Detector,
+    sample_entry: Option<Arc<db::VideoSampleEntry>>,
+}
+
+impl Worker {
+    fn run(&mut self, rx: mpsc::Receiver<Frames>) {
+        while let Ok(f) = rx.recv() {
+            if let Err(e) = self.process(f) {
+                warn!(
+                    "{}: motion detection failed: {}",
+                    self.short_name,
+                    base::prettify_failure(&e)
+                );
+            }
+        }
+        info!("{}: motion detection shutting down", self.short_name);
+    }
+
+    fn process(&mut self, f: Frames) -> Result<(), Error> {
+        let sample_entry = match self.sample_entry {
+            Some(ref e) if e.id == f.video_sample_entry_id => e.clone(),
+            _ => {
+                let e = self
+                    .db
+                    .lock()
+                    .video_sample_entries_by_id()
+                    .get(&f.video_sample_entry_id)
+                    .ok_or_else(|| {
+                        format_err!("no such video sample entry {}", f.video_sample_entry_id)
+                    })?
+                    .clone();
+                self.sample_entry = Some(e.clone());
+                e
+            }
+        };
+        let img = snapshot::decode_rgb(&sample_entry.data, &f.lens, &f.data, Some(ANALYSIS_WIDTH))?;
+        let luma: Vec<u8> = img
+            .pixels
+            .chunks_exact(3)
+            .map(|p| {
+                ((77 * u32::from(p[0]) + 150 * u32::from(p[1]) + 29 * u32::from(p[2])) >> 8) as u8
+            })
+            .collect();
+        let state = match self.detector.observe(img.width, img.height, &luma) {
+            None => return Ok(()),
+            Some(true) => self.moving,
+            Some(false) => self.still,
+        };
+        self.db
+            .lock()
+            .update_signals(f.time..f.time + HOLD, &[self.signal_id], &[state])?;
+        Ok(())
+    }
+}
+
+/// A rectangle to analyze, in percentages of the frame's width and height.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Zone {
+    left: u8,
+    top: u8,
+    right: u8,
+    bottom: u8,
+}
+
+/// Parses zones in the format described in `schema.sql`, such as `0,0,50,100;50,50,100,100`.
+fn parse_zones(zones: &str) -> Result<Vec<Zone>, Error> {
+    zones
+        .split(';')
+        .filter(|z| !z.trim().is_empty())
+        .map(|z| {
+            let c = z
+                .split(',')
+                .map(|c| c.trim().parse::<u8>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| format_err!("bad zone {:?}", z))?;
+            if c.len() != 4 || c[0] >= c[2] || c[1] >= c[3] || c[2] > 100 || c[3] > 100 {
+                bail!("bad zone {:?}", z);
+            }
+            Ok(Zone {
+                left: c[0],
+                top: c[1],
+                right: c[2],
+                bottom: c[3],
+            })
+        })
+        .collect()
+}
+
+/// Compares successive frames' luma against a rolling background.
+struct Detector {
+    /// If empty, the whole frame.
+    zones: Vec<Zone>,
+
+    /// The fraction of analyzed pixels which must change for the frame to be considered moving.
+    min_changed: f32,
+
+    width: usize,
+    height: usize,
+
+    /// For each pixel, whether it's within a zone.
+    mask: Vec<bool>,
+
+    /// For each pixel, the background luma.
+    background: Vec<f32>,
+}
+
+impl Detector {
+    fn new(zones: Vec<Zone>, sensitivity: u8) -> Self {
+        Detector {
+            zones,
+            min_changed: f32::from(101 - sensitivity.max(1).min(100)) / 500.,
+            width: 0,
+            height: 0,
+            mask: Vec::new(),
+            background: Vec::new(),
+        }
+    }
+
+    /// Observes a frame, returning whether it's moving, or `None` if it's the first frame with
+    /// these dimensions (and thus there's no background to compare with).
+    fn observe(&mut self, width: usize, height: usize, luma: &[u8]) -> Option<bool> {
+        assert_eq!(luma.len(), width * height);
+        if (width, height) != (self.width, self.height) {
+            self.width = width;
+            self.height = height;
+            self.mask = (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    self.zones.is_empty()
+                        || self.zones.iter().any(|z| {
+                            100 * x >= usize::from(z.left) * width
+                                && 100 * x < usize::from(z.right) * width
+                                && 100 * y >= usize::from(z.top) * height
+                                && 100 * y < usize::from(z.bottom) * height
+                        })
+                })
+                .collect();
+            self.background = luma.iter().map(|&l| f32::from(l)).collect();
+            return None;
+        }
+        let mut analyzed = 0;
+        let mut changed = 0;
+        for ((&l, bg), &m) in luma.iter().zip(&mut self.background).zip(&self.mask) {
+            let l = f32::from(l);
+            if m {
+                analyzed += 1;
+                if (l - *bg).abs() > PIXEL_THRESHOLD {
+                    changed += 1;
+                }
+            }
+            *bg += BACKGROUND_ALPHA * (l - *bg);
+        }
+        Some(analyzed > 0 && changed as f32 > self.min_changed * analyzed as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_zones() {
+        assert_eq!(
+            parse_zones("0,0,50,100; 50,50,100,100;").unwrap(),
+            vec![
+                Zone {
+                    left: 0,
+                    top: 0,
+                    right: 50,
+                    bottom: 100
+                },
+                Zone {
+                    left: 50,
+                    top: 50,
+                    right: 100,
+                    bottom: 100
+                },
+            ]
+        );
+        assert_eq!(parse_zones("").unwrap(), vec![]);
+        parse_zones("0,0,50").unwrap_err();
+        parse_zones("50,0,50,100").unwrap_err();
+        parse_zones("0,0,101,100").unwrap_err();
+        parse_zones("a,0,50,100").unwrap_err();
+    }
+
+    #[test]
+    fn test_detector() {
+        // Only consider the left half of a 10x10 frame.
+        let mut d = Detector::new(parse_zones("0,0,50,100").unwrap(), 50);
+        let mut frame = vec![100u8; 100];
+        assert_eq!(d.observe(10, 10, &frame), None);
+        assert_eq!(d.observe(10, 10, &frame), Some(false));
+
+        // Changes on the right half are ignored, no matter how large.
+        for y in 0..10 {
+            for x in 5..10 {
+                frame[10 * y + x] = 200;
+            }
+        }
+        assert_eq!(d.observe(10, 10, &frame), Some(false));
+
+        // Sensitivity 50 needs over 10.2% of the 50 analyzed pixels to change: 5 isn't enough;
+        // 6 is.
+        for i in 0..5 {
+            frame[10 * i] = 200;
+        }
+        assert_eq!(d.observe(10, 10, &frame), Some(false));
+        frame[50] = 200;
+        assert_eq!(d.observe(10, 10, &frame), Some(true));
+
+        // A change in dimensions resets the background.
+        assert_eq!(d.observe(5, 5, &frame[0..25]), None);
+    }
+}
@@ -51,10 +51,26 @@
 //! ***** stsz (samples sizes (framing))
 //! ***** co64 (64-bit chunk offset)
 //!
+//! ** (optional) trak (audio: container for an individual track or stream)
+//! *** tkhd (track header, overall information about the track)
+//! *** mdia (container for the media information in a track)
+//! **** mdhd (media header, overall information about the media)
+//! *** minf (media information container)
+//! **** smhd (sound media header, overall information (sound track only))
+//! **** dinf (data information box, container)
+//! ***** dref (data reference box, declares source(s) of media data in track)
+//! **** stbl (sample table box, container for the time/space map)
+//! ***** stsd (sample descriptions (codec types, initilization etc.)
+//! ***** stts ((decoding) time-to-sample)
+//! ***** stsc (sample-to-chunk, partial data-offset information)
+//! ***** stsz (samples sizes (framing))
+//! ***** co64 (64-bit chunk offset)
+//!
 //! * mdat (media data container)
 //! ```
 
 use crate::body::{wrap_error, BoxedError, Chunk};
+use crate::h265;
 use crate::slices::{self, Slices};
 use base::{bail_t, format_err_t, Error, ErrorKind, ResultExt};
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
@@ -96,6 +112,18 @@ const NORMAL_FTYP_BOX: &[u8] = &[
     b'm', b'p', b'4', b'1', // compatible_brands[3]
 ];
 
+/// An `ftyp` (ISO/IEC 14496-12 section 4.3 `FileType`) box for H.265 video.
+/// This is the same as `NORMAL_FTYP_BOX` but without the `avc1` brand, which promises H.264.
+const NORMAL_H265_FTYP_BOX: &[u8] = &[
+    0x00, 0x00, 0x00, 0x1c, // length = 28, sizeof(NORMAL_H265_FTYP_BOX)
+    b'f', b't', b'y', b'p', // type
+    b'i', b's', b'o', b'm', // major_brand
+    0x00, 0x00, 0x00, 0x00, // minor_version
+    b'i', b's', b'o', b'm', // compatible_brands[0]
+    b'i', b's', b'o', b'2', // compatible_brands[1]
+    b'm', b'p', b'4', b'1', // compatible_brands[2]
+];
+
 /// An `ftyp` (ISO/IEC 14496-12 section 4.3 `FileType`) box for an initialization segment.
 /// More restrictive brands because of the default-base-is-moof flag.
 /// Eg ISO/IEC 14496-12 section A.2 says "NOTE The default‐base‐is‐moof flag
@@ -123,6 +151,19 @@ const VIDEO_HDLR_BOX: &[u8] = &[
     0x00, // name, zero-terminated (empty)
 ];
 
+/// An `hdlr` (ISO/IEC 14496-12 section 8.4.3 `HandlerBox`) box suitable for audio.
+const AUDIO_HDLR_BOX: &[u8] = &[
+    0x00, 0x00, 0x00, 0x21, // length == sizeof(kHdlrBox)
+    b'h', b'd', b'l', b'r', // type == hdlr, ISO/IEC 14496-12 section 8.4.3.
+    0x00, 0x00, 0x00, 0x00, // version + flags
+    0x00, 0x00, 0x00, 0x00, // pre_defined
+    b's', b'o', b'u', b'n', // handler = soun
+    0x00, 0x00, 0x00, 0x00, // reserved[0]
+    0x00, 0x00, 0x00, 0x00, // reserved[1]
+    0x00, 0x00, 0x00, 0x00, // reserved[2]
+    0x00, // name, zero-terminated (empty)
+];
+
 /// An `hdlr` (ISO/IEC 14496-12 section 8.4.3 `HandlerBox`) box suitable for subtitles.
 const SUBTITLE_HDLR_BOX: &[u8] = &[
     0x00, 0x00, 0x00, 0x21, // length == sizeof(kHdlrBox)
@@ -203,6 +244,28 @@ const VIDEO_MINF_JUNK: &[u8] = &[
     0x00, 0x00, 0x00, 0x01, // version=0, flags=self-contained
 ];
 
+/// Part of a `minf` (`MediaInformationBox`, ISO/IEC 14496-12 section 8.4.4), used from
+/// `append_audio_minf`.
+const AUDIO_MINF_JUNK: &[u8] = &[
+    b'm', b'i', b'n', b'f', // type = minf, ISO/IEC 14496-12 section 8.4.4.
+    // A smhd box.
+    0x00, 0x00, 0x00, 0x10, // length == sizeof(kSmhdBox)
+    b's', b'm', b'h', b'd', // type = smhd, ISO/IEC 14496-12 section 12.2.2.
+    0x00, 0x00, 0x00, 0x00, // version + flags
+    0x00, 0x00, 0x00, 0x00, // balance (centered) + reserved
+    // A dinf box suitable for a "self-contained" .mp4 file (no URL/URN
+    // references to external data).
+    0x00, 0x00, 0x00, 0x24, // length == sizeof(kDinfBox)
+    b'd', b'i', b'n', b'f', // type = dinf, ISO/IEC 14496-12 section 8.7.1.
+    0x00, 0x00, 0x00, 0x1c, // length
+    b'd', b'r', b'e', b'f', // type = dref, ISO/IEC 14496-12 section 8.7.2.
+    0x00, 0x00, 0x00, 0x00, // version and flags
+    0x00, 0x00, 0x00, 0x01, // entry_count
+    0x00, 0x00, 0x00, 0x0c, // length
+    b'u', b'r', b'l', b' ', // type = url, ISO/IEC 14496-12 section 8.7.2.
+    0x00, 0x00, 0x00, 0x01, // version=0, flags=self-contained
+];
+
 /// Part of a `minf` (`MediaInformationBox`, ISO/IEC 14496-12 section 8.4.4), used from
 /// `append_subtitle_minf`.
 const SUBTITLE_MINF_JUNK: &[u8] = &[
@@ -267,14 +330,17 @@ const SUBTITLE_STBL_JUNK: &[u8] = &[
 
 /// Pointers to each static bytestrings.
 /// The order here must match the `StaticBytestring` enum.
-const STATIC_BYTESTRINGS: [&[u8]; 9] = [
+const STATIC_BYTESTRINGS: [&[u8]; 12] = [
     NORMAL_FTYP_BOX,
+    NORMAL_H265_FTYP_BOX,
     INIT_SEGMENT_FTYP_BOX,
     VIDEO_HDLR_BOX,
+    AUDIO_HDLR_BOX,
     SUBTITLE_HDLR_BOX,
     MVHD_JUNK,
     TKHD_JUNK,
     VIDEO_MINF_JUNK,
+    AUDIO_MINF_JUNK,
     SUBTITLE_MINF_JUNK,
     SUBTITLE_STBL_JUNK,
 ];
@@ -285,12 +351,15 @@ const STATIC_BYTESTRINGS: [&[u8]; 9] = [
 #[derive(Copy, Clone, Debug)]
 enum StaticBytestring {
     NormalFtypBox,
+    NormalH265FtypBox,
     InitSegmentFtypBox,
     VideoHdlrBox,
+    AudioHdlrBox,
     SubtitleHdlrBox,
     MvhdJunk,
     TkhdJunk,
     VideoMinfJunk,
+    AudioMinfJunk,
     SubtitleMinfJunk,
     SubtitleStblJunk,
 }
@@ -310,6 +379,23 @@ struct SegmentLengths {
     stss: usize,
 }
 
+/// The audio portion of a `Segment`, computed in `FileBuilder::append`.
+#[derive(Debug)]
+struct SegmentAudio {
+    audio_sample_entry_id: i32,
+
+    /// The `(duration, bytes)` of each sample, with durations in units of the sample rate.
+    samples: Vec<(u32, u32)>,
+
+    /// The byte range of these samples within the recording's audio sample file.
+    sample_file_range: Range<u64>,
+
+    /// The time from the segment's start (as presented) to its first audio sample, in units of
+    /// the sample rate. If there are no samples, this is the segment's full duration.
+    /// `FileBuilder::close_audio_gaps` accounts for this time.
+    lead: u32,
+}
+
 /// A wrapper around `recording::Segment` that keeps some additional `.mp4`-specific state.
 struct Segment {
     /// The underlying segment (a portion of a recording).
@@ -338,6 +424,9 @@ struct Segment {
     /// The 1-indexed frame number in the `File` of the first frame in this segment.
     first_frame_num: u32,
     num_subtitle_samples: u16,
+
+    /// The audio samples covering this segment, if the recording has audio.
+    audio: Option<SegmentAudio>,
 }
 
 // Manually implement Debug because `index` and `index_once` are not Debug.
@@ -357,6 +446,7 @@ impl fmt::Debug for Segment {
             .field("rel_media_range_90k", &self.rel_media_range_90k)
             .field("first_frame_num", &self.first_frame_num)
             .field("num_subtitle_samples", &self.num_subtitle_samples)
+            .field("audio", &self.audio)
             .finish()
     }
 }
@@ -382,9 +472,69 @@ impl Segment {
             index_once: Once::new(),
             first_frame_num,
             num_subtitle_samples: 0,
+            audio: None,
         })
     }
 
+    /// Selects the audio samples which start within the segment's media range and fills in
+    /// `self.audio`. `start_90k` is the start of the range as presented: the actual start for
+    /// media segments, or the desired start when an edit list hides the leading video frames.
+    ///
+    /// Samples are placed relative to the video using the recording's audio start offset. The
+    /// final sample's duration is adjusted so the audio ends with the video.
+    fn init_audio(
+        &mut self,
+        db: &db::LockedDatabase,
+        audio_sample_entry: &db::AudioSampleEntry,
+        start_90k: i32,
+    ) -> Result<(), Error> {
+        let rate = i64::from(audio_sample_entry.sample_rate);
+        let end_90k = self.rel_media_range_90k.end;
+        let (mut samples, first_start_90k, sample_file_range) = db
+            .with_recording_playback(self.s.id, &mut |playback| {
+                let offset_90k = i64::from(playback.audio_start_offset_90k);
+                let mut it = recording::AudioIndexIterator::default();
+                let mut samples = Vec::new();
+                let mut first_start_90k = None;
+                let mut range: Option<Range<u64>> = None;
+                while it.next(playback.audio_index)? {
+                    let sample_start_90k =
+                        offset_90k + i64::from(it.start) * TIME_UNITS_PER_SEC / rate;
+                    if sample_start_90k < i64::from(start_90k) {
+                        continue;
+                    }
+                    if sample_start_90k >= i64::from(end_90k) {
+                        break;
+                    }
+                    first_start_90k.get_or_insert(sample_start_90k);
+                    samples.push((it.duration as u32, it.bytes as u32));
+                    let end = (it.pos + it.bytes) as u64;
+                    range = Some(match range {
+                        None => it.pos as u64..end,
+                        Some(r) => r.start..end,
+                    });
+                }
+                Ok((samples, first_start_90k, range.unwrap_or(0..0)))
+            })
+            .err_kind(ErrorKind::Unknown)?;
+        let lead_90k = first_start_90k.unwrap_or_else(|| i64::from(end_90k)) - i64::from(start_90k);
+        let lead = lead_90k * rate / TIME_UNITS_PER_SEC;
+        if let Some((last, rest)) = samples.split_last_mut() {
+            let target = i64::from(end_90k - start_90k) * rate / TIME_UNITS_PER_SEC - lead;
+            let before: i64 = rest.iter().map(|&(d, _)| i64::from(d)).sum();
+            if target - before > 0 {
+                last.0 = u32::try_from(target - before).unwrap();
+            }
+        }
+        self.audio = Some(SegmentAudio {
+            audio_sample_entry_id: audio_sample_entry.id,
+            samples,
+            sample_file_range,
+            lead: u32::try_from(lead).err_kind(ErrorKind::Internal)?,
+        });
+        Ok(())
+    }
+
     fn wall(&self, rel_media_90k: i32) -> i32 {
         rescale(
             rel_media_90k,
@@ -631,16 +781,33 @@ pub struct FileBuilder {
     /// appear in the video.
     segments: Vec<Segment>,
     video_sample_entries: SmallVec<[Arc<db::VideoSampleEntry>; 1]>,
+
+    /// The audio sample entry, if the file has an audio track. For normal files and media
+    /// segments, this is set by `append` and cleared by `build` unless every segment has audio
+    /// with this entry.
+    audio_sample_entry: Option<Arc<db::AudioSampleEntry>>,
     next_frame_num: u32,
 
     /// The total media time, after applying edit lists (if applicable) to skip unwanted portions.
     media_duration_90k: u64,
     num_subtitle_samples: u32,
     subtitle_co64_pos: Option<usize>,
+
+    /// The position within `body.buf` of the audio track's chunk offsets (`co64` entries) or,
+    /// in a media segment, of the audio `trun`'s data offset.
+    audio_offsets_pos: Option<usize>,
+
+    /// The time before the audio track's first sample, in units of the sample rate. Set by
+    /// `build` via `close_audio_gaps`.
+    audio_lead: u32,
     body: BodyState,
     type_: Type,
     prev_media_duration_and_cur_runs: Option<(recording::Duration, i32)>,
     include_timestamp_subtitle_track: bool,
+    include_base_media_decode_time: bool,
+
+    /// The video track's `tfdt` value, set by `build` if `include_base_media_decode_time`.
+    base_media_decode_time_90k: Option<u64>,
     content_disposition: Option<HeaderValue>,
 }
 
@@ -682,6 +849,7 @@ enum SliceType {
     VideoSampleData = 7,    // param is index into m.segments
     SubtitleSampleData = 8, // param is index into m.segments
     Truns = 9,              // param is index into m.segments
+    AudioSampleData = 10,   // param is index into m.segments
 
                             // There must be no value > 15, as this is packed into 4 bits in Slice.
 }
@@ -804,6 +972,7 @@ impl slices::Slice for Slice {
             SliceType::VideoSampleData => return f.0.get_video_sample_data(p, range.clone()),
             SliceType::SubtitleSampleData => f.0.get_subtitle_sample_data(p, range.clone(), len),
             SliceType::Truns => self.wrap_truns(f, range.clone(), len as usize),
+            SliceType::AudioSampleData => return f.0.get_audio_sample_data(p, range.clone()),
         };
         Box::new(stream::once(futures::future::ready(
             res.map_err(wrap_error).and_then(move |c| {
@@ -872,10 +1041,13 @@ impl FileBuilder {
         FileBuilder {
             segments: Vec::new(),
             video_sample_entries: SmallVec::new(),
+            audio_sample_entry: None,
             next_frame_num: 1,
             media_duration_90k: 0,
             num_subtitle_samples: 0,
             subtitle_co64_pos: None,
+            audio_offsets_pos: None,
+            audio_lead: 0,
             body: BodyState {
                 slices: Slices::new(),
                 buf: Vec::new(),
@@ -883,6 +1055,8 @@ impl FileBuilder {
             },
             type_,
             include_timestamp_subtitle_track: false,
+            include_base_media_decode_time: false,
+            base_media_decode_time_90k: None,
             content_disposition: None,
             prev_media_duration_and_cur_runs: None,
         }
@@ -904,6 +1078,20 @@ impl FileBuilder {
         Ok(())
     }
 
+    /// Sets if a media segment's `tfdt` boxes should place it within the stream's media timeline
+    /// (the total media duration of all prior recordings of the stream, plus the segment's start
+    /// within its recording). HLS players require this. Default is false, which writes zero.
+    pub fn include_base_media_decode_time(&mut self, b: bool) -> Result<(), Error> {
+        if b && self.type_ != Type::MediaSegment {
+            bail_t!(
+                InvalidArgument,
+                "base media decode time is only supported on media segments"
+            );
+        }
+        self.include_base_media_decode_time = b;
+        Ok(())
+    }
+
     /// Reserves space for the given number of additional segments.
     pub fn reserve(&mut self, additional: usize) {
         self.segments.reserve(additional);
@@ -913,6 +1101,11 @@ impl FileBuilder {
         self.video_sample_entries.push(ent);
     }
 
+    /// Sets the audio sample entry of an initialization segment.
+    pub fn append_audio_sample_entry(&mut self, ent: Arc<db::AudioSampleEntry>) {
+        self.audio_sample_entry = Some(ent);
+    }
+
     /// Appends a segment for (a subset of) the given recording.
     /// `rel_media_range_90k` is the media time range within the recording.
     /// Eg `0 .. row.media_duration_90k` means the full recording.
@@ -939,13 +1132,24 @@ impl FileBuilder {
                 .prev_media_duration_and_runs
                 .map(|(d, r)| (d, r + if row.open_id == 0 { 1 } else { 0 }));
         }
-        let s = Segment::new(
+        let mut s = Segment::new(
             db,
             &row,
             rel_media_range_90k,
             self.next_frame_num,
             start_at_key,
         )?;
+        if let Some(id) = row.audio_sample_entry_id {
+            let ase = db.audio_sample_entries_by_id().get(&id).unwrap().clone();
+            let start_90k = match self.type_ {
+                Type::MediaSegment => s.s.actual_start_90k(),
+                _ => s.rel_media_range_90k.start,
+            };
+            s.init_audio(db, &ase, start_90k)?;
+            if self.audio_sample_entry.is_none() {
+                self.audio_sample_entry = Some(ase);
+            }
+        }
 
         self.next_frame_num += s.s.frames as u32;
         self.segments.push(s);
@@ -987,6 +1191,24 @@ impl FileBuilder {
             etag.update(b":cd:");
             etag.update(cd.as_bytes());
         }
+        if self.type_ != Type::InitSegment {
+            let audio_id = self.audio_sample_entry.as_ref().map(|e| e.id);
+            if self
+                .segments
+                .iter()
+                .any(|s| s.audio.as_ref().map(|a| a.audio_sample_entry_id) != audio_id)
+            {
+                // Audio is only included when it covers every segment with the same codec.
+                self.audio_sample_entry = None;
+            }
+        }
+        if let Some(e) = self.audio_sample_entry.as_ref() {
+            etag.update(b":audio:");
+            etag.update(&e.id.to_be_bytes()[..]);
+            if self.type_ != Type::InitSegment {
+                self.audio_lead = self.close_audio_gaps();
+            }
+        }
         match self.type_ {
             Type::Normal => {}
             Type::InitSegment => {
@@ -996,6 +1218,19 @@ impl FileBuilder {
                 etag.update(b":media:");
             }
         };
+        if self.include_base_media_decode_time {
+            let prev = match (self.prev_media_duration_and_cur_runs, self.segments.first()) {
+                (Some((d, _)), Some(s)) => d.0 + i64::from(s.s.actual_start_90k()),
+                _ => bail_t!(
+                    FailedPrecondition,
+                    "base media decode time requires a recording with known prior media duration"
+                ),
+            };
+            let t = u64::try_from(prev).err_kind(ErrorKind::Internal)?;
+            etag.update(b":bmdt:");
+            etag.update(&t.to_be_bytes()[..]);
+            self.base_media_decode_time_90k = Some(t);
+        }
         for s in &mut self.segments {
             let md = &s.rel_media_range_90k;
 
@@ -1051,6 +1286,9 @@ impl FileBuilder {
         if self.include_timestamp_subtitle_track {
             est_slices += 16 + self.segments.len();
         }
+        if self.audio_sample_entry.is_some() {
+            est_slices += 16 + self.segments.len();
+        }
         self.body.slices.reserve(est_slices);
         const EST_BUF_LEN: usize = 2048;
         self.body.buf.reserve(EST_BUF_LEN);
@@ -1079,7 +1317,15 @@ impl FileBuilder {
                 0
             }
             Type::Normal => {
-                self.body.append_static(StaticBytestring::NormalFtypBox)?;
+                let is_h265 = self
+                    .video_sample_entries
+                    .iter()
+                    .any(|e| h265::is_h265_sample_entry(&e.data));
+                self.body.append_static(if is_h265 {
+                    StaticBytestring::NormalH265FtypBox
+                } else {
+                    StaticBytestring::NormalFtypBox
+                })?;
                 self.append_moov(creation_ts)?;
                 self.append_normal_mdat()?
             }
@@ -1123,6 +1369,7 @@ impl FileBuilder {
             slices: self.body.slices,
             buf: self.body.buf,
             video_sample_entries: self.video_sample_entries,
+            audio_sample_entry: self.audio_sample_entry,
             initial_sample_byte_pos,
             last_modified,
             etag: HeaderValue::try_from(format!("\"{}\"", etag.to_hex().as_str()))
@@ -1139,6 +1386,29 @@ impl FileBuilder {
             self.body
                 .append_slice(r.end - r.start, SliceType::VideoSampleData, i)?;
         }
+        if let Some(mut p) = self.audio_offsets_pos {
+            if self.type_ == Type::MediaSegment {
+                // A single trun's data_offset, relative to the start of the moof.
+                let pos = u32::try_from(self.body.slices.len()).map_err(|_| {
+                    format_err_t!(InvalidArgument, "media segment is larger than 4 GiB")
+                })?;
+                BigEndian::write_u32(&mut self.body.buf[p..p + 4], pos);
+            }
+            for (i, s) in self.segments.iter().enumerate() {
+                let a = s.audio.as_ref().unwrap();
+                if a.samples.is_empty() {
+                    continue;
+                }
+                if self.type_ != Type::MediaSegment {
+                    // One co64 entry per (nonempty) segment.
+                    BigEndian::write_u64(&mut self.body.buf[p..p + 8], self.body.slices.len());
+                    p += 8;
+                }
+                let r = &a.sample_file_range;
+                self.body
+                    .append_slice(r.end - r.start, SliceType::AudioSampleData, i)?;
+            }
+        }
         if let Some(p) = self.subtitle_co64_pos {
             BigEndian::write_u64(&mut self.body.buf[p..p + 8], self.body.slices.len());
             for (i, s) in self.segments.iter().enumerate() {
@@ -1206,6 +1476,9 @@ impl FileBuilder {
             if self.include_timestamp_subtitle_track {
                 self.append_subtitle_trak(creation_ts)?;
             }
+            if self.audio_sample_entry.is_some() {
+                self.append_audio_trak(creation_ts)?;
+            }
             if self.type_ == Type::InitSegment {
                 self.append_mvex()?;
             }
@@ -1236,6 +1509,19 @@ impl FileBuilder {
                                             // sample_degradation_priority: 0
                 ]);
             })?;
+
+            // ...and for the audio track, where every sample is a sync sample.
+            if self.audio_sample_entry.is_some() {
+                let track_id = self.audio_track_id();
+                write_length!(self, {
+                    self.body.buf.extend_from_slice(b"trex\x00\x00\x00\x00");
+                    self.body.append_u32(track_id);
+                    self.body.append_u32(1); // default_sample_description_index
+                    self.body.append_u32(0); // default_sample_duration
+                    self.body.append_u32(0); // default_sample_size
+                    self.body.append_u32(0); // default_sample_flags
+                })?;
+            }
         })
     }
 
@@ -1269,14 +1555,76 @@ impl FileBuilder {
                 // Fragment Base Media Decode Time Box, if present, shall be
                 // positioned after the Track Fragment Header Box and before the
                 // first Track Fragment Run box." Safari cares deeply that this rule is followed.
-                write_length!(self, {
-                    self.body.buf.extend_from_slice(&[
-                        b't', b'f', b'd', b't', 0x00, 0x00, 0x00, 0x00, // version + flags
-                        0x00, 0x00, 0x00, 0x00, // TODO: baseMediaDecodeTime
-                    ]);
-                })?;
+                self.append_tfdt(self.base_media_decode_time_90k)?;
                 self.append_truns()?;
             })?;
+
+            if self.audio_sample_entry.is_some() {
+                self.append_audio_traf()?;
+            }
+        })
+    }
+
+    /// Appends a `TrackFragmentBox` (ISO/IEC 14496-12 section 8.8.6) for the audio track, with
+    /// a single `trun` covering all segments. The data offset is filled in by
+    /// `append_mdat_contents`.
+    fn append_audio_traf(&mut self) -> Result<(), Error> {
+        let track_id = self.audio_track_id();
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"traf");
+            write_length!(self, {
+                // version + flags (default-base-is-moof)
+                self.body.buf.extend_from_slice(b"tfhd\x00\x02\x00\x00");
+                self.body.append_u32(track_id);
+            })?;
+            let sample_rate = u64::from(self.audio_sample_entry.as_ref().unwrap().sample_rate);
+            let t = match (self.base_media_decode_time_90k, self.audio_lead) {
+                (None, 0) => None,
+                (t, lead) => Some(
+                    t.map(|t| t * sample_rate / TIME_UNITS_PER_SEC as u64)
+                        .unwrap_or(0)
+                        + u64::from(lead),
+                ),
+            };
+            self.append_tfdt(t)?;
+            write_length!(self, {
+                // version 0, tr_flags:
+                // 0x000001 data-offset-present
+                // 0x000100 sample-duration-present
+                // 0x000200 sample-size-present
+                self.body.buf.extend_from_slice(b"trun\x00\x00\x03\x01");
+                let sample_count = self
+                    .segments
+                    .iter()
+                    .map(|s| s.audio.as_ref().unwrap().samples.len())
+                    .sum::<usize>();
+                self.body.append_u32(u32::try_from(sample_count).unwrap());
+                self.audio_offsets_pos = Some(self.body.buf.len());
+                self.body.append_u32(0); // placeholder for data_offset
+                for s in &self.segments {
+                    for &(duration, bytes) in &s.audio.as_ref().unwrap().samples {
+                        self.body.append_u32(duration);
+                        self.body.append_u32(bytes);
+                    }
+                }
+            })?;
+        })
+    }
+
+    /// Appends a `TrackFragmentBaseMediaDecodeTimeBox` (ISO/IEC 14496-12 section 8.8.12) with
+    /// the given time in the track's timescale, or zero if unknown.
+    fn append_tfdt(&mut self, base_media_decode_time: Option<u64>) -> Result<(), Error> {
+        write_length!(self, {
+            match base_media_decode_time {
+                None => self.body.buf.extend_from_slice(&[
+                    b't', b'f', b'd', b't', 0x00, 0x00, 0x00, 0x00, // version + flags
+                    0x00, 0x00, 0x00, 0x00, // baseMediaDecodeTime
+                ]),
+                Some(t) => {
+                    self.body.buf.extend_from_slice(b"tfdt\x01\x00\x00\x00");
+                    self.body.append_u64(t);
+                }
+            }
         })
     }
 
@@ -1289,6 +1637,30 @@ impl FileBuilder {
         Ok(())
     }
 
+    /// Accounts for the time before each segment's first audio sample, so that audio stays in
+    /// sync with video across segments. The gap is added to the duration of the preceding audio
+    /// sample; the gap before the track's very first sample is returned instead, to be expressed
+    /// through an edit list or the `tfdt`.
+    fn close_audio_gaps(&mut self) -> u32 {
+        let mut initial = None;
+        let mut pending = 0;
+        let mut prev: Option<&mut (u32, u32)> = None;
+        for s in &mut self.segments {
+            let a = s.audio.as_mut().unwrap();
+            pending += a.lead;
+            if a.samples.is_empty() {
+                continue;
+            }
+            match prev {
+                Some(p) => p.0 += pending,
+                None => initial = Some(pending),
+            }
+            pending = 0;
+            prev = a.samples.last_mut();
+        }
+        initial.unwrap_or(0)
+    }
+
     /// Appends a `MovieHeaderBox` version 1 (ISO/IEC 14496-12 section 8.2.2).
     fn append_mvhd(&mut self, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
@@ -1299,11 +1671,13 @@ impl FileBuilder {
             let d = self.media_duration_90k;
             self.body.append_u64(d);
             self.body.append_static(StaticBytestring::MvhdJunk)?;
-            let next_track_id = if self.include_timestamp_subtitle_track {
-                3
-            } else {
-                2
-            };
+            let mut next_track_id = 2;
+            if self.include_timestamp_subtitle_track {
+                next_track_id += 1;
+            }
+            if self.audio_sample_entry.is_some() {
+                next_track_id += 1;
+            }
             self.body.append_u32(next_track_id);
         })
     }
@@ -1318,6 +1692,25 @@ impl FileBuilder {
         })
     }
 
+    /// Returns the track id of the audio track: after video and (if present) subtitles.
+    fn audio_track_id(&self) -> u32 {
+        if self.include_timestamp_subtitle_track {
+            3
+        } else {
+            2
+        }
+    }
+
+    /// Appends a `TrackBox` (ISO/IEC 14496-12 section 8.3.1) suitable for audio.
+    fn append_audio_trak(&mut self, creation_ts: u32) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"trak");
+            self.append_audio_tkhd(creation_ts)?;
+            self.maybe_append_audio_edts()?;
+            self.append_audio_mdia(creation_ts)?;
+        })
+    }
+
     /// Appends a `TrackBox` (ISO/IEC 14496-12 section 8.3.1) suitable for subtitles.
     fn append_subtitle_trak(&mut self, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
@@ -1352,6 +1745,40 @@ impl FileBuilder {
         })
     }
 
+    /// Appends a `TrackHeaderBox` (ISO/IEC 14496-12 section 8.3.2) suitable for audio.
+    fn append_audio_tkhd(&mut self, creation_ts: u32) -> Result<(), Error> {
+        let track_id = self.audio_track_id();
+        write_length!(self, {
+            // flags 7: track_enabled | track_in_movie | track_in_preview
+            self.body.buf.extend_from_slice(b"tkhd\x01\x00\x00\x07");
+            self.body.append_u64(creation_ts as u64);
+            self.body.append_u64(creation_ts as u64);
+            self.body.append_u32(track_id);
+            self.body.append_u32(0); // reserved
+            self.body.append_u64(self.media_duration_90k);
+
+            // Unlike TKHD_JUNK, this has a full volume, as befits an audio track.
+            #[rustfmt::skip]
+            self.body.buf.extend_from_slice(&[
+                0x00, 0x00, 0x00, 0x00, // reserved
+                0x00, 0x00, 0x00, 0x00, // reserved
+                0x00, 0x00, 0x00, 0x00, // layer + alternate_group
+                0x01, 0x00, 0x00, 0x00, // volume + reserved
+                0x00, 0x01, 0x00, 0x00, // matrix[0]
+                0x00, 0x00, 0x00, 0x00, // matrix[1]
+                0x00, 0x00, 0x00, 0x00, // matrix[2]
+                0x00, 0x00, 0x00, 0x00, // matrix[3]
+                0x00, 0x01, 0x00, 0x00, // matrix[4]
+                0x00, 0x00, 0x00, 0x00, // matrix[5]
+                0x00, 0x00, 0x00, 0x00, // matrix[6]
+                0x00, 0x00, 0x00, 0x00, // matrix[7]
+                0x40, 0x00, 0x00, 0x00, // matrix[8]
+            ]);
+            self.body.append_u32(0); // width, unused.
+            self.body.append_u32(0); // height, unused.
+        })
+    }
+
     /// Appends a `TrackHeaderBox` (ISO/IEC 14496-12 section 8.3.2) suitable for subtitles.
     fn append_subtitle_tkhd(&mut self, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
@@ -1432,31 +1859,94 @@ impl FileBuilder {
     fn append_video_mdia(&mut self, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"mdia");
-            self.append_mdhd(creation_ts)?;
+            self.append_mdhd(
+                creation_ts,
+                TIME_UNITS_PER_SEC as u32,
+                self.media_duration_90k,
+            )?;
             self.body.append_static(StaticBytestring::VideoHdlrBox)?;
             self.append_video_minf()?;
         })
     }
 
+    /// Appends an `EditBox` (ISO/IEC 14496-12 section 8.6.5) suitable for audio, if the first
+    /// audio sample starts after the video: an empty edit for the gap, then the audio media.
+    fn maybe_append_audio_edts(&mut self) -> Result<(), Error> {
+        if self.audio_lead == 0 {
+            return Ok(()); // use implicit one-to-one mapping.
+        }
+        let rate = u64::from(self.audio_sample_entry.as_ref().unwrap().sample_rate);
+        let media_duration: u64 = self
+            .segments
+            .iter()
+            .flat_map(|s| s.audio.as_ref().unwrap().samples.iter())
+            .map(|&(d, _)| u64::from(d))
+            .sum();
+        let per_sec = TIME_UNITS_PER_SEC as u64;
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"edts");
+            write_length!(self, {
+                // Use version 1 for 64-bit times.
+                self.body.buf.extend_from_slice(b"elst\x01\x00\x00\x00");
+                self.body.append_u32(2);
+
+                // The empty edit, with media_time -1.
+                self.body
+                    .append_u64(u64::from(self.audio_lead) * per_sec / rate);
+                self.body.append_u64(u64::max_value());
+                self.body.buf.extend_from_slice(b"\x00\x01\x00\x00");
+
+                self.body.append_u64(media_duration * per_sec / rate);
+                self.body.append_u64(0);
+                self.body.buf.extend_from_slice(b"\x00\x01\x00\x00");
+            })?;
+        })
+    }
+
+    /// Appends a `MediaBox` (ISO/IEC 14496-12 section 8.4.1) suitable for audio.
+    fn append_audio_mdia(&mut self, creation_ts: u32) -> Result<(), Error> {
+        let timescale = self.audio_sample_entry.as_ref().unwrap().sample_rate;
+        let duration = self
+            .segments
+            .iter()
+            .flat_map(|s| s.audio.as_ref().unwrap().samples.iter())
+            .map(|&(d, _)| u64::from(d))
+            .sum();
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"mdia");
+            self.append_mdhd(creation_ts, timescale, duration)?;
+            self.body.append_static(StaticBytestring::AudioHdlrBox)?;
+            self.append_audio_minf()?;
+        })
+    }
+
     /// Appends a `MediaBox` (ISO/IEC 14496-12 section 8.4.1) suitable for subtitles.
     fn append_subtitle_mdia(&mut self, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"mdia");
-            self.append_mdhd(creation_ts)?;
+            self.append_mdhd(
+                creation_ts,
+                TIME_UNITS_PER_SEC as u32,
+                self.media_duration_90k,
+            )?;
             self.body.append_static(StaticBytestring::SubtitleHdlrBox)?;
             self.append_subtitle_minf()?;
         })
     }
 
-    /// Appends a `MediaHeaderBox` (ISO/IEC 14496-12 section 8.4.2) suitable for either the video
-    /// or subtitle track.
-    fn append_mdhd(&mut self, creation_ts: u32) -> Result<(), Error> {
+    /// Appends a `MediaHeaderBox` (ISO/IEC 14496-12 section 8.4.2) suitable for any track.
+    fn append_mdhd(
+        &mut self,
+        creation_ts: u32,
+        timescale: u32,
+        duration: u64,
+    ) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"mdhd\x01\x00\x00\x00");
             self.body.append_u64(u64::from(creation_ts));
             self.body.append_u64(u64::from(creation_ts));
-            self.body.append_u32(TIME_UNITS_PER_SEC as u32);
-            self.body.append_u64(self.media_duration_90k);
+            self.body.append_u32(timescale);
+            self.body.append_u64(duration);
             self.body.append_u32(0x55c40000); // language=und + pre_defined
         })
     }
@@ -1469,6 +1959,14 @@ impl FileBuilder {
         })
     }
 
+    /// Appends a `MediaInformationBox` (ISO/IEC 14496-12 section 8.4.4) suitable for audio.
+    fn append_audio_minf(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.append_static(StaticBytestring::AudioMinfJunk)?;
+            self.append_audio_stbl()?;
+        })
+    }
+
     /// Appends a `MediaInformationBox` (ISO/IEC 14496-12 section 8.4.4) suitable for subtitles.
     fn append_subtitle_minf(&mut self) -> Result<(), Error> {
         write_length!(self, {
@@ -1493,6 +1991,94 @@ impl FileBuilder {
         })
     }
 
+    /// Appends a `SampleTableBox` (ISO/IEC 14496-12 section 8.5.1) suitable for audio.
+    /// Each segment with audio samples is a single chunk. Unlike the video track, the tables are
+    /// written directly into the buffer; audio samples are comparatively few.
+    fn append_audio_stbl(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"stbl");
+
+            // SampleDescriptionBox (ISO/IEC 14496-12 section 8.5.2).
+            write_length!(self, {
+                self.body
+                    .buf
+                    .extend_from_slice(b"stsd\x00\x00\x00\x00\x00\x00\x00\x01");
+                let e = self.audio_sample_entry.as_ref().unwrap();
+                self.body.buf.extend_from_slice(&e.data);
+            })?;
+
+            // In an initialization segment, there are no segments; the tables are empty.
+            let audio: Vec<&SegmentAudio> = self
+                .segments
+                .iter()
+                .map(|s| s.audio.as_ref().unwrap())
+                .filter(|a| !a.samples.is_empty())
+                .collect();
+
+            // TimeToSampleBox (ISO/IEC 14496-12 section 8.6.1), run-length encoded.
+            write_length!(self, {
+                self.body.buf.extend_from_slice(b"stts\x00\x00\x00\x00");
+                let entry_count_pos = self.body.buf.len();
+                self.body.append_u32(0); // placeholder for entry_count
+                let mut entries: Vec<(u32, u32)> = Vec::new();
+                for &(duration, _) in audio.iter().flat_map(|a| a.samples.iter()) {
+                    if let Some((count, d)) = entries.last_mut() {
+                        if *d == duration {
+                            *count += 1;
+                            continue;
+                        }
+                    }
+                    entries.push((1, duration));
+                }
+                for &(count, duration) in &entries {
+                    self.body.append_u32(count);
+                    self.body.append_u32(duration);
+                }
+                BigEndian::write_u32(
+                    &mut self.body.buf[entry_count_pos..entry_count_pos + 4],
+                    u32::try_from(entries.len()).unwrap(),
+                );
+            })?;
+
+            // SampleToChunkBox (ISO/IEC 14496-12 section 8.7.4).
+            write_length!(self, {
+                self.body.buf.extend_from_slice(b"stsc\x00\x00\x00\x00");
+                self.body.append_u32(u32::try_from(audio.len()).unwrap());
+                for (i, a) in audio.iter().enumerate() {
+                    self.body.append_u32(u32::try_from(i + 1).unwrap()); // first_chunk
+                    self.body
+                        .append_u32(u32::try_from(a.samples.len()).unwrap());
+                    self.body.append_u32(1); // sample_description_index
+                }
+            })?;
+
+            // SampleSizeBox (ISO/IEC 14496-12 section 8.7.3).
+            write_length!(self, {
+                self.body
+                    .buf
+                    .extend_from_slice(b"stsz\x00\x00\x00\x00\x00\x00\x00\x00");
+                let sample_count: usize = audio.iter().map(|a| a.samples.len()).sum();
+                self.body.append_u32(u32::try_from(sample_count).unwrap());
+                for &(_, bytes) in audio.iter().flat_map(|a| a.samples.iter()) {
+                    self.body.append_u32(bytes);
+                }
+            })?;
+
+            // ChunkLargeOffsetBox (ISO/IEC 14496-12 section 8.7.5).
+            // Write placeholders; the actual values will be filled in later.
+            write_length!(self, {
+                self.body.buf.extend_from_slice(b"co64\x00\x00\x00\x00");
+                self.body.append_u32(u32::try_from(audio.len()).unwrap());
+                if self.type_ == Type::Normal {
+                    self.audio_offsets_pos = Some(self.body.buf.len());
+                }
+                for _ in 0..audio.len() {
+                    self.body.append_u64(0);
+                }
+            })?;
+        })
+    }
+
     /// Appends a `SampleTableBox` (ISO/IEC 14496-12 section 8.5.1) suitable for subtitles.
     fn append_subtitle_stbl(&mut self) -> Result<(), Error> {
         write_length!(self, {
@@ -1767,6 +2353,7 @@ struct FileInner {
     slices: Slices<Slice>,
     buf: Vec<u8>,
     video_sample_entries: SmallVec<[Arc<db::VideoSampleEntry>; 1]>,
+    audio_sample_entry: Option<Arc<db::AudioSampleEntry>>,
     initial_sample_byte_pos: u64,
     last_modified: SystemTime,
     etag: HeaderValue,
@@ -1817,6 +2404,25 @@ impl FileInner {
         Box::new(f.map_ok(Chunk::from).map_err(wrap_error))
     }
 
+    /// Gets a `Chunk` of audio sample data from disk, as in `get_video_sample_data`.
+    fn get_audio_sample_data(
+        &self,
+        i: usize,
+        r: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Chunk, BoxedError>> + Send + Sync> {
+        let s = &self.segments[i];
+        let sr = &s.audio.as_ref().unwrap().sample_file_range;
+        let f = match self.dirs_by_stream_id.get(&s.s.id.stream()) {
+            None => {
+                return Box::new(stream::iter(std::iter::once(Err(wrap_error(
+                    format_err_t!(NotFound, "{}: stream not found", s.s.id),
+                )))))
+            }
+            Some(d) => d.open_audio_file(s.s.id, (r.start + sr.start)..(r.end + sr.start)),
+        };
+        Box::new(f.map_ok(Chunk::from).map_err(wrap_error))
+    }
+
     fn get_subtitle_sample_data(&self, i: usize, r: Range<u64>, len: u64) -> Result<Chunk, Error> {
         let s = &self.segments[i];
         let md = &s.rel_media_range_90k;
@@ -1899,6 +2505,10 @@ impl http_serve::Entity for File {
             }
             mime.extend_from_slice(e.rfc6381_codec.as_bytes());
         }
+        if let Some(e) = self.0.audio_sample_entry.as_ref() {
+            mime.extend_from_slice(b", ");
+            mime.extend_from_slice(e.rfc6381_codec.as_bytes());
+        }
         mime.extend_from_slice(b"\"");
         hdrs.insert(
             http::header::CONTENT_TYPE,
@@ -2276,6 +2886,7 @@ mod tests {
             &db.syncer_channel,
             TEST_STREAM_ID,
             video_sample_entry_id,
+            None,
         );
 
         // end_pts is the pts of the end of the most recent frame (start + duration).
@@ -2289,7 +2900,8 @@ mod tests {
 
         loop {
             let pkt = match input.next() {
-                Ok(p) => p,
+                Ok(stream::Frame::Video(p)) => p,
+                Ok(stream::Frame::Audio(_)) => panic!("unexpected audio frame"),
                 Err(e) if e.to_string().contains("End of file") => {
                     break;
                 }
@@ -2382,14 +2994,16 @@ mod tests {
         let mut final_durations = None;
         loop {
             let orig_pkt = match orig.next() {
-                Ok(p) => Some(p),
+                Ok(stream::Frame::Video(p)) => Some(p),
+                Ok(stream::Frame::Audio(_)) => panic!("unexpected audio frame"),
                 Err(e) if e.to_string() == "End of file" => None,
                 Err(e) => {
                     panic!("unexpected input error: {}", e);
                 }
             };
             let new_pkt = match new.next() {
-                Ok(p) => Some(p),
+                Ok(stream::Frame::Video(p)) => Some(p),
+                Ok(stream::Frame::Audio(_)) => panic!("unexpected audio frame"),
                 Err(e) if e.to_string() == "End of file" => {
                     break;
                 }
@@ -2424,6 +3038,70 @@ mod tests {
         }
     }
 
+    /// Tests that an H.265 recording's `.mp4` opens through the ffmpeg opener with the original
+    /// sample entry and frames.
+    #[tokio::test]
+    async fn test_h265_round_trip() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let extra_data =
+            h265::parse_extra_data(&h265::tests::ANNEX_B_TEST_INPUT, 1920, 1080).unwrap();
+        let video_sample_entry_id = db
+            .db
+            .lock()
+            .insert_video_sample_entry(extra_data.entry.clone())
+            .unwrap();
+
+        // Each frame is a single length-prefixed IDR NAL unit. ffmpeg only demuxes these, so
+        // their contents needn't decode.
+        let frames: Vec<[u8; 8]> = (0..3)
+            .map(|i| [0x00, 0x00, 0x00, 0x04, 0x26, 0x01, 0xaf, i])
+            .collect();
+        {
+            let dir = db.dirs_by_stream_id.get(&TEST_STREAM_ID).unwrap();
+            let mut output = writer::Writer::new(
+                dir,
+                &db.db,
+                &db.syncer_channel,
+                TEST_STREAM_ID,
+                video_sample_entry_id,
+                None,
+            );
+            const START_TIME: recording::Time = recording::Time(1430006400i64 * TIME_UNITS_PER_SEC);
+            for (i, f) in frames.iter().enumerate() {
+                let pts = 3000 * i as i64;
+                output
+                    .write(f, START_TIME + recording::Duration(pts), pts, i == 0)
+                    .unwrap();
+            }
+            output.close(Some(9000)).unwrap();
+        }
+        db.syncer_channel.flush();
+
+        let mp4 = create_mp4_from_db(&db, 0, 0, false);
+        traverse(mp4.clone()).await;
+        let new_filename = write_mp4(&mp4, db.tmpdir.path()).await;
+        let (new_extra_data, mut new) = stream::FFMPEG
+            .open("test".to_owned(), stream::Source::File(&new_filename))
+            .unwrap();
+        assert_eq!(new_extra_data.entry, extra_data.entry);
+        assert_eq!(new_extra_data.entry.rfc6381_codec, "hvc1.1.6.L93.90");
+        for (i, f) in frames.iter().enumerate() {
+            let pkt = match new.next() {
+                Ok(stream::Frame::Video(p)) => p,
+                Ok(stream::Frame::Audio(_)) => panic!("unexpected audio frame"),
+                Err(e) => panic!("unexpected input error: {}", e),
+            };
+            assert_eq!(pkt.pts, 3000 * i as i64);
+            assert_eq!(pkt.data, &f[..]);
+            assert_eq!(pkt.is_key, i == 0);
+        }
+        drop(new);
+        drop(db.syncer_channel);
+        db.db.lock().clear_on_flush();
+        db.syncer_join.join().unwrap();
+    }
+
     /// Makes a `.mp4` file which is only good for exercising the `Slice` logic for producing
     /// sample tables that match the supplied encoder.
     fn make_mp4_from_encoders(
@@ -2693,6 +3371,164 @@ mod tests {
         traverse(mp4.clone()).await;
     }
 
+    /// Tests the audio track's sample tables, including the adjustment of the final sample's
+    /// duration to match the video.
+    #[tokio::test]
+    async fn test_audio() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let audio_sample_entry_id = db
+            .db
+            .lock()
+            .insert_audio_sample_entry(db::AudioSampleEntryToInsert {
+                data: [0u8; 50].to_vec(),
+                rfc6381_codec: "mp4a.40.2".to_owned(),
+                sample_rate: 8_000,
+                channels: 1,
+            })
+            .unwrap();
+        let mut r = db::RecordingToInsert {
+            audio_sample_entry_id: Some(audio_sample_entry_id),
+            ..Default::default()
+        };
+        let mut encoder = recording::SampleIndexEncoder::default();
+        for _ in 0..3 {
+            encoder.add_sample(90_000, 1, true, &mut r);
+        }
+        let mut audio_encoder = recording::AudioIndexEncoder::default();
+        for _ in 0..24 {
+            audio_encoder.add_sample(1024, 10, &mut r);
+        }
+        let mp4 = make_mp4_from_encoders(Type::Normal, &db, vec![r], 0..270_000, true).unwrap();
+        traverse(mp4.clone()).await;
+        let track = find_track(mp4, 2).await;
+        assert!(track.edts_cursor.is_none());
+        let mut cursor = track.stbl_cursor;
+        cursor.down().await;
+        cursor.find(b"stts").await;
+        assert_eq!(
+            cursor.get_all().await,
+            &[
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x02, // entry_count
+                // entries
+                0x00, 0x00, 0x00, 0x17, 0x00, 0x00, 0x04, 0x00, // 23 samples of 1024
+                0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0xc0, // 1 sample of 24000 - 23*1024
+            ]
+        );
+        cursor.find(b"stsc").await;
+        assert_eq!(
+            cursor.get_all().await,
+            &[
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x01, // entry_count
+                0x00, 0x00, 0x00, 0x01, // first_chunk
+                0x00, 0x00, 0x00, 0x18, // samples_per_chunk
+                0x00, 0x00, 0x00, 0x01, // sample_description_index
+            ]
+        );
+    }
+
+    /// Tests that audio which starts after the video is delayed by an edit list, with the final
+    /// sample shortened so the audio still ends with the video.
+    #[tokio::test]
+    async fn test_audio_start_offset() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let audio_sample_entry_id = db
+            .db
+            .lock()
+            .insert_audio_sample_entry(db::AudioSampleEntryToInsert {
+                data: [0u8; 50].to_vec(),
+                rfc6381_codec: "mp4a.40.2".to_owned(),
+                sample_rate: 8_000,
+                channels: 1,
+            })
+            .unwrap();
+        let mut r = db::RecordingToInsert {
+            audio_sample_entry_id: Some(audio_sample_entry_id),
+            audio_start_offset_90k: 4_500, // 50 ms, or 400 audio samples.
+            ..Default::default()
+        };
+        let mut encoder = recording::SampleIndexEncoder::default();
+        for _ in 0..3 {
+            encoder.add_sample(90_000, 1, true, &mut r);
+        }
+        let mut audio_encoder = recording::AudioIndexEncoder::default();
+        for _ in 0..24 {
+            audio_encoder.add_sample(1024, 10, &mut r);
+        }
+        let mp4 = make_mp4_from_encoders(Type::Normal, &db, vec![r], 0..270_000, true).unwrap();
+        traverse(mp4.clone()).await;
+        let track = find_track(mp4, 2).await;
+        let mut cursor = track.edts_cursor.unwrap();
+        cursor.down().await;
+        cursor.find(b"elst").await;
+        assert_eq!(
+            cursor.get_all().await,
+            &[
+                0x01, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x02, // entry_count
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x11, 0x94, // segment_duration: 4500
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // media_time: empty edit
+                0x00, 0x01, 0x00, 0x00, // media_rate_{integer,fraction}
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x0d, 0x1c, // segment_duration: 265500
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // media_time
+                0x00, 0x01, 0x00, 0x00, // media_rate_{integer,fraction}
+            ]
+        );
+        let mut cursor = track.stbl_cursor;
+        cursor.down().await;
+        cursor.find(b"stts").await;
+        assert_eq!(
+            cursor.get_all().await,
+            &[
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x02, // entry_count
+                // entries
+                0x00, 0x00, 0x00, 0x17, 0x00, 0x00, 0x04, 0x00, // 23 samples of 1024
+                0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x30, // 1 sample of 23600 - 23*1024
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_h265_ftyp() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::default();
+        encoder.add_sample(1, 1, true, &mut r);
+        let row = db.insert_recording_from_encoder(r);
+        let ent = {
+            let mut l = db.db.lock();
+            let mut data = [0u8; 100].to_vec();
+            data[4..8].copy_from_slice(b"hvc1");
+            let id = l
+                .insert_video_sample_entry(db::VideoSampleEntryToInsert {
+                    width: 1920,
+                    height: 1080,
+                    pasp_h_spacing: 1,
+                    pasp_v_spacing: 1,
+                    data,
+                    rfc6381_codec: "hvc1.1.6.L93.90".to_owned(),
+                })
+                .unwrap();
+            l.video_sample_entries_by_id().get(&id).unwrap().clone()
+        };
+        let mut builder = FileBuilder::new(Type::Normal);
+        builder.append_video_sample_entry(ent);
+        builder.append(&db.db.lock(), row, 0..1, true).unwrap();
+        let mp4 = builder
+            .build(db.db.clone(), db.dirs_by_stream_id.clone())
+            .unwrap();
+        traverse(mp4.clone()).await;
+        let mut cursor = BoxCursor::new(mp4);
+        cursor.down().await;
+        assert!(cursor.find(b"ftyp").await);
+        assert_eq!(cursor.get_all().await, &NORMAL_H265_FTYP_BOX[8..]);
+    }
+
     #[tokio::test]
     async fn test_media_segment() {
         testutil::init();
@@ -2746,6 +3582,45 @@ mod tests {
         assert_eq!(cursor.get_u32(20).await, 15); // sample size
     }
 
+    #[tokio::test]
+    async fn test_media_segment_base_media_decode_time() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut encoder = recording::SampleIndexEncoder::default();
+        let mut r = db::RecordingToInsert::default();
+        encoder.add_sample(1000, 1, true, &mut r);
+        db.insert_recording_from_encoder(r);
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::default();
+        for i in 1..6 {
+            encoder.add_sample(2 * i, 3 * i, (i % 2) == 1, &mut r);
+        }
+        let row = db.insert_recording_from_encoder(r);
+
+        // The segment starts at the sync frame at 2+4, after the prior recording's 1000.
+        let mut builder = FileBuilder::new(Type::MediaSegment);
+        builder.include_base_media_decode_time(true).unwrap();
+        builder
+            .append(&db.db.lock(), row, 2 + 4 + 6..2 + 4 + 6 + 8, true)
+            .unwrap();
+        let mp4 = builder
+            .build(db.db.clone(), db.dirs_by_stream_id.clone())
+            .unwrap();
+        traverse(mp4.clone()).await;
+        let mut cursor = BoxCursor::new(mp4);
+        cursor.down().await;
+        assert!(cursor.find(b"moof").await);
+        cursor.down().await;
+        assert!(cursor.find(b"traf").await);
+        cursor.down().await;
+        assert!(cursor.find(b"tfdt").await);
+        assert_eq!(cursor.get_u32(0).await, 0x0100_0000); // version + flags
+        assert_eq!(cursor.get_u64(4).await, 1000 + 2 + 4);
+
+        let mut builder = FileBuilder::new(Type::Normal);
+        builder.include_base_media_decode_time(true).unwrap_err();
+    }
+
     /// Tests `.mp4` files which represent a single frame, as in the live view WebSocket stream.
     #[tokio::test]
     async fn test_single_frame_media_segment() {
@@ -0,0 +1,667 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Bridge between signals/stream health and an MQTT broker.
+//!
+//! Publishes to the following topics, under a configurable prefix:
+//!
+//! *   `<prefix>/signals/<id>`: each signal change, with the same JSON payload as a webhook.
+//! *   `<prefix>/streams/<camera uuid>/<stream type>/health`: each stream health transition,
+//!     retained so that new subscribers see the current health.
+//!
+//! If [Config::accept_commands] is set, it also subscribes to `<prefix>/signals/<id>/set`, which
+//! expects a JSON payload such as `{"state": 2, "durationSec": 60}` and sets the signal from now
+//! until the duration elapses. Anyone who can publish to these topics can set signals, so this
+//! should be enabled only with an authenticated broker.
+//!
+//! The MQTT protocol itself is handled by `rumqttc`.
+
+use base::clock::Clocks;
+use db::{audit, recording, webhook};
+use failure::{bail, format_err, Error};
+use log::{info, warn};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS, Transport};
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use tokio::sync::broadcast;
+
+/// The keep alive interval requested of the broker.
+const KEEP_ALIVE: StdDuration = StdDuration::from_secs(60);
+
+/// How long to wait before reconnecting after the connection fails.
+const RECONNECT_DELAY: StdDuration = StdDuration::from_secs(10);
+
+/// The number of outgoing requests to buffer while the connection is down.
+const REQUEST_CAPACITY: usize = 16;
+
+/// The duration of a signal command which doesn't specify `durationSec`.
+const DEFAULT_SIGNAL_DURATION_SEC: i64 = 120;
+
+pub struct Config {
+    /// The broker's address, as `host:port`.
+    pub addr: String,
+    pub client_id: String,
+    pub topic_prefix: String,
+
+    /// The username and password to present to the broker, if any.
+    pub credentials: Option<(String, String)>,
+
+    /// If set, connects with TLS, trusting the CA certificates in this PEM data.
+    pub tls_ca: Option<Vec<u8>>,
+
+    /// If true, subscribes to `<prefix>/signals/<id>/set` and applies the signal commands
+    /// received there.
+    pub accept_commands: bool,
+}
+
+/// Starts the bridge, which connects to the broker and reconnects as needed.
+/// Must be called from within a tokio runtime.
+pub fn start<C: Clocks + Clone>(db: Arc<db::Database<C>>, config: Config) -> Result<(), Error> {
+    // Subscribe now so that events which happen while connecting are published.
+    let events = db.lock().subscribe_events();
+    let (client, eventloop) = AsyncClient::new(options(&config)?, REQUEST_CAPACITY);
+    let bridge = Arc::new(Bridge { db, config, client });
+    info!("Starting MQTT bridge to {}.", &bridge.config.addr);
+    tokio::spawn({
+        let bridge = bridge.clone();
+        async move { bridge.publish_events(events).await }
+    });
+    tokio::spawn(async move { bridge.poll(eventloop).await });
+    Ok(())
+}
+
+fn options(config: &Config) -> Result<MqttOptions, Error> {
+    let colon = config
+        .addr
+        .rfind(':')
+        .ok_or_else(|| format_err!("MQTT address {:?} isn't host:port", &config.addr))?;
+    let host = &config.addr[..colon];
+    let port = u16::from_str(&config.addr[colon + 1..])
+        .map_err(|_| format_err!("MQTT address {:?} has bad port", &config.addr))?;
+    let mut opts = MqttOptions::new(&config.client_id, host, port);
+    opts.set_keep_alive(KEEP_ALIVE);
+    if let Some((ref username, ref password)) = config.credentials {
+        opts.set_credentials(username, password);
+    }
+    if let Some(ref ca) = config.tls_ca {
+        opts.set_transport(Transport::tls(ca.clone(), None, None));
+    }
+    Ok(opts)
+}
+
+/// The payload of a `<prefix>/signals/<id>/set` message.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct SignalCommand {
+    state: u16,
+    duration_sec: Option<i64>,
+}
+
+struct Bridge<C: Clocks + Clone> {
+    db: Arc<db::Database<C>>,
+    config: Config,
+    client: AsyncClient,
+}
+
+/// A message to publish.
+#[derive(Debug, PartialEq, Eq)]
+struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool,
+}
+
+impl<C: Clocks + Clone> Bridge<C> {
+    /// Drives the connection, reconnecting after failures and handling received commands.
+    async fn poll(&self, mut eventloop: EventLoop) {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker {}.", &self.config.addr);
+
+                    // The session is clean, so subscriptions must be renewed on each connection.
+                    // This can't await room in the request queue, as that's drained only by
+                    // this loop.
+                    if self.config.accept_commands {
+                        let filter = format!("{}/signals/+/set", &self.config.topic_prefix);
+                        if let Err(e) = self.client.try_subscribe(filter, QoS::AtMostOnce) {
+                            warn!("Unable to subscribe to MQTT signal commands: {}", e);
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(p))) => self.command(&p.topic, &p.payload),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection to {} failed: {}", &self.config.addr, e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Publishes events as they happen. Messages are queued while the connection is down.
+    async fn publish_events(&self, mut events: broadcast::Receiver<webhook::Event>) {
+        loop {
+            let e = match events.recv().await {
+                Ok(e) => e,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("MQTT bridge fell behind; dropped {} events", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let m = match self.message(&e) {
+                Some(m) => m,
+                None => continue,
+            };
+            if let Err(e) = self
+                .client
+                .publish(m.topic, QoS::AtMostOnce, m.retain, m.payload)
+                .await
+            {
+                warn!("Unable to publish MQTT message: {}", e);
+                return;
+            }
+        }
+    }
+
+    /// Returns the message to publish for `event`, if any.
+    fn message(&self, event: &webhook::Event) -> Option<Message> {
+        let prefix = &self.config.topic_prefix;
+        let l = self.db.lock();
+        let (topic, retain) = match event {
+            webhook::Event::Signal { signal_id, .. } => {
+                (format!("{}/signals/{}", prefix, signal_id), false)
+            }
+            webhook::Event::StreamHealth { stream_id, .. } => {
+                let s = l.streams_by_id().get(stream_id)?;
+                let c = l.cameras_by_id().get(&s.camera_id)?;
+                let topic = format!("{}/streams/{}/{}/health", prefix, c.uuid, s.type_.as_str());
+                (topic, true)
+            }
+            webhook::Event::DiskFull { .. } => return None,
+        };
+        Some(Message {
+            topic,
+            payload: webhook::payload(&l, event).into_bytes(),
+            retain,
+        })
+    }
+
+    /// Handles a message received on a subscribed topic, logging any error.
+    fn command(&self, topic: &str, payload: &[u8]) {
+        if let Err(e) = self.command_inner(topic, payload) {
+            warn!("Bad MQTT command on {}: {}", topic, e);
+        }
+    }
+
+    fn command_inner(&self, topic: &str, payload: &[u8]) -> Result<(), Error> {
+        let signal_id = parse_signal_topic(&self.config.topic_prefix, topic)
+            .ok_or_else(|| format_err!("unexpected topic"))?;
+        let cmd: SignalCommand = serde_json::from_slice(payload)?;
+        let duration_sec = cmd.duration_sec.unwrap_or(DEFAULT_SIGNAL_DURATION_SEC);
+        if duration_sec <= 0 {
+            bail!("durationSec must be positive");
+        }
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let end = now + recording::Duration(duration_sec * recording::TIME_UNITS_PER_SEC);
+        let mut l = self.db.lock();
+        l.update_signals(now..end, &[signal_id], &[cmd.state])?;
+        let mut e = audit::Event::new(self.db.clocks().realtime().sec, audit::Kind::PostSignals);
+        e.time_90k = Some(now..end);
+        e.detail = Some(format!(
+            "MQTT: signals [{}] states [{}]",
+            signal_id, cmd.state
+        ));
+        l.audit(e);
+        Ok(())
+    }
+}
+
+/// Returns the signal id from a `<prefix>/signals/<id>/set` topic.
+fn parse_signal_topic(prefix: &str, topic: &str) -> Option<u32> {
+    if !topic.starts_with(prefix) {
+        return None;
+    }
+    let rest = &topic[prefix.len()..];
+    if !rest.starts_with("/signals/") || !rest.ends_with("/set") {
+        return None;
+    }
+    let id = rest.get("/signals/".len()..rest.len() - "/set".len())?;
+    u32::from_str(id).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::clock;
+    use db::testutil;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// The largest packet accepted from the bridge.
+    const MAX_PACKET_LEN: usize = 1 << 16;
+
+    /// An MQTT 3.1.1 control packet, limited to the types and options needed to stand in for a
+    /// broker.
+    #[derive(Debug, PartialEq, Eq)]
+    enum Packet {
+        Connect {
+            client_id: String,
+            keep_alive_sec: u16,
+            username: Option<String>,
+            password: Option<String>,
+        },
+        ConnAck {
+            return_code: u8,
+        },
+        Publish {
+            topic: String,
+            payload: Vec<u8>,
+            retain: bool,
+        },
+        Subscribe {
+            packet_id: u16,
+            filter: String,
+        },
+        SubAck {
+            packet_id: u16,
+            return_code: u8,
+        },
+        PingReq,
+        PingResp,
+        Disconnect,
+    }
+
+    fn put_str(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    impl Packet {
+        fn encode(&self) -> Vec<u8> {
+            let mut body = Vec::new();
+            let first = match self {
+                Packet::Connect {
+                    client_id,
+                    keep_alive_sec,
+                    username,
+                    password,
+                } => {
+                    put_str(&mut body, "MQTT");
+                    body.push(4); // protocol level: 3.1.1.
+                    body.push(
+                        0x02 // clean session.
+                        | if username.is_some() { 0x80 } else { 0 }
+                        | if password.is_some() { 0x40 } else { 0 },
+                    );
+                    body.extend_from_slice(&keep_alive_sec.to_be_bytes());
+                    put_str(&mut body, client_id);
+                    for s in username.iter().chain(password.iter()) {
+                        put_str(&mut body, s);
+                    }
+                    0x10
+                }
+                Packet::ConnAck { return_code } => {
+                    body.extend_from_slice(&[0, *return_code]);
+                    0x20
+                }
+                Packet::Publish {
+                    topic,
+                    payload,
+                    retain,
+                } => {
+                    put_str(&mut body, topic);
+                    body.extend_from_slice(payload);
+                    0x30 | (*retain as u8)
+                }
+                Packet::Subscribe { packet_id, filter } => {
+                    body.extend_from_slice(&packet_id.to_be_bytes());
+                    put_str(&mut body, filter);
+                    body.push(0); // requested QoS.
+                    0x82
+                }
+                Packet::SubAck {
+                    packet_id,
+                    return_code,
+                } => {
+                    body.extend_from_slice(&packet_id.to_be_bytes());
+                    body.push(*return_code);
+                    0x90
+                }
+                Packet::PingReq => 0xc0,
+                Packet::PingResp => 0xd0,
+                Packet::Disconnect => 0xe0,
+            };
+            let mut out = Vec::with_capacity(body.len() + 5);
+            out.push(first);
+            let mut len = body.len();
+            loop {
+                let mut b = (len & 0x7f) as u8;
+                len >>= 7;
+                if len > 0 {
+                    b |= 0x80;
+                }
+                out.push(b);
+                if len == 0 {
+                    break;
+                }
+            }
+            out.extend_from_slice(&body);
+            out
+        }
+
+        /// Decodes a packet from its first byte and the remainder after the length.
+        fn decode(first: u8, body: &[u8]) -> Result<Self, Error> {
+            let mut r = Reader(body);
+            let p = match first >> 4 {
+                1 => {
+                    if r.str()? != "MQTT" || r.u8()? != 4 {
+                        bail!("unsupported protocol");
+                    }
+                    let flags = r.u8()?;
+                    let keep_alive_sec = r.u16()?;
+                    let client_id = r.str()?;
+                    let username = if flags & 0x80 != 0 {
+                        Some(r.str()?)
+                    } else {
+                        None
+                    };
+                    let password = if flags & 0x40 != 0 {
+                        Some(r.str()?)
+                    } else {
+                        None
+                    };
+                    Packet::Connect {
+                        client_id,
+                        keep_alive_sec,
+                        username,
+                        password,
+                    }
+                }
+                2 => {
+                    r.u8()?; // acknowledge flags.
+                    Packet::ConnAck {
+                        return_code: r.u8()?,
+                    }
+                }
+                3 => {
+                    let topic = r.str()?;
+                    if (first >> 1) & 0x3 != 0 {
+                        r.u16()?; // packet id, present for QoS 1 and 2.
+                    }
+                    let payload = r.0.to_vec();
+                    r.0 = &[];
+                    Packet::Publish {
+                        topic,
+                        payload,
+                        retain: first & 0x1 != 0,
+                    }
+                }
+                8 => {
+                    let packet_id = r.u16()?;
+                    let filter = r.str()?;
+                    r.u8()?; // requested QoS.
+                    Packet::Subscribe { packet_id, filter }
+                }
+                9 => Packet::SubAck {
+                    packet_id: r.u16()?,
+                    return_code: r.u8()?,
+                },
+                12 => Packet::PingReq,
+                13 => Packet::PingResp,
+                14 => Packet::Disconnect,
+                t => bail!("unsupported packet type {}", t),
+            };
+            if !r.0.is_empty() {
+                bail!("{} trailing bytes in {:?}", r.0.len(), p);
+            }
+            Ok(p)
+        }
+    }
+
+    struct Reader<'a>(&'a [u8]);
+
+    impl<'a> Reader<'a> {
+        fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+            if self.0.len() < n {
+                bail!("packet truncated");
+            }
+            let (head, tail) = self.0.split_at(n);
+            self.0 = tail;
+            Ok(head)
+        }
+
+        fn u8(&mut self) -> Result<u8, Error> {
+            Ok(self.take(1)?[0])
+        }
+
+        fn u16(&mut self) -> Result<u16, Error> {
+            let b = self.take(2)?;
+            Ok(u16::from_be_bytes([b[0], b[1]]))
+        }
+
+        fn str(&mut self) -> Result<String, Error> {
+            let len = usize::from(self.u16()?);
+            Ok(String::from_utf8(self.take(len)?.to_vec())?)
+        }
+    }
+
+    async fn read_packet<R: AsyncRead + Unpin>(r: &mut R) -> Result<Packet, Error> {
+        let first = r.read_u8().await?;
+        let mut len = 0usize;
+        for i in 0..4 {
+            let b = r.read_u8().await?;
+            len |= usize::from(b & 0x7f) << (7 * i);
+            if b & 0x80 == 0 {
+                break;
+            } else if i == 3 {
+                bail!("bad remaining length");
+            }
+        }
+        if len > MAX_PACKET_LEN {
+            bail!("packet of {} bytes is too large", len);
+        }
+        let mut body = vec![0; len];
+        r.read_exact(&mut body).await?;
+        Packet::decode(first, &body)
+    }
+
+    async fn write_packet<W: AsyncWrite + Unpin>(w: &mut W, p: &Packet) -> Result<(), Error> {
+        w.write_all(&p.encode()).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn codec_round_trip() {
+        let packets = vec![
+            Packet::Connect {
+                client_id: "moonfire-nvr".to_owned(),
+                keep_alive_sec: 60,
+                username: None,
+                password: None,
+            },
+            Packet::Connect {
+                client_id: "moonfire-nvr".to_owned(),
+                keep_alive_sec: 60,
+                username: Some("nvr".to_owned()),
+                password: Some("secret".to_owned()),
+            },
+            Packet::ConnAck { return_code: 0 },
+            Packet::Publish {
+                topic: "a/b".to_owned(),
+                payload: vec![b'x'; 200], // needs a two-byte length.
+                retain: true,
+            },
+            Packet::Subscribe {
+                packet_id: 1,
+                filter: "a/+/set".to_owned(),
+            },
+            Packet::SubAck {
+                packet_id: 1,
+                return_code: 0,
+            },
+            Packet::PingReq,
+            Packet::PingResp,
+            Packet::Disconnect,
+        ];
+        for p in packets {
+            let encoded = p.encode();
+            let mut r = &encoded[..];
+            let decoded = futures::executor::block_on(read_packet(&mut r)).unwrap();
+            assert_eq!(decoded, p);
+            assert!(r.is_empty());
+        }
+        assert_eq!(
+            Packet::PingReq.encode(),
+            &[0xc0, 0x00],
+            "fixed header with zero remaining length"
+        );
+    }
+
+    #[test]
+    fn signal_topic() {
+        assert_eq!(parse_signal_topic("nvr", "nvr/signals/3/set"), Some(3));
+        assert_eq!(parse_signal_topic("nvr", "nvr/signals/x/set"), None);
+        assert_eq!(parse_signal_topic("nvr", "nvr/signals/3"), None);
+        assert_eq!(parse_signal_topic("nvr", "other/signals/3/set"), None);
+        assert_eq!(parse_signal_topic("nvr", "nvr/signals/set"), None);
+        let cmd: SignalCommand = serde_json::from_str(r#"{"state": 2}"#).unwrap();
+        assert_eq!(
+            cmd,
+            SignalCommand {
+                state: 2,
+                duration_sec: None
+            }
+        );
+    }
+
+    fn test_config(listener: &TcpListener, accept_commands: bool) -> Config {
+        Config {
+            addr: listener.local_addr().unwrap().to_string(),
+            client_id: "test".to_owned(),
+            topic_prefix: "nvr".to_owned(),
+            credentials: Some(("nvr".to_owned(), "secret".to_owned())),
+            tls_ca: None,
+            accept_commands,
+        }
+    }
+
+    /// Accepts the bridge's connection, checking its `CONNECT`.
+    async fn accept(listener: &TcpListener) -> tokio::net::TcpStream {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        match read_packet(&mut conn).await.unwrap() {
+            Packet::Connect {
+                client_id,
+                username,
+                password,
+                ..
+            } => {
+                assert_eq!(client_id, "test");
+                assert_eq!(username.as_deref(), Some("nvr"));
+                assert_eq!(password.as_deref(), Some("secret"));
+            }
+            p => panic!("unexpected {:?}", p),
+        }
+        write_packet(&mut conn, &Packet::ConnAck { return_code: 0 })
+            .await
+            .unwrap();
+        conn
+    }
+
+    /// Enqueues a stream health event and returns the bridge's next publish, skipping pings.
+    async fn publish_health(
+        tdb: &testutil::TestDb<clock::SimulatedClocks>,
+        conn: &mut tokio::net::TcpStream,
+    ) -> (String, Vec<u8>, bool) {
+        tdb.db
+            .lock()
+            .enqueue_webhook_event(webhook::Event::StreamHealth {
+                stream_id: testutil::TEST_STREAM_ID,
+                healthy: false,
+                error: Some("connection refused".to_owned()),
+                time: recording::Time::new(tdb.db.clocks().realtime()),
+            });
+        loop {
+            match read_packet(conn).await.unwrap() {
+                Packet::PingReq => continue,
+                Packet::Publish {
+                    topic,
+                    payload,
+                    retain,
+                } => return (topic, payload, retain),
+                p => panic!("unexpected {:?}", p),
+            }
+        }
+    }
+
+    /// Runs the bridge against an in-process stand-in for a broker.
+    #[tokio::test]
+    async fn bridge() {
+        testutil::init();
+        let clocks = clock::SimulatedClocks::new(time::Timespec::new(1_600_000_000, 0));
+        let tdb = testutil::TestDb::new(clocks);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        start(tdb.db.clone(), test_config(&listener, true)).unwrap();
+        let mut conn = accept(&listener).await;
+        assert_eq!(
+            read_packet(&mut conn).await.unwrap(),
+            Packet::Subscribe {
+                packet_id: 1,
+                filter: "nvr/signals/+/set".to_owned(),
+            }
+        );
+        write_packet(
+            &mut conn,
+            &Packet::SubAck {
+                packet_id: 1,
+                return_code: 0,
+            },
+        )
+        .await
+        .unwrap();
+
+        // A command for an unknown signal is logged without dropping the connection.
+        write_packet(
+            &mut conn,
+            &Packet::Publish {
+                topic: "nvr/signals/42/set".to_owned(),
+                payload: br#"{"state": 1}"#.to_vec(),
+                retain: false,
+            },
+        )
+        .await
+        .unwrap();
+
+        let (topic, payload, retain) = publish_health(&tdb, &mut conn).await;
+        assert_eq!(
+            topic,
+            format!("nvr/streams/{}/main/health", tdb.test_camera_uuid)
+        );
+        assert!(retain);
+        let p: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(p["type"], "streamHealth");
+        assert_eq!(p["healthy"], false);
+    }
+
+    /// Tests that the bridge doesn't subscribe to command topics unless configured to.
+    #[tokio::test]
+    async fn bridge_without_commands() {
+        testutil::init();
+        let clocks = clock::SimulatedClocks::new(time::Timespec::new(1_600_000_000, 0));
+        let tdb = testutil::TestDb::new(clocks);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        start(tdb.db.clone(), test_config(&listener, false)).unwrap();
+        let mut conn = accept(&listener).await;
+
+        // publish_health panics on anything other than a ping or publish, such as a subscribe.
+        let (topic, _, _) = publish_health(&tdb, &mut conn).await;
+        assert_eq!(
+            topic,
+            format!("nvr/streams/{}/main/health", tdb.test_camera_uuid)
+        );
+    }
+}
@@ -0,0 +1,136 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Distribution of live video frames from each `streamer::Streamer` to RTSP sessions.
+
+use bytes::Bytes;
+use fnv::FnvHashMap;
+use futures::channel::mpsc;
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// The number of frames which may be queued for a subscriber before further frames are dropped.
+const SUBSCRIBER_CAPACITY: usize = 64;
+
+/// A video frame as received from the camera.
+pub struct Frame {
+    /// A per-stream sequence number, which allows subscribers to notice dropped frames.
+    pub seq: u64,
+    pub video_sample_entry_id: i32,
+
+    /// The presentation timestamp, in 90 kHz units, as received from the camera.
+    pub pts: i64,
+    pub is_key: bool,
+
+    /// The sample data, in the same format as stored in the sample file.
+    pub data: Bytes,
+}
+
+#[derive(Default)]
+struct StreamState {
+    next_seq: u64,
+    video_sample_entry_id: Option<i32>,
+    subscribers: Vec<mpsc::Sender<Arc<Frame>>>,
+}
+
+/// Passes live frames from streamers to any interested RTSP sessions.
+///
+/// Publishing never blocks: if a subscriber falls behind, frames are dropped for it, and it's
+/// expected to skip ahead to the next key frame.
+#[derive(Default)]
+pub struct Hub(Mutex<FnvHashMap<i32, StreamState>>);
+
+impl Hub {
+    /// Publishes a frame from the given stream. The data is copied only if there are subscribers.
+    pub fn publish(
+        &self,
+        stream_id: i32,
+        video_sample_entry_id: i32,
+        pts: i64,
+        is_key: bool,
+        data: &[u8],
+    ) {
+        let mut l = self.0.lock();
+        let s = l.entry(stream_id).or_default();
+        s.video_sample_entry_id = Some(video_sample_entry_id);
+        let seq = s.next_seq;
+        s.next_seq += 1;
+        if s.subscribers.is_empty() {
+            return;
+        }
+        let frame = Arc::new(Frame {
+            seq,
+            video_sample_entry_id,
+            pts,
+            is_key,
+            data: Bytes::copy_from_slice(data),
+        });
+        let mut i = 0;
+        while i < s.subscribers.len() {
+            match s.subscribers[i].try_send(frame.clone()) {
+                Err(e) if e.is_disconnected() => {
+                    s.subscribers.swap_remove(i);
+                }
+
+                // If the subscriber is full, it will notice the gap in sequence numbers.
+                _ => i += 1,
+            }
+        }
+    }
+
+    /// Subscribes to frames published from the given stream after this call.
+    pub fn subscribe(&self, stream_id: i32) -> mpsc::Receiver<Arc<Frame>> {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        self.0
+            .lock()
+            .entry(stream_id)
+            .or_default()
+            .subscribers
+            .push(tx);
+        rx
+    }
+
+    /// Returns the video sample entry of the most recently published frame of the given stream.
+    pub fn video_sample_entry_id(&self, stream_id: i32) -> Option<i32> {
+        self.0
+            .lock()
+            .get(&stream_id)
+            .and_then(|s| s.video_sample_entry_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_drops_frames_for_slow_subscribers() {
+        let hub = Hub::default();
+        hub.publish(1, 42, 0, true, b"before");
+        assert_eq!(hub.video_sample_entry_id(1), Some(42));
+        assert_eq!(hub.video_sample_entry_id(2), None);
+        let mut rx = hub.subscribe(1);
+        for i in 0..SUBSCRIBER_CAPACITY as i64 + 2 {
+            hub.publish(1, 42, i, false, b"frame");
+        }
+        let mut seqs = Vec::new();
+        while let Ok(Some(f)) = rx.try_next() {
+            seqs.push(f.seq);
+        }
+
+        // A `futures::channel::mpsc` channel has one slot per sender beyond its capacity.
+        assert_eq!(seqs.len(), SUBSCRIBER_CAPACITY + 1);
+        assert_eq!(seqs[0], 1);
+        hub.publish(1, 42, 100, true, b"key");
+        let f = rx.next().await.unwrap();
+        assert!(f.seq > *seqs.last().unwrap() + 1);
+        assert_eq!(&f.data[..], b"key");
+
+        // Dropped subscribers are removed on the next publish.
+        drop(rx);
+        hub.publish(1, 42, 101, false, b"frame");
+        assert!(hub.0.lock().get(&1).unwrap().subscribers.is_empty());
+    }
+}
@@ -0,0 +1,1016 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Optional RTSP server which re-streams each camera's video, so that other clients needn't
+//! connect to the cameras directly.
+//!
+//! Each stream has a URL such as `rtsp://nvr:8554/<camera uuid or short name>/main`. A `PLAY`
+//! without a `Range: clock=...` header sends live video, as received by
+//! [`crate::streamer::Streamer`]; one with such a header sends recorded video from the given
+//! (UTC) time, paced in real time.
+//!
+//! Only RTP interleaved on the RTSP connection (RFC 2326 section 10.12) is supported. This is
+//! simplest to get through firewalls and NAT, and TCP's flow control allows a slow client to
+//! fall behind on its own without affecting the recording of the stream.
+
+mod hub;
+mod rtp;
+
+pub use hub::Hub;
+pub use rtp::VideoParameters;
+
+use bytes::{Buf, Bytes, BytesMut};
+use db::dir::SampleFileDir;
+use db::recording::{self, rescale};
+use db::{audit, auth};
+use failure::{bail, format_err, Error};
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::{FutureExt, SinkExt, StreamExt};
+use log::{debug, info, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+use uuid::Uuid;
+
+/// The maximum size of a request's headers and body.
+const MAX_REQUEST_BYTES: usize = 16 << 10;
+
+/// The number of RTP packets which may be queued for a connection before a playing session
+/// blocks. A blocked live session will then skip frames; see [`Hub`].
+const WRITE_QUEUE_PACKETS: usize = 256;
+
+/// The session timeout advertised to clients. Clients send keepalives within this time, but
+/// the session actually lasts as long as the connection.
+const SESSION_TIMEOUT_SEC: u32 = 60;
+
+const REALM: &str = "Moonfire NVR";
+
+pub struct Config {
+    pub db: Arc<db::Database>,
+    pub hub: Arc<Hub>,
+    pub allow_unauthenticated_permissions: Option<db::Permissions>,
+}
+
+pub struct Server {
+    db: Arc<db::Database>,
+    hub: Arc<Hub>,
+    allow_unauthenticated_permissions: Option<db::Permissions>,
+    rand: SystemRandom,
+}
+
+/// A parsed RTSP request (RFC 2326 section 6).
+#[derive(Debug)]
+struct Request {
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| &v[..])
+    }
+}
+
+/// A message sent by the client.
+#[derive(Debug)]
+enum Message {
+    Request(Request),
+
+    /// An interleaved data packet, such as an RTCP receiver report. These are ignored.
+    Data,
+}
+
+/// Parses a message from the start of `buf`, consuming it.
+/// Returns `None` if more data is needed.
+fn parse_message(buf: &mut BytesMut) -> Result<Option<Message>, Error> {
+    if buf.first() == Some(&b'$') {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = 4 + usize::from(u16::from_be_bytes([buf[2], buf[3]]));
+        if buf.len() < len {
+            return Ok(None);
+        }
+        buf.advance(len);
+        return Ok(Some(Message::Data));
+    }
+    let head_len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(p) => p + 4,
+        None if buf.len() > MAX_REQUEST_BYTES => bail!("request headers too long"),
+        None => return Ok(None),
+    };
+    let head = std::str::from_utf8(&buf[..head_len])
+        .map_err(|_| format_err!("request headers aren't UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap();
+    let mut parts = request_line.split(' ');
+    let (method, uri) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(u), Some("RTSP/1.0")) => (m.to_owned(), u.to_owned()),
+        _ => bail!("bad request line {:?}", request_line),
+    };
+    let mut headers = Vec::new();
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let colon = line
+            .find(':')
+            .ok_or_else(|| format_err!("bad header line {:?}", line))?;
+        headers.push((
+            line[..colon].trim().to_owned(),
+            line[colon + 1..].trim().to_owned(),
+        ));
+    }
+    let req = Request {
+        method,
+        uri,
+        headers,
+    };
+    let body_len = match req.header("Content-Length") {
+        None => 0,
+        Some(l) => l
+            .parse::<usize>()
+            .map_err(|_| format_err!("bad Content-Length {:?}", l))?,
+    };
+    if head_len + body_len > MAX_REQUEST_BYTES {
+        bail!("request too long");
+    }
+    if buf.len() < head_len + body_len {
+        return Ok(None);
+    }
+
+    // No supported method uses a request body, so just discard it.
+    buf.advance(head_len + body_len);
+    Ok(Some(Message::Request(req)))
+}
+
+/// Parses a time such as `19961108T142300.25Z` (RFC 2326 section 3.7).
+fn parse_clock_time(s: &str) -> Result<recording::Time, Error> {
+    if !s.ends_with('Z') {
+        bail!("clock time {:?} isn't UTC", s);
+    }
+    let s = &s[..s.len() - 1];
+    let (whole, frac) = match s.find('.') {
+        Some(p) => (&s[..p], &s[p + 1..]),
+        None => (s, ""),
+    };
+    let tm = time::strptime(whole, "%Y%m%dT%H%M%S")
+        .map_err(|e| format_err!("bad clock time {:?}: {}", s, e))?;
+    let mut frac_90k = 0;
+    if !frac.is_empty() {
+        if frac.len() > 9 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+            bail!("bad fraction in clock time {:?}", s);
+        }
+        let f: i64 = frac.parse().unwrap();
+        frac_90k = f * recording::TIME_UNITS_PER_SEC / 10i64.pow(frac.len() as u32);
+    }
+    Ok(recording::Time(
+        tm.to_timespec().sec * recording::TIME_UNITS_PER_SEC + frac_90k,
+    ))
+}
+
+/// Parses a `Range` header value, returning `None` if it's not an absolute (`clock`) range.
+/// A missing end means to play until the end of the recorded video.
+fn parse_clock_range(value: &str) -> Result<Option<Range<recording::Time>>, Error> {
+    let value = value.split(';').next().unwrap().trim();
+    if !value.starts_with("clock=") {
+        return Ok(None);
+    }
+    let value = &value["clock=".len()..];
+    let dash = value
+        .find('-')
+        .ok_or_else(|| format_err!("bad clock range {:?}", value))?;
+    let start = parse_clock_time(&value[..dash])?;
+    let end = match &value[dash + 1..] {
+        "" => recording::Time::max_value(),
+        e => parse_clock_time(e)?,
+    };
+    if end <= start {
+        bail!("empty clock range {:?}", value);
+    }
+    Ok(Some(start..end))
+}
+
+/// A response to be sent to the client.
+struct Response {
+    status: &'static str,
+    headers: String,
+    body: String,
+}
+
+impl Response {
+    fn new(status: &'static str) -> Self {
+        Response {
+            status,
+            headers: String::new(),
+            body: String::new(),
+        }
+    }
+
+    /// Returns an error response with a plain-text explanation.
+    fn error(status: &'static str, msg: impl std::fmt::Display) -> Self {
+        Response::new(status).body("text/plain", msg.to_string())
+    }
+
+    fn header(mut self, name: &str, value: impl std::fmt::Display) -> Self {
+        write!(&mut self.headers, "{}: {}\r\n", name, value).unwrap();
+        self
+    }
+
+    fn body(self, content_type: &str, body: String) -> Self {
+        let mut r = self.header("Content-Type", content_type);
+        r.body = body;
+        r
+    }
+
+    fn into_bytes(self, cseq: Option<&str>) -> Bytes {
+        let mut out = format!("RTSP/1.0 {}\r\n", self.status);
+        if let Some(c) = cseq {
+            write!(&mut out, "CSeq: {}\r\n", c).unwrap();
+        }
+        write!(&mut out, "Server: moonfire-nvr\r\n{}", self.headers).unwrap();
+        if !self.body.is_empty() {
+            write!(&mut out, "Content-Length: {}\r\n", self.body.len()).unwrap();
+        }
+        out.push_str("\r\n");
+        out.push_str(&self.body);
+        Bytes::from(out)
+    }
+}
+
+/// Returns true iff the given permissions allow viewing at least one camera's video.
+fn may_view_any_camera(p: &db::Permissions) -> bool {
+    p.view_video || !p.view_video_cameras.is_empty()
+}
+
+/// Checks that the given permissions allow viewing the given camera's video, through either the
+/// `view_video` permission (which covers all cameras) or a grant for this camera.
+fn check_view_camera(p: &db::Permissions, uuid: Uuid) -> Result<(), Response> {
+    if p.view_video
+        || p.view_video_cameras
+            .iter()
+            .any(|c| &c[..] == uuid.as_bytes())
+    {
+        return Ok(());
+    }
+    Err(Response::error(
+        "403 Forbidden",
+        format!("view_video required for camera {}", uuid),
+    ))
+}
+
+/// A stream as named by a request URL.
+struct StreamUrl {
+    stream_id: i32,
+    camera_uuid: Uuid,
+    name: String,
+
+    /// The URL to which media control URLs are relative, ending with a `/`.
+    base: Url,
+}
+
+/// An authenticated client.
+#[derive(Clone)]
+struct Caller {
+    permissions: db::Permissions,
+
+    /// The id and name of the user, if any. Unset with `--allow-unauthenticated-permissions`.
+    user: Option<(i32, String)>,
+}
+
+/// The state of a session, which is set up by `SETUP` and lasts until `TEARDOWN` or the end of
+/// the connection.
+struct Session {
+    id: String,
+    stream_id: i32,
+    camera_uuid: Uuid,
+    base: Url,
+    channel: u8,
+    ssrc: u32,
+    play: Option<tokio::task::JoinHandle<()>>,
+}
+
+struct Connection {
+    server: Arc<Server>,
+    tx: mpsc::Sender<Bytes>,
+
+    /// The most recent `Authorization` header value which was successfully verified and the
+    /// caller it identifies, to avoid an expensive password check on each request.
+    authorized: Option<(String, Caller)>,
+
+    /// The peer's address, recorded with API token use and in the audit log.
+    addr: Option<std::net::IpAddr>,
+    session: Option<Session>,
+
+    /// A play task to start after the current response is sent.
+    pending_play: Option<BoxFuture<'static, Result<(), Error>>>,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Self {
+        Server {
+            db: config.db,
+            hub: config.hub,
+            allow_unauthenticated_permissions: config.allow_unauthenticated_permissions,
+            rand: SystemRandom::new(),
+        }
+    }
+
+    /// Serves connections from the given listener; never returns.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (conn, addr) = match listener.accept().await {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("Unable to accept RTSP connection: {}", e);
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                debug!("RTSP connection from {}", addr);
+                if let Err(e) = server.serve_conn(conn).await {
+                    info!(
+                        "RTSP connection from {} failed: {}",
+                        addr,
+                        base::prettify_failure(&e)
+                    );
+                }
+            });
+        }
+    }
+
+    async fn serve_conn(self: Arc<Self>, conn: TcpStream) -> Result<(), Error> {
+        conn.set_nodelay(true)?;
+        let addr = conn.peer_addr().ok().map(|a| a.ip());
+        let (mut r, mut w) = conn.into_split();
+        let (tx, mut rx) = mpsc::channel::<Bytes>(WRITE_QUEUE_PACKETS);
+        let writer = tokio::spawn(async move {
+            while let Some(b) = rx.next().await {
+                w.write_all(&b[..]).await?;
+            }
+            Ok::<_, std::io::Error>(())
+        });
+        let mut conn = Connection {
+            server: self,
+            tx,
+            authorized: None,
+            addr,
+            session: None,
+            pending_play: None,
+        };
+        let mut buf = BytesMut::with_capacity(4096);
+        let result = async {
+            loop {
+                while let Some(m) = parse_message(&mut buf)? {
+                    if let Message::Request(req) = m {
+                        conn.handle(req).await?;
+                    }
+                }
+                if r.read_buf(&mut buf).await? == 0 {
+                    return Ok::<_, Error>(());
+                }
+            }
+        }
+        .await;
+        conn.teardown();
+        drop(conn);
+        writer.await??;
+        result
+    }
+
+    fn random_u32(&self) -> u32 {
+        let mut b = [0u8; 4];
+        self.rand.fill(&mut b).unwrap();
+        u32::from_be_bytes(b)
+    }
+
+    /// Looks up the stream named by a request URL, ignoring any trailing media control path.
+    fn lookup_stream(&self, uri: &str) -> Result<StreamUrl, Response> {
+        let mut url =
+            Url::parse(uri).map_err(|e| Response::error("400 Bad Request", e.to_string()))?;
+        let (camera, type_) = {
+            let mut segs = url.path_segments().into_iter().flatten();
+            match (segs.next(), segs.next()) {
+                (Some(c), Some(t)) => (c.to_owned(), t.to_owned()),
+                _ => return Err(Response::error("404 Not Found", "no such stream")),
+            }
+        };
+        let type_ = db::StreamType::parse(&type_)
+            .ok_or_else(|| Response::error("404 Not Found", "no such stream type"))?;
+        let db = self.db.lock();
+        let c = match Uuid::parse_str(&camera) {
+            Ok(uuid) => db.get_camera(uuid),
+            Err(_) => db.cameras_by_id().values().find(|c| c.short_name == camera),
+        }
+        .ok_or_else(|| Response::error("404 Not Found", "no such camera"))?;
+        let stream_id = c.streams[type_.index()]
+            .ok_or_else(|| Response::error("404 Not Found", "no such stream"))?;
+        let name = format!("{}-{}", c.short_name, type_.as_str());
+        url.set_path(&format!("/{}/{}/", camera, type_.as_str()));
+        url.set_query(None);
+        Ok(StreamUrl {
+            stream_id,
+            camera_uuid: c.uuid,
+            name,
+            base: url,
+        })
+    }
+
+    /// Returns the stream's current sample file directory, opening it (and its archive
+    /// directory) if this hasn't happened yet. Streams may be added or reconfigured while the
+    /// server is running, so this is looked up on each request.
+    fn sample_file_dir(&self, stream_id: i32) -> Result<Arc<SampleFileDir>, Error> {
+        let mut l = self.db.lock();
+        let s = l
+            .streams_by_id()
+            .get(&stream_id)
+            .ok_or_else(|| format_err!("no such stream {}", stream_id))?;
+        let dir_id = s
+            .sample_file_dir_id
+            .ok_or_else(|| format_err!("stream {} has no sample file directory", stream_id))?;
+        let ids: Vec<i32> = std::iter::once(dir_id)
+            .chain(s.archive_sample_file_dir_id)
+            .collect();
+        l.open_sample_file_dirs(&ids)?;
+        l.sample_file_dirs_by_id()
+            .get(&dir_id)
+            .ok_or_else(|| format_err!("no such dir {}", dir_id))?
+            .get()
+    }
+
+    /// Returns the parameters of the video sample entry with the given id.
+    fn video_parameters(&self, id: i32) -> Result<rtp::VideoParameters, Error> {
+        let db = self.db.lock();
+        let e = db
+            .video_sample_entries_by_id()
+            .get(&id)
+            .ok_or_else(|| format_err!("no such video sample entry {}", id))?;
+        rtp::VideoParameters::parse(&e.data)
+    }
+
+    /// Sends live frames of the given stream until the connection is closed.
+    async fn play_live(
+        self: Arc<Self>,
+        stream_id: i32,
+        mut packetizer: rtp::Packetizer,
+        mut tx: mpsc::Sender<Bytes>,
+    ) -> Result<(), Error> {
+        let mut rx = self.hub.subscribe(stream_id);
+        let mut next_seq = None;
+        let mut params: Option<(i32, rtp::VideoParameters)> = None;
+        let mut packets = Vec::new();
+        while let Some(f) = rx.next().await {
+            // After a gap, wait for a key frame.
+            if next_seq != Some(f.seq) && !f.is_key {
+                next_seq = None;
+                continue;
+            }
+            next_seq = Some(f.seq + 1);
+            if params.as_ref().map(|p| p.0) != Some(f.video_sample_entry_id) {
+                let p = self.video_parameters(f.video_sample_entry_id)?;
+                params = Some((f.video_sample_entry_id, p));
+            }
+            let p = &params.as_ref().unwrap().1;
+            packetizer.push_frame(p, f.pts, f.is_key, &f.data[..], &mut packets)?;
+            for p in packets.drain(..) {
+                if tx.send(p).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends recorded frames of the given stream within the given time range, paced in real
+    /// time. Each frame's RTP timestamp reflects its wall time.
+    async fn play_recorded(
+        self: Arc<Self>,
+        stream_id: i32,
+        range: Range<recording::Time>,
+        mut packetizer: rtp::Packetizer,
+        mut tx: mpsc::Sender<Bytes>,
+    ) -> Result<(), Error> {
+        struct Sample {
+            pos: u64,
+            bytes: usize,
+            wall_90k: i64,
+            is_key: bool,
+        }
+        let dir = self.sample_file_dir(stream_id)?;
+        let mut rows = Vec::new();
+        self.db
+            .lock()
+            .list_recordings_by_time(stream_id, range.clone(), &mut |r| {
+                rows.push(r);
+                Ok(())
+            })?;
+        rows.sort_by_key(|r| r.id.0);
+        let mut clock_start: Option<(i64, tokio::time::Instant)> = None;
+        let mut params: Option<(i32, rtp::VideoParameters)> = None;
+        let mut packets = Vec::new();
+        for row in &rows {
+            // Find the frames to send, starting from the last key frame at or before the range.
+            let wd = i64::from(row.wall_duration_90k);
+            let start = (range.start - row.start).0.max(0).min(wd) as i32;
+            let end = (range.end.0.saturating_sub(row.start.0)).max(0).min(wd) as i32;
+            let media_range = rescale(start, row.wall_duration_90k, row.media_duration_90k)
+                ..rescale(end, row.wall_duration_90k, row.media_duration_90k);
+            let mut samples = Vec::new();
+            self.db.lock().with_recording_playback(row.id, &mut |p| {
+                let mut it = recording::SampleIndexIterator::default();
+                while it.next(p.video_index)? {
+                    if it.start_90k >= media_range.end {
+                        break;
+                    }
+                    if it.is_key() && it.start_90k <= media_range.start {
+                        samples.clear();
+                    } else if samples.is_empty() && !it.is_key() {
+                        continue;
+                    }
+                    let wall = rescale(it.start_90k, row.media_duration_90k, row.wall_duration_90k);
+                    samples.push(Sample {
+                        pos: u64::try_from(it.pos)?,
+                        bytes: usize::try_from(it.bytes)?,
+                        wall_90k: row.start.0 + i64::from(wall),
+                        is_key: it.is_key(),
+                    });
+                }
+                Ok(())
+            })?;
+            let (first, last) = match (samples.first(), samples.last()) {
+                (Some(f), Some(l)) => (f, l),
+                _ => continue,
+            };
+            if params.as_ref().map(|p| p.0) != Some(row.video_sample_entry_id) {
+                let p = self.video_parameters(row.video_sample_entry_id)?;
+                params = Some((row.video_sample_entry_id, p));
+            }
+            let p = &params.as_ref().unwrap().1;
+            let mut file = dir.open_file(row.id, first.pos..last.pos + last.bytes as u64);
+            let mut buf = BytesMut::new();
+            for s in &samples {
+                while buf.len() < s.bytes {
+                    let chunk = file
+                        .next()
+                        .await
+                        .ok_or_else(|| format_err!("{}: sample file is truncated", row.id))??;
+                    buf.extend_from_slice(&chunk[..]);
+                }
+                let data = buf.split_to(s.bytes);
+                let (wall0, instant0) =
+                    *clock_start.get_or_insert_with(|| (s.wall_90k, tokio::time::Instant::now()));
+                let offset_90k = u64::try_from(s.wall_90k - wall0).unwrap_or(0);
+                tokio::time::sleep_until(
+                    instant0
+                        + std::time::Duration::from_micros(
+                            offset_90k * 1_000_000 / recording::TIME_UNITS_PER_SEC as u64,
+                        ),
+                )
+                .await;
+                packetizer.push_frame(p, s.wall_90k, s.is_key, &data[..], &mut packets)?;
+                for p in packets.drain(..) {
+                    if tx.send(p).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Connection {
+    async fn handle(&mut self, req: Request) -> Result<(), Error> {
+        debug!("RTSP request: {} {}", req.method, req.uri);
+        let resp = self.handle_inner(&req).unwrap_or_else(|r| r);
+        self.tx
+            .send(resp.into_bytes(req.header("CSeq")))
+            .await
+            .map_err(|_| format_err!("connection closed"))?;
+        if let Some(play) = self.pending_play.take() {
+            let session = self.session.as_mut().unwrap();
+            let id = session.id.clone();
+            session.play = Some(tokio::spawn(async move {
+                if let Err(e) = play.await {
+                    info!("RTSP session {} failed: {}", id, base::prettify_failure(&e));
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    fn handle_inner(&mut self, req: &Request) -> Result<Response, Response> {
+        match &req.method[..] {
+            "OPTIONS" => {
+                return Ok(Response::new("200 OK").header(
+                    "Public",
+                    "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER, SET_PARAMETER",
+                ))
+            }
+            "DESCRIBE" | "SETUP" | "PLAY" | "TEARDOWN" | "GET_PARAMETER" | "SET_PARAMETER" => {}
+            _ => return Err(Response::new("501 Not Implemented")),
+        }
+        let caller = self.authenticate(req)?;
+        match &req.method[..] {
+            "DESCRIBE" => self.describe(req, &caller.permissions),
+            "SETUP" => self.setup(req, &caller.permissions),
+            "PLAY" => self.play(req, &caller),
+            "TEARDOWN" => {
+                self.check_session(req)?;
+                self.teardown();
+                Ok(Response::new("200 OK"))
+            }
+            _ => Ok(Response::new("200 OK")), // keepalive.
+        }
+    }
+
+    /// Returns the caller, either from `--allow-unauthenticated-permissions` or by HTTP-style
+    /// `Basic` authentication. Fails unless its permissions allow viewing some camera's video;
+    /// the caller should check the specific camera with `check_view_camera`.
+    ///
+    /// The `Basic` password may be either the user's password or one of the user's API tokens.
+    /// Users enrolled in TOTP must use an API token, as RTSP has no way to supply a TOTP code.
+    fn authenticate(&mut self, req: &Request) -> Result<Caller, Response> {
+        let forbidden = || Response::error("403 Forbidden", "view_video required");
+        if let Some(p) = self.server.allow_unauthenticated_permissions.as_ref() {
+            return if may_view_any_camera(p) {
+                Ok(Caller {
+                    permissions: p.clone(),
+                    user: None,
+                })
+            } else {
+                Err(forbidden())
+            };
+        }
+        let unauthorized = || {
+            Response::new("401 Unauthorized")
+                .header("WWW-Authenticate", format!("Basic realm=\"{}\"", REALM))
+        };
+        let h = req.header("Authorization").ok_or_else(unauthorized)?;
+        if let Some((ref a, ref p)) = self.authorized {
+            if a == h {
+                return Ok(p.clone());
+            }
+        }
+        if h.len() < 6 || !h[..6].eq_ignore_ascii_case("Basic ") {
+            return Err(unauthorized());
+        }
+        let decoded = base64::decode(h[6..].trim()).map_err(|_| unauthorized())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| unauthorized())?;
+        let colon = decoded.find(':').ok_or_else(unauthorized)?;
+        let (username, password) = (&decoded[..colon], &decoded[colon + 1..]);
+        let caller = self.check_credentials(username, password).map_err(|e| {
+            info!("RTSP authentication failed: {}", e);
+            unauthorized()
+        })?;
+        if !may_view_any_camera(&caller.permissions) {
+            return Err(forbidden());
+        }
+        self.authorized = Some((h.to_owned(), caller.clone()));
+        Ok(caller)
+    }
+
+    /// Returns the caller identified by the given `Basic` credentials.
+    fn check_credentials(&self, username: &str, password: &str) -> Result<Caller, Error> {
+        let when_sec = self.server.db.clocks().realtime().sec;
+        let mut db = self.server.db.lock();
+        let token = match auth::RawSessionId::decode_base64(password.as_bytes()) {
+            Ok(t) => t,
+            Err(_) => {
+                let permissions = db.verify_password(username, password)?;
+                let id = db
+                    .get_user(username)
+                    .ok_or_else(|| format_err!("no such user {:?}", username))?
+                    .id;
+                return Ok(Caller {
+                    permissions,
+                    user: Some((id, username.to_owned())),
+                });
+            }
+        };
+        let req = auth::Request {
+            when_sec: Some(when_sec),
+            addr: self.addr,
+            user_agent: None,
+        };
+        let (s, u) = db.authenticate_session(req, &token.hash())?;
+        if !s.api_token() {
+            bail!("session cookie supplied in place of API token");
+        }
+        if u.username != username {
+            bail!(
+                "API token belongs to user {:?}, not {:?}",
+                u.username,
+                username
+            );
+        }
+        Ok(Caller {
+            permissions: s.permissions.clone(),
+            user: Some((u.id, u.username.clone())),
+        })
+    }
+
+    fn describe(
+        &mut self,
+        req: &Request,
+        permissions: &db::Permissions,
+    ) -> Result<Response, Response> {
+        let s = self.server.lookup_stream(&req.uri)?;
+        check_view_camera(permissions, s.camera_uuid)?;
+
+        // Describe the stream as most recently received, or failing that, as recorded.
+        let video_sample_entry_id = match self.server.hub.video_sample_entry_id(s.stream_id) {
+            Some(id) => id,
+            None => {
+                let db = self.server.db.lock();
+                let range = db
+                    .streams_by_id()
+                    .get(&s.stream_id)
+                    .and_then(|s| s.range.clone())
+                    .ok_or_else(|| Response::error("404 Not Found", "stream has no video yet"))?;
+                let mut id = None;
+                db.list_recordings_by_time(
+                    s.stream_id,
+                    range.end - recording::Duration(1)..range.end,
+                    &mut |r| {
+                        id = Some(r.video_sample_entry_id);
+                        Ok(())
+                    },
+                )
+                .map_err(|e| Response::error("500 Internal Server Error", e))?;
+                id.ok_or_else(|| Response::error("404 Not Found", "stream has no video yet"))?
+            }
+        };
+        let params = self
+            .server
+            .video_parameters(video_sample_entry_id)
+            .map_err(|e| Response::error("500 Internal Server Error", e))?;
+        let sdp = format!(
+            "v=0\r\n\
+             o=- 0 0 IN IP4 0.0.0.0\r\n\
+             s={}\r\n\
+             t=0 0\r\n\
+             a=control:*\r\n\
+             {}",
+            s.name,
+            params.sdp_media("trackID=0")
+        );
+        Ok(Response::new("200 OK")
+            .header("Content-Base", &s.base)
+            .body("application/sdp", sdp))
+    }
+
+    fn setup(
+        &mut self,
+        req: &Request,
+        permissions: &db::Permissions,
+    ) -> Result<Response, Response> {
+        let s = self.server.lookup_stream(&req.uri)?;
+        check_view_camera(permissions, s.camera_uuid)?;
+        if let Some(session) = self.session.as_ref() {
+            self.check_session(req)?;
+            if session.stream_id != s.stream_id {
+                return Err(Response::error(
+                    "459 Aggregate Operation Not Allowed",
+                    "only one stream per session is supported",
+                ));
+            }
+        }
+        let transport = req
+            .header("Transport")
+            .ok_or_else(|| Response::error("400 Bad Request", "no Transport header"))?;
+
+        // Choose the first acceptable transport of the client's preferences.
+        let mut channel = None;
+        for t in transport.split(',') {
+            let mut params = t.split(';').map(str::trim);
+            if params.next() != Some("RTP/AVP/TCP") {
+                continue;
+            }
+            let mut c = 0;
+            for p in params {
+                if p.starts_with("interleaved=") {
+                    let v = &p["interleaved=".len()..];
+                    let first = v.split('-').next().unwrap();
+                    c = match first.parse::<u8>() {
+                        Ok(c) if c < u8::max_value() => c,
+                        _ => return Err(Response::error("400 Bad Request", "bad interleaved")),
+                    };
+                }
+            }
+            channel = Some(c);
+            break;
+        }
+        let channel = channel.ok_or_else(|| {
+            Response::error("461 Unsupported Transport", "only RTP/AVP/TCP is supported")
+        })?;
+        if self.session.is_none() {
+            let mut id = [0u8; 8];
+            self.server.rand.fill(&mut id).unwrap();
+            self.session = Some(Session {
+                id: format!("{:016x}", u64::from_be_bytes(id)),
+                stream_id: s.stream_id,
+                camera_uuid: s.camera_uuid,
+                base: s.base,
+                channel,
+                ssrc: self.server.random_u32(),
+                play: None,
+            });
+        }
+        let session = self.session.as_mut().unwrap();
+        session.channel = channel;
+        Ok(Response::new("200 OK")
+            .header(
+                "Transport",
+                format!(
+                    "RTP/AVP/TCP;unicast;interleaved={}-{};ssrc={:08X}",
+                    channel,
+                    channel + 1,
+                    session.ssrc
+                ),
+            )
+            .header(
+                "Session",
+                format!("{};timeout={}", session.id, SESSION_TIMEOUT_SEC),
+            ))
+    }
+
+    fn play(&mut self, req: &Request, caller: &Caller) -> Result<Response, Response> {
+        self.check_session(req)?;
+        let (stream_id, camera_uuid) = {
+            let s = self.session.as_ref().unwrap();
+            (s.stream_id, s.camera_uuid)
+        };
+        check_view_camera(&caller.permissions, camera_uuid)?;
+        let range = match req.header("Range") {
+            None => None,
+            Some(r) => parse_clock_range(r).map_err(|e| Response::error("400 Bad Request", e))?,
+        };
+        self.audit_play(caller, stream_id, camera_uuid, range.clone());
+        let seq = self.server.random_u32() as u16;
+        let rtptime = self.server.random_u32();
+        let server = self.server.clone();
+        let tx = self.tx.clone();
+        let session = self.session.as_mut().unwrap();
+        if let Some(p) = session.play.take() {
+            p.abort();
+        }
+        let packetizer = rtp::Packetizer::new(session.channel, session.ssrc, seq, rtptime);
+        let mut resp = Response::new("200 OK")
+            .header("Session", &session.id)
+            .header(
+                "RTP-Info",
+                format!(
+                    "url={}trackID=0;seq={};rtptime={}",
+                    session.base, seq, rtptime
+                ),
+            );
+        self.pending_play = Some(match range {
+            None => {
+                resp = resp.header("Range", "npt=now-");
+                server.play_live(session.stream_id, packetizer, tx).boxed()
+            }
+            Some(r) => {
+                resp = resp.header("Range", req.header("Range").unwrap());
+                server
+                    .play_recorded(session.stream_id, r, packetizer, tx)
+                    .boxed()
+            }
+        });
+        Ok(resp)
+    }
+
+    /// Records a `PLAY` of live video (if `range` is unset) or recorded video in the audit log.
+    fn audit_play(
+        &self,
+        caller: &Caller,
+        stream_id: i32,
+        camera_uuid: Uuid,
+        range: Option<Range<recording::Time>>,
+    ) {
+        let mut e = audit::Event::new(self.server.db.clocks().realtime().sec, audit::Kind::View);
+        if let Some((id, ref name)) = caller.user {
+            e.user_id = Some(id);
+            e.username = Some(name.clone());
+        }
+        e.peer_addr = self.addr;
+        e.camera_uuid = Some(camera_uuid);
+        let mut db = self.server.db.lock();
+        e.detail = db.streams_by_id().get(&stream_id).map(|s| match range {
+            None => format!("rtsp live {}", s.type_),
+            Some(_) => format!("rtsp {}", s.type_),
+        });
+        e.time_90k = range;
+        db.audit(e);
+    }
+
+    /// Checks that the request names the current session.
+    fn check_session(&self, req: &Request) -> Result<(), Response> {
+        let session = self
+            .session
+            .as_ref()
+            .ok_or_else(|| Response::error("455 Method Not Valid in This State", "no session"))?;
+        let id = req
+            .header("Session")
+            .map(|s| s.split(';').next().unwrap().trim());
+        if id != Some(&session.id[..]) {
+            return Err(Response::new("454 Session Not Found"));
+        }
+        Ok(())
+    }
+
+    fn teardown(&mut self) {
+        if let Some(s) = self.session.take() {
+            if let Some(p) = s.play {
+                p.abort();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        let mut buf = BytesMut::from(
+            &b"$\x01\x00\x02ab\
+               GET_PARAMETER rtsp://nvr/cam/main/ RTSP/1.0\r\n\
+               CSeq: 3\r\n\
+               content-length: 4\r\n\
+               \r\n\
+               body\
+               PLAY rtsp://nvr/cam/main/ RTSP/1.0\r\n"[..],
+        );
+        assert!(matches!(
+            parse_message(&mut buf).unwrap(),
+            Some(Message::Data)
+        ));
+        let req = match parse_message(&mut buf).unwrap() {
+            Some(Message::Request(r)) => r,
+            o => panic!("unexpected {:?}", o),
+        };
+        assert_eq!(req.method, "GET_PARAMETER");
+        assert_eq!(req.uri, "rtsp://nvr/cam/main/");
+        assert_eq!(req.header("cseq"), Some("3"));
+        assert_eq!(req.header("Session"), None);
+
+        // The following request is incomplete.
+        assert!(parse_message(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"CSeq: 4\r\n\r\n");
+        match parse_message(&mut buf).unwrap() {
+            Some(Message::Request(r)) => assert_eq!(r.method, "PLAY"),
+            o => panic!("unexpected {:?}", o),
+        };
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"garbage\r\n\r\n"[..]);
+        parse_message(&mut buf).unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_clock_range() {
+        assert!(parse_clock_range("npt=0-").unwrap().is_none());
+        assert_eq!(
+            parse_clock_range("clock=20210102T030405.25Z-20210102T030506Z").unwrap(),
+            Some(
+                recording::Time::parse("2021-01-02T03:04:05:22500Z").unwrap()
+                    ..recording::Time::parse("2021-01-02T03:05:06Z").unwrap()
+            )
+        );
+        assert_eq!(
+            parse_clock_range("clock=20210102T030405Z-;time=20210102T030405Z").unwrap(),
+            Some(
+                recording::Time::parse("2021-01-02T03:04:05Z").unwrap()
+                    ..recording::Time::max_value()
+            )
+        );
+        parse_clock_range("clock=20210102T030405-").unwrap_err();
+        parse_clock_range("clock=20210102T030405Z-20210102T030405Z").unwrap_err();
+    }
+
+    #[test]
+    fn test_check_view_camera() {
+        let granted = Uuid::parse_str("ce2d9bc2-0cd3-4204-9324-7b5ccb07183c").unwrap();
+        let other = Uuid::parse_str("8bd5a7a1-cc5e-4a0b-a5cd-1e4dd7a9c0e0").unwrap();
+        let mut p = db::Permissions::default();
+        assert!(!may_view_any_camera(&p));
+        p.view_video_cameras.push(granted.as_bytes().to_vec());
+        assert!(may_view_any_camera(&p));
+        check_view_camera(&p, granted).unwrap();
+        check_view_camera(&p, other).unwrap_err();
+        p.view_video = true;
+        check_view_camera(&p, other).unwrap();
+    }
+}
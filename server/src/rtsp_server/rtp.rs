@@ -0,0 +1,454 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! RTP packetization of H.264 (RFC 6184) and H.265 (RFC 7798) video, as well as the SDP media
+//! descriptions to match.
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use failure::{bail, format_err, Error};
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+/// The dynamic RTP payload type used for video.
+const PAYLOAD_TYPE: u8 = 96;
+
+/// The maximum RTP payload size. Larger NAL units are fragmented.
+/// This keeps packets within a typical Ethernet MTU, as receivers may relay them over UDP.
+const MAX_PAYLOAD: usize = 1400;
+
+/// The offset of the first child box within a `VisualSampleEntry` (ISO/IEC 14496-12 section
+/// 12.1.3): the box header plus the fixed fields.
+const VISUAL_SAMPLE_ENTRY_CHILDREN: usize = 86;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Codec {
+    H264,
+    H265,
+}
+
+/// Codec parameters, as derived from a video sample entry.
+#[derive(Debug)]
+pub struct VideoParameters {
+    codec: Codec,
+
+    /// The size of each NAL unit's length prefix within the sample data.
+    length_size: usize,
+
+    /// The parameter set NAL units, in decoding order: SPS and PPS for H.264; VPS, SPS, and PPS
+    /// for H.265.
+    parameter_sets: Vec<Vec<u8>>,
+
+    /// The SDP `a=fmtp` parameters.
+    fmtp: String,
+}
+
+/// Returns the contents of the given child box of a sample entry.
+fn find_child_box<'a>(sample_entry: &'a [u8], type_: &[u8]) -> Option<&'a [u8]> {
+    let mut pos = VISUAL_SAMPLE_ENTRY_CHILDREN;
+    while pos + 8 <= sample_entry.len() {
+        let len = usize::try_from(BigEndian::read_u32(&sample_entry[pos..pos + 4])).ok()?;
+        if len < 8 || pos + len > sample_entry.len() {
+            return None;
+        }
+        if &sample_entry[pos + 4..pos + 8] == type_ {
+            return Some(&sample_entry[pos + 8..pos + len]);
+        }
+        pos += len;
+    }
+    None
+}
+
+/// Reads `n` NAL units, each prefixed by a 16-bit length, returning them and the following position.
+fn read_nal_units(data: &[u8], mut pos: usize, n: usize) -> Result<(Vec<Vec<u8>>, usize), Error> {
+    let mut units = Vec::with_capacity(n);
+    for _ in 0..n {
+        let len = data
+            .get(pos..pos + 2)
+            .ok_or_else(|| format_err!("decoder configuration is truncated"))?;
+        let len = usize::from(BigEndian::read_u16(len));
+        let unit = data
+            .get(pos + 2..pos + 2 + len)
+            .ok_or_else(|| format_err!("decoder configuration is truncated"))?;
+        units.push(unit.to_vec());
+        pos += 2 + len;
+    }
+    Ok((units, pos))
+}
+
+impl VideoParameters {
+    /// Parses an `avc1`, `hvc1`, or `hev1` sample entry.
+    pub fn parse(sample_entry: &[u8]) -> Result<Self, Error> {
+        if sample_entry.len() < 8 {
+            bail!("sample entry is too short");
+        }
+        match &sample_entry[4..8] {
+            b"avc1" => {
+                // AVCDecoderConfigurationRecord, ISO/IEC 14496-15 section 5.2.4.1.
+                let avcc = find_child_box(sample_entry, b"avcC")
+                    .ok_or_else(|| format_err!("avc1 sample entry has no avcC box"))?;
+                if avcc.len() < 6 || avcc[0] != 1 {
+                    bail!("bad AVCDecoderConfigurationRecord");
+                }
+                let length_size = usize::from(avcc[4] & 0x03) + 1;
+                let (sps, pos) = read_nal_units(avcc, 6, usize::from(avcc[5] & 0x1F))?;
+                let num_pps = *avcc
+                    .get(pos)
+                    .ok_or_else(|| format_err!("decoder configuration is truncated"))?;
+                let (pps, _) = read_nal_units(avcc, pos + 1, usize::from(num_pps))?;
+                if sps.is_empty() || pps.is_empty() {
+                    bail!("AVCDecoderConfigurationRecord lacks SPS or PPS");
+                }
+                let mut fmtp = format!(
+                    "packetization-mode=1;profile-level-id={:02X}{:02X}{:02X};\
+                     sprop-parameter-sets=",
+                    avcc[1], avcc[2], avcc[3]
+                );
+                for (i, unit) in sps.iter().chain(pps.iter()).enumerate() {
+                    if i > 0 {
+                        fmtp.push(',');
+                    }
+                    fmtp.push_str(&base64::encode(unit));
+                }
+                let mut parameter_sets = sps;
+                parameter_sets.extend(pps);
+                Ok(VideoParameters {
+                    codec: Codec::H264,
+                    length_size,
+                    parameter_sets,
+                    fmtp,
+                })
+            }
+            b"hvc1" | b"hev1" => {
+                // HEVCDecoderConfigurationRecord, ISO/IEC 14496-15 section 8.3.3.1.
+                let hvcc = find_child_box(sample_entry, b"hvcC")
+                    .ok_or_else(|| format_err!("hvc1 sample entry has no hvcC box"))?;
+                if hvcc.len() < 23 || hvcc[0] != 1 {
+                    bail!("bad HEVCDecoderConfigurationRecord");
+                }
+                let length_size = usize::from(hvcc[21] & 0x03) + 1;
+                let mut pos = 23;
+                let mut by_type: [Vec<Vec<u8>>; 3] = Default::default();
+                for _ in 0..hvcc[22] {
+                    let hdr = hvcc
+                        .get(pos..pos + 3)
+                        .ok_or_else(|| format_err!("decoder configuration is truncated"))?;
+                    let nal_type = hdr[0] & 0x3F;
+                    let (units, next) = read_nal_units(
+                        hvcc,
+                        pos + 3,
+                        usize::from(BigEndian::read_u16(&hdr[1..3])),
+                    )?;
+                    pos = next;
+
+                    // VPS, SPS, and PPS are types 32, 33, and 34; see ISO/IEC 23008-2 table 7-1.
+                    if let Some(v) = nal_type
+                        .checked_sub(32)
+                        .and_then(|i| by_type.get_mut(usize::from(i)))
+                    {
+                        v.extend(units);
+                    }
+                }
+                let mut fmtp = String::new();
+                for (name, units) in ["sprop-vps", "sprop-sps", "sprop-pps"]
+                    .iter()
+                    .zip(by_type.iter())
+                {
+                    if units.is_empty() {
+                        bail!("HEVCDecoderConfigurationRecord lacks {}", name);
+                    }
+                    if !fmtp.is_empty() {
+                        fmtp.push(';');
+                    }
+                    write!(&mut fmtp, "{}=", name).unwrap();
+                    for (i, unit) in units.iter().enumerate() {
+                        if i > 0 {
+                            fmtp.push(',');
+                        }
+                        fmtp.push_str(&base64::encode(unit));
+                    }
+                }
+                let [vps, sps, pps] = by_type;
+                let mut parameter_sets = vps;
+                parameter_sets.extend(sps);
+                parameter_sets.extend(pps);
+                Ok(VideoParameters {
+                    codec: Codec::H265,
+                    length_size,
+                    parameter_sets,
+                    fmtp,
+                })
+            }
+            t => bail!(
+                "unsupported sample entry type {:?}",
+                String::from_utf8_lossy(t)
+            ),
+        }
+    }
+
+    /// Splits a frame's sample data into NAL units, preceded by the parameter sets if it's a key
+    /// frame.
+    fn nal_units<'a>(&'a self, is_key: bool, data: &'a [u8]) -> Result<Vec<&'a [u8]>, Error> {
+        let mut units = Vec::new();
+        if is_key {
+            units.extend(self.parameter_sets.iter().map(|u| &u[..]));
+        }
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = data
+                .get(pos..pos + self.length_size)
+                .ok_or_else(|| format_err!("sample data is truncated"))?;
+            let len = usize::try_from(BigEndian::read_uint(len, self.length_size))?;
+            pos += self.length_size;
+            let unit = data
+                .get(pos..pos + len)
+                .ok_or_else(|| format_err!("sample data is truncated"))?;
+            if unit.is_empty() {
+                bail!("empty NAL unit");
+            }
+            units.push(unit);
+            pos += len;
+        }
+        Ok(units)
+    }
+
+    /// Appends a frame in the Annex B byte stream format (ITU-T H.264 / H.265 Annex B), as
+    /// understood by ffmpeg's raw `h264` and `hevc` demuxers.
+    pub fn append_annex_b(
+        &self,
+        is_key: bool,
+        data: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        for unit in self.nal_units(is_key, data)? {
+            out.extend_from_slice(b"\x00\x00\x00\x01");
+            out.extend_from_slice(unit);
+        }
+        Ok(())
+    }
+
+    /// Returns the SDP media description for this video, with the given control URL.
+    pub fn sdp_media(&self, control: &str) -> String {
+        let encoding = match self.codec {
+            Codec::H264 => "H264",
+            Codec::H265 => "H265",
+        };
+        format!(
+            "m=video 0 RTP/AVP {pt}\r\n\
+             a=rtpmap:{pt} {}/90000\r\n\
+             a=fmtp:{pt} {}\r\n\
+             a=control:{}\r\n",
+            encoding,
+            self.fmtp,
+            control,
+            pt = PAYLOAD_TYPE
+        )
+    }
+}
+
+/// Splits video frames into RTP packets, framed for interleaving on an RTSP connection
+/// (RFC 2326 section 10.12).
+pub struct Packetizer {
+    channel: u8,
+    ssrc: u32,
+    next_seq: u16,
+    initial_timestamp: u32,
+
+    /// The offset to add to frames' 90 kHz timestamps; set on the first frame.
+    timestamp_offset: Option<u32>,
+}
+
+impl Packetizer {
+    /// Creates a packetizer. The first frame will have timestamp `initial_timestamp`, regardless
+    /// of its presentation timestamp.
+    pub fn new(channel: u8, ssrc: u32, initial_seq: u16, initial_timestamp: u32) -> Self {
+        Packetizer {
+            channel,
+            ssrc,
+            next_seq: initial_seq,
+            initial_timestamp,
+            timestamp_offset: None,
+        }
+    }
+
+    /// Appends packets for a frame, given its sample data. Key frames are preceded by the
+    /// parameter sets, so that receivers may start decoding (or notice changes) there.
+    pub fn push_frame(
+        &mut self,
+        params: &VideoParameters,
+        pts_90k: i64,
+        is_key: bool,
+        data: &[u8],
+        out: &mut Vec<Bytes>,
+    ) -> Result<(), Error> {
+        let pts = pts_90k as u32; // RTP timestamps wrap.
+        let initial_timestamp = self.initial_timestamp;
+        let offset = *self
+            .timestamp_offset
+            .get_or_insert_with(|| initial_timestamp.wrapping_sub(pts));
+        let timestamp = pts.wrapping_add(offset);
+
+        let units = params.nal_units(is_key, data)?;
+        for (i, unit) in units.iter().enumerate() {
+            self.push_nal_unit(params.codec, timestamp, unit, i == units.len() - 1, out)?;
+        }
+        Ok(())
+    }
+
+    fn push_nal_unit(
+        &mut self,
+        codec: Codec,
+        timestamp: u32,
+        unit: &[u8],
+        last_of_frame: bool,
+        out: &mut Vec<Bytes>,
+    ) -> Result<(), Error> {
+        if unit.len() <= MAX_PAYLOAD {
+            out.push(self.packet(timestamp, last_of_frame, &[], unit));
+            return Ok(());
+        }
+
+        // Use fragmentation units: FU-A (RFC 6184 section 5.8) or FU (RFC 7798 section 4.4.3).
+        let (mut prefix, type_, payload) = match codec {
+            Codec::H264 => {
+                let indicator = (unit[0] & 0xE0) | 28;
+                (vec![indicator, 0], unit[0] & 0x1F, &unit[1..])
+            }
+            Codec::H265 => {
+                if unit.len() < 2 {
+                    bail!("truncated NAL unit header");
+                }
+                let payload_hdr = [(unit[0] & 0x81) | (49 << 1), unit[1]];
+                (
+                    vec![payload_hdr[0], payload_hdr[1], 0],
+                    (unit[0] >> 1) & 0x3F,
+                    &unit[2..],
+                )
+            }
+        };
+        let fu_header_pos = prefix.len() - 1;
+        let chunk_size = MAX_PAYLOAD - prefix.len();
+        let num_chunks = (payload.len() + chunk_size - 1) / chunk_size;
+        for (i, chunk) in payload.chunks(chunk_size).enumerate() {
+            let start = if i == 0 { 0x80 } else { 0 };
+            let end = if i == num_chunks - 1 { 0x40 } else { 0 };
+            prefix[fu_header_pos] = start | end | type_;
+            out.push(self.packet(timestamp, last_of_frame && end != 0, &prefix, chunk));
+        }
+        Ok(())
+    }
+
+    /// Returns an interleaved RTP packet (RFC 3550 section 5.1) with the given payload.
+    fn packet(&mut self, timestamp: u32, marker: bool, prefix: &[u8], payload: &[u8]) -> Bytes {
+        let rtp_len = 12 + prefix.len() + payload.len();
+        let mut b = BytesMut::with_capacity(4 + rtp_len);
+        b.put_u8(b'$');
+        b.put_u8(self.channel);
+        b.put_u16(rtp_len as u16);
+        b.put_u8(0x80); // version 2, no padding, no extension, no CSRCs.
+        b.put_u8(if marker { 0x80 } else { 0 } | PAYLOAD_TYPE);
+        b.put_u16(self.next_seq);
+        b.put_u32(timestamp);
+        b.put_u32(self.ssrc);
+        b.put_slice(prefix);
+        b.put_slice(payload);
+        self.next_seq = self.next_seq.wrapping_add(1);
+        b.freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an `avc1` sample entry with the given `avcC` contents.
+    fn avc1(avcc: &[u8]) -> Vec<u8> {
+        let mut e = vec![0u8; VISUAL_SAMPLE_ENTRY_CHILDREN];
+        e[4..8].copy_from_slice(b"avc1");
+        e.extend_from_slice(&(8 + avcc.len() as u32).to_be_bytes()[..]);
+        e.extend_from_slice(b"avcC");
+        e.extend_from_slice(avcc);
+        let len = e.len() as u32;
+        e[0..4].copy_from_slice(&len.to_be_bytes()[..]);
+        e
+    }
+
+    #[test]
+    fn test_h264() {
+        let params = VideoParameters::parse(&avc1(&[
+            0x01, 0x4d, 0x00, 0x1f, 0xff, // version, profile, compat, level, length size
+            0xe1, 0x00, 0x03, 0x67, 0x4d, 0x00, // 1 SPS
+            0x01, 0x00, 0x02, 0x68, 0xee, // 1 PPS
+        ]))
+        .unwrap();
+        assert_eq!(
+            params.fmtp,
+            "packetization-mode=1;profile-level-id=4D001F;sprop-parameter-sets=Z00A,aO4="
+        );
+
+        let mut big = vec![0x65];
+        big.extend(std::iter::repeat(0xAB).take(2 * MAX_PAYLOAD));
+        let mut data = (big.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(&big);
+        let mut p = Packetizer::new(0, 0x1234_5678, 0xFFFF, 1000);
+        let mut out = Vec::new();
+        p.push_frame(&params, 5000, true, &data, &mut out).unwrap();
+
+        // SPS, PPS, then three FU-A packets.
+        assert_eq!(out.len(), 5);
+        assert_eq!(
+            &out[0][..],
+            &[
+                b'$', 0, 0, 15, // interleaved header
+                0x80, 96, 0xff, 0xff, // V=2, PT=96, seq
+                0x00, 0x00, 0x03, 0xe8, // timestamp
+                0x12, 0x34, 0x56, 0x78, // ssrc
+                0x67, 0x4d, 0x00, // SPS
+            ][..]
+        );
+        assert_eq!(&out[1][4 + 2..4 + 4], &[0x00, 0x00][..]); // seq wrapped
+        assert_eq!(&out[2][4 + 12..4 + 14], &[0x7c, 0x85][..]); // FU-A start
+        assert_eq!(&out[3][4 + 12..4 + 14], &[0x7c, 0x05][..]);
+        assert_eq!(&out[4][4 + 12..4 + 14], &[0x7c, 0x45][..]); // FU-A end
+        assert_eq!(out[3][5], 96); // no marker
+        assert_eq!(out[4][5], 0x80 | 96); // marker on last packet of frame
+        let payload: usize = out[2..].iter().map(|p| p.len() - 4 - 12 - 2).sum();
+        assert_eq!(payload, 2 * MAX_PAYLOAD);
+
+        // Later frames' timestamps are relative to the first.
+        out.clear();
+        p.push_frame(&params, 8000, false, &data[..], &mut out)
+            .unwrap();
+        assert_eq!(out.len(), 3);
+        assert_eq!(&out[0][4 + 4..4 + 8], &4000u32.to_be_bytes()[..]);
+    }
+
+    #[test]
+    fn test_annex_b() {
+        let params = VideoParameters::parse(&avc1(&[
+            0x01, 0x4d, 0x00, 0x1f, 0xfd, // version, profile, compat, level, 2-byte lengths
+            0xe1, 0x00, 0x02, 0x67, 0x4d, // 1 SPS
+            0x01, 0x00, 0x01, 0x68, // 1 PPS
+        ]))
+        .unwrap();
+        let mut out = Vec::new();
+        params
+            .append_annex_b(true, b"\x00\x02\x65\x88\x00\x01\x06", &mut out)
+            .unwrap();
+        params
+            .append_annex_b(false, b"\x00\x02\x41\x9a", &mut out)
+            .unwrap();
+        assert_eq!(
+            &out[..],
+            &b"\x00\x00\x00\x01\x67\x4d\
+               \x00\x00\x00\x01\x68\
+               \x00\x00\x00\x01\x65\x88\
+               \x00\x00\x00\x01\x06\
+               \x00\x00\x00\x01\x41\x9a"[..]
+        );
+        params
+            .append_annex_b(false, b"\x00\x05\x41", &mut out)
+            .unwrap_err();
+    }
+}
@@ -0,0 +1,317 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Decoding of key frames, for JPEG snapshots and motion detection.
+
+use crate::rtsp_server::VideoParameters;
+use crate::stream;
+use db::dir::SampleFileDir;
+use db::recording::{self, rescale};
+use failure::{format_err, Error};
+use futures::stream::StreamExt;
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::ops::Range;
+
+/// The maximum number of frames to feed the decoder, starting with the key frame.
+/// Decoders may buffer a few frames (for B-frame reordering) before producing any output.
+pub const MAX_FRAMES: usize = 8;
+
+const JPEG_QUALITY: u8 = 90;
+
+/// A key frame to decode, along with some of the frames following it.
+#[derive(Debug)]
+pub struct Frames {
+    pub id: db::CompositeId,
+    pub video_sample_entry_id: i32,
+
+    /// The wall time of the key frame.
+    pub start: recording::Time,
+
+    /// The range within the sample file, starting with the key frame.
+    pub sample_file_range: Range<u64>,
+
+    /// The byte length of each frame, starting with the key frame.
+    pub lens: Vec<usize>,
+}
+
+/// Finds the key frame of the given stream nearest `t`, or the latest key frame if `t` is
+/// `None`. Returns `None` if there's no recording at `t` (or at all).
+pub fn find(
+    db: &db::LockedDatabase,
+    stream_id: i32,
+    t: Option<recording::Time>,
+) -> Result<Option<Frames>, Error> {
+    let range = match t {
+        Some(t) => t..t + recording::Duration(1),
+        None => match db
+            .streams_by_id()
+            .get(&stream_id)
+            .and_then(|s| s.range.clone())
+        {
+            Some(r) => r.end - recording::Duration(1)..r.end,
+            None => return Ok(None),
+        },
+    };
+    let mut row = None;
+    db.list_recordings_by_time(stream_id, range, &mut |r| {
+        if row
+            .as_ref()
+            .map_or(true, |o: &db::ListRecordingsRow| o.id.0 < r.id.0)
+        {
+            row = Some(r);
+        }
+        Ok(())
+    })?;
+    match row {
+        Some(r) => frames_near(db, &r, t),
+        None => Ok(None),
+    }
+}
+
+/// Finds the first key frame of the given recording.
+/// Returns `None` if the recording doesn't exist or isn't yet committed.
+pub fn find_first(db: &db::LockedDatabase, id: db::CompositeId) -> Result<Option<Frames>, Error> {
+    let mut row = None;
+    db.list_recordings_by_id(id.stream(), id.recording()..id.recording() + 1, &mut |r| {
+        row = Some(r);
+        Ok(())
+    })?;
+    match row {
+        Some(r) if r.flags & db::RecordingFlags::Uncommitted as i32 == 0 => {
+            frames_near(db, &r, Some(r.start))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Finds the key frame of `row` nearest `t`, or its last key frame if `t` is `None`.
+fn frames_near(
+    db: &db::LockedDatabase,
+    row: &db::ListRecordingsRow,
+    t: Option<recording::Time>,
+) -> Result<Option<Frames>, Error> {
+    // (pos, bytes, is_key, wall time) of each frame.
+    let mut samples = Vec::with_capacity(usize::try_from(row.video_samples)?);
+    db.with_recording_playback(row.id, &mut |p| {
+        let mut it = recording::SampleIndexIterator::default();
+        while it.next(p.video_index)? {
+            let wall = rescale(it.start_90k, row.media_duration_90k, row.wall_duration_90k);
+            samples.push((
+                u64::try_from(it.pos)?,
+                usize::try_from(it.bytes)?,
+                it.is_key(),
+                row.start + recording::Duration(i64::from(wall)),
+            ));
+        }
+        Ok(())
+    })?;
+    let key_frames = samples.iter().enumerate().filter(|(_, s)| s.2);
+    let i = match t {
+        Some(t) => key_frames.min_by_key(|(_, s)| (s.3 - t).0.abs()),
+        None => key_frames.last(),
+    };
+    let i = match i {
+        Some((i, _)) => i,
+        None => return Ok(None),
+    };
+    let mut end = i + 1;
+    while end < samples.len() && end < i + MAX_FRAMES && !samples[end].2 {
+        end += 1;
+    }
+    let frames = &samples[i..end];
+    let last = frames.last().unwrap();
+    Ok(Some(Frames {
+        id: row.id,
+        video_sample_entry_id: row.video_sample_entry_id,
+        start: frames[0].3,
+        sample_file_range: frames[0].0..last.0 + last.1 as u64,
+        lens: frames.iter().map(|s| s.1).collect(),
+    }))
+}
+
+/// Reads the sample data of `frames` from `dir`.
+pub async fn read(dir: &SampleFileDir, frames: &Frames) -> Result<Vec<u8>, base::Error> {
+    let r = &frames.sample_file_range;
+    let mut data = Vec::with_capacity((r.end - r.start) as usize);
+    let mut file = dir.open_file(frames.id, r.clone());
+    while let Some(chunk) = file.next().await {
+        data.extend_from_slice(&chunk?);
+    }
+    Ok(data)
+}
+
+/// A decoded image, as packed 8-bit RGB.
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+/// Decodes the key frame of `frames` (given the sample data of `frames.sample_file_range`) and
+/// encodes it as a JPEG, scaled down to at most `max_width` pixels wide if specified.
+/// This is CPU-intensive; call it from a blocking context.
+pub fn encode_jpeg(
+    sample_entry: &[u8],
+    frames: &Frames,
+    data: &[u8],
+    max_width: Option<u16>,
+) -> Result<Vec<u8>, Error> {
+    let img = decode_rgb(sample_entry, &frames.lens, data, max_width)?;
+    let mut jpeg = Vec::new();
+    jpeg_encoder::Encoder::new(&mut jpeg, JPEG_QUALITY).encode(
+        &img.pixels,
+        u16::try_from(img.width)?,
+        u16::try_from(img.height)?,
+        jpeg_encoder::ColorType::Rgb,
+    )?;
+    Ok(jpeg)
+}
+
+/// Decodes a key frame (given `data`, the concatenation of frames with lengths `lens`, starting
+/// with the key frame), scaled down to at most `max_width` pixels wide if specified.
+/// This is CPU-intensive; call it from a blocking context.
+pub fn decode_rgb(
+    sample_entry: &[u8],
+    lens: &[usize],
+    data: &[u8],
+    max_width: Option<u16>,
+) -> Result<RgbImage, Error> {
+    lazy_static::initialize(&stream::FFMPEG);
+    let params = VideoParameters::parse(sample_entry)?;
+    let mut annex_b = Vec::with_capacity(data.len() + 1024);
+    let mut pos = 0;
+    for (i, &len) in lens.iter().enumerate() {
+        let frame = data
+            .get(pos..pos + len)
+            .ok_or_else(|| format_err!("sample data is truncated"))?;
+        params.append_annex_b(i == 0, frame, &mut annex_b)?;
+        pos += len;
+    }
+
+    // Have ffmpeg read the frames from memory via its "data:" protocol, probing the format.
+    let url = CString::new(format!("data:;base64,{}", base64::encode(&annex_b)))?;
+    let mut open_options = ffmpeg::avutil::Dictionary::new();
+    let mut input = ffmpeg::avformat::InputFormatContext::open(&url, &mut open_options)?;
+    input.find_stream_info()?;
+    let (mut decoder, dims) = {
+        let streams = input.streams();
+        let par = streams.get(0).codecpar();
+        let mut decoder_options = ffmpeg::avutil::Dictionary::new();
+        (par.new_decoder(&mut decoder_options)?, par.dims())
+    };
+    let mut frame = ffmpeg::avutil::VideoFrame::empty()?;
+    loop {
+        let pkt = input
+            .read_frame()
+            .map_err(|e| format_err!("decoder produced no frame: {}", e))?;
+        if decoder.decode_video(&pkt, &mut frame)? {
+            break;
+        }
+    }
+
+    let (width, height) = match max_width {
+        Some(m) if dims.width > i32::from(m) => {
+            // Preserve the aspect ratio, rounding the height to an even number.
+            let m = i32::from(m);
+            (m, ((dims.height * m / dims.width + 1) & !1).max(2))
+        }
+        _ => (dims.width, dims.height),
+    };
+    let mut rgb = ffmpeg::avutil::VideoFrame::owned(ffmpeg::avutil::ImageDimensions {
+        width,
+        height,
+        pix_fmt: ffmpeg::avutil::PixelFormat::rgb24(),
+    })?;
+    let mut scaler = ffmpeg::swscale::Scaler::new(dims, rgb.dims())?;
+    scaler.scale(&frame, &mut rgb);
+    let plane = rgb.plane(0);
+    let (w, h) = (plane.width, plane.height);
+    let mut pixels = Vec::with_capacity(3 * w * h);
+    for y in 0..h {
+        let start = y * plane.linesize;
+        pixels.extend_from_slice(&plane.data[start..start + 3 * w]);
+    }
+    Ok(RgbImage {
+        width: w,
+        height: h,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::clock::RealClocks;
+    use db::testutil::{self, TestDb, TEST_STREAM_ID};
+
+    #[test]
+    fn test_find() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut encoder = recording::SampleIndexEncoder::default();
+        let mut r = db::RecordingToInsert::default();
+        for i in 0..7 {
+            encoder.add_sample(1000, 10 + i, i % 3 == 0, &mut r);
+        }
+        let row = db.insert_recording_from_encoder(r);
+        let l = db.db.lock();
+
+        let f = find(&l, TEST_STREAM_ID, None).unwrap().unwrap();
+        assert_eq!(f.id, row.id);
+        assert_eq!(f.start, row.start + recording::Duration(6000));
+        assert_eq!(f.sample_file_range, 75..91);
+        assert_eq!(f.lens, vec![16]);
+
+        let f = find(
+            &l,
+            TEST_STREAM_ID,
+            Some(row.start + recording::Duration(1400)),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(f.start, row.start);
+        assert_eq!(f.sample_file_range, 0..33);
+        assert_eq!(f.lens, vec![10, 11, 12]);
+
+        let f = find(
+            &l,
+            TEST_STREAM_ID,
+            Some(row.start + recording::Duration(1600)),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(f.start, row.start + recording::Duration(3000));
+        assert_eq!(f.sample_file_range, 33..75);
+
+        assert!(
+            find(&l, TEST_STREAM_ID, Some(row.start - recording::Duration(1)))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_find_first() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut encoder = recording::SampleIndexEncoder::default();
+        let mut r = db::RecordingToInsert::default();
+        encoder.add_sample(1000, 10, false, &mut r);
+        encoder.add_sample(1000, 11, true, &mut r);
+        encoder.add_sample(1000, 12, false, &mut r);
+        let row = db.insert_recording_from_encoder(r);
+        let l = db.db.lock();
+        let f = find_first(&l, row.id).unwrap().unwrap();
+        assert_eq!(f.start, row.start + recording::Duration(1000));
+        assert_eq!(f.sample_file_range, 10..33);
+        assert_eq!(f.lens, vec![11, 12]);
+        assert!(find_first(
+            &l,
+            db::CompositeId::new(TEST_STREAM_ID, row.id.recording() + 1)
+        )
+        .unwrap()
+        .is_none());
+    }
+}
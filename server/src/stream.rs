@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
 use crate::h264;
+use crate::h265;
 use cstr::cstr;
 use failure::format_err;
 use failure::{bail, Error};
@@ -10,7 +11,7 @@ use futures::StreamExt;
 use lazy_static::lazy_static;
 use log::warn;
 use retina::client::Credentials;
-use retina::codec::{CodecItem, VideoParameters};
+use retina::codec::{AudioParameters, CodecItem, VideoParameters};
 use std::convert::TryFrom;
 use std::ffi::CString;
 use std::pin::Pin;
@@ -79,6 +80,11 @@ pub trait Opener: Send + Sync {
         -> Result<(h264::ExtraData, Box<dyn Stream>), Error>;
 }
 
+pub enum Frame<'a> {
+    Video(VideoFrame<'a>),
+    Audio(AudioFrame<'a>),
+}
+
 pub struct VideoFrame<'a> {
     pub pts: i64,
 
@@ -90,8 +96,24 @@ pub struct VideoFrame<'a> {
     pub data: &'a [u8],
 }
 
+pub struct AudioFrame<'a> {
+    /// The presentation timestamp, in units of the audio sample entry's sample rate.
+    /// Like video frames' pts, this is relative to the start of the session, so the two can be
+    /// compared to place audio relative to video.
+    pub pts: i64,
+
+    /// The duration of the frame, in units of the audio sample entry's sample rate.
+    pub duration: i32,
+
+    pub data: &'a [u8],
+}
+
 pub trait Stream: Send {
-    fn next(&mut self) -> Result<VideoFrame, Error>;
+    fn next(&mut self) -> Result<Frame, Error>;
+
+    /// Returns the sample entry describing audio frames, or `None` if the stream yields only
+    /// video.
+    fn audio_sample_entry(&self) -> Option<&db::AudioSampleEntryToInsert>;
 }
 
 pub struct Ffmpeg {}
@@ -208,9 +230,6 @@ impl Opener for Ffmpeg {
         let video = input.streams().get(video_i);
         let codec = video.codecpar();
         let codec_id = codec.codec_id();
-        if !codec_id.is_h264() {
-            bail!("stream's video codec {:?} is not h264", codec_id);
-        }
         let tb = video.time_base();
         if tb.num != 1 || tb.den != 90000 {
             bail!(
@@ -220,11 +239,22 @@ impl Opener for Ffmpeg {
             );
         }
         let dims = codec.dims();
-        let extra_data = h264::ExtraData::parse(
-            codec.extradata(),
-            u16::try_from(dims.width)?,
-            u16::try_from(dims.height)?,
-        )?;
+        let (width, height) = (u16::try_from(dims.width)?, u16::try_from(dims.height)?);
+        let extra_data = if codec_id.is_h264() {
+            h264::ExtraData::parse(codec.extradata(), width, height)?
+        } else {
+            // moonfire-ffmpeg 0.0.1's CodecId only identifies H.264, so an H.265 stream is
+            // recognized by its parameter sets: a VPS, SPS, and PPS in Annex B form or a complete
+            // HEVCDecoderConfigurationRecord. Other codecs' extradata don't have this structure.
+            h265::parse_extra_data(codec.extradata(), width, height).map_err(|e| {
+                format_err!(
+                    "unsupported video codec {:?}; expected H.264 or H.265 (extradata isn't \
+                     valid H.265: {})",
+                    codec_id,
+                    e
+                )
+            })?
+        };
         let need_transform = extra_data.need_transform;
         let stream = Box::new(FfmpegStream {
             input,
@@ -244,7 +274,7 @@ struct FfmpegStream {
 }
 
 impl Stream for FfmpegStream {
-    fn next(&mut self) -> Result<VideoFrame, Error> {
+    fn next(&mut self) -> Result<Frame, Error> {
         let pkt = loop {
             let pkt = self.input.read_frame()?;
             if pkt.stream_index() == self.video_i {
@@ -262,12 +292,16 @@ impl Stream for FfmpegStream {
             self.data.extend_from_slice(data);
         }
         let pts = pkt.pts().ok_or_else(|| format_err!("packet with no pts"))?;
-        Ok(VideoFrame {
+        Ok(Frame::Video(VideoFrame {
             pts,
             is_key: pkt.is_key(),
             duration: pkt.duration(),
             data: &self.data,
-        })
+        }))
+    }
+
+    fn audio_sample_entry(&self) -> Option<&db::AudioSampleEntryToInsert> {
+        None // allowed_media_types is restricted to video above.
     }
 }
 
@@ -302,18 +336,22 @@ impl Opener for RetinaOpener {
         // TODO: connection timeout.
         handle.spawn(async move {
             let r = tokio::time::timeout(RETINA_TIMEOUT, RetinaOpener::play(url, creds)).await;
-            let (mut session, video_params, first_frame) =
+            let (mut session, video_params, audio_params, first_frame) =
                 match r.unwrap_or_else(|_| Err(format_err!("timeout opening stream"))) {
                     Err(e) => {
                         let _ = startup_tx.send(Err(e));
                         return;
                     }
-                    Ok((s, p, f)) => (s, p, f),
+                    Ok((s, v, a, f)) => (s, v, a, f),
                 };
-            if startup_tx.send(Ok(video_params)).is_err() {
+            if startup_tx.send(Ok((video_params, audio_params))).is_err() {
                 return;
             }
-            if frame_tx.send(Ok(first_frame)).await.is_err() {
+            if frame_tx
+                .send(Ok(CodecItem::VideoFrame(first_frame)))
+                .await
+                .is_err()
+            {
                 return;
             }
 
@@ -339,7 +377,12 @@ impl Opener for RetinaOpener {
                                 v.start_ctx()
                             );
                         }
-                        if frame_tx.send(Ok(v)).await.is_err() {
+                        if frame_tx.send(Ok(CodecItem::VideoFrame(v))).await.is_err() {
+                            return; // other end died.
+                        }
+                    }
+                    Some(Ok(CodecItem::AudioFrame(a))) => {
+                        if frame_tx.send(Ok(CodecItem::AudioFrame(a))).await.is_err() {
                             return; // other end died.
                         }
                     }
@@ -347,16 +390,27 @@ impl Opener for RetinaOpener {
                 }
             }
         });
-        let video_params = handle.block_on(startup_rx)??;
+        let (video_params, audio) = handle.block_on(startup_rx)??;
         let dims = video_params.pixel_dimensions();
-        let extra_data = h264::ExtraData::parse(
-            video_params.extra_data(),
-            u16::try_from(dims.0)?,
-            u16::try_from(dims.1)?,
-        )?;
+        let (width, height) = (u16::try_from(dims.0)?, u16::try_from(dims.1)?);
+        let extra_data = h264::ExtraData::parse(video_params.extra_data(), width, height)?;
+        let audio_sample_entry = match audio {
+            None => None,
+            Some((encoding_name, a)) => {
+                let e = audio_sample_entry(&encoding_name, &a);
+                if e.is_none() {
+                    warn!(
+                        "{}: ignoring unsupported audio stream with encoding {:?}",
+                        &label, encoding_name
+                    );
+                }
+                e
+            }
+        };
         let stream = Box::new(RetinaStream {
             frame_rx,
-            frame: None,
+            item: None,
+            audio_sample_entry,
         });
         Ok((extra_data, stream))
     }
@@ -371,6 +425,7 @@ impl RetinaOpener {
         (
             Pin<Box<impl futures::Stream<Item = Result<retina::codec::CodecItem, Error>>>>,
             VideoParameters,
+            Option<(String, AudioParameters)>,
             retina::codec::VideoFrame,
         ),
         Error,
@@ -384,8 +439,39 @@ impl RetinaOpener {
                 Some(retina::codec::Parameters::Video(v)) => Some((i, v.clone())),
                 _ => None,
             })
-            .ok_or_else(|| format_err!("couldn't find H.264 video stream"))?;
+            .ok_or_else(|| {
+                // Retina only depacketizes H.264 video; other codecs have no parameters.
+                if session
+                    .streams()
+                    .iter()
+                    .any(|s| s.encoding_name.eq_ignore_ascii_case("h265"))
+                {
+                    format_err!(
+                        "H.265 isn't supported by the Retina RTSP library; \
+                         use --rtsp-library=ffmpeg"
+                    )
+                } else {
+                    format_err!("couldn't find video stream")
+                }
+            })?;
+        let audio = session
+            .streams()
+            .iter()
+            .enumerate()
+            .find_map(|(i, s)| match s.parameters() {
+                Some(retina::codec::Parameters::Audio(a)) => {
+                    Some((i, s.encoding_name.clone(), a.clone()))
+                }
+                _ => None,
+            });
         session.setup(video_i).await?;
+        let audio = match audio {
+            None => None,
+            Some((audio_i, encoding_name, audio_params)) => {
+                session.setup(audio_i).await?;
+                Some((encoding_name, audio_params))
+            }
+        };
         let session = session.play(retina::client::PlayPolicy::default()).await?;
         let mut session = Box::pin(session.demuxed()?);
 
@@ -404,29 +490,101 @@ impl RetinaOpener {
                 }
             }
         };
-        Ok((session, video_params, first_frame))
+        Ok((session, video_params, audio, first_frame))
+    }
+}
+
+/// Returns an `AudioSampleEntryToInsert` for the given RTSP audio stream, if it is supported.
+///
+/// AAC streams come with a ready-made `mp4a` box. G.711 streams have no codec-specific data, so
+/// this builds a bare `ulaw`/`alaw` box as understood by QuickTime and ffmpeg.
+fn audio_sample_entry(
+    encoding_name: &str,
+    params: &AudioParameters,
+) -> Option<db::AudioSampleEntryToInsert> {
+    if let Some(data) = params.sample_entry() {
+        let rfc6381_codec = params.rfc6381_codec()?.to_owned();
+        let (channels, sample_rate) = parse_audio_sample_entry(data)?;
+        return Some(db::AudioSampleEntryToInsert {
+            data: data.to_vec(),
+            rfc6381_codec,
+            sample_rate,
+            channels,
+        });
+    }
+    let fourcc: &[u8; 4] = match encoding_name {
+        "pcmu" | "PCMU" => b"ulaw",
+        "pcma" | "PCMA" => b"alaw",
+        _ => return None,
+    };
+    let sample_rate = params.clock_rate();
+    let mut data = Vec::with_capacity(36);
+    data.extend_from_slice(&36u32.to_be_bytes());
+    data.extend_from_slice(fourcc);
+    data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]); // reserved, data_reference_index
+    data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]); // reserved
+    data.extend_from_slice(&1u16.to_be_bytes()); // channelcount
+    data.extend_from_slice(&16u16.to_be_bytes()); // samplesize
+    data.extend_from_slice(&[0, 0, 0, 0]); // pre_defined, reserved
+    data.extend_from_slice(&(sample_rate.checked_mul(1 << 16)?).to_be_bytes());
+    Some(db::AudioSampleEntryToInsert {
+        data,
+        rfc6381_codec: String::from_utf8(fourcc.to_vec()).unwrap(),
+        sample_rate,
+        channels: 1,
+    })
+}
+
+/// Parses `(channelcount, samplerate)` from a serialized ISO/IEC 14496-12 AudioSampleEntry box.
+fn parse_audio_sample_entry(data: &[u8]) -> Option<(u16, u32)> {
+    if data.len() < 36 {
+        return None;
     }
+    let channels = u16::from_be_bytes([data[24], data[25]]);
+    let sample_rate = u32::from_be_bytes([data[32], data[33], data[34], data[35]]) >> 16;
+    if channels == 0 || sample_rate == 0 {
+        return None;
+    }
+    Some((channels, sample_rate))
 }
 
 struct RetinaStream {
-    frame_rx: tokio::sync::mpsc::Receiver<Result<retina::codec::VideoFrame, Error>>,
-    frame: Option<retina::codec::VideoFrame>,
+    frame_rx: tokio::sync::mpsc::Receiver<Result<CodecItem, Error>>,
+    item: Option<CodecItem>,
+    audio_sample_entry: Option<db::AudioSampleEntryToInsert>,
 }
 
 impl Stream for RetinaStream {
-    fn next(&mut self) -> Result<VideoFrame, Error> {
-        // TODO: use Option::insert after bumping MSRV to 1.53.
-        self.frame = Some(
-            self.frame_rx
-                .blocking_recv()
-                .ok_or_else(|| format_err!("stream ended"))??,
-        );
-        let frame = self.frame.as_ref().unwrap();
-        Ok(VideoFrame {
-            pts: frame.timestamp.elapsed(),
-            duration: 0,
-            is_key: frame.is_random_access_point,
-            data: &frame.data()[..],
+    fn next(&mut self) -> Result<Frame, Error> {
+        loop {
+            // TODO: use Option::insert after bumping MSRV to 1.53.
+            self.item = Some(
+                self.frame_rx
+                    .blocking_recv()
+                    .ok_or_else(|| format_err!("stream ended"))??,
+            );
+            match self.item.as_ref().unwrap() {
+                CodecItem::AudioFrame(_) if self.audio_sample_entry.is_none() => continue,
+                _ => break,
+            }
+        }
+        Ok(match self.item.as_ref().unwrap() {
+            CodecItem::VideoFrame(v) => Frame::Video(VideoFrame {
+                pts: v.timestamp.elapsed(),
+                duration: 0,
+                is_key: v.is_random_access_point,
+                data: &v.data()[..],
+            }),
+            CodecItem::AudioFrame(a) => Frame::Audio(AudioFrame {
+                pts: a.timestamp.elapsed(),
+                duration: i32::try_from(a.frame_length.get())?,
+                data: &a.data[..],
+            }),
+            _ => unreachable!(),
         })
     }
+
+    fn audio_sample_entry(&self) -> Option<&db::AudioSampleEntryToInsert> {
+        self.audio_sample_entry.as_ref()
+    }
 }
@@ -2,18 +2,116 @@
 // Copyright (C) 2020 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
+use crate::motion;
+use crate::rtsp_server;
 use crate::stream;
 use base::clock::{Clocks, TimerGuard};
-use db::{dir, recording, writer, Camera, Database, Stream};
+use base::metrics::{Counter, REGISTRY};
+use db::{dir, recording, webhook, writer, Camera, Database, Stream, StreamState, StreamStatus};
 use failure::{bail, Error};
 use log::{debug, info, trace, warn};
+use parking_lot::Mutex;
 use std::result::Result;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::Arc;
+use time::Timespec;
 use url::Url;
 
 pub static ROTATE_INTERVAL_SEC: i64 = 60;
 
+/// The minimum period over which `StreamStatus::bits_per_sec` and `frames_per_sec` are averaged.
+const RATE_WINDOW_SEC: i64 = 5;
+
+/// Value of `StreamMetrics::last_frame_nanos` before the first frame.
+const NO_FRAME: i64 = i64::MIN;
+
+/// Metrics of a [Streamer], labeled by its short name.
+struct StreamMetrics {
+    frames: Arc<Counter>,
+    bytes: Arc<Counter>,
+    reconnects: Arc<Counter>,
+    errors: Arc<Counter>,
+
+    /// Monotonic time of the most recent video frame in nanoseconds, or `NO_FRAME`.
+    last_frame_nanos: Arc<AtomicI64>,
+}
+
+fn monotonic_nanos<C: Clocks>(clocks: &C) -> i64 {
+    let t = clocks.monotonic();
+    t.sec * 1_000_000_000 + i64::from(t.nsec)
+}
+
+impl StreamMetrics {
+    fn new<C: Clocks + Clone>(short_name: &str, clocks: C) -> Self {
+        let labels = [("stream", short_name)];
+        let last_frame_nanos = Arc::new(AtomicI64::new(NO_FRAME));
+        REGISTRY.gauge_fn(
+            "moonfire_stream_last_frame_age_seconds",
+            "Time since the most recent video frame was received, or NaN if none has been.",
+            &labels,
+            Box::new({
+                let last_frame_nanos = last_frame_nanos.clone();
+                move || match last_frame_nanos.load(Ordering::Relaxed) {
+                    NO_FRAME => f64::NAN,
+                    t => (monotonic_nanos(&clocks) - t) as f64 / 1e9,
+                }
+            }),
+        );
+        StreamMetrics {
+            frames: REGISTRY.counter(
+                "moonfire_stream_frames_total",
+                "Video frames received.",
+                &labels,
+            ),
+            bytes: REGISTRY.counter(
+                "moonfire_stream_bytes_total",
+                "Video and audio bytes written to sample files.",
+                &labels,
+            ),
+            reconnects: REGISTRY.counter(
+                "moonfire_stream_reconnects_total",
+                "Times the stream was reopened after the connection ended.",
+                &labels,
+            ),
+            errors: REGISTRY.counter(
+                "moonfire_stream_errors_total",
+                "Errors which ended the connection, such as RTSP failures.",
+                &labels,
+            ),
+            last_frame_nanos,
+        }
+    }
+}
+
+/// Frames and bytes received since `start`, for the rates reported in the stream's status.
+struct RateWindow {
+    start: Timespec,
+    frames: u32,
+    bytes: u64,
+}
+
+impl RateWindow {
+    fn new(start: Timespec) -> Self {
+        RateWindow {
+            start,
+            frames: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Updates `status`'s rates and starts a new window if this one is long enough.
+    fn update(&mut self, now: Timespec, status: &mut StreamStatus) {
+        let elapsed = now - self.start;
+        if elapsed < time::Duration::seconds(RATE_WINDOW_SEC) {
+            return;
+        }
+        let secs = elapsed.num_milliseconds() as f64 / 1000.;
+        status.bits_per_sec = self.bytes as f64 * 8. / secs;
+        status.frames_per_sec = f64::from(self.frames) / secs;
+        *self = RateWindow::new(now);
+    }
+}
+
 /// Common state that can be used by multiple `Streamer` instances.
 pub struct Environment<'a, 'tmp, C>
 where
@@ -22,6 +120,12 @@ where
     pub opener: &'a dyn stream::Opener,
     pub db: &'tmp Arc<Database<C>>,
     pub shutdown: &'tmp Arc<AtomicBool>,
+
+    /// Where to publish frames for the RTSP server, if it's enabled.
+    pub rtsp_hub: Option<&'tmp Arc<rtsp_server::Hub>>,
+
+    /// Where to send key frames for motion detection, if any stream has it configured.
+    pub motion: Option<&'tmp motion::Workers>,
 }
 
 /// Connects to a given RTSP stream and writes recordings to the database via [`writer::Writer`].
@@ -44,6 +148,13 @@ where
     url: Url,
     username: Option<String>,
     password: Option<String>,
+    rtsp_hub: Option<Arc<rtsp_server::Hub>>,
+    motion: Option<motion::Sender>,
+    metrics: StreamMetrics,
+    status: Arc<Mutex<StreamStatus>>,
+
+    /// True if a failure has been reported to webhooks and the stream hasn't since recovered.
+    failing: bool,
 }
 
 impl<'a, C> Streamer<'a, C>
@@ -64,6 +175,8 @@ where
         if !url.username().is_empty() || url.password().is_some() {
             bail!("RTSP URL shouldn't include credentials");
         }
+        let short_name = format!("{}-{}", c.short_name, s.type_.as_str());
+        let metrics = StreamMetrics::new(&short_name, env.db.clocks());
         Ok(Streamer {
             shutdown: env.shutdown.clone(),
             rotate_offset_sec,
@@ -73,10 +186,15 @@ where
             syncer_channel,
             opener: env.opener,
             stream_id,
-            short_name: format!("{}-{}", c.short_name, s.type_.as_str()),
+            short_name,
             url,
             username: c.username.clone(),
             password: c.password.clone(),
+            rtsp_hub: env.rtsp_hub.cloned(),
+            motion: env.motion.and_then(|m| m.sender(stream_id)),
+            metrics,
+            status: s.status.clone(),
+            failing: false,
         })
     }
 
@@ -88,19 +206,51 @@ where
     /// Note that when using Retina as the RTSP library, this must be called
     /// within a tokio runtime context; see [tokio::runtime::Handle].
     pub fn run(&mut self) {
+        let mut first = true;
         while !self.shutdown.load(Ordering::SeqCst) {
+            if !first {
+                self.metrics.reconnects.inc();
+            }
+            first = false;
+            self.set_state(StreamState::Connecting);
             if let Err(e) = self.run_once() {
+                self.metrics.errors.inc();
+                let msg = base::prettify_failure(&e);
                 let sleep_time = time::Duration::seconds(1);
                 warn!(
                     "{}: sleeping for {:?} after error: {}",
-                    self.short_name,
-                    sleep_time,
-                    base::prettify_failure(&e)
+                    self.short_name, sleep_time, &msg
                 );
+                let now = self.now();
+                self.status.lock().set_error(msg.clone(), now);
+                if !self.failing {
+                    self.failing = true;
+                    self.report_health(false, Some(msg), now);
+                }
                 self.db.clocks().sleep(sleep_time);
             }
         }
         info!("{}: shutting down", self.short_name);
+        self.set_state(StreamState::Idle);
+    }
+
+    fn now(&self) -> recording::Time {
+        recording::Time::new(self.db.clocks().realtime())
+    }
+
+    fn set_state(&self, state: StreamState) {
+        self.status.lock().set_state(state, self.now());
+    }
+
+    fn report_health(&self, healthy: bool, error: Option<String>, time: recording::Time) {
+        self.db
+            .lock()
+            .enqueue_webhook_event(webhook::Event::StreamHealth {
+                stream_id: self.stream_id,
+                healthy,
+                error,
+                time,
+            });
     }
 
     fn run_once(&mut self) -> Result<(), Error> {
@@ -119,10 +269,20 @@ where
             )?
         };
         let realtime_offset = self.db.clocks().realtime() - clocks.monotonic();
+        self.set_state(StreamState::Connected);
+        let mut window = RateWindow::new(clocks.monotonic());
         let video_sample_entry_id = {
             let _t = TimerGuard::new(&clocks, || "inserting video sample entry");
             self.db.lock().insert_video_sample_entry(extra_data.entry)?
         };
+        let (audio_sample_entry_id, audio_sample_rate) = match stream.audio_sample_entry() {
+            None => (None, 1),
+            Some(e) => {
+                let _t = TimerGuard::new(&clocks, || "inserting audio sample entry");
+                let id = self.db.lock().insert_audio_sample_entry(e.clone())?;
+                (Some(id), i64::from(e.sample_rate))
+            }
+        };
         let mut seen_key_frame = false;
 
         // Seconds since epoch at which to next rotate.
@@ -133,12 +293,46 @@ where
             &self.syncer_channel,
             self.stream_id,
             video_sample_entry_id,
+            audio_sample_entry_id,
         );
         while !self.shutdown.load(Ordering::SeqCst) {
             let pkt = {
                 let _t = TimerGuard::new(&clocks, || "getting next packet");
                 stream.next()?
             };
+            let pkt = match pkt {
+                stream::Frame::Video(v) => {
+                    self.metrics.frames.inc();
+                    self.metrics
+                        .last_frame_nanos
+                        .store(monotonic_nanos(&clocks), Ordering::Relaxed);
+                    let now = clocks.monotonic();
+                    window.frames += 1;
+                    window.bytes += v.data.len() as u64;
+                    let frame_time = recording::Time::new(now + realtime_offset);
+                    let mut s = self.status.lock();
+                    s.last_frame = Some(frame_time);
+                    window.update(now, &mut s);
+                    drop(s);
+                    if self.failing {
+                        self.failing = false;
+                        self.report_health(true, None, frame_time);
+                    }
+                    v
+                }
+                stream::Frame::Audio(a) => {
+                    // The writer discards audio until the first video frame opens a recording.
+                    let _t = TimerGuard::new(&clocks, || {
+                        format!("writing {} audio bytes", a.data.len())
+                    });
+                    let len = a.data.len();
+                    window.bytes += len as u64;
+                    let pts_90k = a.pts * recording::TIME_UNITS_PER_SEC / audio_sample_rate;
+                    w.write_audio(a.data, pts_90k, a.duration)?;
+                    self.metrics.bytes.inc_by(len as u64);
+                    continue;
+                }
+            };
             if !seen_key_frame && !pkt.is_key {
                 continue;
             } else if !seen_key_frame {
@@ -184,7 +378,21 @@ where
                 }
             };
             let _t = TimerGuard::new(&clocks, || format!("writing {} bytes", pkt.data.len()));
+            let len = pkt.data.len();
             w.write(pkt.data, local_time, pkt.pts, pkt.is_key)?;
+            self.metrics.bytes.inc_by(len as u64);
+            if let Some(h) = self.rtsp_hub.as_ref() {
+                h.publish(
+                    self.stream_id,
+                    video_sample_entry_id,
+                    pkt.pts,
+                    pkt.is_key,
+                    pkt.data,
+                );
+            }
+            if let Some(m) = self.motion.as_mut() {
+                m.push(video_sample_entry_id, local_time, pkt.is_key, pkt.data);
+            }
             rotate = Some(r);
         }
         if rotate.is_some() {
@@ -240,13 +448,16 @@ mod tests {
     }
 
     impl Stream for ProxyingStream {
-        fn next(&mut self) -> Result<stream::VideoFrame, Error> {
+        fn next(&mut self) -> Result<stream::Frame, Error> {
             if self.pkts_left == 0 {
                 bail!("end of stream");
             }
             self.pkts_left -= 1;
 
-            let mut frame = self.inner.next()?;
+            let mut frame = match self.inner.next()? {
+                stream::Frame::Video(v) => v,
+                stream::Frame::Audio(_) => bail!("unexpected audio frame"),
+            };
 
             // XXX: comment wrong.
             // Emulate the behavior of real cameras that send some pre-buffered frames immediately
@@ -275,7 +486,11 @@ mod tests {
                 frame.duration = i32::try_from(3600 * recording::TIME_UNITS_PER_SEC).unwrap();
             }
 
-            Ok(frame)
+            Ok(stream::Frame::Video(frame))
+        }
+
+        fn audio_sample_entry(&self) -> Option<&db::AudioSampleEntryToInsert> {
+            self.inner.audio_sample_entry()
         }
     }
 
@@ -360,6 +575,8 @@ mod tests {
             opener: &opener,
             db: &db.db,
             shutdown: &opener.shutdown,
+            rtsp_hub: None,
+            motion: None,
         };
         let mut stream;
         {
@@ -385,6 +602,15 @@ mod tests {
         }
         stream.run();
         assert!(opener.streams.lock().is_empty());
+        {
+            // The streamer has shut down after the mock opener's final error.
+            let l = db.db.lock();
+            let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+            let status = s.status.lock();
+            assert_eq!(status.state, db::StreamState::Idle);
+            assert!(status.last_error.as_ref().unwrap().contains("done"));
+            assert!(status.last_frame.is_some());
+        }
         db.syncer_channel.flush();
         let db = db.db.lock();
 
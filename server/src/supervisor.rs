@@ -0,0 +1,238 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Supervision of a running server's streamer and syncer threads.
+//!
+//! Streams can be (re)started individually, so that configuration changes made through the web
+//! API take effect without restarting the process.
+
+use crate::motion;
+use crate::rtsp_server;
+use crate::stream;
+use crate::streamer;
+use base::clock::Clocks;
+use db::{dir, recording, writer};
+use failure::Error;
+use fnv::FnvHashMap;
+use log::{info, warn};
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+struct Syncer {
+    dir: Arc<dir::SampleFileDir>,
+    channel: writer::SyncerChannel<::std::fs::File>,
+    join: thread::JoinHandle<()>,
+}
+
+struct Streamer {
+    shutdown: Arc<AtomicBool>,
+    join: thread::JoinHandle<()>,
+}
+
+struct Inner {
+    syncers: FnvHashMap<i32, Syncer>,
+    streamers: FnvHashMap<i32, Streamer>,
+    motion: Option<motion::Workers>,
+}
+
+pub struct Supervisor {
+    db: Arc<db::Database>,
+    opener: &'static dyn stream::Opener,
+    rtsp_hub: Option<Arc<rtsp_server::Hub>>,
+    handle: tokio::runtime::Handle,
+    inner: Mutex<Inner>,
+}
+
+impl Supervisor {
+    /// Starts a streamer for every stream which should be recording, along with syncers for
+    /// their directories. Must be called from within a tokio runtime.
+    pub fn start(
+        db: Arc<db::Database>,
+        opener: &'static dyn stream::Opener,
+        rtsp_hub: Option<Arc<rtsp_server::Hub>>,
+        motion: Option<motion::Workers>,
+    ) -> Result<Arc<Self>, Error> {
+        let s = Arc::new(Supervisor {
+            db,
+            opener,
+            rtsp_hub,
+            handle: tokio::runtime::Handle::current(),
+            inner: Mutex::new(Inner {
+                syncers: FnvHashMap::default(),
+                streamers: FnvHashMap::default(),
+                motion,
+            }),
+        });
+        let stream_ids: Vec<i32> = s.db.lock().streams_by_id().keys().copied().collect();
+        s.restart_streams(&stream_ids)?;
+        Ok(s)
+    }
+
+    /// Stops the given streams' streamers, if running.
+    ///
+    /// This blocks until the streamers exit, which may take as long as a stalled RTSP read.
+    /// Callers should do this before deleting a stream, so that no recording is added to it
+    /// afterward.
+    pub fn stop_streams(&self, stream_ids: &[i32]) {
+        let mut inner = self.inner.lock();
+        let stopped: Vec<Streamer> = stream_ids
+            .iter()
+            .filter_map(|id| inner.streamers.remove(id))
+            .collect();
+        for s in &stopped {
+            s.shutdown.store(true, Ordering::SeqCst);
+        }
+        for s in stopped {
+            s.join.join().unwrap();
+        }
+    }
+
+    /// Stops the given streams' streamers, if running, then starts new ones for those streams
+    /// which (still) exist and should be recording.
+    ///
+    /// A stream which can't be started (such as one with an invalid URL) doesn't prevent starting
+    /// the others; the first such error is returned afterward.
+    pub fn restart_streams(&self, stream_ids: &[i32]) -> Result<(), Error> {
+        self.stop_streams(stream_ids);
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+
+        // Start syncers for any directories which don't have them yet. Archive directories need
+        // them too, to unlink archived recordings once they're deleted.
+        let mut dirs = Vec::new();
+        {
+            let mut l = self.db.lock();
+            for id in stream_ids {
+                let s = match l.streams_by_id().get(id) {
+                    Some(s) if s.record => s,
+                    _ => continue,
+                };
+                for dir_id in s
+                    .sample_file_dir_id
+                    .into_iter()
+                    .chain(s.archive_sample_file_dir_id)
+                {
+                    if !inner.syncers.contains_key(&dir_id) && !dirs.contains(&dir_id) {
+                        dirs.push(dir_id);
+                    }
+                }
+            }
+            l.open_sample_file_dirs(&dirs)?;
+        }
+        for dir_id in dirs {
+            let dir = {
+                let l = self.db.lock();
+                let d = l.sample_file_dirs_by_id().get(&dir_id).unwrap();
+                info!("Starting syncer for path {}", d.path);
+                d.get()?
+            };
+            let (channel, join) = writer::start_syncer(self.db.clone(), dir_id)?;
+            inner.syncers.insert(dir_id, Syncer { dir, channel, join });
+        }
+
+        let mut first_err = None;
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let l = self.db.lock();
+        let streams = l.streams_by_id().len();
+        for (i, (id, stream)) in l.streams_by_id().iter().enumerate() {
+            if !stream.record || !stream_ids.contains(id) {
+                continue;
+            }
+            let camera = l.cameras_by_id().get(&stream.camera_id).unwrap();
+            let sample_file_dir_id = match stream.sample_file_dir_id {
+                Some(s) => s,
+                None => {
+                    warn!(
+                        "Can't record stream {} ({}/{}) because it has no sample file dir",
+                        id,
+                        camera.short_name,
+                        stream.type_.as_str()
+                    );
+                    stream
+                        .status
+                        .lock()
+                        .set_error("no sample file dir".to_owned(), now);
+                    continue;
+                }
+            };
+            let rotate_offset_sec = streamer::ROTATE_INTERVAL_SEC * i as i64 / streams as i64;
+            let syncer = inner.syncers.get(&sample_file_dir_id).unwrap();
+            let shutdown = Arc::new(AtomicBool::new(false));
+            let env = streamer::Environment {
+                db: &self.db,
+                opener: self.opener,
+                shutdown: &shutdown,
+                rtsp_hub: self.rtsp_hub.as_ref(),
+                motion: inner.motion.as_ref(),
+            };
+            let mut streamer = match streamer::Streamer::new(
+                &env,
+                syncer.dir.clone(),
+                syncer.channel.clone(),
+                *id,
+                camera,
+                stream,
+                rotate_offset_sec,
+                streamer::ROTATE_INTERVAL_SEC,
+            ) {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Can't start streamer for stream {}: {}", id, e);
+                    stream.status.lock().set_error(e.to_string(), now);
+                    first_err.get_or_insert(e);
+                    continue;
+                }
+            };
+            info!("Starting streamer for {}", streamer.short_name());
+            let name = format!("s-{}", streamer.short_name());
+            let handle = self.handle.clone();
+            let join = thread::Builder::new()
+                .name(name)
+                .spawn(move || {
+                    let _enter = handle.enter();
+                    streamer.run();
+                })
+                .expect("can't create thread");
+            inner.streamers.insert(*id, Streamer { shutdown, join });
+        }
+        match first_err {
+            None => Ok(()),
+            Some(e) => Err(e),
+        }
+    }
+
+    /// Prompts the syncer of the given directory, if any, to delete recordings as needed to meet
+    /// lowered retention limits.
+    pub fn rotate(&self, dir_id: i32) {
+        if let Some(s) = self.inner.lock().syncers.get(&dir_id) {
+            s.channel.rotate();
+        }
+    }
+
+    /// Stops all streamers, motion detection, and syncers, in that order.
+    pub fn shutdown(&self) {
+        let mut inner = self.inner.lock();
+        info!("Shutting down streamers.");
+        for s in inner.streamers.values() {
+            s.shutdown.store(true, Ordering::SeqCst);
+        }
+        for (_, s) in inner.streamers.drain() {
+            s.join.join().unwrap();
+        }
+        if let Some(m) = inner.motion.take() {
+            info!("Shutting down motion detection.");
+            m.join();
+        }
+
+        // The syncers shut down when all channels to them have been dropped.
+        // The database maintains one; and `inner.syncers` holds one. Drop both.
+        self.db.lock().clear_on_flush();
+        for (_, s) in inner.syncers.drain() {
+            drop(s.channel);
+            s.join.join().unwrap();
+        }
+    }
+}
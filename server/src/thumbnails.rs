@@ -0,0 +1,139 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Background generation of recording thumbnails.
+//!
+//! After each database flush, this looks for committed recordings without a thumbnail and
+//! stores a small JPEG of each one's first key frame via `LockedDatabase::insert_thumbnail`.
+
+use crate::snapshot;
+use db::dir::SampleFileDir;
+use db::CompositeId;
+use failure::{format_err, Error};
+use fnv::FnvHashMap;
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+use log::{info, warn};
+use std::sync::Arc;
+
+/// The width of generated thumbnails, in pixels. The height preserves the aspect ratio.
+const THUMBNAIL_WIDTH: u16 = 240;
+
+/// The maximum number of recordings of a single stream to look up at once.
+const BATCH_SIZE: usize = 16;
+
+/// Starts the thumbnail generator, which runs until `LockedDatabase::clear_on_flush` is called.
+/// Must be called from within a tokio runtime.
+pub fn start(db: Arc<db::Database>) -> Result<tokio::task::JoinHandle<()>, Error> {
+    let (tx, rx) = mpsc::unbounded();
+    let dirs_by_stream_id = {
+        let mut l = db.lock();
+        let d = dirs_by_stream_id(&l)?;
+
+        // Kick off an initial pass to catch up on recordings made while not running.
+        tx.unbounded_send(()).unwrap();
+        l.on_flush(Box::new(move || {
+            let _ = tx.unbounded_send(());
+        }));
+        d
+    };
+    let mut generator = Generator {
+        db,
+        dirs_by_stream_id,
+        next_recording_id: FnvHashMap::default(),
+    };
+    Ok(tokio::spawn(async move { generator.run(rx).await }))
+}
+
+/// Returns the sample file directory of each stream which has one.
+fn dirs_by_stream_id(l: &db::LockedDatabase) -> Result<FnvHashMap<i32, Arc<SampleFileDir>>, Error> {
+    let mut d = FnvHashMap::with_capacity_and_hasher(l.streams_by_id().len(), Default::default());
+    for (&id, s) in l.streams_by_id().iter() {
+        let dir_id = match s.sample_file_dir_id {
+            Some(d) => d,
+            None => continue,
+        };
+        d.insert(id, l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?);
+    }
+    Ok(d)
+}
+
+struct Generator {
+    db: Arc<db::Database>,
+    dirs_by_stream_id: FnvHashMap<i32, Arc<SampleFileDir>>,
+
+    /// The lowest recording id of each stream which may still lack a thumbnail.
+    next_recording_id: FnvHashMap<i32, i32>,
+}
+
+impl Generator {
+    async fn run(&mut self, mut rx: mpsc::UnboundedReceiver<()>) {
+        while rx.next().await.is_some() {
+            // Coalesce any other pending wakeups into this pass.
+            while let Ok(Some(())) = rx.try_next() {}
+
+            // Pick up streams added or moved through the web API since the last pass.
+            match dirs_by_stream_id(&self.db.lock()) {
+                Ok(d) => self.dirs_by_stream_id = d,
+                Err(e) => warn!("Unable to refresh sample file dirs: {}", e),
+            }
+            let stream_ids: Vec<i32> = self.dirs_by_stream_id.keys().copied().collect();
+            for stream_id in stream_ids {
+                loop {
+                    let ids = match self.list(stream_id) {
+                        Ok(ids) => ids,
+                        Err(e) => {
+                            warn!("Unable to list recordings of stream {}: {}", stream_id, e);
+                            break;
+                        }
+                    };
+                    for &id in &ids {
+                        if let Err(e) = self.generate(id).await {
+                            warn!("Unable to generate thumbnail for recording {}: {}", id, e);
+                        }
+                        self.next_recording_id.insert(stream_id, id.recording() + 1);
+                    }
+                    if ids.len() < BATCH_SIZE {
+                        break;
+                    }
+                }
+            }
+        }
+        info!("Thumbnail generator shutting down.");
+    }
+
+    fn list(&self, stream_id: i32) -> Result<Vec<CompositeId>, Error> {
+        let start = self.next_recording_id.get(&stream_id).copied().unwrap_or(0);
+        self.db.lock().list_recordings_without_thumbnails(
+            CompositeId::new(stream_id, start)..CompositeId::new(stream_id + 1, 0),
+            BATCH_SIZE,
+        )
+    }
+
+    async fn generate(&self, id: CompositeId) -> Result<(), Error> {
+        let (frames, sample_entry) = {
+            let db = self.db.lock();
+            let frames = match snapshot::find_first(&db, id)? {
+                Some(f) => f,
+                None => return Ok(()), // deleted in the meantime.
+            };
+            let sample_entry = db
+                .video_sample_entries_by_id()
+                .get(&frames.video_sample_entry_id)
+                .unwrap()
+                .clone();
+            (frames, sample_entry)
+        };
+        let dir = self
+            .dirs_by_stream_id
+            .get(&id.stream())
+            .ok_or_else(|| format_err!("stream {} has no sample file dir", id.stream()))?;
+        let data = snapshot::read(dir, &frames).await?;
+        let jpeg = tokio::task::spawn_blocking(move || {
+            snapshot::encode_jpeg(&sample_entry.data, &frames, &data, Some(THUMBNAIL_WIDTH))
+        })
+        .await??;
+        self.db.lock().insert_thumbnail(id, &jpeg)
+    }
+}
@@ -3,15 +3,20 @@
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
 use crate::body::Body;
+use crate::export;
+use crate::hls;
 use crate::json;
 use crate::mp4;
 use crate::onvif;
+use crate::snapshot;
+use crate::supervisor::Supervisor;
+use base::metrics::{DURATION_BUCKETS, REGISTRY};
 use base::{bail_t, ErrorKind};
 use base::{clock::Clocks, format_err_t};
 use core::borrow::Borrow;
 use core::str::FromStr;
 use db::dir::SampleFileDir;
-use db::{auth, recording};
+use db::{audit, auth, recording};
 use failure::{bail, format_err, Error};
 use fnv::FnvHashMap;
 use futures::stream::StreamExt;
@@ -26,6 +31,7 @@ use nom::bytes::complete::{tag, take_while1};
 use nom::combinator::{all_consuming, map, map_res, opt};
 use nom::sequence::{preceded, tuple};
 use nom::IResult;
+use parking_lot::Mutex;
 use std::cmp;
 use std::convert::TryFrom;
 use std::net::IpAddr;
@@ -60,23 +66,61 @@ enum Path {
     TopLevel,                                         // "/api/"
     Request,                                          // "/api/request"
     InitSegment(i32, bool),                           // "/api/init/<id>.mp4{.txt}"
+    Cameras,                                          // "/api/cameras"
     Camera(Uuid),                                     // "/api/cameras/<uuid>/"
+    Dirs,                                             // "/api/dirs"
     Signals,                                          // "/api/signals"
     StreamRecordings(Uuid, db::StreamType),           // "/api/cameras/<uuid>/<type>/recordings"
     StreamViewMp4(Uuid, db::StreamType, bool),        // "/api/cameras/<uuid>/<type>/view.mp4{.txt}"
     StreamViewMp4Segment(Uuid, db::StreamType, bool), // "/api/cameras/<uuid>/<type>/view.m4s{.txt}"
     StreamLiveMp4Segments(Uuid, db::StreamType),      // "/api/cameras/<uuid>/<type>/live.m4s"
+    StreamViewM3u8(Uuid, db::StreamType),             // "/api/cameras/<uuid>/<type>/view.m3u8"
+    StreamLiveM3u8(Uuid, db::StreamType),             // "/api/cameras/<uuid>/<type>/live.m3u8"
+    StreamHlsSegment(Uuid, db::StreamType),           // "/api/cameras/<uuid>/<type>/hls.m4s"
+    StreamSnapshotJpg(Uuid, db::StreamType),          // "/api/cameras/<uuid>/<type>/snapshot.jpg"
+    StreamThumbnailJpg(Uuid, db::StreamType),         // "/api/cameras/<uuid>/<type>/thumbnail.jpg"
+    StreamHolds(Uuid, db::StreamType),                // "/api/cameras/<uuid>/<type>/holds"
+    Hold(i32),                                        // "/api/holds/<id>"
+    Exports,                                          // "/api/exports"
+    Export(Uuid),                                     // "/api/exports/<id>"
+    ExportTar(Uuid),                                  // "/api/exports/<id>.tar"
+    Users,                                            // "/api/users"
+    User(i32),                                        // "/api/users/<id>"
+    Sessions,                                         // "/api/sessions"
+    Session(auth::SessionHash),                       // "/api/sessions/<id>"
+    Tokens,                                           // "/api/tokens"
+    Token(auth::SessionHash),                         // "/api/tokens/<id>"
+    Totp,                                             // "/api/totp"
+    Audit,                                            // "/api/audit"
+    Health,                                           // "/api/health"
+    Webhooks,                                         // "/api/webhooks"
+    Webhook(i32),                                     // "/api/webhooks/<id>"
+    Discovery,                                        // "/api/discovery"
+    DiscoveryCameras,                                 // "/api/discovery/cameras"
+    Metrics,                                          // "/metrics"
     Login,                                            // "/api/login"
     Logout,                                           // "/api/logout"
-    Onvif,                                            // "/onvif/..."
+    OnvifNotify(String),                              // "/onvif/notify/<token>"
     Static,                                           // (anything that doesn't start with "/api/")
     NotFound,
 }
 
 impl Path {
+    /// Returns the name of the path's variant without its parameters, for use as a metric label.
+    fn route(&self) -> String {
+        let mut name = format!("{:?}", self);
+        if let Some(paren) = name.find('(') {
+            name.truncate(paren);
+        }
+        name
+    }
+
     fn decode(path: &str) -> Self {
-        if path.starts_with("/onvif/") {
-            return Path::Onvif
+        if path.starts_with("/onvif/notify/") {
+            return Path::OnvifNotify(path["/onvif/notify/".len()..].to_owned());
+        }
+        if path == "/metrics" {
+            return Path::Metrics;
         }
         if !path.starts_with("/api/") {
             return Path::Static;
@@ -90,6 +134,18 @@ impl Path {
             "/logout" => return Path::Logout,
             "/request" => return Path::Request,
             "/signals" => return Path::Signals,
+            "/exports" => return Path::Exports,
+            "/cameras" => return Path::Cameras,
+            "/dirs" => return Path::Dirs,
+            "/users" => return Path::Users,
+            "/sessions" => return Path::Sessions,
+            "/tokens" => return Path::Tokens,
+            "/totp" => return Path::Totp,
+            "/audit" => return Path::Audit,
+            "/health" => return Path::Health,
+            "/webhooks" => return Path::Webhooks,
+            "/discovery" => return Path::Discovery,
+            "/discovery/cameras" => return Path::DiscoveryCameras,
             _ => {}
         };
         if path.starts_with("/init/") {
@@ -108,6 +164,49 @@ impl Path {
             }
             return Path::NotFound;
         }
+        if path.starts_with("/holds/") {
+            return match i32::from_str(&path["/holds/".len()..]) {
+                Ok(id) => Path::Hold(id),
+                Err(_) => Path::NotFound,
+            };
+        }
+        if path.starts_with("/webhooks/") {
+            return match i32::from_str(&path["/webhooks/".len()..]) {
+                Ok(id) => Path::Webhook(id),
+                Err(_) => Path::NotFound,
+            };
+        }
+        if path.starts_with("/users/") {
+            return match i32::from_str(&path["/users/".len()..]) {
+                Ok(id) => Path::User(id),
+                Err(_) => Path::NotFound,
+            };
+        }
+        if path.starts_with("/sessions/") {
+            return match decode_session_id(&path["/sessions/".len()..]) {
+                Some(h) => Path::Session(h),
+                None => Path::NotFound,
+            };
+        }
+        if path.starts_with("/tokens/") {
+            return match decode_session_id(&path["/tokens/".len()..]) {
+                Some(h) => Path::Token(h),
+                None => Path::NotFound,
+            };
+        }
+        if path.starts_with("/exports/") {
+            let id = &path["/exports/".len()..];
+            let (id, tar) = if id.ends_with(".tar") {
+                (&id[..id.len() - ".tar".len()], true)
+            } else {
+                (id, false)
+            };
+            return match Uuid::parse_str(id) {
+                Ok(id) if tar => Path::ExportTar(id),
+                Ok(id) => Path::Export(id),
+                Err(_) => Path::NotFound,
+            };
+        }
         if !path.starts_with("/cameras/") {
             return Path::NotFound;
         }
@@ -152,11 +251,31 @@ impl Path {
             "/view.m4s" => Path::StreamViewMp4Segment(uuid, type_, false),
             "/view.m4s.txt" => Path::StreamViewMp4Segment(uuid, type_, true),
             "/live.m4s" => Path::StreamLiveMp4Segments(uuid, type_),
+            "/view.m3u8" => Path::StreamViewM3u8(uuid, type_),
+            "/live.m3u8" => Path::StreamLiveM3u8(uuid, type_),
+            "/hls.m4s" => Path::StreamHlsSegment(uuid, type_),
+            "/snapshot.jpg" => Path::StreamSnapshotJpg(uuid, type_),
+            "/thumbnail.jpg" => Path::StreamThumbnailJpg(uuid, type_),
+            "/holds" => Path::StreamHolds(uuid, type_),
             _ => Path::NotFound,
         }
     }
 }
 
+/// Decodes a session id hash as it appears in `/api/sessions/<id>` or `/api/tokens/<id>`:
+/// URL-safe base64, rather than
+/// the standard base64 of the CSRF token, so that it can't contain a `/`.
+fn decode_session_id(id: &str) -> Option<auth::SessionHash> {
+    if id.len() != 32 {
+        return None;
+    }
+    let mut h = auth::SessionHash::default();
+    match base64::decode_config_slice(id, base64::URL_SAFE_NO_PAD, &mut h.0[..]) {
+        Ok(24) => Some(h),
+        _ => None,
+    }
+}
+
 fn plain_response<B: Into<Body>>(status: http::StatusCode, body: B) -> Response<Body> {
     Response::builder()
         .status(status)
@@ -165,6 +284,16 @@ fn plain_response<B: Into<Body>>(status: http::StatusCode, body: B) -> Response<
         .expect("hardcoded head should be valid")
 }
 
+fn m3u8_response(playlist: String) -> Response<Body> {
+    Response::builder()
+        .header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/vnd.apple.mpegurl"),
+        )
+        .body(playlist.into())
+        .expect("hardcoded head should be valid")
+}
+
 fn not_found<B: Into<Body>>(body: B) -> HttpError {
     HttpError(plain_response(StatusCode::NOT_FOUND, body))
 }
@@ -256,13 +385,150 @@ impl FromStr for Segments {
     }
 }
 
+/// Calls `f` with each recording in `s` that overlaps its time range, along with the relevant
+/// portion as wall and media time ranges relative to the start of the recording.
+fn visit_segments(
+    db: &db::LockedDatabase,
+    stream_id: i32,
+    s: &Segments,
+    f: &mut dyn FnMut(db::ListRecordingsRow, Range<i32>, Range<i32>) -> Result<(), Error>,
+) -> Result<(), HttpError> {
+    let mut prev = None; // previous recording id
+    let mut cur_off = 0;
+    db.list_recordings_by_id(stream_id, s.ids.clone(), &mut |r| {
+        let recording_id = r.id.recording();
+
+        if let Some(o) = s.open_id {
+            if r.open_id != o {
+                bail!(
+                    "recording {} has open id {}, requested {}",
+                    r.id,
+                    r.open_id,
+                    o
+                );
+            }
+        }
+
+        // Check for missing recordings.
+        match prev {
+            None if recording_id == s.ids.start => {}
+            None => bail!("no such recording {}/{}", stream_id, s.ids.start),
+            Some(id) if r.id.recording() != id + 1 => {
+                bail!("no such recording {}/{}", stream_id, id + 1);
+            }
+            _ => {}
+        };
+        prev = Some(recording_id);
+
+        // Visit the relevant part of the recording, if any.
+        // Note all calculations here are in wall times / wall durations.
+        let end_time = s.end_time.unwrap_or(i64::max_value());
+        let wd = i64::from(r.wall_duration_90k);
+        if s.start_time <= cur_off + wd && cur_off < end_time {
+            let start = cmp::max(0, s.start_time - cur_off);
+            let end = cmp::min(wd, end_time - cur_off);
+            let wr = i32::try_from(start).unwrap()..i32::try_from(end).unwrap();
+            trace!(
+                "...visiting recording {} with wall duration {:?} \
+                   (out of total {})",
+                r.id,
+                wr,
+                wd
+            );
+            use recording::rescale;
+            let mr = rescale(wr.start, r.wall_duration_90k, r.media_duration_90k)
+                ..rescale(wr.end, r.wall_duration_90k, r.media_duration_90k);
+            f(r, wr, mr)?;
+        } else {
+            trace!("...skipping recording {} wall dur {}", r.id, wd);
+        }
+        cur_off += wd;
+        Ok(())
+    })
+    .map_err(internal_server_err)?;
+
+    // Check for missing recordings.
+    match prev {
+        Some(id) if s.ids.end != id + 1 => {
+            return Err(not_found(format!(
+                "no such recording {}/{}",
+                stream_id,
+                s.ids.end - 1
+            )));
+        }
+        None => {
+            return Err(not_found(format!(
+                "no such recording {}/{}",
+                stream_id, s.ids.start
+            )));
+        }
+        _ => {}
+    };
+    if let Some(end) = s.end_time {
+        if end > cur_off {
+            bail_t!(
+                InvalidArgument,
+                "end time {} is beyond specified recordings",
+                end
+            );
+        }
+    }
+    Ok(())
+}
+
 struct Caller {
     permissions: db::Permissions,
     session: Option<json::Session>,
+
+    /// The id of the user who owns the session or API token, if any.
+    user_id: Option<i32>,
+
+    /// True iff the caller authenticated with an API token rather than a session cookie.
+    api_token: bool,
+}
+
+impl Caller {
+    /// Returns true iff the caller may view the given camera's video, through either the
+    /// `view_video` permission (which covers all cameras) or a grant for this camera.
+    fn may_view_camera(&self, uuid: Uuid) -> bool {
+        self.permissions.view_video
+            || self
+                .permissions
+                .view_video_cameras
+                .iter()
+                .any(|c| &c[..] == uuid.as_bytes())
+    }
+
+    fn check_view_camera(&self, uuid: Uuid) -> Result<(), base::Error> {
+        if !self.may_view_camera(uuid) {
+            bail_t!(PermissionDenied, "view_video required for camera {}", uuid);
+        }
+        Ok(())
+    }
 }
 
 type ResponseResult = Result<Response<Body>, HttpError>;
 
+/// The maximum number of events returned by `GET /api/audit` if the caller doesn't specify.
+const DEFAULT_AUDIT_LIMIT: usize = 1000;
+
+/// A recording stream is reported as stalled by `GET /api/health` if it hasn't received a frame
+/// in this long.
+const STALLED_STREAM_SEC: i64 = 30;
+
+/// How long `POST /api/discovery` waits for devices to respond, if the caller doesn't specify.
+const DEFAULT_DISCOVERY_TIMEOUT_SEC: u64 = 3;
+const MAX_DISCOVERY_TIMEOUT_SEC: u64 = 30;
+
+/// Returns true unless `req` is a range request for a later part of the entity. Browsers make
+/// many such requests while playing a single `.mp4`; only the first should be audited.
+fn starts_entity(req: &Request<hyper::Body>) -> bool {
+    match req.headers().get(header::RANGE) {
+        None => true,
+        Some(r) => r.as_bytes().starts_with(b"bytes=0-"),
+    }
+}
+
 fn serve_json<T: serde::ser::Serialize>(req: &Request<hyper::Body>, out: &T) -> ResponseResult {
     let (mut resp, writer) = http_serve::streaming_body(&req).build();
     resp.headers_mut().insert(
@@ -281,6 +547,22 @@ fn csrf_matches(csrf: &str, session: auth::SessionHash) -> bool {
     ::ring::constant_time::verify_slices_are_equal(&b64[..], csrf.as_bytes()).is_ok()
 }
 
+/// Extracts an API token from an `Authorization: Bearer` header, if any.
+fn extract_bearer_token(
+    req: &Request<hyper::Body>,
+) -> Result<Option<auth::RawSessionId>, base::Error> {
+    let hdr = match req.headers().get(header::AUTHORIZATION) {
+        None => return Ok(None),
+        Some(h) => h.as_bytes(),
+    };
+    if !hdr.starts_with(b"Bearer ") {
+        return Ok(None);
+    }
+    auth::RawSessionId::decode_base64(&hdr[b"Bearer ".len()..])
+        .map(Some)
+        .map_err(|_| format_err_t!(Unauthenticated, "malformed bearer token"))
+}
+
 /// Extracts `s` cookie from the HTTP request. Does not authenticate.
 fn extract_sid(req: &Request<hyper::Body>) -> Option<auth::RawSessionId> {
     let hdr = match req.headers().get(header::COOKIE) {
@@ -331,7 +613,9 @@ async fn extract_bytes(req: &mut Request<hyper::Body>) -> Result<Bytes, HttpErro
 /// Extracts an `application/xml+soap` POST body from a request.
 async fn extract_soap_xml_body(req: &mut Request<hyper::Body>) -> Result<Bytes, HttpError> {
     let content_type = match req.headers().get(header::CONTENT_TYPE) {
-        Some(header) => header.to_str().map_err(|e| bad_req("missing content type header"))?,
+        Some(header) => header
+            .to_str()
+            .map_err(|_| bad_req("bad content type header"))?,
         _ => "",
     };
     if !content_type.contains("application/soap+xml") {
@@ -340,22 +624,127 @@ async fn extract_soap_xml_body(req: &mut Request<hyper::Body>) -> Result<Bytes,
     extract_bytes(req).await
 }
 
+/// Returns the sample file directory of each stream which has one, opening directories as
+/// necessary.
+fn dirs_by_stream_id(
+    l: &mut db::LockedDatabase,
+) -> Result<Arc<FnvHashMap<i32, Arc<SampleFileDir>>>, Error> {
+    let dirs_to_open: Vec<i32> = l
+        .streams_by_id()
+        .values()
+        .filter_map(|s| s.sample_file_dir_id)
+        .collect();
+    l.open_sample_file_dirs(&dirs_to_open)?;
+    let mut d = FnvHashMap::with_capacity_and_hasher(l.streams_by_id().len(), Default::default());
+    for (&id, s) in l.streams_by_id().iter() {
+        let dir_id = match s.sample_file_dir_id {
+            Some(d) => d,
+            None => continue,
+        };
+        d.insert(id, l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?);
+    }
+    Ok(Arc::new(d))
+}
+
+/// Converts a camera's configuration as supplied to the API into a database change, returning
+/// it along with each stream's byte limit.
+fn camera_change(c: json::CameraChange) -> Result<(db::CameraChange, [i64; 2]), HttpError> {
+    if c.short_name.is_empty() {
+        return Err(bad_req("shortName must be non-empty"));
+    }
+    let mut streams: [db::StreamChange; 2] = Default::default();
+    let mut retain_bytes = [0; 2];
+    for (i, s) in vec![c.streams.main, c.streams.sub].into_iter().enumerate() {
+        let s = match s {
+            Some(s) => s,
+            None => continue,
+        };
+        let type_ = db::StreamType::from_index(i).unwrap();
+        if !s.rtsp_url.is_empty() {
+            let url = url::Url::parse(&s.rtsp_url)
+                .map_err(|e| bad_req(format!("bad {} rtspUrl: {}", type_, e)))?;
+            if !url.username().is_empty() || url.password().is_some() {
+                return Err(bad_req(format!(
+                    "{} rtspUrl shouldn't include credentials",
+                    type_
+                )));
+            }
+        }
+        if s.retain_bytes < 0 {
+            return Err(bad_req(format!(
+                "{} retainBytes must be non-negative",
+                type_
+            )));
+        }
+        streams[i] = db::StreamChange {
+            sample_file_dir_id: s.sample_file_dir_id,
+            rtsp_url: s.rtsp_url,
+            record: s.record,
+            flush_if_sec: s.flush_if_sec,
+            retain_max_age_sec: s.retain_max_age_sec,
+            retain_min_age_sec: s.retain_min_age_sec,
+            archive_sample_file_dir_id: s.archive_sample_file_dir_id,
+            archive_age_sec: s.archive_age_sec,
+        };
+        retain_bytes[i] = s.retain_bytes;
+    }
+    Ok((
+        db::CameraChange {
+            short_name: c.short_name,
+            description: c.description,
+            onvif_host: c.onvif_host,
+            username: c.username,
+            password: c.password,
+            streams,
+        },
+        retain_bytes,
+    ))
+}
+
+/// Sets each of a camera's streams' byte limits, returning the ids of its streams.
+fn update_retention(
+    l: &mut db::LockedDatabase,
+    camera_id: i32,
+    retain_bytes: [i64; 2],
+) -> Result<Vec<i32>, Error> {
+    let streams = l.cameras_by_id().get(&camera_id).unwrap().streams;
+    let mut changes = Vec::new();
+    for (i, id) in streams.iter().enumerate() {
+        if let Some(id) = *id {
+            changes.push(db::RetentionChange {
+                stream_id: id,
+                new_record: l.streams_by_id().get(&id).unwrap().record,
+                new_limit: retain_bytes[i],
+            });
+        }
+    }
+    l.update_retention(&changes)?;
+    Ok(changes.iter().map(|c| c.stream_id).collect())
+}
 
 pub struct Config<'a> {
     pub db: Arc<db::Database>,
     pub ui_dir: Option<&'a std::path::Path>,
+    pub export_dir: &'a std::path::Path,
     pub trust_forward_hdrs: bool,
     pub time_zone_name: String,
     pub allow_unauthenticated_permissions: Option<db::Permissions>,
+
+    /// The supervisor of streamers and syncers, if recording. Configuration changes made through
+    /// the API are applied to it.
+    pub supervisor: Option<Arc<Supervisor>>,
 }
 
 pub struct Service {
     db: Arc<db::Database>,
     ui_dir: Option<Arc<FsDir>>,
-    dirs_by_stream_id: Arc<FnvHashMap<i32, Arc<SampleFileDir>>>,
+    dirs_by_stream_id: Mutex<Arc<FnvHashMap<i32, Arc<SampleFileDir>>>>,
+    supervisor: Option<Arc<Supervisor>>,
     time_zone_name: String,
     allow_unauthenticated_permissions: Option<db::Permissions>,
     trust_forward_hdrs: bool,
+    live_hls: Mutex<hls::LiveSequences>,
+    exports: export::Jobs,
 }
 
 /// Useful HTTP `Cache-Control` values to set on successful (HTTP 200) API responses.
@@ -387,27 +776,18 @@ impl Service {
                 Ok(d) => ui_dir = Some(d),
             };
         }
-        let dirs_by_stream_id = {
-            let l = config.db.lock();
-            let mut d =
-                FnvHashMap::with_capacity_and_hasher(l.streams_by_id().len(), Default::default());
-            for (&id, s) in l.streams_by_id().iter() {
-                let dir_id = match s.sample_file_dir_id {
-                    Some(d) => d,
-                    None => continue,
-                };
-                d.insert(id, l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?);
-            }
-            Arc::new(d)
-        };
+        let dirs_by_stream_id = dirs_by_stream_id(&mut config.db.lock())?;
 
         Ok(Service {
             db: config.db,
-            dirs_by_stream_id,
+            dirs_by_stream_id: Mutex::new(dirs_by_stream_id),
+            supervisor: config.supervisor,
             ui_dir,
             allow_unauthenticated_permissions: config.allow_unauthenticated_permissions,
             trust_forward_hdrs: config.trust_forward_hdrs,
             time_zone_name: config.time_zone_name,
+            live_hls: Mutex::new(hls::LiveSequences::default()),
+            exports: export::Jobs::new(config.export_dir.to_owned()),
         })
     }
 
@@ -418,9 +798,7 @@ impl Service {
         uuid: Uuid,
         stream_type: db::StreamType,
     ) -> ResponseResult {
-        if !caller.permissions.view_video {
-            bail_t!(PermissionDenied, "view_video required");
-        }
+        caller.check_view_camera(uuid)?;
 
         let stream_id;
         let open_id;
@@ -447,6 +825,10 @@ impl Service {
                 Box::new(move |l| sub_tx.unbounded_send(l).is_ok()),
             )
             .expect("stream_id refed by camera");
+            let mut e = self.audit_event(&req, &caller, audit::Kind::View);
+            e.camera_uuid = Some(uuid);
+            e.detail = Some(format!("live {}", stream_type));
+            db.audit(e);
         }
 
         let response =
@@ -552,11 +934,15 @@ impl Service {
         }
         let row = row.unwrap();
         use http_serve::Entity;
-        let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id.clone())?;
+        let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id())?;
         let mut hdrs = header::HeaderMap::new();
         mp4.add_headers(&mut hdrs);
         let mime_type = hdrs.get(header::CONTENT_TYPE).unwrap();
         let (prev_media_duration, prev_runs) = row.prev_media_duration_and_runs.unwrap();
+        let audio_hdr = match row.audio_sample_entry_id {
+            Some(id) => format!("X-Audio-Sample-Entry-Id: {}\r\n", id),
+            None => String::new(),
+        };
         let hdr = format!(
             "Content-Type: {}\r\n\
             X-Recording-Start: {}\r\n\
@@ -564,7 +950,8 @@ impl Service {
             X-Media-Time-Range: {}-{}\r\n\
             X-Prev-Media-Duration: {}\r\n\
             X-Runs: {}\r\n\
-            X-Video-Sample-Entry-Id: {}\r\n\r\n",
+            X-Video-Sample-Entry-Id: {}\r\n\
+            {}\r\n",
             mime_type.to_str().unwrap(),
             row.start.0,
             open_id,
@@ -573,7 +960,8 @@ impl Service {
             live.media_off_90k.end,
             prev_media_duration.0,
             prev_runs + if row.run_offset == 0 { 1 } else { 0 },
-            &row.video_sample_entry_id
+            &row.video_sample_entry_id,
+            audio_hdr,
         );
         let mut v = hdr.into_bytes();
         mp4.append_into_vec(&mut v).await?;
@@ -594,6 +982,25 @@ impl Service {
         }
     }
 
+    async fn stream_holds(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        use http::method::Method;
+        match *req.method() {
+            Method::POST => self.post_stream_hold(req, caller, uuid, type_).await,
+            Method::GET | Method::HEAD => self.get_stream_holds(&req, caller, uuid, type_),
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST, GET, or HEAD expected",
+            )
+            .into()),
+        }
+    }
+
     /// Serves an HTTP request.
     /// Note that the `serve` wrapper handles responses the same whether they
     /// are `Ok` or `Err`. But returning `Err` here with the `?` operator is
@@ -611,10 +1018,56 @@ impl Service {
             ),
             Path::TopLevel => (CacheControl::PrivateDynamic, self.top_level(&req, caller)?),
             Path::Request => (CacheControl::PrivateDynamic, self.request(&req)?),
-            Path::Camera(uuid) => (CacheControl::PrivateDynamic, self.camera(&req, uuid)?),
+            Path::Cameras => (
+                CacheControl::PrivateDynamic,
+                self.post_camera(req, caller).await?,
+            ),
+            Path::Camera(uuid) => (
+                CacheControl::PrivateDynamic,
+                self.camera(req, caller, uuid).await?,
+            ),
+            Path::Dirs => (CacheControl::PrivateDynamic, self.dirs(req, caller).await?),
+            Path::Users => (CacheControl::PrivateDynamic, self.users(req, caller).await?),
+            Path::User(id) => (
+                CacheControl::PrivateDynamic,
+                self.user(req, caller, id).await?,
+            ),
+            Path::Sessions => (CacheControl::PrivateDynamic, self.sessions(&req, caller)?),
+            Path::Session(hash) => (
+                CacheControl::PrivateDynamic,
+                self.session(&req, caller, hash)?,
+            ),
+            Path::Tokens => (
+                CacheControl::PrivateDynamic,
+                self.tokens(req, caller).await?,
+            ),
+            Path::Token(hash) => (
+                CacheControl::PrivateDynamic,
+                self.token(&req, caller, hash)?,
+            ),
+            Path::Totp => (CacheControl::PrivateDynamic, self.totp(req, caller).await?),
+            Path::Audit => (CacheControl::PrivateDynamic, self.audit(&req, caller)?),
+            Path::Health => (CacheControl::PrivateDynamic, self.health(&req, caller)?),
+            Path::Webhooks => (
+                CacheControl::PrivateDynamic,
+                self.webhooks(req, caller).await?,
+            ),
+            Path::Webhook(id) => (
+                CacheControl::PrivateDynamic,
+                self.webhook(&req, caller, id)?,
+            ),
+            Path::Discovery => (
+                CacheControl::PrivateDynamic,
+                self.discovery(req, caller).await?,
+            ),
+            Path::DiscoveryCameras => (
+                CacheControl::PrivateDynamic,
+                self.post_discovery_camera(req, caller).await?,
+            ),
+            Path::Metrics => (CacheControl::PrivateDynamic, self.metrics(&req, caller)?),
             Path::StreamRecordings(uuid, type_) => (
                 CacheControl::PrivateDynamic,
-                self.stream_recordings(&req, uuid, type_)?,
+                self.stream_recordings(&req, caller, uuid, type_)?,
             ),
             Path::StreamViewMp4(uuid, type_, debug) => (
                 CacheControl::PrivateStatic,
@@ -628,6 +1081,39 @@ impl Service {
                 CacheControl::PrivateDynamic,
                 self.stream_live_m4s(req, caller, uuid, type_)?,
             ),
+            Path::StreamViewM3u8(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_view_m3u8(&req, caller, uuid, type_)?,
+            ),
+            Path::StreamLiveM3u8(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_live_m3u8(caller, uuid, type_)?,
+            ),
+            Path::StreamHlsSegment(uuid, type_) => (
+                CacheControl::PrivateStatic,
+                self.stream_hls_m4s(&req, caller, uuid, type_)?,
+            ),
+            Path::StreamSnapshotJpg(uuid, type_) => {
+                self.stream_snapshot_jpg(&req, caller, uuid, type_).await?
+            }
+            Path::StreamThumbnailJpg(uuid, type_) => (
+                CacheControl::PrivateStatic,
+                self.stream_thumbnail_jpg(&req, caller, uuid, type_)?,
+            ),
+            Path::StreamHolds(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_holds(req, caller, uuid, type_).await?,
+            ),
+            Path::Hold(id) => (CacheControl::PrivateDynamic, self.hold(&req, caller, id)?),
+            Path::Exports => (
+                CacheControl::PrivateDynamic,
+                self.post_export(req, caller).await?,
+            ),
+            Path::Export(id) => (CacheControl::PrivateDynamic, self.export(&req, caller, id)?),
+            Path::ExportTar(id) => (
+                CacheControl::PrivateDynamic,
+                self.export_tar(&req, caller, id)?,
+            ),
             Path::NotFound => return Err(not_found("path not understood")),
             Path::Login => (CacheControl::PrivateDynamic, self.login(req).await?),
             Path::Logout => (CacheControl::PrivateDynamic, self.logout(req).await?),
@@ -636,7 +1122,7 @@ impl Service {
                 self.signals(req, caller).await?,
             ),
             Path::Static => (CacheControl::None, self.static_file(req).await?),
-            Path::Onvif => (CacheControl::None, self.onvif(req).await?)
+            Path::OnvifNotify(token) => (CacheControl::None, self.onvif_notify(req, &token).await?),
         };
         match cache {
             CacheControl::PrivateStatic => {
@@ -669,17 +1155,139 @@ impl Service {
         let p = Path::decode(req.uri().path());
         let always_allow_unauthenticated = matches!(
             p,
-            Path::NotFound | Path::Request | Path::Login | Path::Logout | Path::Static | Path::Onvif
+            Path::NotFound
+                | Path::Request
+                | Path::Login
+                | Path::Logout
+                | Path::Static
+                | Path::OnvifNotify(_)
         );
         debug!("request on: {}: {:?}", req.uri(), p);
-        let caller = match self.authenticate(&req, always_allow_unauthenticated) {
-            Ok(c) => c,
-            Err(e) => return Ok(from_base_error(e)),
+        let route = p.route();
+        let start = self.db.clocks().monotonic();
+        let resp = match self.authenticate(&req, always_allow_unauthenticated) {
+            Ok(caller) => self
+                .serve_inner(req, p, caller)
+                .await
+                .unwrap_or_else(|e| e.0),
+            Err(e) => from_base_error(e),
         };
-        Ok(self
-            .serve_inner(req, p, caller)
-            .await
-            .unwrap_or_else(|e| e.0))
+        REGISTRY
+            .counter(
+                "moonfire_http_requests_total",
+                "HTTP requests by route and response status.",
+                &[("route", &route[..]), ("status", resp.status().as_str())],
+            )
+            .inc();
+        REGISTRY
+            .histogram(
+                "moonfire_http_request_duration_seconds",
+                "Time taken to produce HTTP response headers, by route.",
+                DURATION_BUCKETS,
+                &[("route", &route[..])],
+            )
+            .observe_duration(self.db.clocks().monotonic() - start);
+        Ok(resp)
+    }
+
+    /// Serves metrics in the Prometheus text format in response to `GET /metrics`.
+    fn metrics(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        use http::method::Method;
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Err(
+                plain_response(StatusCode::METHOD_NOT_ALLOWED, "GET or HEAD expected").into(),
+            );
+        }
+        if !caller.permissions.read_camera_configs {
+            bail_t!(PermissionDenied, "read_camera_configs required");
+        }
+        self.db.lock().update_metrics();
+        Ok(Response::builder()
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; version=0.0.4"),
+            )
+            .body(REGISTRY.render().into())
+            .unwrap())
+    }
+
+    /// Summarizes whether recording is working in response to `GET /api/health`.
+    ///
+    /// Returns `503 Service Unavailable` if any recording stream has stalled or any sample file
+    /// directory used by a stream isn't open.
+    fn health(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        use http::method::Method;
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Err(
+                plain_response(StatusCode::METHOD_NOT_ALLOWED, "GET or HEAD expected").into(),
+            );
+        }
+        if !caller.permissions.read_camera_configs {
+            bail_t!(PermissionDenied, "read_camera_configs required");
+        }
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let max_age = recording::Duration(STALLED_STREAM_SEC * recording::TIME_UNITS_PER_SEC);
+        let db = self.db.lock();
+        let mut stalled_streams = Vec::new();
+        let mut over_retention_streams = Vec::new();
+        let mut dir_ids = Vec::new();
+        for s in db.streams_by_id().values() {
+            dir_ids.extend(
+                s.sample_file_dir_id
+                    .into_iter()
+                    .chain(s.archive_sample_file_dir_id),
+            );
+            if s.retention_overage_bytes > 0 {
+                let c = &db.cameras_by_id()[&s.camera_id];
+                over_retention_streams.push(json::OverRetentionStream {
+                    camera_uuid: c.uuid,
+                    camera_short_name: &c.short_name,
+                    stream_type: s.type_.as_str(),
+                    retain_bytes: s.retain_bytes,
+                    retention_overage_bytes: s.retention_overage_bytes,
+                });
+            }
+
+            // Streams are only expected to deliver frames if this server is recording them.
+            if !s.record || self.supervisor.is_none() {
+                continue;
+            }
+            let status = s.status.lock();
+            if status.is_stalled(now, max_age) {
+                let c = &db.cameras_by_id()[&s.camera_id];
+                stalled_streams.push(json::StalledStream {
+                    camera_uuid: c.uuid,
+                    camera_short_name: &c.short_name,
+                    stream_type: s.type_.as_str(),
+                    status: json::StreamStatus::wrap(&status),
+                });
+            }
+        }
+        let unopened_dirs: Vec<_> = db
+            .sample_file_dirs_by_id()
+            .values()
+            .filter(|d| dir_ids.contains(&d.id) && !d.is_open())
+            .map(|d| json::UnopenedDir {
+                id: d.id,
+                path: &d.path,
+            })
+            .collect();
+        let healthy = stalled_streams.is_empty()
+            && unopened_dirs.is_empty()
+            && over_retention_streams.is_empty();
+        let mut resp = serve_json(
+            req,
+            &json::Health {
+                healthy,
+                stalled_streams,
+                unopened_dirs,
+                over_retention_streams,
+            },
+        )?;
+        if !healthy {
+            *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        }
+        Ok(resp)
     }
 
     fn top_level(&self, req: &Request<::hyper::Body>, caller: Caller) -> ResponseResult {
@@ -700,91 +1308,363 @@ impl Service {
             bail_t!(PermissionDenied, "read_camera_configs required");
         }
 
+        // List only the cameras the caller may view, or all of them to one who may read camera
+        // configs.
+        let may_list = |c: &db::Camera| {
+            caller.permissions.read_camera_configs || caller.may_view_camera(c.uuid)
+        };
         let db = self.db.lock();
         serve_json(
             req,
             &json::TopLevel {
                 time_zone_name: &self.time_zone_name,
-                cameras: (&db, days, camera_configs),
-                session: caller.session,
+                cameras: (&db, days, camera_configs, &may_list),
+                session: caller.session.clone(),
                 signals: (&db, days),
                 signal_types: &db,
             },
         )
     }
 
-    fn camera(&self, req: &Request<::hyper::Body>, uuid: Uuid) -> ResponseResult {
-        let db = self.db.lock();
-        let camera = db
-            .get_camera(uuid)
-            .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
-        serve_json(
-            req,
-            &json::Camera::wrap(camera, &db, true, false).map_err(internal_server_err)?,
-        )
+    /// Returns the sample file directory of each stream, as of the last configuration change.
+    fn dirs_by_stream_id(&self) -> Arc<FnvHashMap<i32, Arc<SampleFileDir>>> {
+        self.dirs_by_stream_id.lock().clone()
     }
 
-    async fn onvif(&self, mut req: Request<::hyper::Body>) -> ResponseResult {
-        println!("GOT NOTIFICATION: {:?}", &req);
-        let b = extract_soap_xml_body(&mut req).await?;
-        onvif::process_notification(b).await;
-        Ok(plain_response(StatusCode::OK, "OK"))
+    /// Stops the given streams' streamers, if any, before a configuration change which may
+    /// delete them.
+    async fn stop_streams(&self, stream_ids: Vec<i32>) -> Result<(), HttpError> {
+        let supervisor = match self.supervisor.clone() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        tokio::task::spawn_blocking(move || supervisor.stop_streams(&stream_ids))
+            .await
+            .map_err(internal_server_err)
     }
 
-    fn stream_recordings(
-        &self,
-        req: &Request<::hyper::Body>,
-        uuid: Uuid,
-        type_: db::StreamType,
-    ) -> ResponseResult {
-        let (r, split) = {
-            let mut time = recording::Time::min_value()..recording::Time::max_value();
-            let mut split = recording::Duration(i64::max_value());
-            if let Some(q) = req.uri().query() {
-                for (key, value) in form_urlencoded::parse(q.as_bytes()) {
-                    let (key, value) = (key.borrow(), value.borrow());
-                    match key {
-                        "startTime90k" => {
-                            time.start = recording::Time::parse(value)
-                                .map_err(|_| bad_req("unparseable startTime90k"))?
-                        }
-                        "endTime90k" => {
-                            time.end = recording::Time::parse(value)
-                                .map_err(|_| bad_req("unparseable endTime90k"))?
-                        }
-                        "split90k" => {
-                            split = recording::Duration(
-                                i64::from_str(value)
-                                    .map_err(|_| bad_req("unparseable split90k"))?,
-                            )
-                        }
-                        _ => {}
+    /// Applies a configuration change affecting the given streams: refreshes the directory of
+    /// each stream, (re)starts the streams' streamers as appropriate, and prompts their
+    /// directories to rotate in case retention limits were lowered.
+    async fn restart_streams(&self, stream_ids: Vec<i32>) -> Result<(), HttpError> {
+        let dir_ids = {
+            let mut l = self.db.lock();
+            *self.dirs_by_stream_id.lock() =
+                dirs_by_stream_id(&mut l).map_err(internal_server_err)?;
+            let mut dir_ids = Vec::new();
+            for id in &stream_ids {
+                if let Some(d) = l.streams_by_id().get(id).and_then(|s| s.sample_file_dir_id) {
+                    if !dir_ids.contains(&d) {
+                        dir_ids.push(d);
                     }
                 }
             }
-            (time, split)
+            dir_ids
         };
-        let db = self.db.lock();
-        let mut out = json::ListRecordings {
-            recordings: Vec::new(),
-            video_sample_entries: (&db, Vec::new()),
+        let supervisor = match self.supervisor.clone() {
+            Some(s) => s,
+            None => return Ok(()),
         };
-        let camera = db.get_camera(uuid).ok_or_else(|| {
-            plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
-        })?;
-        let stream_id = camera.streams[type_.index()].ok_or_else(|| {
-            plain_response(
-                StatusCode::NOT_FOUND,
-                format!("no such stream {}/{}", uuid, type_),
+        tokio::task::spawn_blocking(move || {
+            supervisor.restart_streams(&stream_ids)?;
+            for d in dir_ids {
+                supervisor.rotate(d);
+            }
+            Ok::<_, Error>(())
+        })
+        .await
+        .map_err(internal_server_err)?
+        .map_err(internal_server_err)
+    }
+
+    async fn camera(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        use http::method::Method;
+        match *req.method() {
+            Method::GET | Method::HEAD => self.get_camera(&req, caller, uuid),
+            Method::PUT => self.put_camera(req, caller, uuid).await,
+            Method::DELETE => self.delete_camera(&req, caller, uuid).await,
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, PUT, or DELETE expected",
             )
-        })?;
-        db.list_aggregated_recordings(stream_id, r, split, &mut |row| {
-            let end = row.ids.end - 1; // in api, ids are inclusive.
-            out.recordings.push(json::Recording {
-                start_id: row.ids.start,
-                end_id: if end == row.ids.start {
-                    None
-                } else {
+            .into()),
+        }
+    }
+
+    /// Adds a camera in response to `POST /api/cameras`.
+    async fn post_camera(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if *req.method() != http::method::Method::POST {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected").into());
+        }
+        if !caller.permissions.update_config {
+            bail_t!(PermissionDenied, "update_config required");
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::CameraChange =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let (change, retain_bytes) = camera_change(r)?;
+        let (uuid, stream_ids) = {
+            let mut l = self.db.lock();
+            let id = l.add_camera(change).map_err(|e| bad_req(e.to_string()))?;
+            let stream_ids =
+                update_retention(&mut l, id, retain_bytes).map_err(internal_server_err)?;
+            let uuid = l.cameras_by_id().get(&id).unwrap().uuid;
+            l.audit(self.config_event(&req, &caller, format!("added camera {}", uuid)));
+            (uuid, stream_ids)
+        };
+        info!("added camera {}", uuid);
+        self.restart_streams(stream_ids).await?;
+        serve_json(&req, &json::PostCameraResponse { uuid })
+    }
+
+    /// Replaces a camera's configuration in response to `PUT /api/cameras/<uuid>/`.
+    async fn put_camera(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        if !caller.permissions.update_config {
+            bail_t!(PermissionDenied, "update_config required");
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::CameraChange =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let (change, retain_bytes) = camera_change(r)?;
+        let (id, mut stream_ids) = {
+            let l = self.db.lock();
+            let c = l
+                .get_camera(uuid)
+                .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+            let stream_ids: Vec<i32> = c.streams.iter().filter_map(|s| *s).collect();
+            (c.id, stream_ids)
+        };
+
+        // Stop the camera's streams while changing them, and start them again (with the old
+        // configuration if the change failed) afterward.
+        self.stop_streams(stream_ids.clone()).await?;
+        let result = {
+            let mut l = self.db.lock();
+            l.update_camera(id, change)
+                .map_err(|e| bad_req(e.to_string()))
+                .and_then(|()| {
+                    update_retention(&mut l, id, retain_bytes).map_err(internal_server_err)
+                })
+        };
+        if let Ok(ref new_ids) = result {
+            for &new_id in new_ids {
+                if !stream_ids.contains(&new_id) {
+                    stream_ids.push(new_id);
+                }
+            }
+        }
+        self.restart_streams(stream_ids).await?;
+        result?;
+        info!("updated camera {}", uuid);
+        let e = self.config_event(&req, &caller, format!("updated camera {}", uuid));
+        self.db.lock().audit(e);
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    /// Deletes a camera without recordings in response to `DELETE /api/cameras/<uuid>/`.
+    async fn delete_camera(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        if !caller.permissions.update_config {
+            bail_t!(PermissionDenied, "update_config required");
+        }
+        let (id, stream_ids) = {
+            let l = self.db.lock();
+            let c = l
+                .get_camera(uuid)
+                .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+            let stream_ids: Vec<i32> = c.streams.iter().filter_map(|s| *s).collect();
+            (c.id, stream_ids)
+        };
+        self.stop_streams(stream_ids.clone()).await?;
+        let result = self
+            .db
+            .lock()
+            .delete_camera(id)
+            .map_err(|e| bad_req(e.to_string()));
+        self.restart_streams(stream_ids).await?;
+        result?;
+        info!("deleted camera {}", uuid);
+        let e = self.config_event(req, &caller, format!("deleted camera {}", uuid));
+        self.db.lock().audit(e);
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    /// Lists or adds sample file directories in response to `GET` or `POST` on `/api/dirs`.
+    async fn dirs(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        use http::method::Method;
+        match *req.method() {
+            Method::GET | Method::HEAD => {
+                if !caller.permissions.read_camera_configs {
+                    bail_t!(PermissionDenied, "read_camera_configs required");
+                }
+                let l = self.db.lock();
+                let dirs = l
+                    .sample_file_dirs_by_id()
+                    .values()
+                    .map(|d| json::Dir {
+                        id: d.id,
+                        path: &d.path,
+                        uuid: d.uuid,
+                    })
+                    .collect();
+                serve_json(&req, &json::Dirs { dirs })
+            }
+            Method::POST => {
+                if !caller.permissions.update_config {
+                    bail_t!(PermissionDenied, "update_config required");
+                }
+                let r = extract_json_body(&mut req).await?;
+                let r: json::PostDirRequest =
+                    serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+                let detail = format!("added sample file dir {:?}", r.path);
+                let mut l = self.db.lock();
+                let id = l
+                    .add_sample_file_dir(r.path)
+                    .map_err(|e| bad_req(e.to_string()))?;
+                l.audit(self.config_event(&req, &caller, detail));
+                info!("added sample file dir {}", id);
+                serve_json(&req, &json::PostDirResponse { id })
+            }
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or POST expected",
+            )
+            .into()),
+        }
+    }
+
+    fn get_camera(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        if !caller.permissions.read_camera_configs {
+            caller.check_view_camera(uuid)?;
+        }
+        let db = self.db.lock();
+        let camera = db
+            .get_camera(uuid)
+            .ok_or_else(|| not_found(format!("no such camera {}", uuid)))?;
+        serve_json(
+            req,
+            &json::Camera::wrap(camera, &db, true, false).map_err(internal_server_err)?,
+        )
+    }
+
+    /// Applies ONVIF events in response to a camera's `POST /onvif/notify/<token>`. This is
+    /// unauthenticated, as cameras can't log in; the token identifies the subscription.
+    async fn onvif_notify(&self, mut req: Request<::hyper::Body>, token: &str) -> ResponseResult {
+        if *req.method() != http::method::Method::POST {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected").into());
+        }
+        let b = extract_soap_xml_body(&mut req).await?;
+        onvif::process_notification(&self.db, token, &b).map_err(from_base_error)?;
+        Ok(plain_response(StatusCode::OK, "OK"))
+    }
+
+    fn stream_recordings(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        caller.check_view_camera(uuid)?;
+        let (r, split, thumbnails) = {
+            let mut time = recording::Time::min_value()..recording::Time::max_value();
+            let mut split = recording::Duration(i64::max_value());
+            let mut thumbnails = false;
+            if let Some(q) = req.uri().query() {
+                for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                    let (key, value) = (key.borrow(), value.borrow());
+                    match key {
+                        "startTime90k" => {
+                            time.start = recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable startTime90k"))?
+                        }
+                        "endTime90k" => {
+                            time.end = recording::Time::parse(value)
+                                .map_err(|_| bad_req("unparseable endTime90k"))?
+                        }
+                        "split90k" => {
+                            split = recording::Duration(
+                                i64::from_str(value)
+                                    .map_err(|_| bad_req("unparseable split90k"))?,
+                            )
+                        }
+                        "thumbnails" => thumbnails = value == "true",
+                        _ => {}
+                    }
+                }
+            }
+            (time, split, thumbnails)
+        };
+        let db = self.db.lock();
+        let mut out = json::ListRecordings {
+            recordings: Vec::new(),
+            video_sample_entries: (&db, Vec::new()),
+            audio_sample_entries: (&db, Vec::new()),
+        };
+        let camera = db.get_camera(uuid).ok_or_else(|| {
+            plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
+        })?;
+        let stream_id = camera.streams[type_.index()].ok_or_else(|| {
+            plain_response(
+                StatusCode::NOT_FOUND,
+                format!("no such stream {}/{}", uuid, type_),
+            )
+        })?;
+
+        // If requested, find the start time of each recording with a thumbnail.
+        let mut thumbnail_starts = std::collections::BTreeMap::new();
+        if thumbnails {
+            let mut starts = FnvHashMap::default();
+            let mut ids = i32::max_value()..i32::min_value();
+            db.list_recordings_by_time(stream_id, r.clone(), &mut |row| {
+                let id = row.id.recording();
+                starts.insert(id, row.start.0);
+                ids.start = cmp::min(ids.start, id);
+                ids.end = cmp::max(ids.end, id + 1);
+                Ok(())
+            })
+            .map_err(internal_server_err)?;
+            if !starts.is_empty() {
+                db.list_thumbnails(stream_id, ids, &mut |id| {
+                    if let Some(&start) = starts.get(&id) {
+                        thumbnail_starts.insert(id, start);
+                    }
+                })
+                .map_err(internal_server_err)?;
+            }
+        }
+        db.list_aggregated_recordings(stream_id, r, split, &mut |row| {
+            let end = row.ids.end - 1; // in api, ids are inclusive.
+            out.recordings.push(json::Recording {
+                start_id: row.ids.start,
+                end_id: if end == row.ids.start {
+                    None
+                } else {
                     Some(end)
                 },
                 start_time_90k: row.time.start.0,
@@ -794,7 +1674,21 @@ impl Service {
                 first_uncommitted: row.first_uncommitted,
                 video_samples: row.video_samples,
                 video_sample_entry_id: row.video_sample_entry_id,
+                audio_sample_entry_id: row.audio_sample_entry_id,
                 growing: row.growing,
+                thumbnails: if thumbnails {
+                    Some(
+                        thumbnail_starts
+                            .range(row.ids.clone())
+                            .map(|(&recording_id, &start_time_90k)| json::Thumbnail {
+                                recording_id,
+                                start_time_90k,
+                            })
+                            .collect(),
+                    )
+                } else {
+                    None
+                },
             });
             if !out
                 .video_sample_entries
@@ -803,6 +1697,11 @@ impl Service {
             {
                 out.video_sample_entries.1.push(row.video_sample_entry_id);
             }
+            if let Some(id) = row.audio_sample_entry_id {
+                if !out.audio_sample_entries.1.contains(&id) {
+                    out.audio_sample_entries.1.push(id);
+                }
+            }
             Ok(())
         })
         .map_err(internal_server_err)?;
@@ -817,8 +1716,22 @@ impl Service {
             .get(&id)
             .ok_or_else(|| not_found("not such init segment"))?;
         builder.append_video_sample_entry(ent.clone());
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                if key == "audioSampleEntryId" {
+                    let audio_id = i32::from_str(value)
+                        .map_err(|_| bad_req("unparseable audioSampleEntryId"))?;
+                    let audio_ent = db
+                        .audio_sample_entries_by_id()
+                        .get(&audio_id)
+                        .ok_or_else(|| not_found("no such audio sample entry"))?;
+                    builder.append_audio_sample_entry(audio_ent.clone());
+                }
+            }
+        }
         let mp4 = builder
-            .build(self.db.clone(), self.dirs_by_stream_id.clone())
+            .build(self.db.clone(), self.dirs_by_stream_id())
             .map_err(from_base_error)?;
         if debug {
             Ok(plain_response(StatusCode::OK, format!("{:#?}", mp4)))
@@ -836,9 +1749,7 @@ impl Service {
         mp4_type: mp4::Type,
         debug: bool,
     ) -> ResponseResult {
-        if !caller.permissions.view_video {
-            bail_t!(PermissionDenied, "view_video required");
-        }
+        caller.check_view_camera(uuid)?;
         let (stream_id, camera_name);
         {
             let db = self.db.lock();
@@ -854,6 +1765,7 @@ impl Service {
             })?;
         };
         let mut start_time_for_filename = None;
+        let mut viewed: Option<Range<recording::Time>> = None;
         let mut builder = mp4::FileBuilder::new(mp4_type);
         if let Some(q) = req.uri().query() {
             for (key, value) in form_urlencoded::parse(q.as_bytes()) {
@@ -882,95 +1794,19 @@ impl Service {
                         }
                         builder.reserve(est_segments);
                         let db = self.db.lock();
-                        let mut prev = None; // previous recording id
-                        let mut cur_off = 0;
-                        db.list_recordings_by_id(stream_id, s.ids.clone(), &mut |r| {
-                            let recording_id = r.id.recording();
-
-                            if let Some(o) = s.open_id {
-                                if r.open_id != o {
-                                    bail!(
-                                        "recording {} has open id {}, requested {}",
-                                        r.id,
-                                        r.open_id,
-                                        o
-                                    );
-                                }
-                            }
-
-                            // Check for missing recordings.
-                            match prev {
-                                None if recording_id == s.ids.start => {}
-                                None => bail!("no such recording {}/{}", stream_id, s.ids.start),
-                                Some(id) if r.id.recording() != id + 1 => {
-                                    bail!("no such recording {}/{}", stream_id, id + 1);
-                                }
-                                _ => {}
-                            };
-                            prev = Some(recording_id);
-
-                            // Add a segment for the relevant part of the recording, if any.
-                            // Note all calculations here are in wall times / wall durations.
-                            let end_time = s.end_time.unwrap_or(i64::max_value());
-                            let wd = i64::from(r.wall_duration_90k);
-                            if s.start_time <= cur_off + wd && cur_off < end_time {
-                                let start = cmp::max(0, s.start_time - cur_off);
-                                let end = cmp::min(wd, end_time - cur_off);
-                                let wr = i32::try_from(start).unwrap()..i32::try_from(end).unwrap();
-                                trace!(
-                                    "...appending recording {} with wall duration {:?} \
-                                       (out of total {})",
-                                    r.id,
-                                    wr,
-                                    wd
-                                );
-                                if start_time_for_filename.is_none() {
-                                    start_time_for_filename =
-                                        Some(r.start + recording::Duration(start));
-                                }
-                                use recording::rescale;
-                                let mr =
-                                    rescale(wr.start, r.wall_duration_90k, r.media_duration_90k)
-                                        ..rescale(
-                                            wr.end,
-                                            r.wall_duration_90k,
-                                            r.media_duration_90k,
-                                        );
-                                builder.append(&db, r, mr, true)?;
-                            } else {
-                                trace!("...skipping recording {} wall dur {}", r.id, wd);
+                        visit_segments(&db, stream_id, &s, &mut |r, wr, mr| {
+                            let start = r.start + recording::Duration(i64::from(wr.start));
+                            let end = r.start + recording::Duration(i64::from(wr.end));
+                            if start_time_for_filename.is_none() {
+                                start_time_for_filename = Some(start);
                             }
-                            cur_off += wd;
+                            viewed = Some(match viewed.take() {
+                                None => start..end,
+                                Some(v) => cmp::min(v.start, start)..cmp::max(v.end, end),
+                            });
+                            builder.append(&db, r, mr, true)?;
                             Ok(())
-                        })
-                        .map_err(internal_server_err)?;
-
-                        // Check for missing recordings.
-                        match prev {
-                            Some(id) if s.ids.end != id + 1 => {
-                                return Err(not_found(format!(
-                                    "no such recording {}/{}",
-                                    stream_id,
-                                    s.ids.end - 1
-                                )));
-                            }
-                            None => {
-                                return Err(not_found(format!(
-                                    "no such recording {}/{}",
-                                    stream_id, s.ids.start
-                                )));
-                            }
-                            _ => {}
-                        };
-                        if let Some(end) = s.end_time {
-                            if end > cur_off {
-                                bail_t!(
-                                    InvalidArgument,
-                                    "end time {} is beyond specified recordings",
-                                    end
-                                );
-                            }
-                        }
+                        })?;
                     }
                     "ts" => builder
                         .include_timestamp_subtitle_track(value == "true")
@@ -1005,72 +1841,365 @@ impl Service {
                 .map_err(from_base_error)?;
         }
         let mp4 = builder
-            .build(self.db.clone(), self.dirs_by_stream_id.clone())
+            .build(self.db.clone(), self.dirs_by_stream_id())
             .map_err(from_base_error)?;
         if debug {
             return Ok(plain_response(StatusCode::OK, format!("{:#?}", mp4)));
         }
+        if starts_entity(req) {
+            let mut e = self.audit_event(req, &caller, audit::Kind::View);
+            e.camera_uuid = Some(uuid);
+            e.time_90k = viewed;
+            e.detail = Some(stream_type.to_string());
+            self.db.lock().audit(e);
+        }
         Ok(http_serve::serve(mp4, req))
     }
 
-    async fn static_file(&self, req: Request<hyper::Body>) -> ResponseResult {
-        let dir = self
-            .ui_dir
-            .clone()
-            .ok_or_else(|| not_found("--ui-dir not configured; no static files available."))?;
-        let static_req = match StaticFileRequest::parse(req.uri().path()) {
-            None => return Err(not_found("static file not found")),
-            Some(r) => r,
-        };
-        let f = dir.get(static_req.path, req.headers());
-        let node = f.await.map_err(|e| {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                not_found("no such static file")
-            } else {
-                internal_server_err(e)
-            }
+    /// Serves a video-on-demand HLS playlist for the recordings described by the `s` parameters.
+    fn stream_view_m3u8(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        caller.check_view_camera(uuid)?;
+        let db = self.db.lock();
+        let camera = db.get_camera(uuid).ok_or_else(|| {
+            plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
         })?;
-        let mut hdrs = http::HeaderMap::new();
-        node.add_encoding_headers(&mut hdrs);
-        hdrs.insert(
-            header::CACHE_CONTROL,
-            HeaderValue::from_static(if static_req.immutable {
-                // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Cache-Control#Caching_static_assets
-                "public, max-age=604800, immutable"
-            } else {
-                "public"
-            }),
-        );
-        hdrs.insert(
-            header::CONTENT_TYPE,
-            HeaderValue::from_static(static_req.mime),
-        );
-        let e = node.into_file_entity(hdrs).map_err(internal_server_err)?;
-        Ok(http_serve::serve(e, &req))
+        let stream_id = camera.streams[stream_type.index()]
+            .ok_or_else(|| format_err_t!(NotFound, "no such stream {}/{}", uuid, stream_type))?;
+        let mut segments = Vec::new();
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "s" => {
+                        let s = Segments::from_str(value)
+                            .map_err(|()| bad_req(format!("invalid s parameter: {}", value)))?;
+                        visit_segments(&db, stream_id, &s, &mut |r, _, mr| {
+                            hls::append_segments(&db, &r, mr, &mut segments)
+                        })?;
+                    }
+                    _ => return Err(bad_req(format!("parameter {} not understood", key))),
+                }
+            }
+        }
+        Ok(m3u8_response(hls::vod_playlist(&segments)))
     }
 
-    fn authreq(&self, req: &Request<::hyper::Body>) -> auth::Request {
-        auth::Request {
-            when_sec: Some(self.db.clocks().realtime().sec),
-            addr: if self.trust_forward_hdrs {
-                req.headers()
-                    .get("X-Real-IP")
-                    .and_then(|v| v.to_str().ok())
-                    .and_then(|v| IpAddr::from_str(v).ok())
-            } else {
-                None
-            },
-            user_agent: req
-                .headers()
-                .get(header::USER_AGENT)
-                .map(|ua| ua.as_bytes().to_vec()),
+    /// Serves a live HLS playlist of the stream's most recent segments.
+    fn stream_live_m3u8(
+        &self,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        caller.check_view_camera(uuid)?;
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let stream_id;
+        let mut segments = Vec::new();
+        {
+            let db = self.db.lock();
+            let camera = db.get_camera(uuid).ok_or_else(|| {
+                plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
+            })?;
+            stream_id = camera.streams[stream_type.index()].ok_or_else(|| {
+                format_err_t!(NotFound, "no such stream {}/{}", uuid, stream_type)
+            })?;
+            let mut rows = Vec::new();
+            db.list_recordings_by_time(
+                stream_id,
+                now - hls::LIVE_LOOKBACK..recording::Time::max_value(),
+                &mut |r| {
+                    rows.push(r);
+                    Ok(())
+                },
+            )
+            .map_err(internal_server_err)?;
+            rows.sort_by_key(|r| r.id.0);
+            for r in &rows {
+                hls::append_segments(&db, r, 0..r.media_duration_90k, &mut segments)
+                    .map_err(internal_server_err)?;
+            }
         }
+        let playlist = self.live_hls.lock().playlist(stream_id, &segments);
+        Ok(m3u8_response(playlist))
     }
 
-    fn request(&self, req: &Request<::hyper::Body>) -> ResponseResult {
-        let authreq = self.authreq(req);
-        let host = req
-            .headers()
+    /// Serves a media segment referenced by an HLS playlist.
+    ///
+    /// Unlike `view.m4s`, the `s` parameter must name exactly one recording and its open id,
+    /// and its times are media times, not wall times.
+    fn stream_hls_m4s(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        caller.check_view_camera(uuid)?;
+        let mut s = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "s" => {
+                        s = Some(
+                            Segments::from_str(value)
+                                .map_err(|()| bad_req(format!("invalid s parameter: {}", value)))?,
+                        )
+                    }
+                    _ => return Err(bad_req(format!("parameter {} not understood", key))),
+                }
+            }
+        }
+        let s = s.ok_or_else(|| bad_req("s parameter required"))?;
+        let (open_id, media_range_90k) = match (s.ids.end - s.ids.start, s.open_id, s.end_time) {
+            (1, Some(o), Some(e)) => (
+                o,
+                i32::try_from(s.start_time).map_err(|_| bad_req("start time out of range"))?
+                    ..i32::try_from(e).map_err(|_| bad_req("end time out of range"))?,
+            ),
+            _ => {
+                return Err(bad_req(
+                    "s must specify a single recording, open id, and time range",
+                ))
+            }
+        };
+        let mut builder = mp4::FileBuilder::new(mp4::Type::MediaSegment);
+        builder
+            .include_base_media_decode_time(true)
+            .map_err(from_base_error)?;
+        {
+            let mut db = self.db.lock();
+            let camera = db.get_camera(uuid).ok_or_else(|| {
+                plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
+            })?;
+            let stream_id = camera.streams[stream_type.index()].ok_or_else(|| {
+                format_err_t!(NotFound, "no such stream {}/{}", uuid, stream_type)
+            })?;
+            let mut row = None;
+            db.list_recordings_by_id(stream_id, s.ids.clone(), &mut |r| {
+                row = Some(r);
+                Ok(())
+            })
+            .map_err(internal_server_err)?;
+            let row = row.filter(|r| r.open_id == open_id).ok_or_else(|| {
+                not_found(format!(
+                    "no such recording {}/{}@{}",
+                    stream_id, s.ids.start, open_id
+                ))
+            })?;
+            if starts_entity(req) {
+                // Media times are close enough to wall times for the audit log.
+                let mut e = self.audit_event(req, &caller, audit::Kind::View);
+                e.camera_uuid = Some(uuid);
+                e.time_90k = Some(
+                    row.start + recording::Duration(i64::from(media_range_90k.start))
+                        ..row.start + recording::Duration(i64::from(media_range_90k.end)),
+                );
+                e.detail = Some(format!("hls {}", stream_type));
+                db.audit(e);
+            }
+            builder
+                .append(&db, row, media_range_90k, true)
+                .map_err(from_base_error)?;
+        }
+        let mp4 = builder
+            .build(self.db.clone(), self.dirs_by_stream_id())
+            .map_err(from_base_error)?;
+        Ok(http_serve::serve(mp4, req))
+    }
+
+    /// Serves the thumbnail of the recording with id `id`, as generated by the `thumbnails`
+    /// module. These never change once generated, so they're cached.
+    fn stream_thumbnail_jpg(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        caller.check_view_camera(uuid)?;
+        let mut id = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                if key == "id" {
+                    id = Some(i32::from_str(&value).map_err(|_| bad_req("unparseable id"))?);
+                }
+            }
+        }
+        let id = id.ok_or_else(|| bad_req("id required"))?;
+        let db = self.db.lock();
+        let camera = db
+            .get_camera(uuid)
+            .ok_or_else(|| format_err_t!(NotFound, "no such camera {}", uuid))?;
+        let stream_id = camera.streams[stream_type.index()]
+            .ok_or_else(|| format_err_t!(NotFound, "no such stream {}/{}", uuid, stream_type))?;
+        let jpeg = db
+            .get_thumbnail(db::CompositeId::new(stream_id, id))
+            .map_err(internal_server_err)?
+            .ok_or_else(|| not_found("no thumbnail for this recording"))?;
+        Ok(Response::builder()
+            .header(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"))
+            .body(jpeg.into())
+            .expect("hardcoded head should be valid"))
+    }
+
+    /// Serves a JPEG of the key frame nearest the time `t`, or of the latest key frame if `t` is
+    /// absent. Historical snapshots never change, so they're cached; the latest one isn't.
+    async fn stream_snapshot_jpg(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> Result<(CacheControl, Response<Body>), HttpError> {
+        caller.check_view_camera(uuid)?;
+        let mut t = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                if key == "t" {
+                    t = Some(
+                        recording::Time::parse(&value)
+                            .map_err(|e| bad_req(format!("bad t {:?}: {}", value, e)))?,
+                    );
+                }
+            }
+        }
+        let (stream_id, frames, sample_entry) = {
+            let mut db = self.db.lock();
+            let camera = db.get_camera(uuid).ok_or_else(|| {
+                plain_response(StatusCode::NOT_FOUND, format!("no such camera {}", uuid))
+            })?;
+            let stream_id = camera.streams[stream_type.index()].ok_or_else(|| {
+                format_err_t!(NotFound, "no such stream {}/{}", uuid, stream_type)
+            })?;
+            let frames = snapshot::find(&db, stream_id, t)
+                .map_err(internal_server_err)?
+                .ok_or_else(|| not_found("no recorded frame at the given time"))?;
+            let sample_entry = db
+                .video_sample_entries_by_id()
+                .get(&frames.video_sample_entry_id)
+                .unwrap()
+                .clone();
+            let mut e = self.audit_event(req, &caller, audit::Kind::View);
+            e.camera_uuid = Some(uuid);
+            e.time_90k = t.map(|t| t..t);
+            e.detail = Some(format!("snapshot {}", stream_type));
+            db.audit(e);
+            (stream_id, frames, sample_entry)
+        };
+        let dir = self
+            .dirs_by_stream_id()
+            .get(&stream_id)
+            .cloned()
+            .ok_or_else(|| not_found("stream has no sample file directory"))?;
+        let data = snapshot::read(&dir, &frames).await?;
+        let jpeg = tokio::task::spawn_blocking(move || {
+            snapshot::encode_jpeg(&sample_entry.data, &frames, &data, None)
+        })
+        .await
+        .map_err(internal_server_err)?
+        .map_err(internal_server_err)?;
+        let cache = match t {
+            Some(_) => CacheControl::PrivateStatic,
+            None => CacheControl::PrivateDynamic,
+        };
+        let resp = Response::builder()
+            .header(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"))
+            .body(jpeg.into())
+            .expect("hardcoded head should be valid");
+        Ok((cache, resp))
+    }
+
+    async fn static_file(&self, req: Request<hyper::Body>) -> ResponseResult {
+        let dir = self
+            .ui_dir
+            .clone()
+            .ok_or_else(|| not_found("--ui-dir not configured; no static files available."))?;
+        let static_req = match StaticFileRequest::parse(req.uri().path()) {
+            None => return Err(not_found("static file not found")),
+            Some(r) => r,
+        };
+        let f = dir.get(static_req.path, req.headers());
+        let node = f.await.map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                not_found("no such static file")
+            } else {
+                internal_server_err(e)
+            }
+        })?;
+        let mut hdrs = http::HeaderMap::new();
+        node.add_encoding_headers(&mut hdrs);
+        hdrs.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(if static_req.immutable {
+                // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Cache-Control#Caching_static_assets
+                "public, max-age=604800, immutable"
+            } else {
+                "public"
+            }),
+        );
+        hdrs.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(static_req.mime),
+        );
+        let e = node.into_file_entity(hdrs).map_err(internal_server_err)?;
+        Ok(http_serve::serve(e, &req))
+    }
+
+    fn authreq(&self, req: &Request<::hyper::Body>) -> auth::Request {
+        auth::Request {
+            when_sec: Some(self.db.clocks().realtime().sec),
+            addr: if self.trust_forward_hdrs {
+                req.headers()
+                    .get("X-Real-IP")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| IpAddr::from_str(v).ok())
+            } else {
+                None
+            },
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .map(|ua| ua.as_bytes().to_vec()),
+        }
+    }
+
+    /// Returns an audit log event of the given kind, attributed to `caller`.
+    fn audit_event(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: &Caller,
+        kind: audit::Kind,
+    ) -> audit::Event {
+        let mut e = audit::Event::new(self.db.clocks().realtime().sec, kind);
+        e.user_id = caller.user_id;
+        e.username = caller.session.as_ref().map(|s| s.username.clone());
+        e.peer_addr = self.authreq(req).addr;
+        e
+    }
+
+    /// Returns an audit log event for a configuration change described by `detail`.
+    fn config_event(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: &Caller,
+        detail: String,
+    ) -> audit::Event {
+        let mut e = self.audit_event(req, caller, audit::Kind::Config);
+        e.detail = Some(detail);
+        e
+    }
+
+    fn request(&self, req: &Request<::hyper::Body>) -> ResponseResult {
+        let authreq = self.authreq(req);
+        let host = req
+            .headers()
             .get(header::HOST)
             .map(|h| String::from_utf8_lossy(h.as_bytes()));
         let agent = authreq
@@ -1113,118 +2242,955 @@ impl Service {
                 .unwrap_or(false)
     }
 
-    async fn login(&self, mut req: Request<::hyper::Body>) -> ResponseResult {
+    async fn login(&self, mut req: Request<::hyper::Body>) -> ResponseResult {
+        let r = extract_json_body(&mut req).await?;
+        let r: json::LoginRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let authreq = self.authreq(&req);
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .ok_or_else(|| bad_req("missing Host header!"))?;
+        let host = host.as_bytes();
+        let domain = match memchr(b':', host) {
+            Some(colon) => &host[0..colon],
+            None => host,
+        }
+        .to_owned();
+        let mut l = self.db.lock();
+
+        // If the request came in over https, tell the browser to only send the cookie on https
+        // requests also.
+        let is_secure = self.is_secure(&req);
+
+        // Use SameSite=Lax rather than SameSite=Strict. Safari apparently doesn't send
+        // SameSite=Strict cookies on WebSocket upgrade requests. There's no real security
+        // difference for Moonfire NVR anyway. SameSite=Strict exists as CSRF protection for
+        // sites that (unlike Moonfire NVR) don't follow best practices by (a)
+        // mutating based on GET requests and (b) not using CSRF tokens.
+        use auth::SessionFlag;
+        let flags = (SessionFlag::HttpOnly as i32)
+            | (SessionFlag::SameSite as i32)
+            | if is_secure {
+                SessionFlag::Secure as i32
+            } else {
+                0
+            };
+        let mut event = audit::Event::new(authreq.when_sec.unwrap(), audit::Kind::Login);
+        event.username = Some(r.username.to_owned());
+        event.peer_addr = authreq.addr;
+        let result = l
+            .login_by_password(
+                authreq,
+                &r.username,
+                r.password,
+                r.totp_code,
+                Some(domain),
+                flags,
+            )
+            .map(|(sid, s)| (sid, s.user_id));
+        let sid = match result {
+            Ok((sid, user_id)) => {
+                event.user_id = Some(user_id);
+                l.audit(event);
+                sid
+            }
+            Err(e) => {
+                event.kind = audit::Kind::LoginFailed;
+                event.detail = Some(e.to_string());
+                l.audit(event);
+                return Err(plain_response(StatusCode::UNAUTHORIZED, e.to_string()).into());
+            }
+        };
+        let cookie = encode_sid(sid, flags);
+        Ok(Response::builder()
+            .header(
+                header::SET_COOKIE,
+                HeaderValue::try_from(cookie).expect("cookie can't have invalid bytes"),
+            )
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    async fn logout(&self, mut req: Request<hyper::Body>) -> ResponseResult {
+        let r = extract_json_body(&mut req).await?;
+        let r: json::LogoutRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+
+        let mut res = Response::new(b""[..].into());
+        if let Some(sid) = extract_sid(&req) {
+            let authreq = self.authreq(&req);
+            let mut l = self.db.lock();
+            let hash = sid.hash();
+            let logout_event = match l.authenticate_session(authreq.clone(), &hash) {
+                Ok((s, u)) => {
+                    if !csrf_matches(r.csrf, s.csrf()) {
+                        warn!("logout request with missing/incorrect csrf");
+                        return Err(bad_req("logout with incorrect csrf token"));
+                    }
+                    info!("revoking session");
+                    let mut e = audit::Event::new(authreq.when_sec.unwrap(), audit::Kind::Logout);
+                    e.user_id = Some(u.id);
+                    e.username = Some(u.username.clone());
+                    e.peer_addr = authreq.addr;
+                    Some(e)
+                }
+                Err(e) => {
+                    // TODO: distinguish "no such session", "session is no longer valid", and
+                    // "user ... is disabled" (which are all client error / bad state) from database
+                    // errors.
+                    warn!("logout failed: {}", e);
+                    None
+                }
+            };
+            if let Some(e) = logout_event {
+                // TODO: inline this above with non-lexical lifetimes.
+                l.revoke_session(auth::RevocationReason::LoggedOut, None, authreq, &hash)
+                    .map_err(internal_server_err)?;
+                l.audit(e);
+            }
+
+            // By now the session is invalid (whether it was valid to start with or not).
+            // Clear useless cookie.
+            res.headers_mut().append(
+                header::SET_COOKIE,
+                HeaderValue::from_str("s=; Max-Age=0; Path=/").unwrap(),
+            );
+        }
+        *res.status_mut() = StatusCode::NO_CONTENT;
+        Ok(res)
+    }
+
+    async fn post_signals(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.update_signals {
+            bail_t!(PermissionDenied, "update_signals required");
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostSignalsRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let mut l = self.db.lock();
+        let start = match r.start {
+            json::PostSignalsTimeBase::Epoch(t) => t,
+            json::PostSignalsTimeBase::Now(d) => now + d,
+        };
+        let end = match r.end {
+            json::PostSignalsTimeBase::Epoch(t) => t,
+            json::PostSignalsTimeBase::Now(d) => now + d,
+        };
+        l.update_signals(start..end, &r.signal_ids, &r.states)
+            .map_err(from_base_error)?;
+        let mut e = self.audit_event(&req, &caller, audit::Kind::PostSignals);
+        e.time_90k = Some(start..end);
+        e.detail = Some(format!("signals {:?} states {:?}", r.signal_ids, r.states));
+        l.audit(e);
+        serve_json(&req, &json::PostSignalsResponse { time_90k: now })
+    }
+
+    fn get_stream_holds(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        caller.check_view_camera(uuid)?;
+        let l = self.db.lock();
+        let camera = l
+            .get_camera(uuid)
+            .ok_or_else(|| format_err_t!(NotFound, "no such camera {}", uuid))?;
+        let stream_id = camera.streams[type_.index()]
+            .ok_or_else(|| format_err_t!(NotFound, "no such stream {}/{}", uuid, type_))?;
+        let holds = l
+            .holds_by_id()
+            .values()
+            .filter(|h| h.stream_id == stream_id)
+            .map(json::Hold::wrap)
+            .collect();
+        serve_json(req, &json::Holds { holds })
+    }
+
+    async fn post_stream_hold(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        type_: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.manage_holds {
+            bail_t!(PermissionDenied, "manage_holds required");
+        }
+        caller.check_view_camera(uuid)?;
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostHoldRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        if r.start_time_90k >= r.end_time_90k {
+            return Err(bad_req("startTime90k must be before endTime90k"));
+        }
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let mut l = self.db.lock();
+        let camera = l
+            .get_camera(uuid)
+            .ok_or_else(|| format_err_t!(NotFound, "no such camera {}", uuid))?;
+        let stream_id = camera.streams[type_.index()]
+            .ok_or_else(|| format_err_t!(NotFound, "no such stream {}/{}", uuid, type_))?;
+        let id = l
+            .add_hold(db::HoldToInsert {
+                stream_id,
+                time: r.start_time_90k..r.end_time_90k,
+                reason: r.reason,
+                creator: caller.session.as_ref().map(|s| s.username.clone()),
+                creation_time: now,
+            })
+            .map_err(internal_server_err)?;
+        let detail = format!("added hold {} on stream {}/{}", id, uuid, type_);
+        l.audit(self.config_event(&req, &caller, detail));
+        info!("stream {}/{}: added hold {}", uuid, type_, id);
+        serve_json(&req, &json::PostHoldResponse { id })
+    }
+
+    /// Releases a hold in response to `DELETE /api/holds/<id>`.
+    fn hold(&self, req: &Request<hyper::Body>, caller: Caller, id: i32) -> ResponseResult {
+        if *req.method() != http::method::Method::DELETE {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "DELETE expected").into());
+        }
+        if !caller.permissions.manage_holds {
+            bail_t!(PermissionDenied, "manage_holds required");
+        }
+        let mut l = self.db.lock();
+        let uuid = match l.holds_by_id().get(&id) {
+            None => return Err(not_found(format!("no such hold {}", id))),
+            Some(h) => {
+                let s = &l.streams_by_id()[&h.stream_id];
+                l.cameras_by_id()[&s.camera_id].uuid
+            }
+        };
+        caller.check_view_camera(uuid)?;
+        l.release_hold(id).map_err(internal_server_err)?;
+        l.audit(self.config_event(req, &caller, format!("released hold {}", id)));
+        info!("released hold {}", id);
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    /// Lists or adds users in response to `GET` or `POST` on `/api/users`.
+    async fn users(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        use http::method::Method;
+        if !caller.permissions.admin_users {
+            bail_t!(PermissionDenied, "admin_users required");
+        }
+        match *req.method() {
+            Method::GET | Method::HEAD => {
+                let l = self.db.lock();
+                let users = l.users_by_id().values().map(json::User::wrap).collect();
+                serve_json(&req, &json::Users { users })
+            }
+            Method::POST => {
+                let r = extract_json_body(&mut req).await?;
+                let r: json::PostUserRequest =
+                    serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+                if r.username.is_empty() {
+                    return Err(bad_req("username must be non-empty"));
+                }
+                let mut change = db::UserChange::add_user(r.username);
+                if let Some(p) = r.password {
+                    change.set_password(p);
+                }
+                if r.disabled {
+                    change.disable();
+                }
+                change.permissions = r.permissions.into();
+                let mut l = self.db.lock();
+                let u = l
+                    .apply_user_change(change)
+                    .map_err(|e| bad_req(e.to_string()))?;
+                info!("added user {:?}", u.username);
+                let id = u.id;
+                let detail = format!("added user {:?}", u.username);
+                l.audit(self.config_event(&req, &caller, detail));
+                serve_json(&req, &json::PostUserResponse { id })
+            }
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or POST expected",
+            )
+            .into()),
+        }
+    }
+
+    /// Gets or changes a user in response to `GET` or `PATCH` on `/api/users/<id>`.
+    async fn user(&self, mut req: Request<hyper::Body>, caller: Caller, id: i32) -> ResponseResult {
+        use http::method::Method;
+        if !caller.permissions.admin_users {
+            bail_t!(PermissionDenied, "admin_users required");
+        }
+        match *req.method() {
+            Method::GET | Method::HEAD => {
+                let l = self.db.lock();
+                let u = l
+                    .users_by_id()
+                    .get(&id)
+                    .ok_or_else(|| not_found(format!("no such user {}", id)))?;
+                serve_json(&req, &json::User::wrap(u))
+            }
+            Method::PATCH => {
+                let r = extract_json_body(&mut req).await?;
+                let r: json::PatchUserRequest =
+                    serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+                let mut l = self.db.lock();
+                let mut change = l
+                    .users_by_id()
+                    .get(&id)
+                    .ok_or_else(|| not_found(format!("no such user {}", id)))?
+                    .change();
+                if let Some(n) = r.username {
+                    if n.is_empty() {
+                        return Err(bad_req("username must be non-empty"));
+                    }
+                    change.username = n;
+                }
+                let password_changed = r.password.is_some();
+                if let Some(p) = r.password {
+                    change.set_password(p);
+                }
+                match r.disabled {
+                    Some(true) => change.disable(),
+                    Some(false) => change.enable(),
+                    None => {}
+                }
+                if let Some(p) = r.permissions {
+                    change.permissions = p.into();
+                }
+                if r.clear_totp {
+                    change.clear_totp();
+                }
+                let u = l
+                    .apply_user_change(change)
+                    .map_err(|e| bad_req(e.to_string()))?;
+                info!("updated user {:?}", u.username);
+                let detail = format!("updated user {:?}", u.username);
+                if password_changed {
+                    // Log out whoever knew the old password. API tokens are left alone; they're
+                    // listed and revoked individually through `/api/sessions`.
+                    let hashes: Vec<auth::SessionHash> = l
+                        .list_sessions(Some(id))
+                        .map_err(internal_server_err)?
+                        .into_iter()
+                        .filter(|(_, s, _)| !s.api_token())
+                        .map(|(h, _, _)| h)
+                        .collect();
+                    let authreq = self.authreq(&req);
+                    for h in &hashes {
+                        l.revoke_session(
+                            auth::RevocationReason::RevokedByAdmin,
+                            Some("password changed".to_owned()),
+                            authreq.clone(),
+                            h,
+                        )
+                        .map_err(internal_server_err)?;
+                    }
+                }
+                l.audit(self.config_event(&req, &caller, detail));
+                serve_json(&req, &json::User::wrap(&l.users_by_id()[&id]))
+            }
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or PATCH expected",
+            )
+            .into()),
+        }
+    }
+
+    /// Lists unrevoked sessions in response to `GET /api/sessions`.
+    fn sessions(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        use http::method::Method;
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Err(
+                plain_response(StatusCode::METHOD_NOT_ALLOWED, "GET or HEAD expected").into(),
+            );
+        }
+        if !caller.permissions.admin_users {
+            bail_t!(PermissionDenied, "admin_users required");
+        }
+        let mut user_id = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                if key == "userId" {
+                    user_id = Some(i32::from_str(value).map_err(|_| bad_req("bad userId"))?);
+                }
+            }
+        }
+        let mut l = self.db.lock();
+        let sessions = l
+            .list_sessions(user_id)
+            .map_err(internal_server_err)?
+            .into_iter()
+            .map(|(h, s, u)| json::UserSession::wrap(h, s, u))
+            .collect();
+        serve_json(req, &json::UserSessions { sessions })
+    }
+
+    /// Revokes a session in response to `DELETE /api/sessions/<id>`.
+    fn session(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        hash: auth::SessionHash,
+    ) -> ResponseResult {
+        if *req.method() != http::method::Method::DELETE {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "DELETE expected").into());
+        }
+        if !caller.permissions.admin_users {
+            bail_t!(PermissionDenied, "admin_users required");
+        }
+        let event = self.config_event(req, &caller, format!("revoked session {:?}", hash));
+        let detail = caller
+            .session
+            .map(|s| format!("revoked by user {:?}", s.username));
+        let authreq = self.authreq(req);
+        let mut l = self.db.lock();
+        l.revoke_session(
+            auth::RevocationReason::RevokedByAdmin,
+            detail,
+            authreq,
+            &hash,
+        )
+        .map_err(|e| match e.downcast_ref::<base::Error>() {
+            Some(b) if b.kind() == ErrorKind::NotFound => not_found("no such session"),
+            _ => internal_server_err(e),
+        })?;
+        l.audit(event);
+        info!("revoked session {:?}", hash);
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    /// Lists or creates the caller's API tokens in response to `GET` or `POST` on `/api/tokens`.
+    async fn tokens(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        use http::method::Method;
+        let user_id = caller
+            .user_id
+            .ok_or_else(|| format_err_t!(Unauthenticated, "API tokens require a user"))?;
+        match *req.method() {
+            Method::GET | Method::HEAD => {
+                let mut l = self.db.lock();
+                let sessions = l
+                    .list_sessions(Some(user_id))
+                    .map_err(internal_server_err)?
+                    .into_iter()
+                    .filter(|(_, s, _)| s.api_token())
+                    .map(|(h, s, u)| json::UserSession::wrap(h, s, u))
+                    .collect();
+                serve_json(&req, &json::UserSessions { sessions })
+            }
+            Method::POST => {
+                let r = extract_json_body(&mut req).await?;
+                let r: json::PostTokenRequest =
+                    serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+                if r.name.is_empty() {
+                    return Err(bad_req("name must be non-empty"));
+                }
+                if !r
+                    .permissions
+                    .is_subset_of(&json::Permissions::from(&caller.permissions))
+                {
+                    bail_t!(
+                        PermissionDenied,
+                        "token can't have permissions beyond the caller's"
+                    );
+                }
+                let authreq = self.authreq(&req);
+                if let (Some(e), Some(now)) = (r.expiration_time_sec, authreq.when_sec) {
+                    if e <= now {
+                        return Err(bad_req("expirationTimeSec must be in the future"));
+                    }
+                }
+                let mut l = self.db.lock();
+                let (token, _) = l
+                    .make_api_token(
+                        authreq,
+                        user_id,
+                        r.name,
+                        r.expiration_time_sec,
+                        r.permissions.into(),
+                    )
+                    .map_err(|e| bad_req(e.to_string()))?;
+                let id = token.hash();
+                l.audit(self.config_event(&req, &caller, format!("created API token {:?}", id)));
+                info!("created API token {:?}", id);
+                serve_json(
+                    &req,
+                    &json::PostTokenResponse {
+                        id,
+                        token: base64::encode_config(&token, base64::STANDARD_NO_PAD),
+                    },
+                )
+            }
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or POST expected",
+            )
+            .into()),
+        }
+    }
+
+    /// Manages the caller's own TOTP enrollment in response to `POST` (begin), `PUT` (confirm), or
+    /// `DELETE` (remove) on `/api/totp`.
+    async fn totp(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        use http::method::Method;
+        let user_id = caller
+            .user_id
+            .ok_or_else(|| format_err_t!(Unauthenticated, "TOTP requires a user"))?;
+        if caller.api_token {
+            bail_t!(
+                PermissionDenied,
+                "TOTP enrollment can't be changed with an API token"
+            );
+        }
+        match *req.method() {
+            Method::POST => {
+                let uri = self
+                    .db
+                    .lock()
+                    .begin_totp_enrollment(user_id)
+                    .map_err(|e| bad_req(e.to_string()))?;
+                serve_json(&req, &json::PostTotpResponse { uri })
+            }
+            Method::PUT => {
+                let r = extract_json_body(&mut req).await?;
+                let r: json::PutTotpRequest =
+                    serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+                let now = self.db.clocks().realtime().sec;
+                let mut l = self.db.lock();
+                let recovery_codes = l
+                    .confirm_totp_enrollment(user_id, r.code, now)
+                    .map_err(|e| bad_req(e.to_string()))?;
+                l.audit(self.config_event(&req, &caller, "enrolled in TOTP".to_owned()));
+                info!("user {} enrolled in TOTP", user_id);
+                serve_json(&req, &json::PutTotpResponse { recovery_codes })
+            }
+            Method::DELETE => {
+                let r = extract_json_body(&mut req).await?;
+                let r: json::DeleteTotpRequest =
+                    serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+                let now = self.db.clocks().realtime().sec;
+                let mut l = self.db.lock();
+                l.check_totp_code(user_id, r.code, now)
+                    .map_err(|e| format_err_t!(PermissionDenied, "{}", e))?;
+                let mut change = l
+                    .users_by_id()
+                    .get(&user_id)
+                    .ok_or_else(|| internal_server_err(format_err!("no such user {}", user_id)))?
+                    .change();
+                change.clear_totp();
+                l.apply_user_change(change).map_err(internal_server_err)?;
+                l.audit(self.config_event(&req, &caller, "removed TOTP enrollment".to_owned()));
+                info!("user {} removed TOTP enrollment", user_id);
+                Ok(Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(b""[..].into())
+                    .unwrap())
+            }
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST, PUT, or DELETE expected",
+            )
+            .into()),
+        }
+    }
+
+    /// Lists audit log events in response to `GET /api/audit`.
+    fn audit(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        use http::method::Method;
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Err(
+                plain_response(StatusCode::METHOD_NOT_ALLOWED, "GET or HEAD expected").into(),
+            );
+        }
+        if !caller.permissions.admin_users {
+            bail_t!(PermissionDenied, "admin_users required");
+        }
+        let mut filter = audit::Filter {
+            limit: Some(DEFAULT_AUDIT_LIMIT),
+            ..Default::default()
+        };
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTimeSec" => {
+                        filter.start_time_sec =
+                            Some(i64::from_str(value).map_err(|_| bad_req("bad startTimeSec"))?)
+                    }
+                    "endTimeSec" => {
+                        filter.end_time_sec =
+                            Some(i64::from_str(value).map_err(|_| bad_req("bad endTimeSec"))?)
+                    }
+                    "kind" => {
+                        filter.kind =
+                            Some(audit::Kind::from_str(value).map_err(|e| bad_req(e.to_string()))?)
+                    }
+                    "username" => filter.username = Some(value.to_owned()),
+                    "cameraUuid" => {
+                        filter.camera_uuid =
+                            Some(Uuid::parse_str(value).map_err(|_| bad_req("bad cameraUuid"))?)
+                    }
+                    "limit" => {
+                        filter.limit =
+                            Some(usize::from_str(value).map_err(|_| bad_req("bad limit"))?)
+                    }
+                    _ => return Err(bad_req(format!("parameter {} not understood", key))),
+                };
+            }
+        }
+        let events = self
+            .db
+            .lock()
+            .list_audit_events(&filter)
+            .map_err(internal_server_err)?;
+        serve_json(
+            req,
+            &json::AuditEvents {
+                events: events.iter().map(json::AuditEvent::wrap).collect(),
+            },
+        )
+    }
+
+    /// Lists or adds webhooks in response to `GET` or `POST` on `/api/webhooks`.
+    async fn webhooks(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        use http::method::Method;
+        if !caller.permissions.update_config {
+            bail_t!(PermissionDenied, "update_config required");
+        }
+        match *req.method() {
+            Method::GET | Method::HEAD => {
+                let l = self.db.lock();
+                let webhooks = l
+                    .webhooks_by_id()
+                    .values()
+                    .map(json::Webhook::wrap)
+                    .collect();
+                serve_json(&req, &json::Webhooks { webhooks })
+            }
+            Method::POST => {
+                let r = extract_json_body(&mut req).await?;
+                let r: json::PostWebhookRequest =
+                    serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+                let (url, filter) = r.filter();
+                let mut l = self.db.lock();
+                let id = l
+                    .add_webhook(url, filter)
+                    .map_err(|e| bad_req(e.to_string()))?;
+                let w = &l.webhooks_by_id()[&id];
+                info!("added webhook {} ({})", id, &w.url);
+                let secret = w.secret.clone();
+                let detail = format!("added webhook {} ({})", id, &w.url);
+                l.audit(self.config_event(&req, &caller, detail));
+                serve_json(&req, &json::PostWebhookResponse { id, secret })
+            }
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or POST expected",
+            )
+            .into()),
+        }
+    }
+
+    /// Deletes a webhook and its pending deliveries in response to `DELETE /api/webhooks/<id>`.
+    fn webhook(&self, req: &Request<hyper::Body>, caller: Caller, id: i32) -> ResponseResult {
+        if *req.method() != http::method::Method::DELETE {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "DELETE expected").into());
+        }
+        if !caller.permissions.update_config {
+            bail_t!(PermissionDenied, "update_config required");
+        }
+        let mut l = self.db.lock();
+        if !l.webhooks_by_id().contains_key(&id) {
+            return Err(not_found(format!("no such webhook {}", id)));
+        }
+        l.delete_webhook(id).map_err(internal_server_err)?;
+        info!("deleted webhook {}", id);
+        l.audit(self.config_event(req, &caller, format!("deleted webhook {}", id)));
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(b""[..].into())
+            .unwrap())
+    }
+
+    /// Probes the local network for ONVIF devices in response to `POST /api/discovery`.
+    ///
+    /// If credentials are supplied, also returns each device's profiles.
+    async fn discovery(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if *req.method() != http::method::Method::POST {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected").into());
+        }
+        if !caller.permissions.update_config {
+            bail_t!(PermissionDenied, "update_config required");
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostDiscoveryRequest =
+            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
+        let timeout_sec = r.timeout_sec.unwrap_or(DEFAULT_DISCOVERY_TIMEOUT_SEC);
+        if timeout_sec > MAX_DISCOVERY_TIMEOUT_SEC {
+            return Err(bad_req(format!(
+                "timeoutSec must be at most {}",
+                MAX_DISCOVERY_TIMEOUT_SEC
+            )));
+        }
+        let found = onvif::discover(std::time::Duration::from_secs(timeout_sec))
+            .await
+            .map_err(internal_server_err)?;
+        let r = &r;
+        let devices = futures::future::join_all(found.into_iter().map(|d| async move {
+            let onvif_host = d.onvif_host();
+            let (profiles, error) = match (&onvif_host, &r.username, &r.password) {
+                (Some(h), Some(u), Some(p)) => match onvif::get_profiles(h, u, p).await {
+                    Ok(p) => (Some(json::Profile::wrap_all(p)), None),
+                    Err(e) => (None, Some(e.to_string())),
+                },
+                _ => (None, None),
+            };
+            json::DiscoveredDevice {
+                name: d.scope("name"),
+                hardware: d.scope("hardware"),
+                endpoint: d.endpoint,
+                onvif_host,
+                profiles,
+                error,
+            }
+        }))
+        .await;
+        serve_json(&req, &json::Discovery { devices })
+    }
+
+    /// Adds a camera using its ONVIF profiles in response to `POST /api/discovery/cameras`.
+    async fn post_discovery_camera(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        if *req.method() != http::method::Method::POST {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected").into());
+        }
+        if !caller.permissions.update_config {
+            bail_t!(PermissionDenied, "update_config required");
+        }
         let r = extract_json_body(&mut req).await?;
-        let r: json::LoginRequest =
+        let r: json::PostDiscoveryCameraRequest =
             serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
-        let authreq = self.authreq(&req);
-        let host = req
-            .headers()
-            .get(header::HOST)
-            .ok_or_else(|| bad_req("missing Host header!"))?;
-        let host = host.as_bytes();
-        let domain = match memchr(b':', host) {
-            Some(colon) => &host[0..colon],
-            None => host,
+        if r.short_name.is_empty() {
+            return Err(bad_req("shortName must be non-empty"));
         }
-        .to_owned();
-        let mut l = self.db.lock();
-
-        // If the request came in over https, tell the browser to only send the cookie on https
-        // requests also.
-        let is_secure = self.is_secure(&req);
+        let profiles = onvif::get_profiles(&r.onvif_host, &r.username, &r.password)
+            .await
+            .map_err(|e| {
+                plain_response(
+                    StatusCode::BAD_GATEWAY,
+                    format!("unable to get camera's profiles: {}", e),
+                )
+            })?;
+        let (main, sub) = onvif::select_profiles(
+            &profiles,
+            r.main_profile.as_deref(),
+            r.sub_profile.as_deref(),
+        )
+        .map_err(|e| bad_req(e.to_string()))?;
+        let change = onvif::camera_change(
+            r.short_name,
+            r.description,
+            r.onvif_host,
+            r.username,
+            r.password,
+            main,
+            sub,
+        );
+        let uuid = {
+            let mut l = self.db.lock();
+            let id = l.add_camera(change).map_err(|e| bad_req(e.to_string()))?;
+            let uuid = l.cameras_by_id().get(&id).unwrap().uuid;
+            let detail = format!("added camera {} via ONVIF", uuid);
+            l.audit(self.config_event(&req, &caller, detail));
+            uuid
+        };
+        info!("added camera {} via ONVIF", uuid);
+        serve_json(&req, &json::PostCameraResponse { uuid })
+    }
 
-        // Use SameSite=Lax rather than SameSite=Strict. Safari apparently doesn't send
-        // SameSite=Strict cookies on WebSocket upgrade requests. There's no real security
-        // difference for Moonfire NVR anyway. SameSite=Strict exists as CSRF protection for
-        // sites that (unlike Moonfire NVR) don't follow best practices by (a)
-        // mutating based on GET requests and (b) not using CSRF tokens.
-        use auth::SessionFlag;
-        let flags = (SessionFlag::HttpOnly as i32)
-            | (SessionFlag::SameSite as i32)
-            | if is_secure {
-                SessionFlag::Secure as i32
-            } else {
-                0
-            };
-        let (sid, _) = l
-            .login_by_password(authreq, &r.username, r.password, Some(domain), flags)
-            .map_err(|e| plain_response(StatusCode::UNAUTHORIZED, e.to_string()))?;
-        let cookie = encode_sid(sid, flags);
+    /// Revokes one of the caller's API tokens in response to `DELETE /api/tokens/<id>`.
+    fn token(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        hash: auth::SessionHash,
+    ) -> ResponseResult {
+        if *req.method() != http::method::Method::DELETE {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "DELETE expected").into());
+        }
+        let user_id = caller
+            .user_id
+            .ok_or_else(|| format_err_t!(Unauthenticated, "API tokens require a user"))?;
+        let authreq = self.authreq(req);
+        let mut l = self.db.lock();
+        let found = l
+            .list_sessions(Some(user_id))
+            .map_err(internal_server_err)?
+            .iter()
+            .any(|(h, s, _)| *h == hash && s.api_token());
+        if !found {
+            return Err(not_found("no such token"));
+        }
+        l.revoke_session(auth::RevocationReason::RevokedByUser, None, authreq, &hash)
+            .map_err(internal_server_err)?;
+        l.audit(self.config_event(req, &caller, format!("revoked API token {:?}", hash)));
+        info!("revoked API token {:?}", hash);
         Ok(Response::builder()
-            .header(
-                header::SET_COOKIE,
-                HeaderValue::try_from(cookie).expect("cookie can't have invalid bytes"),
-            )
             .status(StatusCode::NO_CONTENT)
             .body(b""[..].into())
             .unwrap())
     }
 
-    async fn logout(&self, mut req: Request<hyper::Body>) -> ResponseResult {
+    /// Starts an export in response to `POST /api/exports`.
+    async fn post_export(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if *req.method() != http::method::Method::POST {
+            return Err(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected").into());
+        }
         let r = extract_json_body(&mut req).await?;
-        let r: json::LogoutRequest =
+        let r: json::PostExportRequest =
             serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
-
-        let mut res = Response::new(b""[..].into());
-        if let Some(sid) = extract_sid(&req) {
-            let authreq = self.authreq(&req);
+        if r.start_time_90k >= r.end_time_90k {
+            return Err(bad_req("startTime90k must be before endTime90k"));
+        }
+        let stream_ids = {
+            let l = self.db.lock();
+            r.streams
+                .iter()
+                .map(|s| {
+                    let type_ = db::StreamType::parse(&s.stream_type).ok_or_else(|| {
+                        bad_req(format!("unknown stream type {:?}", &s.stream_type))
+                    })?;
+                    caller.check_view_camera(s.camera_uuid)?;
+                    let camera = l
+                        .get_camera(s.camera_uuid)
+                        .ok_or_else(|| not_found(format!("no such camera {}", s.camera_uuid)))?;
+                    camera.streams[type_.index()].ok_or_else(|| {
+                        not_found(format!("no such stream {}/{}", s.camera_uuid, type_))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        if stream_ids.is_empty() {
+            return Err(bad_req("at least one stream is required"));
+        }
+        let export = export::Export::plan(
+            &self.db,
+            &self.dirs_by_stream_id(),
+            &stream_ids,
+            r.start_time_90k..r.end_time_90k,
+        )
+        .map_err(|e| bad_req(e.to_string()))?;
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let events: Vec<_> = r
+            .streams
+            .iter()
+            .map(|s| {
+                let mut e = self.audit_event(&req, &caller, audit::Kind::Export);
+                e.camera_uuid = Some(s.camera_uuid);
+                e.time_90k = Some(r.start_time_90k..r.end_time_90k);
+                e.detail = Some(s.stream_type.to_string());
+                e
+            })
+            .collect();
+        let job = self
+            .exports
+            .start(
+                export,
+                caller.session.as_ref().map(|s| s.username.clone()),
+                caller.user_id,
+                now,
+            )
+            .map_err(internal_server_err)?;
+        {
             let mut l = self.db.lock();
-            let hash = sid.hash();
-            let need_revoke = match l.authenticate_session(authreq.clone(), &hash) {
-                Ok((s, _)) => {
-                    if !csrf_matches(r.csrf, s.csrf()) {
-                        warn!("logout request with missing/incorrect csrf");
-                        return Err(bad_req("logout with incorrect csrf token"));
-                    }
-                    info!("revoking session");
-                    true
-                }
-                Err(e) => {
-                    // TODO: distinguish "no such session", "session is no longer valid", and
-                    // "user ... is disabled" (which are all client error / bad state) from database
-                    // errors.
-                    warn!("logout failed: {}", e);
-                    false
-                }
-            };
-            if need_revoke {
-                // TODO: inline this above with non-lexical lifetimes.
-                l.revoke_session(auth::RevocationReason::LoggedOut, None, authreq, &hash)
-                    .map_err(internal_server_err)?;
+            for e in events {
+                l.audit(e);
             }
+        }
+        info!("started export {}", job.id);
+        serve_json(&req, &json::PostExportResponse { id: job.id })
+    }
 
-            // By now the session is invalid (whether it was valid to start with or not).
-            // Clear useless cookie.
-            res.headers_mut().append(
-                header::SET_COOKIE,
-                HeaderValue::from_str("s=; Max-Age=0; Path=/").unwrap(),
+    /// Returns the given export job, if it exists and the caller may access it: the caller must
+    /// have started it and must still be allowed to view each camera it includes.
+    fn get_export(&self, caller: &Caller, id: Uuid) -> Result<Arc<export::Job>, HttpError> {
+        let job = self
+            .exports
+            .get(id)
+            .ok_or_else(|| not_found(format!("no such export {}", id)))?;
+        if job.creator_id != caller.user_id {
+            bail_t!(
+                PermissionDenied,
+                "export {} was started by another user",
+                id
             );
         }
-        *res.status_mut() = StatusCode::NO_CONTENT;
-        Ok(res)
+        for &uuid in &job.camera_uuids {
+            caller.check_view_camera(uuid)?;
+        }
+        Ok(job)
     }
 
-    async fn post_signals(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
-        if !caller.permissions.update_signals {
-            bail_t!(PermissionDenied, "update_signals required");
+    /// Describes an export's progress on `GET`, or deletes it on `DELETE`.
+    fn export(&self, req: &Request<hyper::Body>, caller: Caller, id: Uuid) -> ResponseResult {
+        use http::method::Method;
+        let job = self.get_export(&caller, id)?;
+        match *req.method() {
+            Method::GET | Method::HEAD => serve_json(req, &json::Export::wrap(&job)),
+            Method::DELETE => {
+                let removed = self
+                    .exports
+                    .remove(id)
+                    .map_err(|e| bad_req(e.to_string()))?;
+                if !removed {
+                    return Err(not_found(format!("no such export {}", id)));
+                }
+                info!("deleted export {}", id);
+                Ok(Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(b""[..].into())
+                    .unwrap())
+            }
+            _ => Err(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or DELETE expected",
+            )
+            .into()),
         }
-        let r = extract_json_body(&mut req).await?;
-        let r: json::PostSignalsRequest =
-            serde_json::from_slice(&r).map_err(|e| bad_req(e.to_string()))?;
-        let now = recording::Time::new(self.db.clocks().realtime());
-        let mut l = self.db.lock();
-        let start = match r.start {
-            json::PostSignalsTimeBase::Epoch(t) => t,
-            json::PostSignalsTimeBase::Now(d) => now + d,
-        };
-        let end = match r.end {
-            json::PostSignalsTimeBase::Epoch(t) => t,
-            json::PostSignalsTimeBase::Now(d) => now + d,
-        };
-        l.update_signals(start..end, &r.signal_ids, &r.states)
-            .map_err(from_base_error)?;
-        serve_json(&req, &json::PostSignalsResponse { time_90k: now })
+    }
+
+    /// Serves a finished export's tar archive.
+    fn export_tar(&self, req: &Request<hyper::Body>, caller: Caller, id: Uuid) -> ResponseResult {
+        let job = self.get_export(&caller, id)?;
+        if !matches!(job.status(), export::Status::Done) {
+            bail_t!(FailedPrecondition, "export {} isn't done", id);
+        }
+        let f = std::fs::File::open(self.exports.path(id)).map_err(internal_server_err)?;
+        let mut hdrs = http::HeaderMap::new();
+        hdrs.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-tar"),
+        );
+        hdrs.insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::try_from(format!("attachment; filename=\"export-{}.tar\"", id)).unwrap(),
+        );
+        let e = http_serve::ChunkedReadFile::new(f, hdrs).map_err(internal_server_err)?;
+        Ok(http_serve::serve(e, req))
     }
 
     fn get_signals(&self, req: &Request<hyper::Body>) -> ResponseResult {
@@ -1257,9 +3223,9 @@ impl Service {
         serve_json(req, &signals)
     }
 
-    /// Authenticates the session (if any) and returns a Caller.
+    /// Authenticates the API token or session (if any) and returns a Caller.
     ///
-    /// If there's no session,
+    /// An API token which isn't valid is an error. If there's no token or valid session,
     /// 1.  if `allow_unauthenticated_permissions` is configured, returns okay
     ///     with those permissions.
     /// 2.  if the caller specifies `unauth_path`, returns okay with no
@@ -1274,10 +3240,35 @@ impl Service {
         req: &Request<hyper::Body>,
         unauth_path: bool,
     ) -> Result<Caller, base::Error> {
+        if let Some(token) = extract_bearer_token(req)? {
+            let authreq = self.authreq(req);
+            return match self.db.lock().authenticate_session(authreq, &token.hash()) {
+                Ok((s, u)) if s.api_token() => Ok(Caller {
+                    permissions: s.permissions.clone(),
+                    session: Some(json::Session {
+                        username: u.username.clone(),
+                        csrf: s.csrf(),
+                    }),
+                    user_id: Some(u.id),
+                    api_token: true,
+                }),
+                Ok(_) => bail_t!(Unauthenticated, "bearer token is a session cookie"),
+                Err(e) if e.kind() == base::ErrorKind::Unauthenticated => {
+                    // As below, log the specific reason but don't return it.
+                    warn!("API token authentication failed: {:?}", &e);
+                    bail_t!(Unauthenticated, "invalid bearer token");
+                }
+                Err(e) => Err(e),
+            };
+        }
+
         if let Some(sid) = extract_sid(req) {
             let authreq = self.authreq(req);
 
             match self.db.lock().authenticate_session(authreq, &sid.hash()) {
+                Ok((s, _)) if s.api_token() => {
+                    warn!("API token used as a session cookie");
+                }
                 Ok((s, u)) => {
                     return Ok(Caller {
                         permissions: s.permissions.clone(),
@@ -1285,6 +3276,8 @@ impl Service {
                             username: u.username.clone(),
                             csrf: s.csrf(),
                         }),
+                        user_id: Some(u.id),
+                        api_token: false,
                     })
                 }
                 Err(e) if e.kind() == base::ErrorKind::Unauthenticated => {
@@ -1301,6 +3294,8 @@ impl Service {
             return Ok(Caller {
                 permissions: s.clone(),
                 session: None,
+                user_id: None,
+                api_token: false,
             });
         }
 
@@ -1308,6 +3303,8 @@ impl Service {
             return Ok(Caller {
                 permissions: db::Permissions::default(),
                 session: None,
+                user_id: None,
+                api_token: false,
             });
         }
 
@@ -1412,9 +3409,11 @@ mod tests {
                 super::Service::new(super::Config {
                     db: db.db.clone(),
                     ui_dir: None,
+                    export_dir: &db.tmpdir.path().join("exports"),
                     allow_unauthenticated_permissions,
                     trust_forward_hdrs: true,
                     time_zone_name: "".to_owned(),
+                    supervisor: None,
                 })
                 .unwrap(),
             );
@@ -1498,6 +3497,7 @@ mod tests {
     #[test]
     fn paths() {
         use super::Path;
+        use db::auth;
         use uuid::Uuid;
         let cam_uuid = Uuid::parse_str("35144640-ff1e-4619-b0d5-4c74c185741c").unwrap();
         assert_eq!(Path::decode("/foo"), Path::Static);
@@ -1548,6 +3548,30 @@ mod tests {
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/live.m4s"),
             Path::StreamLiveMp4Segments(cam_uuid, db::StreamType::Main)
         );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/view.m3u8"),
+            Path::StreamViewM3u8(cam_uuid, db::StreamType::Main)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/live.m3u8"),
+            Path::StreamLiveM3u8(cam_uuid, db::StreamType::Sub)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/hls.m4s"),
+            Path::StreamHlsSegment(cam_uuid, db::StreamType::Main)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/snapshot.jpg"),
+            Path::StreamSnapshotJpg(cam_uuid, db::StreamType::Sub)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/thumbnail.jpg"),
+            Path::StreamThumbnailJpg(cam_uuid, db::StreamType::Sub)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/holds"),
+            Path::StreamHolds(cam_uuid, db::StreamType::Main)
+        );
         assert_eq!(
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/junk"),
             Path::NotFound
@@ -1555,6 +3579,59 @@ mod tests {
         assert_eq!(Path::decode("/api/login"), Path::Login);
         assert_eq!(Path::decode("/api/logout"), Path::Logout);
         assert_eq!(Path::decode("/api/signals"), Path::Signals);
+        assert_eq!(Path::decode("/api/cameras"), Path::Cameras);
+        assert_eq!(Path::decode("/api/dirs"), Path::Dirs);
+        assert_eq!(Path::decode("/api/users"), Path::Users);
+        assert_eq!(Path::decode("/api/users/3"), Path::User(3));
+        assert_eq!(Path::decode("/api/users/x"), Path::NotFound);
+        assert_eq!(Path::decode("/api/sessions"), Path::Sessions);
+        let mut hash = auth::SessionHash::default();
+        hash.0[0] = 0xfb;
+        hash.0[1] = 0xff;
+        assert_eq!(
+            Path::decode("/api/sessions/-_8AAAAAAAAAAAAAAAAAAAAAAAAAAAAA"),
+            Path::Session(hash)
+        );
+        assert_eq!(Path::decode("/api/sessions/+/8"), Path::NotFound);
+        assert_eq!(Path::decode("/api/tokens"), Path::Tokens);
+        assert_eq!(Path::decode("/api/totp"), Path::Totp);
+        assert_eq!(Path::decode("/api/audit"), Path::Audit);
+        assert_eq!(Path::decode("/api/health"), Path::Health);
+        assert_eq!(Path::decode("/api/webhooks"), Path::Webhooks);
+        assert_eq!(Path::decode("/api/webhooks/3"), Path::Webhook(3));
+        assert_eq!(Path::decode("/api/webhooks/x"), Path::NotFound);
+        assert_eq!(Path::decode("/api/discovery"), Path::Discovery);
+        assert_eq!(
+            Path::decode("/api/discovery/cameras"),
+            Path::DiscoveryCameras
+        );
+        assert_eq!(
+            Path::decode("/onvif/notify/abc"),
+            Path::OnvifNotify("abc".to_owned())
+        );
+        assert_eq!(Path::decode("/metrics"), Path::Metrics);
+        assert_eq!(Path::decode("/api/metrics"), Path::NotFound);
+        assert_eq!(
+            Path::StreamViewMp4(uuid::Uuid::nil(), db::StreamType::Main, false).route(),
+            "StreamViewMp4"
+        );
+        assert_eq!(
+            Path::decode("/api/tokens/-_8AAAAAAAAAAAAAAAAAAAAAAAAAAAAA"),
+            Path::Token(hash)
+        );
+        assert_eq!(Path::decode("/api/holds/42"), Path::Hold(42));
+        assert_eq!(Path::decode("/api/holds/x"), Path::NotFound);
+        let export_uuid = Uuid::parse_str("9f2a6c1e-4b35-4f6b-9a3c-1d2e3f405162").unwrap();
+        assert_eq!(Path::decode("/api/exports"), Path::Exports);
+        assert_eq!(
+            Path::decode("/api/exports/9f2a6c1e-4b35-4f6b-9a3c-1d2e3f405162"),
+            Path::Export(export_uuid)
+        );
+        assert_eq!(
+            Path::decode("/api/exports/9f2a6c1e-4b35-4f6b-9a3c-1d2e3f405162.tar"),
+            Path::ExportTar(export_uuid)
+        );
+        assert_eq!(Path::decode("/api/exports/x.tar"), Path::NotFound);
         assert_eq!(Path::decode("/api/junk"), Path::NotFound);
     }
 
@@ -1674,6 +3751,100 @@ mod tests {
             .await
             .unwrap();
         assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+        // Both attempts should be in the audit log, most recent first.
+        let l = s.db.db.lock();
+        let events = l.list_audit_events(&Default::default()).unwrap();
+        let kinds: Vec<_> = events.iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            &[db::audit::Kind::Login, db::audit::Kind::LoginFailed]
+        );
+        assert_eq!(events[0].username.as_deref(), Some("slamb"));
+        assert!(events[0].user_id.is_some());
+    }
+
+    #[tokio::test]
+    async fn api_token() {
+        testutil::init();
+        let s = Server::new(None);
+        let cli = reqwest::Client::new();
+        let mut p = HashMap::new();
+        p.insert("username", "slamb");
+        p.insert("password", "hunter2");
+        let resp = cli
+            .post(&format!("{}/api/login", &s.base_url))
+            .json(&p)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let cookie = SessionCookie::new(resp.headers());
+        let tokens_url = format!("{}/api/tokens", &s.base_url);
+
+        // A token can't have permissions beyond those of its creator.
+        let resp = cli
+            .post(&tokens_url)
+            .header(reqwest::header::COOKIE, cookie.header())
+            .json(&serde_json::json!({"name": "ha", "permissions": {"viewVideo": true}}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+
+        let resp: serde_json::Value = cli
+            .post(&tokens_url)
+            .header(reqwest::header::COOKIE, cookie.header())
+            .json(&serde_json::json!({"name": "ha"}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let id = resp.get("id").unwrap().as_str().unwrap().to_owned();
+        let token = resp.get("token").unwrap().as_str().unwrap().to_owned();
+        let bearer = format!("Bearer {}", token);
+
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .header(reqwest::header::AUTHORIZATION, &bearer)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+        // The token isn't accepted as a cookie, and garbage isn't accepted as a token.
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .header(reqwest::header::COOKIE, format!("s={}", token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .header(reqwest::header::AUTHORIZATION, "Bearer asdf")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        // Once revoked, the token no longer works.
+        let resp = cli
+            .delete(&format!("{}/{}", &tokens_url, &id))
+            .header(reqwest::header::COOKIE, cookie.header())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .header(reqwest::header::AUTHORIZATION, &bearer)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
     }
 
     #[tokio::test]
@@ -1772,6 +3943,67 @@ mod tests {
         assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
     }
 
+    #[tokio::test]
+    async fn per_camera_permissions() {
+        testutil::init();
+        let s = Server::new(None);
+        let cli = reqwest::Client::new();
+        {
+            let mut l = s.db.db.lock();
+            let mut c = l.get_user("slamb").unwrap().change();
+            c.permissions
+                .view_video_cameras
+                .push(s.db.test_camera_uuid.as_bytes().to_vec());
+            l.apply_user_change(c).unwrap();
+            let mut c = db::UserChange::add_user("other".to_owned());
+            c.set_password("hunter2".to_owned());
+            l.apply_user_change(c).unwrap();
+        }
+        let login_url = format!("{}/api/login", &s.base_url);
+        let recordings_url = |uuid| format!("{}/api/cameras/{}/main/recordings", &s.base_url, uuid);
+        for &(username, allowed) in &[("slamb", true), ("other", false)] {
+            let mut p = HashMap::new();
+            p.insert("username", username);
+            p.insert("password", "hunter2");
+            let resp = cli.post(&login_url).json(&p).send().await.unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+            let cookie = SessionCookie::new(resp.headers());
+
+            let resp = cli
+                .get(&recordings_url(s.db.test_camera_uuid))
+                .header(reqwest::header::COOKIE, cookie.header())
+                .send()
+                .await
+                .unwrap();
+            let expected = if allowed {
+                reqwest::StatusCode::OK
+            } else {
+                reqwest::StatusCode::FORBIDDEN
+            };
+            assert_eq!(resp.status(), expected);
+            let resp = cli
+                .get(&recordings_url(uuid::Uuid::nil()))
+                .header(reqwest::header::COOKIE, cookie.header())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+
+            // The top level lists only the viewable camera.
+            let top: serde_json::Value = cli
+                .get(&format!("{}/api/", &s.base_url))
+                .header(reqwest::header::COOKIE, cookie.header())
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            let cameras = top["cameras"].as_array().unwrap();
+            assert_eq!(cameras.len(), if allowed { 1 } else { 0 });
+        }
+    }
+
     #[test]
     fn encode_sid() {
         use super::encode_sid;
@@ -1818,13 +4050,17 @@ mod bench {
             let db = TestDb::new(::base::clock::RealClocks {});
             let test_camera_uuid = db.test_camera_uuid;
             testutil::add_dummy_recordings_to_db(&db.db, 1440);
+            let mut permissions = db::Permissions::new();
+            permissions.view_video = true;
             let service = Arc::new(
                 super::Service::new(super::Config {
                     db: db.db.clone(),
                     ui_dir: None,
-                    allow_unauthenticated_permissions: Some(db::Permissions::default()),
+                    export_dir: &db.tmpdir.path().join("exports"),
+                    allow_unauthenticated_permissions: Some(permissions),
                     trust_forward_hdrs: false,
                     time_zone_name: "".to_owned(),
+                    supervisor: None,
                 })
                 .unwrap(),
             );
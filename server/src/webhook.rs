@@ -0,0 +1,325 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Delivery of webhook events queued by `db::webhook`.
+//!
+//! Each event is POSTed as JSON with three extra headers: `X-Moonfire-Delivery`, an id which stays
+//! the same across retries of the same delivery; `X-Moonfire-Timestamp`, the time of this attempt
+//! in seconds since the epoch; and `X-Moonfire-Signature`, `sha256=` followed by the hex-encoded
+//! HMAC-SHA256 of the timestamp, a `.`, and the body, keyed by the webhook's secret. Signing the
+//! timestamp lets receivers reject replayed requests.
+//!
+//! Deliveries to different webhooks are sent concurrently, so a slow or unreachable webhook
+//! doesn't hold up the others. Each webhook's due deliveries are attempted one at a time.
+
+use base::clock::Clocks;
+use db::webhook::Delivery;
+use failure::Error;
+use fnv::FnvHashMap;
+use log::{info, warn};
+use ring::hmac;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+/// The maximum number of deliveries to look up at once.
+const BATCH_SIZE: usize = 16;
+
+/// How often to check for deliveries which are due to be retried.
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(5);
+
+/// How long to wait for a webhook's response.
+const TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+/// Returns the `X-Moonfire-Signature` header value for `payload` sent at `timestamp_sec`.
+pub fn signature(secret: &str, timestamp_sec: i64, payload: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let mut ctx = hmac::Context::with_key(&key);
+    ctx.update(timestamp_sec.to_string().as_bytes());
+    ctx.update(b".");
+    ctx.update(payload.as_bytes());
+    format!("sha256={}", base::strutil::hex(ctx.sign().as_ref()))
+}
+
+/// Starts delivering webhook events, including ones queued before the last shutdown.
+/// Must be called from within a tokio runtime.
+pub fn start<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+) -> Result<tokio::task::JoinHandle<()>, Error> {
+    let deliverer = Deliverer::new(db)?;
+    Ok(tokio::spawn(async move { deliverer.run().await }))
+}
+
+struct Deliverer<C: Clocks + Clone> {
+    db: Arc<db::Database<C>>,
+    client: reqwest::Client,
+}
+
+impl<C: Clocks + Clone> Deliverer<C> {
+    fn new(db: Arc<db::Database<C>>) -> Result<Self, Error> {
+        Ok(Deliverer {
+            db,
+            client: reqwest::Client::builder().timeout(TIMEOUT).build()?,
+        })
+    }
+
+    async fn run(&self) {
+        info!("Starting webhook delivery.");
+        let notify = self.db.lock().webhook_notify();
+        loop {
+            while self.deliver_due().await {}
+            tokio::select! {
+                _ = notify.notified() => {},
+                _ = tokio::time::sleep(POLL_INTERVAL) => {},
+            }
+        }
+    }
+
+    /// Attempts a batch of the deliveries which are due, returning true if there may be more.
+    async fn deliver_due(&self) -> bool {
+        let now_sec = self.db.clocks().realtime().sec;
+        let due = self
+            .db
+            .lock()
+            .list_due_webhook_deliveries(now_sec, BATCH_SIZE);
+        let due = match due {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("Unable to list webhook deliveries: {}", e);
+                return false;
+            }
+        };
+        let mut by_webhook: FnvHashMap<i32, Vec<&Delivery>> = FnvHashMap::default();
+        for d in &due {
+            by_webhook.entry(d.webhook_id).or_default().push(d);
+        }
+        let ok = futures::future::join_all(by_webhook.values().map(|ds| self.deliver_all(ds)))
+            .await
+            .into_iter()
+            .all(|ok| ok);
+        ok && due.len() == BATCH_SIZE
+    }
+
+    /// Attempts the given deliveries to a single webhook in order, returning false if a result
+    /// couldn't be recorded.
+    async fn deliver_all(&self, deliveries: &[&Delivery]) -> bool {
+        for d in deliveries {
+            let result = self.send(d).await;
+            if let Err(ref e) = result {
+                warn!(
+                    "Unable to deliver event {} to webhook {} ({}): {}",
+                    d.id, d.webhook_id, &d.url, e
+                );
+            }
+            let failed = result.is_err();
+            let now_sec = self.db.clocks().realtime().sec;
+            let finished = self.db.lock().finish_webhook_delivery(d, now_sec, result);
+            match finished {
+                Ok(true) if failed => warn!(
+                    "Giving up on event {} to webhook {} after {} attempts",
+                    d.id,
+                    d.webhook_id,
+                    d.attempts + 1
+                ),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Unable to record webhook delivery {}: {}", d.id, e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    async fn send(&self, d: &Delivery) -> Result<(), String> {
+        let timestamp_sec = self.db.clocks().realtime().sec;
+        let resp = self
+            .client
+            .post(&d.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Moonfire-Delivery", d.id.to_string())
+            .header("X-Moonfire-Timestamp", timestamp_sec.to_string())
+            .header(
+                "X-Moonfire-Signature",
+                signature(&d.secret, timestamp_sec, &d.payload),
+            )
+            .body(d.payload.clone())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !resp.status().is_success() {
+            return Err(format!("unexpected status {}", resp.status()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::clock;
+    use db::{recording, testutil, webhook};
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, StatusCode};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// The headers and body of a request received by [listen].
+    struct Received {
+        delivery: String,
+        timestamp: String,
+        sig: String,
+        body: String,
+    }
+
+    /// Returns headers and body of each request received by a local HTTP listener. The first
+    /// request fails with a server error; later ones succeed.
+    fn listen() -> (std::net::SocketAddr, mpsc::UnboundedReceiver<Received>) {
+        let (tx, rx) = mpsc::unbounded();
+        let failed = Arc::new(AtomicBool::new(false));
+        let make_svc = make_service_fn(move |_conn| {
+            let tx = tx.clone();
+            let failed = failed.clone();
+            futures::future::ok::<_, std::convert::Infallible>(service_fn(
+                move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    let failed = failed.clone();
+                    async move {
+                        let hdr = |name| {
+                            req.headers()
+                                .get(name)
+                                .map(|v| v.to_str().unwrap().to_owned())
+                                .unwrap_or_default()
+                        };
+                        let delivery = hdr("X-Moonfire-Delivery");
+                        let timestamp = hdr("X-Moonfire-Timestamp");
+                        let sig = hdr("X-Moonfire-Signature");
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let body = String::from_utf8(body.to_vec()).unwrap();
+                        tx.unbounded_send(Received {
+                            delivery,
+                            timestamp,
+                            sig,
+                            body,
+                        })
+                        .unwrap();
+                        let status = if failed.swap(true, Ordering::SeqCst) {
+                            StatusCode::OK
+                        } else {
+                            StatusCode::INTERNAL_SERVER_ERROR
+                        };
+                        Ok::<_, std::convert::Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                },
+            ))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn deliver_and_retry() {
+        testutil::init();
+        let clocks = clock::SimulatedClocks::new(time::Timespec::new(1_600_000_000, 0));
+        let tdb = testutil::TestDb::new(clocks.clone());
+        let (addr, mut rx) = listen();
+        let secret = {
+            let mut l = tdb.db.lock();
+            let id = l
+                .add_webhook(
+                    format!("http://{}/hook", addr),
+                    webhook::Filter {
+                        stream_health: true,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            l.enqueue_webhook_event(webhook::Event::StreamHealth {
+                stream_id: testutil::TEST_STREAM_ID,
+                healthy: false,
+                error: Some("connection refused".to_owned()),
+                time: recording::Time::new(clocks.realtime()),
+            });
+            l.webhooks_by_id().get(&id).unwrap().secret.clone()
+        };
+        let d = Deliverer::new(tdb.db.clone()).unwrap();
+
+        // The first attempt fails, so the event isn't due again until after a delay.
+        assert!(!d.deliver_due().await);
+        let first = rx.next().await.unwrap();
+        assert_eq!(first.timestamp, "1600000000");
+        assert_eq!(first.sig, signature(&secret, 1_600_000_000, &first.body));
+        assert_ne!(first.sig, signature(&secret, 1_600_000_001, &first.body));
+        let p: serde_json::Value = serde_json::from_str(&first.body).unwrap();
+        assert_eq!(p["type"], "streamHealth");
+        assert_eq!(p["cameraUuid"], tdb.test_camera_uuid.to_string());
+        assert!(!d.deliver_due().await);
+        assert!(rx.try_next().is_err());
+
+        // The retry succeeds with the same delivery id, signed with the new timestamp.
+        clocks.sleep(time::Duration::seconds(60));
+        assert!(!d.deliver_due().await);
+        let retry = rx.next().await.unwrap();
+        assert_eq!(retry.delivery, first.delivery);
+        assert_eq!(retry.body, first.body);
+        assert_eq!(retry.timestamp, "1600000060");
+        assert_eq!(retry.sig, signature(&secret, 1_600_000_060, &retry.body));
+        assert!(tdb
+            .db
+            .lock()
+            .list_due_webhook_deliveries(i64::max_value(), 10)
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Tests that a webhook which doesn't respond doesn't hold up deliveries to other webhooks.
+    #[tokio::test]
+    async fn deliver_concurrently() {
+        testutil::init();
+        let clocks = clock::SimulatedClocks::new(time::Timespec::new(1_600_000_000, 0));
+        let tdb = testutil::TestDb::new(clocks.clone());
+
+        // This listener accepts connections but never responds.
+        let stalled = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled_addr = stalled.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            loop {
+                conns.push(stalled.accept().await.unwrap());
+            }
+        });
+        let (addr, mut rx) = listen();
+        {
+            let mut l = tdb.db.lock();
+            for addr in &[stalled_addr, addr] {
+                l.add_webhook(
+                    format!("http://{}/hook", addr),
+                    webhook::Filter {
+                        stream_health: true,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            }
+            l.enqueue_webhook_event(webhook::Event::StreamHealth {
+                stream_id: testutil::TEST_STREAM_ID,
+                healthy: false,
+                error: None,
+                time: recording::Time::new(clocks.realtime()),
+            });
+        }
+        let d = Deliverer::new(tdb.db.clone()).unwrap();
+        tokio::select! {
+            _ = d.deliver_due() => panic!("delivery to the stalled webhook finished"),
+            r = rx.next() => assert!(r.is_some()),
+        }
+    }
+}